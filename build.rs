@@ -0,0 +1,32 @@
+//! Records the wasm3 build options in effect, so the provider can report
+//! them. wasm3's options are C preprocessor defines, which the `cc` crate
+//! building `wasm3-sys` reads from the same `CFLAGS` variables checked here.
+
+use std::env;
+
+fn main() {
+    let target = env::var("TARGET").unwrap_or_default();
+    let host = env::var("HOST").unwrap_or_default();
+    let kind = if target == host { "HOST" } else { "TARGET" };
+    // In the order the cc crate looks for them; it uses the first one set
+    let names = [
+        format!("CFLAGS_{}", target),
+        format!("CFLAGS_{}", target.replace('-', "_")),
+        format!("{}_CFLAGS", kind),
+        "CFLAGS".to_owned(),
+    ];
+    for name in &names {
+        println!("cargo:rerun-if-env-changed={}", name);
+    }
+    let flags = names
+        .iter()
+        .find_map(|name| env::var(name).ok())
+        .unwrap_or_default();
+    let defines: Vec<&str> = flags
+        .split_whitespace()
+        .filter_map(|flag| flag.strip_prefix("-D"))
+        .filter(|define| define.starts_with("d_m3"))
+        .collect();
+    println!("cargo:rustc-env=WASM3_BUILD_DEFINES={}", defines.join(" "));
+    println!("cargo:rustc-env=WASM3_BUILD_TARGET={}", target);
+}
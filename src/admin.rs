@@ -0,0 +1,321 @@
+//! The admin API: a Unix socket on the node that operators manage the
+//! provider through, without going through the Kubernetes API, so it still
+//! works when the API server cannot be reached. `wasm3-provider ctl` is its
+//! client.
+//!
+//! Each connection carries one request and one response, each a line of
+//! JSON. A request names its command in `command`:
+//!
+//! | Command | Does |
+//! | --- | --- |
+//! | `pods` | Lists the pods the provider knows about |
+//! | `handles` | Dumps the state of each container's instance, as `wasm3-debug` does |
+//! | `reconcile` | Reconciles the provider's pods with the API server now |
+//! | `rotate-logs` | Starts a new log file for each running container |
+//! | `events` | Lists the lifecycle steps recorded for a pod |
+//!
+//! `handles` and `rotate-logs` take an optional `pod`, as
+//! `<namespace>/<name>`, to act on one pod, and `events` a required one. The
+//! response holds the command's `result`, or an `error`.
+//!
+//! The socket is created readable and writable by its owner only, so only
+//! the user the provider runs as, and root, can use it.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{debug, error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::output::ContainerOutput;
+use crate::{lifecycle_log, log_files, reconcile, SharedPodState};
+
+/// The longest request read, so a client cannot make the provider buffer
+/// without end.
+const MAX_REQUEST: u64 = 64 * 1024;
+
+/// The length of a pod UID, which is a hyphenated UUID.
+const UID_LEN: usize = 36;
+
+/// A request to the admin API.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Pods,
+    Handles {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pod: Option<String>,
+    },
+    Reconcile,
+    RotateLogs {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pod: Option<String>,
+    },
+    Events {
+        pod: String,
+    },
+}
+
+#[derive(Deserialize, Serialize)]
+struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Sends `request` to the admin API listening on `socket` and returns its
+/// result.
+pub async fn call(socket: &Path, request: &Request) -> anyhow::Result<serde_json::Value> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .map_err(|e| anyhow::anyhow!("unable to connect to {}: {}", socket.display(), e))?;
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response).await?;
+    let response: Response = serde_json::from_str(&response)
+        .map_err(|e| anyhow::anyhow!("invalid response from the provider: {}", e))?;
+    match (response.result, response.error) {
+        (_, Some(error)) => Err(anyhow::anyhow!(error)),
+        (result, None) => Ok(result.unwrap_or(serde_json::Value::Null)),
+    }
+}
+
+/// Serves the admin API on `socket` until the process exits.
+pub(crate) async fn serve(shared: SharedPodState, socket: PathBuf) {
+    // A socket left by an earlier run would make binding fail
+    match std::fs::remove_file(&socket) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Unable to remove old admin socket: {:?}", e),
+    }
+    let mut listener = match bind(&socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(
+                "Unable to serve the admin API on {}: {:?}",
+                socket.display(),
+                e
+            );
+            return;
+        }
+    };
+    info!("Serving the admin API on {}", socket.display());
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Unable to accept admin connection: {:?}", e);
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(&shared, stream).await {
+                debug!("Admin connection failed: {:?}", e);
+            }
+        });
+    }
+}
+
+fn bind(socket: &Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(dir) = socket.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let listener = UnixListener::bind(socket)?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+async fn handle(shared: &SharedPodState, stream: UnixStream) -> anyhow::Result<()> {
+    let (read, mut write) = tokio::io::split(stream);
+    let mut line = String::new();
+    BufReader::new(read.take(MAX_REQUEST))
+        .read_line(&mut line)
+        .await?;
+    let result = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => {
+            debug!("Admin request: {:?}", request);
+            run(shared, request).await
+        }
+        Err(e) => Err(anyhow::anyhow!("invalid request: {}", e)),
+    };
+    let response = match result {
+        Ok(result) => Response {
+            result: Some(result),
+            error: None,
+        },
+        Err(e) => Response {
+            result: None,
+            error: Some(format!("{:#}", e)),
+        },
+    };
+    let mut line = serde_json::to_vec(&response)?;
+    line.push(b'\n');
+    write.write_all(&line).await?;
+    Ok(())
+}
+
+async fn run(shared: &SharedPodState, request: Request) -> anyhow::Result<serde_json::Value> {
+    match request {
+        Request::Pods => Ok(pods(shared).await),
+        Request::Handles { pod } => handles(shared, pod.as_deref()).await,
+        Request::Reconcile => {
+            reconcile::reconcile(shared).await?;
+            Ok(serde_json::Value::Null)
+        }
+        Request::RotateLogs { pod } => rotate_logs(shared, pod.as_deref()).await,
+        Request::Events { pod } => events(shared, &pod).await,
+    }
+}
+
+/// The pod key of `<namespace>/<name>`, or of `<name>` in the default
+/// namespace.
+fn pod_key(pod: &str) -> anyhow::Result<String> {
+    let (namespace, name) = parse_pod(pod)?;
+    Ok(kubelet::pod::pod_key(namespace, name))
+}
+
+/// The namespace and name of `<namespace>/<name>`, or of `<name>` in the
+/// default namespace.
+fn parse_pod(pod: &str) -> anyhow::Result<(&str, &str)> {
+    let (namespace, name) = match pod.find('/') {
+        Some(i) => (&pod[..i], &pod[i + 1..]),
+        None => ("default", pod),
+    };
+    if namespace.is_empty() || name.is_empty() {
+        return Err(anyhow::anyhow!(
+            "invalid pod {:?}, expected <namespace>/<name>",
+            pod
+        ));
+    }
+    Ok((namespace, name))
+}
+
+async fn pods(shared: &SharedPodState) -> serde_json::Value {
+    let known = shared.known_pods.read().await.clone();
+    let mut pods = Vec::new();
+    for (key, uid) in known {
+        pods.push(serde_json::json!({
+            "key": key,
+            "uid": uid,
+            "running": shared.handles.get(&key).await.is_some(),
+            "static": shared.static_pods.contains(&key),
+        }));
+    }
+    pods.sort_by(|a, b| a["key"].as_str().cmp(&b["key"].as_str()));
+    serde_json::Value::Array(pods)
+}
+
+async fn handles(shared: &SharedPodState, pod: Option<&str>) -> anyhow::Result<serde_json::Value> {
+    let key = pod.map(pod_key).transpose()?;
+    let states = shared.debug_states.read().await;
+    let mut dump = serde_json::Map::new();
+    for (pod_key, containers) in states.iter() {
+        if key.as_ref().map_or(false, |key| key != pod_key) {
+            continue;
+        }
+        let containers: serde_json::Map<_, _> = containers
+            .iter()
+            .map(|(name, state)| (name.clone(), state.dump(name).into()))
+            .collect();
+        dump.insert(pod_key.clone(), containers.into());
+    }
+    if let (Some(pod), true) = (pod, dump.is_empty()) {
+        return Err(anyhow::anyhow!("pod {} has no running containers", pod));
+    }
+    Ok(dump.into())
+}
+
+/// Starts a new log file for each running container, as a restart would,
+/// and returns how many were rotated. Old files are kept as the log
+/// retention allows.
+async fn rotate_logs(
+    shared: &SharedPodState,
+    pod: Option<&str>,
+) -> anyhow::Result<serde_json::Value> {
+    let key = pod.map(pod_key).transpose()?;
+    let outputs: Vec<(Arc<ContainerOutput>, PathBuf)> = {
+        let states = shared.debug_states.read().await;
+        states
+            .iter()
+            .filter(|(pod_key, _)| key.as_ref().map_or(true, |key| key == *pod_key))
+            .flat_map(|(_, containers)| containers.values())
+            .filter_map(|state| state.output())
+            .collect()
+    };
+    let retention = shared.config.log_retention;
+    let rotated = tokio::task::spawn_blocking(move || -> anyhow::Result<usize> {
+        let mut rotated = 0;
+        for (output, log) in outputs {
+            let dir = match log.parent() {
+                Some(dir) => dir,
+                None => continue,
+            };
+            let path = log_files::create_instance_log(dir, retention)?;
+            let file = std::fs::OpenOptions::new().append(true).open(&path)?;
+            output.rotate(file)?;
+            rotated += 1;
+        }
+        Ok(rotated)
+    })
+    .await??;
+    info!("Rotated the logs of {} containers", rotated);
+    Ok(serde_json::json!({ "rotated": rotated }))
+}
+
+/// The lifecycle steps recorded for `pod`, oldest first. A pod that is gone
+/// is looked for in the retained logs, where the latest pod of the name is
+/// used.
+async fn events(shared: &SharedPodState, pod: &str) -> anyhow::Result<serde_json::Value> {
+    let (namespace, name) = parse_pod(pod)?;
+    let live = shared.log_path.join(namespace).join(name);
+    let retained = shared.retained_log_path.join(namespace);
+    let (pod, name) = (pod.to_owned(), name.to_owned());
+    let records = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let dir = if live.is_dir() {
+            live
+        } else {
+            latest_retained(&retained, &name)?
+                .ok_or_else(|| anyhow::anyhow!("no logs found for pod {}", pod))?
+        };
+        Ok(lifecycle_log::read(&lifecycle_log::events_file(&dir))?)
+    })
+    .await??;
+    Ok(serde_json::Value::Array(records))
+}
+
+/// The most recently retained log directory of a pod named `name`, which are
+/// named `<name>-<uid>`. This does blocking IO.
+fn latest_retained(dir: &Path, name: &str) -> std::io::Result<Option<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let prefix = format!("{}-", name);
+    let mut latest = None;
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        // Another pod's name can start with this one's, but a UID is always
+        // the rest
+        let is_pod = file_name.to_str().map_or(false, |file_name| {
+            file_name.starts_with(&prefix) && file_name.len() - prefix.len() == UID_LEN
+        });
+        if !is_pod {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        if latest.as_ref().map_or(true, |(time, _)| modified > *time) {
+            latest = Some((modified, entry.path()));
+        }
+    }
+    Ok(latest.map(|(_, path)| path))
+}
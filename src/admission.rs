@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, PatchParams};
+use kubelet::pod::{key_from_pod, Pod};
+use log::{error, info};
+use tokio::sync::RwLock;
+
+use crate::events::EventRecorder;
+use crate::handles;
+use crate::qos::{self, QosClass};
+use crate::SharedPodState;
+
+/// A pod that has been admitted to run on this node.
+#[derive(Clone)]
+pub(crate) struct AdmittedPod {
+    priority: i32,
+    qos: QosClass,
+    pod: Pod,
+    /// Set once the node has started stopping the pod. It keeps its slot
+    /// until its instances have exited, but is not picked to make room again.
+    stopping: bool,
+}
+
+impl AdmittedPod {
+    fn new(pod: &Pod) -> Self {
+        AdmittedPod {
+            priority: pod_priority(pod),
+            qos: qos::qos_class(pod),
+            pod: pod.clone(),
+            stopping: false,
+        }
+    }
+
+    /// The pod's key.
+    pub(crate) fn key(&self) -> String {
+        key_from_pod(&self.pod)
+    }
+}
+
+/// Tracks the pods admitted to this node against its pod capacity.
+pub(crate) struct Admission {
+    max_pods: usize,
+    admitted: RwLock<HashMap<String, AdmittedPod>>,
+}
+
+/// The priority of a pod. The priority admission controller resolves
+/// `priorityClassName` into `spec.priority`, so that is all we need to read.
+fn pod_priority(pod: &Pod) -> i32 {
+    pod.as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|s| s.priority)
+        .unwrap_or(0)
+}
+
+impl Admission {
+    pub(crate) fn new(max_pods: usize) -> Self {
+        Admission {
+            max_pods,
+            admitted: Default::default(),
+        }
+    }
+
+    /// Admits a pod, returning `None` once it has a slot. If the node is
+    /// full, the lowest priority pod with a priority below this one is marked
+    /// as stopping and returned so the caller can preempt it. Among pods of
+    /// the same priority the one with the lowest QoS class goes first. The
+    /// victim's slot is only freed once its instances have exited, so the
+    /// pod is not admitted yet: call this again after the preemption.
+    /// Returns an error if there is no room.
+    pub(crate) async fn admit(&self, pod: &Pod) -> anyhow::Result<Option<AdmittedPod>> {
+        let key = key_from_pod(pod);
+        let mut candidate = AdmittedPod::new(pod);
+        let priority = candidate.priority;
+        let mut admitted = self.admitted.write().await;
+        if let Some(existing) = admitted.get(&key) {
+            candidate.stopping = existing.stopping;
+        }
+        if admitted.contains_key(&key) || admitted.len() < self.max_pods {
+            admitted.insert(key, candidate);
+            return Ok(None);
+        }
+
+        let victim_key = admitted
+            .iter()
+            .filter(|(_, p)| !p.stopping && p.priority < priority)
+            .min_by_key(|(_, p)| (p.priority, p.qos))
+            .map(|(k, _)| k.clone())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Node is at capacity of {} pods and no running pod has a priority lower than {}",
+                    self.max_pods,
+                    priority
+                )
+            })?;
+        Ok(admitted.get_mut(&victim_key).map(|victim| {
+            victim.stopping = true;
+            victim.clone()
+        }))
+    }
+
+    /// Returns true if the pod holds a slot, as it does from when it is first
+    /// admitted until it finishes, including while it is being restarted.
+    pub(crate) async fn is_admitted(&self, key: &str) -> bool {
+        self.admitted.read().await.contains_key(key)
+    }
+
+    /// Releases the slot held by a pod.
+    pub(crate) async fn release(&self, key: &str) {
+        self.admitted.write().await.remove(key);
+    }
+
+    /// Marks a pod as stopping, so it is not picked to make room again while
+    /// it keeps its slot until its instances have exited.
+    async fn mark_stopping(&self, key: &str) {
+        if let Some(pod) = self.admitted.write().await.get_mut(key) {
+            pod.stopping = true;
+        }
+    }
+
+    /// Returns true if a pod the node stopped still holds its slot because
+    /// its instances have not exited yet.
+    pub(crate) async fn any_stopping(&self) -> bool {
+        self.admitted.read().await.values().any(|p| p.stopping)
+    }
+
+    /// Returns the pods currently admitted with their QoS class, in the
+    /// order they should be evicted: by QoS class, then by priority. Pods
+    /// already being stopped are left out.
+    pub(crate) async fn eviction_order(&self) -> Vec<(Pod, QosClass)> {
+        let admitted = self.admitted.read().await;
+        let mut pods: Vec<&AdmittedPod> = admitted.values().filter(|p| !p.stopping).collect();
+        pods.sort_by_key(|p| (p.qos, p.priority));
+        pods.into_iter().map(|p| (p.pod.clone(), p.qos)).collect()
+    }
+}
+
+/// Stops a pod that was picked to make room for `preemptor` and marks it
+/// failed. Returns whether its instances exited, freeing its slot, in time
+/// for `preemptor` to take it, see [`stop_pod`].
+pub(crate) async fn preempt(shared: &SharedPodState, victim: AdmittedPod, preemptor: &Pod) -> bool {
+    let victim_pod = victim.pod;
+    let key = key_from_pod(&victim_pod);
+    info!(
+        "Preempting pod {} (priority {}, {}) for pod {}",
+        key,
+        victim.priority,
+        victim.qos.as_str(),
+        key_from_pod(preemptor)
+    );
+    let message = format!(
+        "Preempted in order to admit critical pod {}/{}",
+        preemptor.namespace(),
+        preemptor.name()
+    );
+    stop_pod(shared, &victim_pod, "Preempting", "Preempted", &message).await
+}
+
+/// Stops a pod the node will no longer run, records `event` on it and marks it
+/// failed with `reason`. The pod keeps its slot until its instances have
+/// exited, and is not picked to make room again meanwhile. wasm3 cannot
+/// interrupt a module, so an instance running `_start` or a call exits when
+/// that returns. Returns whether every instance exited within
+/// [`handles::STOP_TIMEOUT`]. If not, the slot is released in the background
+/// once they do.
+pub(crate) async fn stop_pod(
+    shared: &SharedPodState,
+    pod: &Pod,
+    event: &str,
+    reason: &str,
+    message: &str,
+) -> bool {
+    let key = key_from_pod(pod);
+    shared.admission.mark_stopping(&key).await;
+    // A container that exits while the pod is stopped is not started again
+    shared.deleted.write().await.insert(crate::pod_uid(pod));
+    let client = kube::Client::new(shared.kubeconfig.clone());
+    EventRecorder::new(client.clone(), pod, shared)
+        .normal(event, message)
+        .await;
+
+    let release = {
+        let admission = shared.admission.clone();
+        let key = key.clone();
+        async move { admission.release(&key).await }
+    };
+    let stopped = match shared.handles.get(&key).await {
+        Some(handle) => {
+            handles::stop(
+                handle,
+                &key,
+                handles::STOP_TIMEOUT,
+                shared.clock.as_ref(),
+                release,
+            )
+            .await
+        }
+        None => {
+            release.await;
+            true
+        }
+    };
+
+    let api: Api<KubePod> = Api::namespaced(client, pod.namespace());
+    let status = serde_json::json!({
+        "metadata": {
+            "resourceVersion": "",
+        },
+        "status": {
+            "phase": "Failed",
+            "reason": reason,
+            "message": message,
+        }
+    });
+    match serde_json::to_vec(&status) {
+        Ok(patch) => {
+            if let Err(e) = api
+                .patch_status(pod.name(), &PatchParams::default(), patch)
+                .await
+            {
+                error!("Unable to mark pod {} as {}: {:?}", key, reason, e);
+            }
+        }
+        Err(e) => error!("Unable to serialize status for pod {}: {:?}", key, e),
+    }
+    stopped
+}
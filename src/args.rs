@@ -0,0 +1,114 @@
+//! Expansion of pod metadata into module arguments, for pods with the
+//! `wasm3.krustlet.dev/arg-templates` annotation set to `true`. Modules cannot
+//! run an init script to work out who they are, so an argument such as
+//! `--id={podName}.{namespace}` is expanded by the provider before it becomes
+//! part of the module's WASI argv.
+//!
+//! The fields are `podName`, `namespace`, `podUID`, `nodeName`,
+//! `containerName`, `labels.<key>` and `annotations.<key>`. `{{` and `}}` stand
+//! for literal braces. An unknown field or a missing label or annotation fails
+//! the container rather than passing on a half expanded argument.
+
+use kubelet::pod::Pod;
+
+/// Expands the fields in each of `args` for a container of `pod` running on
+/// `node_name`.
+pub(crate) fn expand(
+    args: &[String],
+    pod: &Pod,
+    container: &str,
+    node_name: &str,
+) -> anyhow::Result<Vec<String>> {
+    args.iter()
+        .map(|arg| expand_arg(arg, pod, container, node_name))
+        .collect()
+}
+
+fn expand_arg(arg: &str, pod: &Pod, container: &str, node_name: &str) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(arg.len());
+    let mut rest = arg;
+    while let Some(i) = rest.find(|c| c == '{' || c == '}') {
+        expanded.push_str(&rest[..i]);
+        let brace = &rest[i..i + 1];
+        rest = &rest[i + 1..];
+        // A doubled brace is a literal one
+        if rest.starts_with(brace) {
+            expanded.push_str(brace);
+            rest = &rest[1..];
+            continue;
+        }
+        if brace == "}" {
+            return Err(anyhow::anyhow!("unmatched }} in argument {:?}", arg));
+        }
+        let end = rest
+            .find('}')
+            .ok_or_else(|| anyhow::anyhow!("unterminated {{ in argument {:?}", arg))?;
+        let field = rest[..end].trim();
+        expanded.push_str(&lookup(field, pod, container, node_name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "argument {:?} uses {{{}}}, which has no value for the pod",
+                arg,
+                field
+            )
+        })?);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn lookup(field: &str, pod: &Pod, container: &str, node_name: &str) -> Option<String> {
+    if let Some(key) = field.strip_prefix("labels.") {
+        return pod.labels().get(key).cloned();
+    }
+    if let Some(key) = field.strip_prefix("annotations.") {
+        return pod.annotations().get(key).cloned();
+    }
+    match field {
+        "podName" => Some(pod.name().to_owned()),
+        "namespace" => Some(pod.namespace().to_owned()),
+        "podUID" => Some(crate::pod_uid(pod)),
+        "nodeName" => Some(node_name.to_owned()),
+        "containerName" => Some(container.to_owned()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pod() -> Pod {
+        let pod: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": "hello",
+                "namespace": "edge",
+                "uid": "1234",
+                "labels": { "app": "greeter" },
+            },
+        }))
+        .unwrap();
+        Pod::new(pod)
+    }
+
+    #[test]
+    fn expands_fields_and_literal_braces() {
+        let args = vec![
+            "--id={podName}.{namespace}".to_owned(),
+            "{{{labels.app}}}".to_owned(),
+            "{ containerName }@{nodeName}".to_owned(),
+        ];
+        assert_eq!(
+            expand(&args, &pod(), "main", "node-1").unwrap(),
+            vec!["--id=hello.edge", "{greeter}", "main@node-1"]
+        );
+    }
+
+    #[test]
+    fn missing_fields_fail() {
+        let pod = pod();
+        for arg in &["{labels.tier}", "{unknown}", "{podName", "podName}"] {
+            assert!(expand(&[arg.to_string()], &pod, "main", "node-1").is_err());
+        }
+    }
+}
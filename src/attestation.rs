@@ -0,0 +1,158 @@
+//! Execution reports for edge security audits. With `WASM3_ATTESTATION`
+//! set, every pod is given the `wasm3.krustlet.dev/attestation` annotation
+//! once its containers have started, and again when its modules are
+//! reloaded. The annotation is a JSON record of what the node ran: the
+//! provider and wasm3 versions, and for each container the image it was
+//! pulled by digest, the SHA-256 digest of the module bytes it runs, and the
+//! capabilities it was given.
+//!
+//! With `WASM3_ATTESTATION_KEY_FILE` set, the record also carries a
+//! `signature`, an HMAC-SHA256 over the record without it, serialized as
+//! compact JSON with keys in sorted order, under the key in the file. An
+//! auditor who holds the key recomputes it to check that the record came
+//! from the node and was not changed since.
+
+use std::path::Path;
+
+use hmac::{Hmac, Mac, NewMac};
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, PatchParams};
+use kubelet::pod::Pod;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+
+use crate::build_info;
+use crate::wasi_runtime::ModuleData;
+
+/// Annotation holding the execution report.
+const ATTESTATION_ANNOTATION: &str = "wasm3.krustlet.dev/attestation";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What one container of a pod runs.
+pub(crate) struct ContainerReport {
+    pub(crate) name: String,
+    /// The image by digest, if it was pulled from a registry
+    pub(crate) image_id: Option<String>,
+    /// The module bytes the container runs
+    pub(crate) module: ModuleData,
+    pub(crate) wasi_profile: String,
+    /// Capabilities the container was given beyond its WASI profile, such
+    /// as `kv` or `read-only-root`
+    pub(crate) capabilities: Vec<&'static str>,
+}
+
+/// Writes execution reports to pods.
+#[derive(Clone)]
+pub(crate) struct Attestor {
+    node_name: String,
+    /// The key records are signed with, if they are
+    key: Option<Vec<u8>>,
+}
+
+impl Attestor {
+    /// Creates an attestor, reading the signing key from `key_file` if one is
+    /// given.
+    pub(crate) async fn new(node_name: &str, key_file: Option<&Path>) -> anyhow::Result<Self> {
+        let key = match key_file {
+            Some(path) => {
+                let key = tokio::fs::read(path).await.map_err(|e| {
+                    anyhow::anyhow!("unable to read attestation key {}: {}", path.display(), e)
+                })?;
+                if key.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "attestation key {} is empty",
+                        path.display()
+                    ));
+                }
+                Some(key)
+            }
+            None => None,
+        };
+        Ok(Attestor {
+            node_name: node_name.to_owned(),
+            key,
+        })
+    }
+
+    /// The report of `containers`, signed if there is a key.
+    fn record(&self, pod: &Pod, containers: &[ContainerReport]) -> serde_json::Value {
+        let containers: Vec<serde_json::Value> = containers
+            .iter()
+            .map(|c| {
+                serde_json::json!({
+                    "name": c.name,
+                    "imageID": c.image_id,
+                    "moduleDigest": format!("sha256:{}", hex::encode(Sha256::digest(&c.module))),
+                    "wasiProfile": c.wasi_profile,
+                    "capabilities": c.capabilities,
+                })
+            })
+            .collect();
+        let mut record = serde_json::json!({
+            "node": self.node_name,
+            "pod": pod.name(),
+            "namespace": pod.namespace(),
+            "uid": crate::pod_uid(pod),
+            "time": chrono::Utc::now().to_rfc3339(),
+            "providerVersion": build_info::PROVIDER_VERSION,
+            "wasm3Version": build_info::WASM3_VERSION,
+            "wasiVersion": build_info::WASI_VERSION,
+            "containers": containers,
+        });
+        if let Some(key) = &self.key {
+            // serde_json keeps object keys sorted, so this is the form an
+            // auditor gets back by removing the signature
+            let signed = record.to_string();
+            if let Ok(mut mac) = HmacSha256::new_varkey(key) {
+                mac.update(signed.as_bytes());
+                record["signature"] = serde_json::Value::String(format!(
+                    "hmac-sha256:{}",
+                    hex::encode(mac.finalize().into_bytes())
+                ));
+            }
+        }
+        record
+    }
+
+    /// Writes the report of `containers` to the pod's annotation in the
+    /// background. A report that cannot be written is logged and dropped.
+    pub(crate) fn attest(&self, client: kube::Client, pod: &Pod, containers: Vec<ContainerReport>) {
+        let name = pod.name().to_owned();
+        let api: Api<KubePod> = Api::namespaced(client, pod.namespace());
+        let pod = pod.clone();
+        let attestor = self.clone();
+        tokio::spawn(async move {
+            // Hashing the modules takes a moment, so it is kept off the
+            // executor
+            let record =
+                tokio::task::spawn_blocking(move || attestor.record(&pod, &containers)).await;
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Unable to build execution report of pod {}: {:?}", name, e);
+                    return;
+                }
+            };
+            let patch = serde_json::json!({
+                "metadata": {
+                    "annotations": {
+                        ATTESTATION_ANNOTATION: record.to_string(),
+                    }
+                }
+            });
+            let result = match serde_json::to_vec(&patch) {
+                Ok(patch) => api
+                    .patch(&name, &PatchParams::default(), patch)
+                    .await
+                    .map(|_| ())
+                    .map_err(anyhow::Error::from),
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(()) => debug!("Wrote execution report of pod {}", name),
+                Err(e) => warn!("Unable to write execution report of pod {}: {:?}", name, e),
+            }
+        });
+    }
+}
@@ -0,0 +1,253 @@
+//! Authentication and authorization of callers of the health listener's
+//! protected endpoints, configured by [`EndpointAuth`].
+//!
+//! Callers present a bearer token. With `token`, it must be one of the tokens
+//! in the configured file. With `webhook`, the API server authenticates it
+//! with a `TokenReview`, and a `SubjectAccessReview` checks the caller may
+//! `get` the node's subresource for the endpoint, `nodes/metrics` for
+//! `/metrics`, as the kubelet does. Webhook results are cached briefly so a
+//! scraper does not cost two API calls per scrape.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec};
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec,
+};
+use kube::api::{Api, PostParams};
+use log::{debug, error};
+use sha2::{Digest, Sha256};
+use warp::http::StatusCode;
+
+use crate::config::EndpointAuth;
+
+/// How long a webhook decision allowing a caller is reused.
+const ALLOWED_TTL: Duration = Duration::from_secs(120);
+
+/// How long a webhook decision turning a caller away is reused.
+const DENIED_TTL: Duration = Duration::from_secs(30);
+
+/// The most webhook decisions cached before the cache is cleared.
+const MAX_CACHED: usize = 1024;
+
+/// Why a request was turned away.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Denied {
+    /// No token, or one that is not valid
+    Unauthenticated,
+    /// A valid token whose user may not call the endpoint
+    Forbidden,
+    /// The API server could not be asked
+    Unavailable,
+}
+
+impl Denied {
+    pub(crate) fn status(self) -> StatusCode {
+        match self {
+            Denied::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Denied::Forbidden => StatusCode::FORBIDDEN,
+            Denied::Unavailable => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denied::Unauthenticated => write!(f, "Unauthorized"),
+            Denied::Forbidden => write!(f, "Forbidden"),
+            Denied::Unavailable => write!(f, "Unable to authorize the request"),
+        }
+    }
+}
+
+impl warp::reject::Reject for Denied {}
+
+/// Checks the bearer tokens of requests to protected endpoints.
+pub(crate) enum Authenticator {
+    None,
+    Tokens(Vec<String>),
+    Webhook(Webhook),
+}
+
+impl Authenticator {
+    /// Sets up the configured authentication. Token files are read once, so
+    /// the provider must be restarted to pick up new tokens.
+    pub(crate) async fn new(
+        auth: &EndpointAuth,
+        kubeconfig: &kube::Config,
+        node_name: &str,
+    ) -> anyhow::Result<Self> {
+        Ok(match auth {
+            EndpointAuth::None => Authenticator::None,
+            EndpointAuth::Token(path) => {
+                let tokens: Vec<String> = tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|e| {
+                        anyhow::anyhow!("unable to read token file {}: {}", path.display(), e)
+                    })?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(str::to_owned)
+                    .collect();
+                if tokens.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "token file {} holds no tokens",
+                        path.display()
+                    ));
+                }
+                Authenticator::Tokens(tokens)
+            }
+            EndpointAuth::Webhook => Authenticator::Webhook(Webhook {
+                client: kube::Client::new(kubeconfig.clone()),
+                node_name: node_name.to_owned(),
+                cache: Default::default(),
+            }),
+        })
+    }
+
+    /// Checks the `Authorization` header of a request for the node's
+    /// `subresource`.
+    pub(crate) async fn check(
+        &self,
+        authorization: Option<&str>,
+        subresource: &str,
+    ) -> Result<(), Denied> {
+        if let Authenticator::None = self {
+            return Ok(());
+        }
+        let token = authorization
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .ok_or(Denied::Unauthenticated)?;
+        match self {
+            Authenticator::None => Ok(()),
+            Authenticator::Tokens(tokens) => {
+                // Every token is compared in full so the time taken does not
+                // say how close a guess was
+                let matched = tokens
+                    .iter()
+                    .fold(false, |matched, t| constant_time_eq(t, token) | matched);
+                if matched {
+                    Ok(())
+                } else {
+                    Err(Denied::Unauthenticated)
+                }
+            }
+            Authenticator::Webhook(webhook) => webhook.check(token, subresource).await,
+        }
+    }
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Asks the API server who a token belongs to and what they may do.
+pub(crate) struct Webhook {
+    client: kube::Client,
+    node_name: String,
+    /// Decisions keyed by the hash of the token and the subresource, so
+    /// tokens are not kept in memory
+    cache: Mutex<HashMap<(String, String), (Instant, Result<(), Denied>)>>,
+}
+
+impl Webhook {
+    async fn check(&self, token: &str, subresource: &str) -> Result<(), Denied> {
+        let key = (
+            hex::encode(Sha256::digest(token.as_bytes())),
+            subresource.to_owned(),
+        );
+        if let Some((expires, decision)) = self.cache.lock().unwrap().get(&key) {
+            if *expires > Instant::now() {
+                return *decision;
+            }
+        }
+        let decision = match self.review(token, subresource).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                // Not cached, so the next request asks again
+                error!(
+                    "Unable to review a request for nodes/{}: {:?}",
+                    subresource, e
+                );
+                return Err(Denied::Unavailable);
+            }
+        };
+        let ttl = if decision.is_ok() {
+            ALLOWED_TTL
+        } else {
+            DENIED_TTL
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.clear();
+        }
+        cache.insert(key, (Instant::now() + ttl, decision));
+        decision
+    }
+
+    async fn review(&self, token: &str, subresource: &str) -> anyhow::Result<Result<(), Denied>> {
+        let tokens: Api<TokenReview> = Api::all(self.client.clone());
+        let review = TokenReview {
+            spec: TokenReviewSpec {
+                token: Some(token.to_owned()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let status = tokens
+            .create(&PostParams::default(), &review)
+            .await?
+            .status
+            .unwrap_or_default();
+        let user = match status.user {
+            Some(user) if status.authenticated == Some(true) => user,
+            _ => {
+                debug!("Token rejected: {}", status.error.unwrap_or_default());
+                return Ok(Err(Denied::Unauthenticated));
+            }
+        };
+
+        let reviews: Api<SubjectAccessReview> = Api::all(self.client.clone());
+        let username = user.username.clone().unwrap_or_default();
+        let review = SubjectAccessReview {
+            spec: SubjectAccessReviewSpec {
+                user: user.username,
+                groups: user.groups,
+                uid: user.uid,
+                extra: user.extra,
+                resource_attributes: Some(ResourceAttributes {
+                    verb: Some("get".to_owned()),
+                    group: Some(String::new()),
+                    resource: Some("nodes".to_owned()),
+                    subresource: Some(subresource.to_owned()),
+                    name: Some(self.node_name.clone()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let allowed = reviews
+            .create(&PostParams::default(), &review)
+            .await?
+            .status
+            .map_or(false, |s| s.allowed);
+        if allowed {
+            Ok(Ok(()))
+        } else {
+            debug!("User {} may not get nodes/{}", username, subresource);
+            Ok(Err(Denied::Forbidden))
+        }
+    }
+}
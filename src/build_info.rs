@@ -0,0 +1,133 @@
+//! Build and capability information published on the node so operators can
+//! see what each wasm3 node supports.
+
+/// The version of this provider.
+pub const PROVIDER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The version of wasm3 bundled by the wasm3-rs revision pinned in
+/// `Cargo.toml`. Update this alongside the pin.
+pub const WASM3_VERSION: &str = "0.4.7";
+
+/// The WASI version modules are linked against.
+pub const WASI_VERSION: &str = "snapshot_preview1";
+
+/// The target the provider was built for, such as
+/// `armv7-unknown-linux-gnueabihf`.
+pub const BUILD_TARGET: &str = env!("WASM3_BUILD_TARGET");
+
+/// The wasm3 defines this build was compiled with, from the C compiler flags
+/// the build saw, separated by spaces.
+const BUILD_DEFINES: &str = env!("WASM3_BUILD_DEFINES");
+
+/// A wasm3 build option, set as a C define when the provider is built.
+pub struct BuildOption {
+    pub name: &'static str,
+    /// The value wasm3 uses when the option is not set
+    pub default: &'static str,
+    pub description: &'static str,
+}
+
+/// The wasm3 build options worth changing for small devices such as 32-bit
+/// ARM boards. The table in the README is printed from these by
+/// `wasm3-provider build-info --markdown`.
+pub const BUILD_OPTIONS: &[BuildOption] = &[
+    BuildOption {
+        name: "d_m3Use32BitSlots",
+        default: "1",
+        description: "Keeps values in 32-bit stack slots, which halves the stack a module needs",
+    },
+    BuildOption {
+        name: "d_m3CodePageAlignSize",
+        default: "4096",
+        description: "The size in bytes compiled code is allocated in; smaller pages waste less memory on modules with few functions",
+    },
+    BuildOption {
+        name: "d_m3MaxFunctionStackHeight",
+        default: "2000",
+        description: "The most stack slots one function may use; lower it to fail deep functions at load rather than at run time",
+    },
+    BuildOption {
+        name: "d_m3HasFloat",
+        default: "1",
+        description: "Set to 0 on boards without a floating point unit; modules using floats then fail to load",
+    },
+    BuildOption {
+        name: "d_m3FixedHeap",
+        default: "false",
+        description: "Allocates from one static heap of this many bytes instead of the system allocator",
+    },
+];
+
+/// Each of [`BUILD_OPTIONS`] with the value this build used, and whether it
+/// was set rather than left at wasm3's default.
+pub fn build_options() -> Vec<(&'static BuildOption, String, bool)> {
+    BUILD_OPTIONS
+        .iter()
+        .map(|option| {
+            let set = BUILD_DEFINES.split_whitespace().find_map(|define| {
+                let (name, value) = match define.find('=') {
+                    Some(i) => (&define[..i], &define[i + 1..]),
+                    // A bare -D defines the name as 1
+                    None => (define, "1"),
+                };
+                if name == option.name {
+                    Some(value.to_owned())
+                } else {
+                    None
+                }
+            });
+            match set {
+                Some(value) => (option, value, true),
+                None => (option, option.default.to_owned(), false),
+            }
+        })
+        .collect()
+}
+
+const LABEL_PREFIX: &str = "wasm3.krustlet.dev";
+
+/// Node labels describing the runtime. Label values are short enough to be
+/// used in node selectors.
+pub(crate) fn node_labels() -> Vec<(String, String)> {
+    vec![
+        (
+            format!("{}/wasm3-version", LABEL_PREFIX),
+            WASM3_VERSION.to_owned(),
+        ),
+        (
+            format!("{}/wasi-version", LABEL_PREFIX),
+            WASI_VERSION.to_owned(),
+        ),
+    ]
+}
+
+/// Node annotations with the full build and feature details.
+pub(crate) fn node_annotations() -> Vec<(String, String)> {
+    vec![
+        (
+            format!("{}/provider-version", LABEL_PREFIX),
+            PROVIDER_VERSION.to_owned(),
+        ),
+        (format!("{}/features", LABEL_PREFIX), features().join(",")),
+        (
+            format!("{}/build-target", LABEL_PREFIX),
+            BUILD_TARGET.to_owned(),
+        ),
+        (
+            format!("{}/build-options", LABEL_PREFIX),
+            BUILD_DEFINES
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+        (
+            format!("{}/wasi-functions", LABEL_PREFIX),
+            crate::validation::WASI_FUNCTIONS.join(","),
+        ),
+    ]
+}
+
+/// The optional features this build supports.
+fn features() -> Vec<&'static str> {
+    vec!["wasi", "host-metrics", "host-log"]
+}
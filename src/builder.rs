@@ -0,0 +1,306 @@
+//! Building a [`WasiProvider`], for crates that embed the provider and want
+//! to change where it keeps things or run code of their own around the
+//! containers it starts.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use kubelet::pod::Pod;
+use kubelet::store::Store;
+use log::info;
+
+use crate::clock::{Clock, SystemClock};
+use crate::hooks::{Hooks, HostExtension, Stopped};
+use crate::{
+    admin, admission, attestation, auth, burst, cleanup, config, cordon, cpu, credentials,
+    eviction, gateway, health, log_stream, metrics, outbox, prewarm, ratelimit, reconcile,
+    recovery, reload, static_pods, store, webhook,
+};
+use crate::{ProviderConfig, SharedPodState, WasiProvider};
+use crate::{
+    DEAD_LETTER_DIR, KV_DIR, LOG_DIR_NAME, OUTBOX_DIR, RETAINED_LOG_DIR_NAME, SNAPSHOT_DIR,
+    VOLUME_DIR,
+};
+
+/// Builds a [`WasiProvider`], see [`WasiProvider::builder`]. A module store,
+/// a kubelet config and a kubeconfig must be given. Provider specific
+/// settings are read from the environment unless they are given too.
+#[derive(Default)]
+pub struct WasiProviderBuilder {
+    store: Option<Arc<dyn Store + Sync + Send>>,
+    data_dir: Option<PathBuf>,
+    node_name: String,
+    max_pods: u16,
+    kubeconfig: Option<kube::Config>,
+    provider_config: Option<ProviderConfig>,
+    log_path: Option<PathBuf>,
+    clock: Option<Arc<dyn Clock>>,
+    hooks: Hooks,
+}
+
+impl WasiProviderBuilder {
+    /// Sets the store modules are pulled into.
+    pub fn store(mut self, store: Arc<dyn Store + Sync + Send>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Sets the kubelet config, which gives the node's name, its pod limit
+    /// and the data directory the provider keeps its files in.
+    pub fn kubelet_config(mut self, config: &kubelet::config::Config) -> Self {
+        self.data_dir = Some(config.data_dir.clone());
+        self.node_name = config.node_name.clone();
+        self.max_pods = config.max_pods;
+        self
+    }
+
+    /// Sets the kubeconfig the provider talks to the API server with.
+    pub fn kubeconfig(mut self, kubeconfig: kube::Config) -> Self {
+        self.kubeconfig = Some(kubeconfig);
+        self
+    }
+
+    /// Sets the provider specific settings, instead of reading them from the
+    /// environment.
+    pub fn provider_config(mut self, provider_config: ProviderConfig) -> Self {
+        self.provider_config = Some(provider_config);
+        self
+    }
+
+    /// Sets the directory container logs are written to. Defaults to
+    /// `wasi-logs` in the data directory.
+    pub fn log_path(mut self, log_path: impl Into<PathBuf>) -> Self {
+        self.log_path = Some(log_path.into());
+        self
+    }
+
+    /// Sets the clock restart backoff, module deadlines and container log
+    /// timestamps read the time from. Defaults to [`SystemClock`]; tests can
+    /// drive a pod through its restarts with a
+    /// [`SimulatedClock`](crate::clock::SimulatedClock).
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Adds host functions for modules to import, see [`HostExtension`].
+    pub fn host_extension(mut self, extension: impl HostExtension + 'static) -> Self {
+        self.hooks.host_extensions.push(Arc::new(extension));
+        self
+    }
+
+    /// Adds a hook called before each container starts, see
+    /// [`PreStartHook`](crate::hooks::PreStartHook). Hooks are called in the
+    /// order they were added.
+    pub fn pre_start<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Pod, &str) -> anyhow::Result<()> + Send + Sync + 'static,
+    {
+        self.hooks.pre_start.push(Arc::new(hook));
+        self
+    }
+
+    /// Adds a hook called after each container stops, see
+    /// [`PostStopHook`](crate::hooks::PostStopHook). Hooks are called in the
+    /// order they were added.
+    pub fn post_stop<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Pod, &Stopped<'_>) + Send + Sync + 'static,
+    {
+        self.hooks.post_stop.push(Arc::new(hook));
+        self
+    }
+
+    /// Builds the provider and starts its background tasks.
+    pub async fn build(self) -> anyhow::Result<WasiProvider> {
+        let store = self
+            .store
+            .ok_or_else(|| anyhow::anyhow!("provider needs a module store"))?;
+        let data_dir = self
+            .data_dir
+            .ok_or_else(|| anyhow::anyhow!("provider needs a kubelet config"))?;
+        let kubeconfig = self
+            .kubeconfig
+            .ok_or_else(|| anyhow::anyhow!("provider needs a kubeconfig"))?;
+        let provider_config = match self.provider_config {
+            Some(provider_config) => provider_config,
+            None => ProviderConfig::from_env()?,
+        };
+        self.hooks.validate()?;
+        let log_path = self.log_path.unwrap_or_else(|| data_dir.join(LOG_DIR_NAME));
+        let volume_path = data_dir.join(VOLUME_DIR);
+        tokio::fs::create_dir_all(&log_path).await?;
+        tokio::fs::create_dir_all(&volume_path).await?;
+        let kv_path = data_dir.join(KV_DIR);
+        let snapshot_path = data_dir.join(SNAPSHOT_DIR);
+        let dead_letter_path = data_dir.join(DEAD_LETTER_DIR);
+        let outbox_path = data_dir.join(OUTBOX_DIR);
+        {
+            let kv_path = kv_path.clone();
+            let snapshot_path = snapshot_path.clone();
+            let dead_letter_path = dead_letter_path.clone();
+            let outbox_path = outbox_path.clone();
+            tokio::task::spawn_blocking(move || {
+                cleanup::remove_temp_files(&kv_path);
+                cleanup::remove_temp_files(&snapshot_path);
+                cleanup::remove_temp_files(&dead_letter_path);
+                cleanup::remove_temp_files(&outbox_path);
+            })
+            .await?;
+        }
+        if provider_config.low_memory {
+            info!("Running in low memory mode");
+        }
+        let store = match &provider_config.module_source {
+            config::ModuleSource::Registry => match &provider_config.module_cache {
+                Some(cache) => {
+                    // Not the URL itself, which may hold a SAS token
+                    info!("Caching modules in a shared object store");
+                    Arc::new(store::CachingStore::new(store, store::object_store(cache)?))
+                }
+                None => store,
+            },
+            config::ModuleSource::Directory(path) => {
+                info!("Loading modules from directory {}", path.display());
+                Arc::new(store::DirectoryStore::new(path))
+            }
+        };
+        let credentials = match &provider_config.credential_config {
+            Some(path) => credentials::CredentialConfig::load(path).await?,
+            None => Default::default(),
+        };
+        let metrics: Arc<metrics::Metrics> = Default::default();
+        let webhook = match &provider_config.lifecycle_webhook {
+            Some(url) => Some(Arc::new(webhook::Notifier::new(
+                url,
+                &self.node_name,
+                metrics.clone(),
+            )?)),
+            None => None,
+        };
+        let attestor = if provider_config.attestation {
+            info!("Annotating running pods with execution reports");
+            Some(Arc::new(
+                attestation::Attestor::new(
+                    &self.node_name,
+                    provider_config.attestation_key_file.as_deref(),
+                )
+                .await?,
+            ))
+        } else {
+            None
+        };
+        let burst = Arc::new(burst::Burst::new(
+            provider_config.max_concurrent_starts,
+            metrics.clone(),
+        ));
+        let api_limiter = Arc::new(ratelimit::WriteLimiter::new(
+            provider_config.api_qps,
+            provider_config.api_burst,
+            provider_config.status_debounce,
+            metrics.clone(),
+        ));
+        let outbox = {
+            let kubeconfig = kubeconfig.clone();
+            let metrics = metrics.clone();
+            Arc::new(
+                tokio::task::spawn_blocking(move || {
+                    outbox::Outbox::open(outbox_path, kubeconfig, metrics)
+                })
+                .await?,
+            )
+        };
+        let cpus = match &provider_config.executor {
+            config::Executor::Shared => None,
+            config::Executor::Pinned(cpus) => {
+                let pool = cpu::CpuPool::new(cpus)?;
+                info!("Pinning module instances to cores {:?}", pool.cpus());
+                Some(Arc::new(pool))
+            }
+        };
+        let shared = SharedPodState {
+            handles: Default::default(),
+            known_pods: Default::default(),
+            deleted: Default::default(),
+            orphans: Default::default(),
+            admission: Arc::new(admission::Admission::new(self.max_pods as usize)),
+            memory_pressure: Default::default(),
+            cordon: Default::default(),
+            node_metadata: Default::default(),
+            static_pods: Default::default(),
+            reloads: Default::default(),
+            work_queues: Default::default(),
+            routes: Default::default(),
+            debug_states: Default::default(),
+            credentials: Arc::new(credentials),
+            metrics,
+            webhook,
+            burst,
+            attestor,
+            api_limiter,
+            outbox,
+            recovery: Default::default(),
+            cpus,
+            store,
+            log_watcher: log_stream::watch(&log_path),
+            log_path,
+            retained_log_path: data_dir.join(RETAINED_LOG_DIR_NAME),
+            volume_path,
+            kv_path,
+            snapshot_path,
+            dead_letter_path,
+            kubeconfig,
+            node_name: self.node_name,
+            config: Arc::new(provider_config),
+            clock: self.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+            hooks: Arc::new(self.hooks),
+        };
+        // Before the kubelet hands over any pods
+        recovery::recover(&shared).await;
+        tokio::spawn(outbox::replay_loop(
+            shared.outbox.clone(),
+            shared.clock.clone(),
+        ));
+        if let Some(dir) = &shared.config.pod_manifest_path {
+            info!("Running static pods from {}", dir.display());
+            tokio::spawn(static_pods::static_pod_loop(shared.clone(), dir.clone()));
+        }
+        tokio::spawn(reconcile::reconcile_loop(shared.clone()));
+        tokio::spawn(reload::reload_loop(shared.clone()));
+        if shared.config.module_prewarm {
+            tokio::spawn(prewarm::prewarm_loop(shared.clone()));
+        }
+        if let Some(threshold) = shared.config.eviction_memory_available {
+            tokio::spawn(eviction::eviction_loop(shared.clone(), threshold));
+        }
+        tokio::spawn(cordon::cordon_loop(shared.clone()));
+        if let Some(socket) = &shared.config.admin_socket {
+            tokio::spawn(admin::serve(shared.clone(), socket.clone()));
+        }
+        if let Some(addr) = shared.config.gateway_addr {
+            info!("Serving the HTTP trigger gateway on {}", addr);
+            tokio::spawn(gateway::serve(shared.clone(), addr));
+        }
+        if let Some(addr) = shared.config.health_addr {
+            let checker = health::HealthChecker::new(
+                shared.kubeconfig.clone(),
+                data_dir,
+                shared.config.health_registry.clone(),
+            );
+            let auth = auth::Authenticator::new(
+                &shared.config.health_auth,
+                &shared.kubeconfig,
+                &shared.node_name,
+            )
+            .await?;
+            tokio::spawn(health::serve(
+                checker,
+                shared.metrics.clone(),
+                addr,
+                Arc::new(auth),
+                shared.config.health_tls.clone(),
+            ));
+        }
+        Ok(WasiProvider { shared })
+    }
+}
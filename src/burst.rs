@@ -0,0 +1,193 @@
+//! Sharing work between pods that start at the same time, such as the
+//! replicas of a Deployment scaled up on one node. Each pod still goes
+//! through its own states, but pods whose containers run the same module
+//! wait on one pull and one validation of it rather than each repeating
+//! them, and hold the same bytes in memory. With
+//! `WASM3_MAX_CONCURRENT_STARTS` set, at most that many instances are set up
+//! at once, so a burst does not have hundreds of threads parsing modules at
+//! the same time and every pod reaching `Running` late.
+//!
+//! Only modules pulled by digest are shared, since a tag may name a
+//! different module from one pull to the next.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+use tokio::sync::Semaphore;
+
+use crate::metrics::Metrics;
+use crate::validation::ValidationError;
+use crate::wasi_runtime::ModuleData;
+
+/// The result of checking a module, and the module to run instead if it had
+/// to be rewritten.
+pub(crate) type Validated = Result<Option<ModuleData>, ValidationError>;
+
+/// Work in progress, keyed by what it is for, that anyone after the same
+/// result can wait on.
+struct InFlight<T: Clone> {
+    /// The work for each key, numbered so that it is only removed once it is
+    /// done, by whoever waited on it
+    work: Mutex<HashMap<String, (u64, Shared<BoxFuture<'static, T>>)>>,
+    next: AtomicU64,
+}
+
+impl<T: Clone> Default for InFlight<T> {
+    fn default() -> Self {
+        InFlight {
+            work: Default::default(),
+            next: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> InFlight<T> {
+    /// Waits for the work in progress for `key`, or runs `work` if there is
+    /// none. Returns the result, and whether it came from someone else's
+    /// work.
+    async fn run<F>(&self, key: &str, work: F) -> (T, bool)
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let (id, shared, joined) = {
+            let mut in_flight = self.work.lock().unwrap();
+            match in_flight.get(key) {
+                Some((id, shared)) => (*id, shared.clone(), true),
+                None => {
+                    let id = self.next.fetch_add(1, Ordering::Relaxed);
+                    let shared = work.boxed().shared();
+                    in_flight.insert(key.to_owned(), (id, shared.clone()));
+                    (id, shared, false)
+                }
+            }
+        };
+        let result = shared.await;
+        // Whoever started the work may have given up waiting on it
+        let mut in_flight = self.work.lock().unwrap();
+        if in_flight
+            .get(key)
+            .map_or(false, |(current, _)| *current == id)
+        {
+            in_flight.remove(key);
+        }
+        (result, joined)
+    }
+}
+
+/// The work shared between pods starting on the node.
+pub(crate) struct Burst {
+    pulls: InFlight<Result<ModuleData, String>>,
+    /// Modules pulled by digest that a pod still holds, keyed by the image
+    /// reference with the digest
+    modules: Mutex<HashMap<String, Weak<[u8]>>>,
+    validations: InFlight<Result<Validated, String>>,
+    /// Modules checked so far, keyed like `validations`, for as long as a
+    /// pod holds the module
+    validated: Mutex<HashMap<String, (Weak<[u8]>, Validated)>>,
+    /// Permits to set up an instance, if their number is limited
+    starts: Option<Arc<Semaphore>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Burst {
+    pub(crate) fn new(max_concurrent_starts: Option<usize>, metrics: Arc<Metrics>) -> Self {
+        Burst {
+            pulls: Default::default(),
+            modules: Default::default(),
+            validations: Default::default(),
+            validated: Default::default(),
+            starts: max_concurrent_starts.map(|n| Arc::new(Semaphore::new(n))),
+            metrics,
+        }
+    }
+
+    /// Returns the module pulled by the digest `reference`, running `pull`
+    /// only if no other pod holds the module or is pulling it. Errors are
+    /// described as [`describe_pull_error`](crate::registry::describe_pull_error)
+    /// does, so they can be shared.
+    pub(crate) async fn pull<F>(&self, reference: &str, pull: F) -> Result<ModuleData, String>
+    where
+        F: Future<Output = Result<ModuleData, String>> + Send + 'static,
+    {
+        let held = self
+            .modules
+            .lock()
+            .unwrap()
+            .get(reference)
+            .and_then(Weak::upgrade);
+        if let Some(module) = held {
+            self.count_shared("pull");
+            return Ok(module);
+        }
+        let (result, joined) = self.pulls.run(reference, pull).await;
+        if joined {
+            self.count_shared("pull");
+        }
+        let module = result?;
+        let mut modules = self.modules.lock().unwrap();
+        modules.retain(|_, module| module.strong_count() > 0);
+        modules.insert(reference.to_owned(), Arc::downgrade(&module));
+        Ok(module)
+    }
+
+    /// Returns the result of checking `module` against `checks`, a
+    /// description of everything the check depends on, running `validate`
+    /// only if the same module has not been checked against the same things
+    /// while a pod held it.
+    pub(crate) async fn validate<F>(
+        &self,
+        module: &ModuleData,
+        checks: &str,
+        validate: F,
+    ) -> anyhow::Result<Validated>
+    where
+        F: Future<Output = anyhow::Result<Validated>> + Send + 'static,
+    {
+        // A module's address identifies it for as long as it is held, which
+        // the caller does until this returns and the cache checks after
+        let key = format!("{:p}/{}", Arc::as_ptr(module) as *const u8, checks);
+        let cached = self
+            .validated
+            .lock()
+            .unwrap()
+            .get(&key)
+            .and_then(|(held, result)| {
+                held.upgrade()
+                    .filter(|held| Arc::ptr_eq(held, module))
+                    .map(|_| result.clone())
+            });
+        if let Some(result) = cached {
+            self.count_shared("validation");
+            return Ok(result);
+        }
+        let (result, joined) = self
+            .validations
+            .run(&key, validate.map(|r| r.map_err(|e| format!("{:#}", e))))
+            .await;
+        if joined {
+            self.count_shared("validation");
+        }
+        let result = result.map_err(|e| anyhow::anyhow!(e))?;
+        let mut validated = self.validated.lock().unwrap();
+        validated.retain(|_, (held, _)| held.strong_count() > 0);
+        validated.insert(key, (Arc::downgrade(module), result.clone()));
+        Ok(result)
+    }
+
+    /// The permits to set up an instance, if their number is limited.
+    pub(crate) fn starts(&self) -> Option<Arc<Semaphore>> {
+        self.starts.clone()
+    }
+
+    fn count_shared(&self, work: &str) {
+        self.metrics.inc_counter(
+            "wasm3_shared_work_total",
+            "Module pulls and validations skipped because another pod did the same work",
+            &[("work", work)],
+            1.0,
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! Removal of what pods leave on disk under the data directory: container
+//! logs, key-value stores, dead letters, volume contents and temporary
+//! files.
+//!
+//! A pod's artifacts are removed when its pod state is dropped. If the
+//! provider crashes first they would stay forever, so every reconcile also
+//! removes the logs and stores of pods no longer assigned to this node, and
+//! on startup temporary files left by interrupted writes are removed.
+//!
+//! When a deleted pod's logs are retained, they are moved to
+//! `<data dir>/wasi-logs-deleted/<namespace>/<pod>-<uid>/` and removed by the
+//! first reconcile after the retention period.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use kubelet::pod::pod_key;
+use log::{debug, error, info, warn};
+
+use crate::SharedPodState;
+
+/// Prefix of the temporary files `tempfile` creates.
+const TEMP_PREFIX: &str = ".tmp";
+
+/// Removes everything a deleted pod left on disk, keeping its logs for a
+/// while if the provider is configured to. `volumes` are the host paths of
+/// the pod's volumes; only those under the provider's volume directory are
+/// removed, never host paths.
+pub(crate) async fn remove_pod(
+    shared: &SharedPodState,
+    namespace: &str,
+    name: &str,
+    uid: &str,
+    volumes: Vec<PathBuf>,
+) {
+    if shared.config.deleted_pod_log_retention.is_some() {
+        let from = shared.log_path.join(namespace).join(name);
+        let to = shared
+            .retained_log_path
+            .join(namespace)
+            .join(format!("{}-{}", name, uid));
+        match retain_logs(&from, &to).await {
+            Ok(()) => debug!("Retaining logs of deleted pod in {}", to.display()),
+            Err(e) => {
+                error!("Unable to retain logs in {}: {:?}", from.display(), e);
+                remove_dir(&from).await;
+            }
+        }
+    } else {
+        crate::log_files::remove_pod_logs(&shared.log_path, namespace, name).await;
+    }
+    crate::kv::remove_pod_kv(&shared.kv_path, namespace, name).await;
+    crate::dead_letter::remove_pod_letters(&shared.dead_letter_path, namespace, name).await;
+
+    // Volumes live in a directory per pod below the volume directory
+    let pod_dirs: HashSet<PathBuf> = volumes
+        .iter()
+        .filter_map(|v| v.strip_prefix(&shared.volume_path).ok())
+        .filter_map(|v| v.components().next())
+        .map(|c| shared.volume_path.join(c))
+        .collect();
+    for dir in pod_dirs {
+        remove_dir(&dir).await;
+    }
+}
+
+/// Moves the container log directories of a pod into a fresh directory, so
+/// its modification time says when the pod was deleted.
+async fn retain_logs(from: &Path, to: &Path) -> io::Result<()> {
+    let mut containers = match tokio::fs::read_dir(from).await {
+        Ok(containers) => containers,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    tokio::fs::create_dir_all(to).await?;
+    while let Some(container) = containers.next_entry().await? {
+        tokio::fs::rename(container.path(), to.join(container.file_name())).await?;
+    }
+    tokio::fs::remove_dir(from).await
+}
+
+/// Removes the logs and stores of pods that are neither in `cluster_pods`,
+/// as `(namespace, name)`, nor known to the provider, and retained logs
+/// older than the retention period.
+pub(crate) async fn sweep(shared: &SharedPodState, cluster_pods: HashSet<(String, String)>) {
+    // Read after the cluster was listed, so a pod assigned since is known
+    // before any of its directories exist
+    let known: HashSet<String> = shared.known_pods.read().await.keys().cloned().collect();
+    let live = move |namespace: &str, name: &str| {
+        cluster_pods.contains(&(namespace.to_owned(), name.to_owned()))
+            || known.contains(&pod_key(namespace, name))
+    };
+    let roots = vec![
+        shared.log_path.clone(),
+        shared.kv_path.clone(),
+        shared.dead_letter_path.clone(),
+    ];
+    let retained = shared.retained_log_path.clone();
+    let retention = shared.config.deleted_pod_log_retention;
+    let result = tokio::task::spawn_blocking(move || -> io::Result<()> {
+        for root in &roots {
+            for (namespace, name, dir) in pod_dirs(root)? {
+                if !live(&namespace, &name) {
+                    info!(
+                        "Removing leftover data of pod {} in {}",
+                        name,
+                        dir.display()
+                    );
+                    remove_dir_blocking(&dir);
+                }
+            }
+        }
+        let now = SystemTime::now();
+        for (_, _, dir) in pod_dirs(&retained)? {
+            let deleted = dir.metadata()?.modified()?;
+            let age = now.duration_since(deleted).unwrap_or_default();
+            if retention.map(|r| age >= r).unwrap_or(true) {
+                debug!("Removing retained logs in {}", dir.display());
+                remove_dir_blocking(&dir);
+            }
+        }
+        Ok(())
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("Unable to remove leftover pod data: {:?}", e),
+        Err(e) => error!("Pod data cleanup panicked: {:?}", e),
+    }
+}
+
+/// Removes temporary files left by writes that were interrupted by a crash
+/// anywhere below `root`. Only call this before any instance runs. This does
+/// blocking IO.
+pub(crate) fn remove_temp_files(root: &Path) {
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("Unable to read {}: {:?}", root.display(), e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_temp = entry
+            .file_name()
+            .to_str()
+            .map(|n| n.starts_with(TEMP_PREFIX))
+            .unwrap_or(false);
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => remove_temp_files(&path),
+            Ok(t) if t.is_file() && is_temp => {
+                debug!("Removing leftover temporary file {}", path.display());
+                if let Err(e) = std::fs::remove_file(&path) {
+                    warn!("Unable to remove {}: {:?}", path.display(), e);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The `<root>/<namespace>/<pod>` directories below `root`.
+fn pod_dirs(root: &Path) -> io::Result<Vec<(String, String, PathBuf)>> {
+    let mut dirs = Vec::new();
+    let namespaces = match std::fs::read_dir(root) {
+        Ok(namespaces) => namespaces,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(dirs),
+        Err(e) => return Err(e),
+    };
+    for namespace in namespaces {
+        let namespace = namespace?;
+        if !namespace.file_type()?.is_dir() {
+            continue;
+        }
+        let namespace_name = namespace.file_name().to_string_lossy().into_owned();
+        for pod in std::fs::read_dir(namespace.path())? {
+            let pod = pod?;
+            if pod.file_type()?.is_dir() {
+                let name = pod.file_name().to_string_lossy().into_owned();
+                dirs.push((namespace_name.clone(), name, pod.path()));
+            }
+        }
+    }
+    Ok(dirs)
+}
+
+async fn remove_dir(dir: &Path) {
+    match tokio::fs::remove_dir_all(dir).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => error!("Unable to remove {}: {:?}", dir.display(), e),
+    }
+}
+
+fn remove_dir_blocking(dir: &Path) {
+    match std::fs::remove_dir_all(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => error!("Unable to remove {}: {:?}", dir.display(), e),
+    }
+}
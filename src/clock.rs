@@ -0,0 +1,146 @@
+//! The clock the provider reads the time from and waits on, for restart
+//! backoff, module deadlines and idle watches, container log timestamps and
+//! the provider's periodic loops. Providers use
+//! [`SystemClock`] unless the crate embedding them sets another with
+//! [`WasiProviderBuilder::clock`](crate::WasiProviderBuilder::clock), such as
+//! a [`SimulatedClock`] that a test moves forward by hand, so that a pod's
+//! restarts over half an hour of backoff take no time at all.
+
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture, FutureExt};
+use tokio::sync::oneshot;
+
+/// A source of time.
+pub trait Clock: Send + Sync {
+    /// The current wall clock time, which timestamps are written with.
+    fn now(&self) -> DateTime<Utc>;
+
+    /// The current monotonic time, which durations are measured with.
+    fn instant(&self) -> Instant;
+
+    /// Resolves once `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The time as the operating system keeps it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::delay_for(duration).boxed()
+    }
+}
+
+/// Where a simulated clock stands.
+#[derive(Default)]
+struct Simulated {
+    /// How far the clock has been moved since it was created
+    elapsed: Duration,
+    /// Those waiting for the clock to reach a time, as an offset from when it
+    /// was created, by the number of their sleep
+    sleepers: Vec<(u64, Duration, oneshot::Sender<()>)>,
+    next: u64,
+}
+
+/// Removes a sleep that is given up on before it is woken.
+struct Sleeper {
+    id: u64,
+    state: Weak<Mutex<Simulated>>,
+}
+
+impl Drop for Sleeper {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.upgrade() {
+            let mut state = state.lock().unwrap();
+            state.sleepers.retain(|(id, _, _)| *id != self.id);
+        }
+    }
+}
+
+/// A clock that stands still until it is moved forward with
+/// [`advance`](SimulatedClock::advance).
+pub struct SimulatedClock {
+    start: DateTime<Utc>,
+    origin: Instant,
+    state: Arc<Mutex<Simulated>>,
+}
+
+impl SimulatedClock {
+    /// Creates a clock that reads `start` until it is moved.
+    pub fn new(start: DateTime<Utc>) -> Self {
+        SimulatedClock {
+            start,
+            origin: Instant::now(),
+            state: Default::default(),
+        }
+    }
+
+    /// Moves the clock forward by `by`, waking everyone whose sleep has
+    /// passed.
+    pub fn advance(&self, by: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.elapsed += by;
+        let elapsed = state.elapsed;
+        let (woken, waiting): (Vec<_>, Vec<_>) = state
+            .sleepers
+            .drain(..)
+            .partition(|(_, until, _)| *until <= elapsed);
+        state.sleepers = waiting;
+        for (_, _, wake) in woken {
+            let _ = wake.send(());
+        }
+    }
+
+    /// How many sleeps are waiting for the clock to move, so a test can
+    /// tell when the code it drives has reached its next wait.
+    pub fn sleepers(&self) -> usize {
+        self.state.lock().unwrap().sleepers.len()
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> DateTime<Utc> {
+        let elapsed = self.state.lock().unwrap().elapsed;
+        self.start
+            + chrono::Duration::from_std(elapsed).unwrap_or_else(|_| chrono::Duration::max_value())
+    }
+
+    fn instant(&self) -> Instant {
+        self.origin + self.state.lock().unwrap().elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        if duration == Duration::from_secs(0) {
+            return future::ready(()).boxed();
+        }
+        let (wake, woken) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+        let id = state.next;
+        state.next += 1;
+        let until = state.elapsed + duration;
+        state.sleepers.push((id, until, wake));
+        let sleeper = Sleeper {
+            id,
+            state: Arc::downgrade(&self.state),
+        };
+        async move {
+            let _sleeper = sleeper;
+            // A clock that is dropped wakes its sleepers rather than leaving
+            // them waiting forever
+            let _ = woken.await;
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,417 @@
+//! Composition of a module with library modules. A pod can name extra images
+//! whose modules are linked as libraries: a container's imports from a
+//! library's name are resolved to that library's exports before the module
+//! is started.
+//!
+//! wasm3 gives every runtime a single linear memory, so each library runs in
+//! a runtime of its own next to the module and shares nothing with it. Calls
+//! into a library go through host functions that copy the arguments onto the
+//! library's stack and call its export. With separate memories, pointers
+//! mean nothing across the boundary, and since wasm3's Rust bindings only
+//! link host functions whose signature is known when the provider is built,
+//! library functions are limited to the signatures [`supported`] accepts.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_void, CStr, CString};
+
+use kubelet::pod::Pod;
+use wasm3::Module;
+use wasm3_sys as ffi;
+
+use crate::host::{self, FilePolicy};
+use crate::validation::{
+    ExternKind, FuncType, ModuleInfo, ValidationError, VALUE_I32, WASI_MODULES,
+};
+use crate::wasi_runtime::ModuleData;
+
+/// Annotation holding comma separated `name=image` pairs of libraries to link
+/// the pod's modules with, e.g. `wasm3.krustlet.dev/libraries:
+/// math=registry.example.com/math:v1`.
+pub(crate) const LIBRARIES_ANNOTATION: &str = "wasm3.krustlet.dev/libraries";
+
+/// The most parameters a library function may take.
+const MAX_LIBRARY_PARAMS: usize = 4;
+
+/// Returns the libraries the pod asked for, as pairs of the import module
+/// name they are linked under and their image.
+pub(crate) fn libraries(pod: &Pod) -> anyhow::Result<Vec<(String, String)>> {
+    let value = match pod.annotations().get(LIBRARIES_ANNOTATION) {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+    let mut libraries: Vec<(String, String)> = Vec::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, image) = match entry.find('=') {
+            Some(i) => (entry[..i].trim(), entry[i + 1..].trim()),
+            None => {
+                return Err(anyhow::anyhow!(
+                    "invalid {} annotation {:?}, expected name=image entries",
+                    LIBRARIES_ANNOTATION,
+                    value
+                ))
+            }
+        };
+        if name.is_empty() || image.is_empty() {
+            return Err(anyhow::anyhow!(
+                "invalid {} annotation {:?}, expected name=image entries",
+                LIBRARIES_ANNOTATION,
+                value
+            ));
+        }
+        if WASI_MODULES.contains(&name) || name == host::HOST_MODULE {
+            return Err(anyhow::anyhow!(
+                "library name {} in {} is reserved",
+                name,
+                LIBRARIES_ANNOTATION
+            ));
+        }
+        if libraries.iter().any(|(n, _)| n == name) {
+            return Err(anyhow::anyhow!(
+                "library {} is listed twice in {}",
+                name,
+                LIBRARIES_ANNOTATION
+            ));
+        }
+        libraries.push((name.to_owned(), image.to_owned()));
+    }
+    Ok(libraries)
+}
+
+/// Returns true if a library function with this type can be linked: up to
+/// [`MAX_LIBRARY_PARAMS`] i32 parameters and no result or an i32.
+pub(crate) fn supported(ty: &FuncType) -> bool {
+    ty.params.len() <= MAX_LIBRARY_PARAMS
+        && ty.params.iter().all(|t| *t == VALUE_I32)
+        && (ty.results.is_empty() || ty.results == [VALUE_I32])
+}
+
+/// Checks that every import the module makes from one of `libraries` is a
+/// function the library exports with the same, supported, type.
+pub(crate) fn check_imports(
+    info: &ModuleInfo,
+    libraries: &[(String, ModuleInfo)],
+) -> Result<(), ValidationError> {
+    let mut linked = 0;
+    for import in &info.imports {
+        let library = match libraries.iter().find(|(name, _)| *name == import.module) {
+            Some((_, library)) => library,
+            None => continue,
+        };
+        if import.kind != ExternKind::Function {
+            return Err(ValidationError::LibraryLink(format!(
+                "{}.{} is not a function, and only functions can be imported from libraries",
+                import.module, import.field
+            )));
+        }
+        let expected = info.import_type(import).cloned().unwrap_or_default();
+        let found = library.export_type(&import.field).ok_or_else(|| {
+            ValidationError::UnresolvedImport {
+                module: import.module.clone(),
+                field: import.field.clone(),
+            }
+        })?;
+        if *found != expected {
+            return Err(ValidationError::LibraryLink(format!(
+                "{}.{} is imported as {} but the library exports it as {}",
+                import.module, import.field, expected, found
+            )));
+        }
+        if !supported(found) {
+            return Err(ValidationError::LibraryLink(format!(
+                "{}.{} has type {}, but library functions can only take up to {} i32 parameters and return nothing or an i32",
+                import.module, import.field, found, MAX_LIBRARY_PARAMS
+            )));
+        }
+        linked += 1;
+    }
+    if linked > TRAMPOLINES.len() {
+        return Err(ValidationError::LibraryLink(format!(
+            "module imports {} library functions, more than the {} that can be linked",
+            linked,
+            TRAMPOLINES.len()
+        )));
+    }
+    Ok(())
+}
+
+/// A library function a trampoline calls.
+#[derive(Clone, Copy)]
+struct Target {
+    runtime: ffi::IM3Runtime,
+    function: ffi::IM3Function,
+    params: usize,
+    result: bool,
+}
+
+thread_local! {
+    /// What each trampoline calls, by its index in [`TRAMPOLINES`].
+    static TARGETS: RefCell<Vec<Target>> = RefCell::new(Vec::new());
+}
+
+/// The trap reported by a trampoline that has nothing to call.
+const UNLINKED: &[u8] = b"[trap] library function is not linked\0";
+
+/// Calls the library function behind trampoline `slot` with the arguments on
+/// the calling module's stack.
+unsafe fn call_target(slot: usize, sp: *mut u64) -> *const c_void {
+    let target = match TARGETS.with(|t| t.borrow().get(slot).copied()) {
+        Some(target) => target,
+        None => return UNLINKED.as_ptr() as *const c_void,
+    };
+    // Arguments follow the result slot when there is one
+    let args = if target.result { sp.add(1) } else { sp };
+    // Calls take their arguments from, and leave their result at, the base of
+    // the runtime's stack
+    let stack = (*target.runtime).stack as *mut u64;
+    std::ptr::copy_nonoverlapping(args, stack, target.params);
+    let trap = ffi::m3_Call(target.function);
+    if !trap.is_null() {
+        return trap as *const c_void;
+    }
+    if target.result {
+        *(sp as *mut i32) = *(stack as *const i32);
+    }
+    std::ptr::null()
+}
+
+type RawCall = unsafe extern "C" fn(ffi::IM3Runtime, *mut u64, *mut c_void) -> *const c_void;
+
+/// wasm3 does not pass host functions any data of their own, so each linked
+/// import gets its own function, which looks up what to call by its slot.
+macro_rules! trampolines {
+    ($($slot:literal => $name:ident),* $(,)?) => {
+        $(
+            unsafe extern "C" fn $name(
+                _runtime: ffi::IM3Runtime,
+                sp: *mut u64,
+                _mem: *mut c_void,
+            ) -> *const c_void {
+                call_target($slot, sp)
+            }
+        )*
+
+        const TRAMPOLINES: &[RawCall] = &[$($name),*];
+    };
+}
+
+trampolines!(
+    0 => trampoline_0, 1 => trampoline_1, 2 => trampoline_2, 3 => trampoline_3,
+    4 => trampoline_4, 5 => trampoline_5, 6 => trampoline_6, 7 => trampoline_7,
+    8 => trampoline_8, 9 => trampoline_9, 10 => trampoline_10, 11 => trampoline_11,
+    12 => trampoline_12, 13 => trampoline_13, 14 => trampoline_14, 15 => trampoline_15,
+    16 => trampoline_16, 17 => trampoline_17, 18 => trampoline_18, 19 => trampoline_19,
+    20 => trampoline_20, 21 => trampoline_21, 22 => trampoline_22, 23 => trampoline_23,
+    24 => trampoline_24, 25 => trampoline_25, 26 => trampoline_26, 27 => trampoline_27,
+    28 => trampoline_28, 29 => trampoline_29, 30 => trampoline_30, 31 => trampoline_31,
+);
+
+/// A library loaded into a runtime of its own.
+struct Library {
+    env: ffi::IM3Environment,
+    runtime: ffi::IM3Runtime,
+    /// wasm3 keeps pointers into the module bytes
+    _bytes: ModuleData,
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        // Freeing the runtime frees the modules loaded into it
+        unsafe {
+            if !self.runtime.is_null() {
+                ffi::m3_FreeRuntime(self.runtime);
+            }
+            if !self.env.is_null() {
+                ffi::m3_FreeEnvironment(self.env);
+            }
+        }
+    }
+}
+
+fn check(result: ffi::M3Result, what: &str) -> Result<(), String> {
+    if result.is_null() {
+        Ok(())
+    } else {
+        let message = unsafe { CStr::from_ptr(result) }.to_string_lossy();
+        Err(format!("{}: {}", what, message))
+    }
+}
+
+impl Library {
+    /// Parses, loads and links a library, then runs its `_initialize`, if it
+    /// has one. The library's WASI `path_open`, `fd_close` and functions that
+    /// change the filesystem are replaced as the module's are, so it is held
+    /// to the same [`FilePolicy`], and so
+    /// are the functions that read the arguments and environment, so it sees
+    /// the module's.
+    fn load(bytes: &ModuleData, stack_size: u32, files: FilePolicy) -> Result<Self, String> {
+        unsafe {
+            let mut library = Library {
+                env: ffi::m3_NewEnvironment(),
+                runtime: std::ptr::null_mut(),
+                _bytes: bytes.clone(),
+            };
+            if library.env.is_null() {
+                return Err("cannot create environment".into());
+            }
+            library.runtime = ffi::m3_NewRuntime(library.env, stack_size, std::ptr::null_mut());
+            if library.runtime.is_null() {
+                return Err("cannot create runtime".into());
+            }
+            let mut module = std::ptr::null_mut();
+            check(
+                ffi::m3_ParseModule(library.env, &mut module, bytes.as_ptr(), bytes.len() as u32),
+                "cannot parse module",
+            )?;
+            if let Err(e) = check(
+                ffi::m3_LoadModule(library.runtime, module),
+                "cannot load module",
+            ) {
+                ffi::m3_FreeModule(module);
+                return Err(e);
+            }
+            check(ffi::m3_LinkWASI(module), "cannot link WASI")?;
+            let mut replaced: Vec<(&str, &[u8], RawCall)> = host::COMMAND_LINE_FUNCTIONS
+                .iter()
+                .map(|(name, function)| (*name, host::COMMAND_LINE_SIGNATURE, *function))
+                .collect();
+            if files.replaces_path_open() {
+                replaced.push((
+                    "path_open",
+                    host::PATH_OPEN_SIGNATURE,
+                    host::path_open as RawCall,
+                ));
+            }
+            if files.read_only_root {
+                replaced.extend(
+                    host::READ_ONLY_FUNCTIONS
+                        .iter()
+                        .map(|(name, signature)| (*name, *signature, host::read_only as RawCall)),
+                );
+            }
+            if files.max_open_files.is_some() {
+                replaced.push((
+                    "fd_close",
+                    host::FD_CLOSE_SIGNATURE,
+                    host::fd_close as RawCall,
+                ));
+            }
+            for wasi in WASI_MODULES {
+                let wasi = CString::new(*wasi).unwrap_or_default();
+                for (name, signature, function) in &replaced {
+                    let name = CString::new(*name).unwrap_or_default();
+                    // Fails for libraries that do not import the function,
+                    // which is fine
+                    ffi::m3_LinkRawFunction(
+                        module,
+                        wasi.as_ptr(),
+                        name.as_ptr(),
+                        signature.as_ptr() as *const _,
+                        Some(*function),
+                    );
+                }
+            }
+            if let Some(initialize) = library.find(crate::snapshot::INITIALIZE) {
+                check(ffi::m3_Call(initialize), "_initialize failed")?;
+            }
+            Ok(library)
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<ffi::IM3Function> {
+        let name = CString::new(name).ok()?;
+        let mut function = std::ptr::null_mut();
+        let result = unsafe { ffi::m3_FindFunction(&mut function, self.runtime, name.as_ptr()) };
+        if result.is_null() && !function.is_null() {
+            Some(function)
+        } else {
+            None
+        }
+    }
+}
+
+/// The libraries a module is linked with. They must outlive every call the
+/// module makes, so keep this alive for as long as the module runs.
+#[derive(Default)]
+pub(crate) struct Linked {
+    _libraries: Vec<Library>,
+}
+
+impl Drop for Linked {
+    fn drop(&mut self) {
+        // Blocking threads are reused, so nothing may call into the
+        // libraries once they are freed
+        TARGETS.with(|t| t.borrow_mut().clear());
+    }
+}
+
+/// Loads the libraries the module imports from and links each of those
+/// imports to its library's export. Only one module can be linked with
+/// libraries on a thread at a time.
+pub(crate) fn link(
+    module: &mut Module,
+    info: &ModuleInfo,
+    libraries: &[(String, ModuleData)],
+    stack_size: u32,
+    files: FilePolicy,
+) -> Result<Linked, String> {
+    let mut loaded: HashMap<&str, Library> = HashMap::new();
+    let mut targets = Vec::new();
+    for import in &info.imports {
+        let bytes = match libraries.iter().find(|(name, _)| *name == import.module) {
+            Some((_, bytes)) => bytes,
+            None => continue,
+        };
+        if !loaded.contains_key(import.module.as_str()) {
+            let library = Library::load(bytes, stack_size, files)
+                .map_err(|e| format!("library {}: {}", import.module, e))?;
+            loaded.insert(import.module.as_str(), library);
+        }
+        let library = &loaded[import.module.as_str()];
+        let ty = info
+            .import_type(import)
+            .filter(|ty| supported(ty))
+            .ok_or_else(|| format!("{}.{} cannot be linked", import.module, import.field))?;
+        let function = library
+            .find(&import.field)
+            .ok_or_else(|| format!("library {} does not export {}", import.module, import.field))?;
+        let trampoline = *TRAMPOLINES
+            .get(targets.len())
+            .ok_or_else(|| "too many library imports".to_owned())?;
+        link_trampoline(module, &import.module, &import.field, ty, trampoline)
+            .map_err(|e| format!("cannot link {}.{}: {}", import.module, import.field, e))?;
+        targets.push(Target {
+            runtime: library.runtime,
+            function,
+            params: ty.params.len(),
+            result: !ty.results.is_empty(),
+        });
+    }
+    TARGETS.with(|t| *t.borrow_mut() = targets);
+    Ok(Linked {
+        _libraries: loaded.into_iter().map(|(_, library)| library).collect(),
+    })
+}
+
+/// Links `trampoline` under the signature of `ty`, which must be
+/// [`supported`].
+fn link_trampoline(
+    module: &mut Module,
+    library: &str,
+    field: &str,
+    ty: &FuncType,
+    trampoline: RawCall,
+) -> wasm3::error::Result<()> {
+    match (ty.params.len(), ty.results.is_empty()) {
+        (0, true) => module.link_function::<(), ()>(library, field, trampoline),
+        (0, false) => module.link_function::<(), i32>(library, field, trampoline),
+        (1, true) => module.link_function::<i32, ()>(library, field, trampoline),
+        (1, false) => module.link_function::<i32, i32>(library, field, trampoline),
+        (2, true) => module.link_function::<(i32, i32), ()>(library, field, trampoline),
+        (2, false) => module.link_function::<(i32, i32), i32>(library, field, trampoline),
+        (3, true) => module.link_function::<(i32, i32, i32), ()>(library, field, trampoline),
+        (3, false) => module.link_function::<(i32, i32, i32), i32>(library, field, trampoline),
+        (4, true) => module.link_function::<(i32, i32, i32, i32), ()>(library, field, trampoline),
+        _ => module.link_function::<(i32, i32, i32, i32), i32>(library, field, trampoline),
+    }
+}
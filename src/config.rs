@@ -0,0 +1,1499 @@
+//! Provider specific configuration that is not covered by the kubelet
+//! [`Config`](kubelet::config::Config).
+//!
+//! The kubelet owns the command line, so provider settings are read from
+//! `WASM3_*` environment variables by [`ProviderConfig::from_env`]. Settings
+//! can also be kept in a YAML file named by `WASM3_CONFIG_FILE`, with
+//! environment variables taking priority over it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub use crate::validation::WasiProfile;
+
+/// Environment variable holding the path of a YAML file of provider settings.
+pub const CONFIG_FILE_ENV: &str = "WASM3_CONFIG_FILE";
+
+/// Environment variable holding a comma separated list of namespaces the
+/// provider accepts pods from.
+pub const NAMESPACES_ENV: &str = "WASM3_NAMESPACES";
+
+/// Environment variable holding the address to serve `/healthz` and `/readyz`
+/// on.
+pub const HEALTH_ADDR_ENV: &str = "WASM3_HEALTH_ADDR";
+
+/// Environment variable holding a registry host whose reachability is part of
+/// readiness.
+pub const HEALTH_REGISTRY_ENV: &str = "WASM3_HEALTH_REGISTRY";
+
+/// Environment variable holding the PEM certificate the health endpoints are
+/// served with over TLS.
+pub const HEALTH_TLS_CERT_ENV: &str = "WASM3_HEALTH_TLS_CERT_FILE";
+
+/// Environment variable holding the PEM private key of the health endpoint
+/// certificate.
+pub const HEALTH_TLS_KEY_ENV: &str = "WASM3_HEALTH_TLS_KEY_FILE";
+
+/// Environment variable choosing how callers of `/metrics` are
+/// authenticated: `none`, `token` or `webhook`.
+pub const HEALTH_AUTH_ENV: &str = "WASM3_HEALTH_AUTH";
+
+/// Environment variable holding the path of the bearer tokens accepted when
+/// `WASM3_HEALTH_AUTH` is `token`.
+pub const HEALTH_TOKEN_FILE_ENV: &str = "WASM3_HEALTH_TOKEN_FILE";
+
+/// Environment variable holding the path of the node's registry credential
+/// helper configuration.
+pub const CREDENTIAL_CONFIG_ENV: &str = "WASM3_CREDENTIAL_CONFIG";
+
+/// Environment variable holding the HTTP(S) proxy used to reach registries.
+pub const REGISTRY_PROXY_ENV: &str = "WASM3_REGISTRY_PROXY";
+
+/// Environment variable holding hosts that bypass the registry proxy.
+pub const REGISTRY_NO_PROXY_ENV: &str = "WASM3_REGISTRY_NO_PROXY";
+
+/// Environment variable holding a PEM bundle of root CAs trusted for
+/// registries.
+pub const REGISTRY_CA_FILE_ENV: &str = "WASM3_REGISTRY_CA_FILE";
+
+/// Environment variable holding the path of the log access audit file.
+pub const LOG_AUDIT_FILE_ENV: &str = "WASM3_LOG_AUDIT_FILE";
+
+/// Environment variable enabling module memoization for pods with
+/// `restartPolicy: Always`.
+pub const MEMOIZE_MODULES_ENV: &str = "WASM3_MEMOIZE_MODULES";
+
+/// Environment variable holding the number of seconds a module may take to
+/// parse.
+pub const PARSE_TIMEOUT_ENV: &str = "WASM3_PARSE_TIMEOUT_SECS";
+
+/// Environment variable holding the number of seconds a module may take to
+/// instantiate.
+pub const INSTANTIATE_TIMEOUT_ENV: &str = "WASM3_INSTANTIATE_TIMEOUT_SECS";
+
+/// Environment variable holding the number of seconds a module may take to
+/// link against WASI.
+pub const LINK_TIMEOUT_ENV: &str = "WASM3_LINK_TIMEOUT_SECS";
+
+/// Environment variable holding a comma separated list of host environment
+/// variables pods may inherit.
+pub const ENV_ALLOWLIST_ENV: &str = "WASM3_ENV_ALLOWLIST";
+
+/// Environment variable holding a comma separated list of environment
+/// variables set in every module from the node's labels and annotations.
+pub const NODE_ENV_ENV: &str = "WASM3_NODE_ENV";
+
+/// Environment variable holding how many log files to keep for each
+/// container, including the current one.
+pub const LOG_RETENTION_ENV: &str = "WASM3_LOG_RETENTION";
+
+/// Environment variable enabling the per pod key-value host functions.
+pub const HOST_KV_ENV: &str = "WASM3_HOST_KV";
+
+/// Environment variable enabling the host functions that let modules watch
+/// Kubernetes resources.
+pub const HOST_WATCH_ENV: &str = "WASM3_HOST_WATCH";
+
+/// Environment variable holding the number of seconds a finished pod's
+/// handle, and with it its logs, is kept.
+pub const FINISHED_POD_TTL_ENV: &str = "WASM3_FINISHED_POD_TTL_SECS";
+
+/// Environment variable holding the number of seconds the logs of a deleted
+/// pod are kept on the node for post-mortem retrieval.
+pub const DELETED_POD_LOG_RETENTION_ENV: &str = "WASM3_DELETED_POD_LOG_RETENTION_SECS";
+
+/// Environment variable holding comma separated `registry/repository` glob
+/// patterns that images must match.
+pub const ALLOWED_IMAGES_ENV: &str = "WASM3_ALLOWED_IMAGES";
+
+/// Environment variable holding the available memory, such as `100Mi`, below
+/// which the node is under memory pressure and pods are evicted.
+pub const EVICTION_MEMORY_AVAILABLE_ENV: &str = "WASM3_EVICTION_MEMORY_AVAILABLE";
+
+/// Environment variable enabling stopping `BestEffort` pods as soon as the
+/// node is cordoned.
+pub const DRAIN_BEST_EFFORT_ENV: &str = "WASM3_DRAIN_BEST_EFFORT";
+
+/// Environment variable holding a comma separated list of host files, such as
+/// sensor readings, that pods may ask to read.
+pub const HOST_FILES_ENV: &str = "WASM3_HOST_FILES";
+
+/// Environment variable enabling the low memory mode for small edge devices.
+pub const LOW_MEMORY_ENV: &str = "WASM3_LOW_MEMORY";
+
+/// The largest stack a module gets in low memory mode.
+pub const LOW_MEMORY_MAX_STACK_SIZE: u32 = 64 * 1024;
+
+/// The 64KiB pages of memory a module may grow to in low memory mode when
+/// neither its container nor its namespace sets a limit.
+pub const LOW_MEMORY_MEMORY_PAGES: u32 = 256;
+
+/// Environment variable holding the largest module, in bytes, the provider
+/// will parse.
+pub const MAX_MODULE_SIZE_ENV: &str = "WASM3_MAX_MODULE_SIZE";
+
+/// Environment variable holding the largest stack, in bytes, a container's
+/// stack is raised to after its module overflows the stack.
+pub const STACK_AUTOTUNE_MAX_ENV: &str = "WASM3_STACK_AUTOTUNE_MAX";
+
+/// Environment variable holding the most host files each container may have
+/// open through WASI at once.
+pub const MAX_OPEN_FILES_ENV: &str = "WASM3_MAX_OPEN_FILES";
+
+/// Environment variable holding the most module instances that may be set
+/// up at once.
+pub const MAX_CONCURRENT_STARTS_ENV: &str = "WASM3_MAX_CONCURRENT_STARTS";
+
+/// Environment variable holding the image of a module to run alongside every
+/// pod.
+pub const SIDECAR_IMAGE_ENV: &str = "WASM3_SIDECAR_IMAGE";
+
+/// Environment variable holding default module limits per namespace, as comma
+/// separated `namespace:key=value;key=value` entries.
+pub const NAMESPACE_LIMITS_ENV: &str = "WASM3_NAMESPACE_LIMITS";
+
+/// Environment variable naming a directory of static pod manifests, run
+/// without the API server, as the kubelet's `--pod-manifest-path` does.
+pub const POD_MANIFEST_PATH_ENV: &str = "WASM3_POD_MANIFEST_PATH";
+
+/// Environment variable setting the path of the Unix socket node operators
+/// manage the provider through with `wasm3-provider ctl`.
+pub const ADMIN_SOCKET_ENV: &str = "WASM3_ADMIN_SOCKET";
+
+/// Environment variable holding runtime profiles pods can pick by name, as
+/// comma separated `name:key=value;key=value` entries. They add to or replace
+/// the built in `small`, `standard` and `compute` profiles.
+pub const RUNTIME_PROFILES_ENV: &str = "WASM3_RUNTIME_PROFILES";
+
+/// Environment variable naming an object store that caches modules for all
+/// nodes, either `s3://<bucket>/<prefix>` or an `http(s)://` URL.
+pub const MODULE_CACHE_ENV: &str = "WASM3_MODULE_CACHE";
+
+/// Environment variable holding the path of a file with a bearer token for an
+/// HTTP module cache.
+pub const MODULE_CACHE_TOKEN_FILE_ENV: &str = "WASM3_MODULE_CACHE_TOKEN_FILE";
+
+/// Environment variable holding the node's zone, for the
+/// `topology.kubernetes.io/zone` label.
+pub const TOPOLOGY_ZONE_ENV: &str = "WASM3_TOPOLOGY_ZONE";
+
+/// Environment variable holding the node's region, for the
+/// `topology.kubernetes.io/region` label.
+pub const TOPOLOGY_REGION_ENV: &str = "WASM3_TOPOLOGY_REGION";
+
+/// Environment variable holding the node's instance type, for the
+/// `node.kubernetes.io/instance-type` label.
+pub const INSTANCE_TYPE_ENV: &str = "WASM3_INSTANCE_TYPE";
+
+/// Environment variable naming the cloud whose instance metadata service the
+/// topology labels are looked up from: `aws`, `azure` or `gce`.
+pub const CLOUD_METADATA_ENV: &str = "WASM3_CLOUD_METADATA";
+
+/// Environment variable selecting where modules come from, either `registry`
+/// or `dir:<path>`.
+pub const MODULE_SOURCE_ENV: &str = "WASM3_MODULE_SOURCE";
+
+/// Environment variable holding a URL that pod lifecycle events are POSTed
+/// to as JSON.
+pub const LIFECYCLE_WEBHOOK_ENV: &str = "WASM3_LIFECYCLE_WEBHOOK";
+
+/// Environment variable enabling the execution report annotation on running
+/// pods, `true` or `false`.
+pub const ATTESTATION_ENV: &str = "WASM3_ATTESTATION";
+
+/// Environment variable holding the path of the node key execution reports
+/// are signed with.
+pub const ATTESTATION_KEY_FILE_ENV: &str = "WASM3_ATTESTATION_KEY_FILE";
+
+/// Environment variable enabling the `ModulePrewarm` watch, `true` or
+/// `false`.
+pub const MODULE_PREWARM_ENV: &str = "WASM3_MODULE_PREWARM";
+
+/// Environment variable setting how many writes per second the provider may
+/// make to the Kubernetes API, `0` for no limit.
+pub const API_QPS_ENV: &str = "WASM3_API_QPS";
+
+/// Environment variable setting how many writes to the Kubernetes API the
+/// provider may make at once above the QPS.
+pub const API_BURST_ENV: &str = "WASM3_API_BURST";
+
+/// Environment variable setting the least time, in milliseconds, between
+/// status patches for the same pod.
+pub const STATUS_DEBOUNCE_ENV: &str = "WASM3_STATUS_DEBOUNCE_MS";
+
+/// Environment variable setting how module output is buffered before it is
+/// written to the container log: `line`, `block` or `unbuffered`.
+pub const STDOUT_BUFFERING_ENV: &str = "WASM3_STDOUT_BUFFERING";
+
+/// Environment variable setting how often, in milliseconds, buffered module
+/// output is written to the container log.
+pub const STDOUT_FLUSH_INTERVAL_ENV: &str = "WASM3_STDOUT_FLUSH_INTERVAL_MS";
+
+/// Environment variable setting the most bytes per second each container may
+/// write to standard output and error, such as `64Ki`. Output past it is
+/// dropped.
+pub const STDOUT_RATE_LIMIT_ENV: &str = "WASM3_STDOUT_RATE_LIMIT";
+
+/// Environment variable choosing how container logs are written: `plain` or
+/// `cri`.
+pub const CONTAINER_LOG_FORMAT_ENV: &str = "WASM3_CONTAINER_LOG_FORMAT";
+
+/// Environment variable choosing where module instances run: `shared` or
+/// `pinned`.
+pub const EXECUTOR_ENV: &str = "WASM3_EXECUTOR";
+
+/// Environment variable holding the cores the pinned executor uses, such as
+/// `0-3,6`.
+pub const EXECUTOR_CPUS_ENV: &str = "WASM3_EXECUTOR_CPUS";
+
+/// Environment variable holding the address the HTTP trigger gateway
+/// listens on.
+pub const GATEWAY_ADDR_ENV: &str = "WASM3_GATEWAY_ADDR";
+
+/// Environment variable holding the number of seconds the gateway waits for
+/// a handler to respond.
+pub const GATEWAY_TIMEOUT_ENV: &str = "WASM3_GATEWAY_TIMEOUT_SECS";
+
+/// Environment variable holding the largest request or response body, such
+/// as `1Mi`, the gateway passes on.
+pub const GATEWAY_MAX_BODY_ENV: &str = "WASM3_GATEWAY_MAX_BODY";
+
+/// Where the provider loads modules from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModuleSource {
+    /// Use the store the provider was created with, normally an OCI registry
+    Registry,
+    /// Resolve modules from a local directory with a
+    /// [`DirectoryStore`](crate::store::DirectoryStore)
+    Directory(PathBuf),
+}
+
+impl Default for ModuleSource {
+    fn default() -> Self {
+        ModuleSource::Registry
+    }
+}
+
+impl std::str::FromStr for ModuleSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "registry" => Ok(ModuleSource::Registry),
+            _ if s.starts_with("dir:") && s.len() > "dir:".len() => {
+                Ok(ModuleSource::Directory(PathBuf::from(&s["dir:".len()..])))
+            }
+            _ => Err(anyhow::anyhow!(
+                "invalid module source {:?}, expected `registry` or `dir:<path>`",
+                s
+            )),
+        }
+    }
+}
+
+/// An object store that modules pulled from registries are cached in, shared
+/// by every node that names it. See
+/// [`CachingStore`](crate::store::CachingStore).
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModuleCache {
+    /// An S3 bucket, using the standard `AWS_*` environment variables for
+    /// credentials and region
+    S3 {
+        /// The bucket name
+        bucket: String,
+        /// Key prefix modules are stored under
+        prefix: String,
+    },
+    /// A base URL objects are read and written under with `GET` and `PUT`,
+    /// such as an Azure Blob Storage container SAS URL
+    Http {
+        /// The base URL, including any query to send with every request
+        url: String,
+        /// File holding a bearer token to send, such as a Google Cloud
+        /// Storage OAuth token
+        token_file: Option<PathBuf>,
+    },
+}
+
+impl std::str::FromStr for ModuleCache {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix("s3://") {
+            let (bucket, prefix) = match rest.find('/') {
+                Some(i) => (&rest[..i], &rest[i + 1..]),
+                None => (rest, ""),
+            };
+            if !bucket.is_empty() {
+                return Ok(ModuleCache::S3 {
+                    bucket: bucket.to_owned(),
+                    prefix: prefix.trim_matches('/').to_owned(),
+                });
+            }
+        } else if s.starts_with("https://") || s.starts_with("http://") {
+            return Ok(ModuleCache::Http {
+                url: s.to_owned(),
+                token_file: None,
+            });
+        }
+        Err(anyhow::anyhow!(
+            "invalid module cache {:?}, expected `s3://<bucket>/<prefix>` or an http(s) URL",
+            s
+        ))
+    }
+}
+
+/// A cloud instance metadata service the node's topology can be read from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloudMetadata {
+    /// The EC2 instance metadata service, using IMDSv2
+    Aws,
+    /// The Azure instance metadata service
+    Azure,
+    /// The Google Compute Engine metadata server
+    Gce,
+}
+
+impl std::str::FromStr for CloudMetadata {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "aws" => Ok(CloudMetadata::Aws),
+            "azure" => Ok(CloudMetadata::Azure),
+            "gce" => Ok(CloudMetadata::Gce),
+            _ => Err(anyhow::anyhow!(
+                "invalid cloud {:?}, expected aws, azure or gce",
+                s
+            )),
+        }
+    }
+}
+
+/// Where module instances run.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Executor {
+    /// On the async runtime's pool of blocking threads
+    Shared,
+    /// Each on a thread of its own, pinned to one of the cores with the rest
+    /// of its pod. Every core the provider may run on is used when empty.
+    Pinned(Vec<usize>),
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::Shared
+    }
+}
+
+/// A certificate and its private key, both PEM files.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// How callers of the health listener's protected endpoints are
+/// authenticated. `/healthz` and `/readyz` stay open so probes keep working.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EndpointAuth {
+    /// Anyone who can reach the listener may call them
+    None,
+    /// Callers present one of the bearer tokens in the file, one per line
+    Token(PathBuf),
+    /// Callers present a Kubernetes bearer token, which the API server
+    /// authenticates with a `TokenReview` and authorizes with a
+    /// `SubjectAccessReview` for the node's `nodes/metrics` subresource, as
+    /// the kubelet does
+    Webhook,
+}
+
+impl Default for EndpointAuth {
+    fn default() -> Self {
+        EndpointAuth::None
+    }
+}
+
+/// How module output is buffered before it is written to the container log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StdoutBuffering {
+    /// Complete lines are written as soon as they end
+    Line,
+    /// Output is written in blocks of 64KiB
+    Block,
+    /// Every write goes straight to the log
+    Unbuffered,
+}
+
+impl std::str::FromStr for StdoutBuffering {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "line" => Ok(StdoutBuffering::Line),
+            "block" => Ok(StdoutBuffering::Block),
+            "unbuffered" => Ok(StdoutBuffering::Unbuffered),
+            _ => Err(anyhow::anyhow!(
+                "invalid buffering {:?}, expected line, block or unbuffered",
+                s
+            )),
+        }
+    }
+}
+
+/// How container log files are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContainerLogFormat {
+    /// Module output as it was written
+    Plain,
+    /// One CRI record per line, `<timestamp> <stream> <F|P> <content>`, as
+    /// container runtimes write
+    Cri,
+}
+
+impl std::str::FromStr for ContainerLogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "plain" => Ok(ContainerLogFormat::Plain),
+            "cri" => Ok(ContainerLogFormat::Cri),
+            _ => Err(anyhow::anyhow!(
+                "invalid log format {:?}, expected plain or cri",
+                s
+            )),
+        }
+    }
+}
+
+/// Where on the node an environment variable's value is read from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeField {
+    /// The node label with this key
+    Label(String),
+    /// The node annotation with this key
+    Annotation(String),
+}
+
+/// An environment variable set in every module from the node's metadata,
+/// such as `NODE_REGION` from the `topology.kubernetes.io/region` label.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeEnvVar {
+    pub name: String,
+    pub field: NodeField,
+}
+
+impl std::str::FromStr for NodeEnvVar {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "invalid node variable {:?}, expected NAME=label:<key> or NAME=annotation:<key>",
+                s
+            )
+        };
+        let (name, field) = match s.find('=') {
+            Some(i) => (s[..i].trim(), s[i + 1..].trim()),
+            None => return Err(invalid()),
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(invalid());
+        }
+        let field = if let Some(key) = field.strip_prefix("label:") {
+            NodeField::Label(key.to_owned())
+        } else if let Some(key) = field.strip_prefix("annotation:") {
+            NodeField::Annotation(key.to_owned())
+        } else {
+            return Err(invalid());
+        };
+        match &field {
+            NodeField::Label(key) | NodeField::Annotation(key) if key.is_empty() => Err(invalid()),
+            _ => Ok(NodeEnvVar {
+                name: name.to_owned(),
+                field,
+            }),
+        }
+    }
+}
+
+/// How module output reaches the container log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StdoutPolicy {
+    pub buffering: StdoutBuffering,
+    /// How often buffered output is written even if the buffer is not full
+    pub flush_interval: Duration,
+    /// How the output is written to the log file
+    pub format: ContainerLogFormat,
+    /// The most bytes per second a module may write, if output is limited
+    pub rate_limit: Option<u64>,
+}
+
+impl Default for StdoutPolicy {
+    fn default() -> Self {
+        StdoutPolicy {
+            buffering: StdoutBuffering::Line,
+            flush_interval: Duration::from_secs(1),
+            format: ContainerLogFormat::Plain,
+            rate_limit: None,
+        }
+    }
+}
+
+/// How long each step of setting up a module may take before the container
+/// fails with `CreateContainerError`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SetupTimeouts {
+    /// Parsing the module binary
+    pub parse: Duration,
+    /// Loading the parsed module into a runtime
+    pub instantiate: Duration,
+    /// Linking WASI and resolving the entrypoint
+    pub link: Duration,
+}
+
+impl Default for SetupTimeouts {
+    fn default() -> Self {
+        SetupTimeouts {
+            parse: Duration::from_secs(30),
+            instantiate: Duration::from_secs(30),
+            link: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Limits applied to the modules of a pod when its spec does not set them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ModuleLimits {
+    /// Bytes of stack each module instance is created with
+    pub stack_size: Option<u32>,
+    /// The most 64KiB pages of linear memory a module may grow to. A
+    /// container's memory limit takes priority over this.
+    pub memory_pages: Option<u32>,
+    /// The largest module, in bytes, that may be parsed. This takes priority
+    /// over the node's [`ProviderConfig::max_module_size`].
+    pub max_module_size: Option<u64>,
+}
+
+impl std::str::FromStr for ModuleLimits {
+    type Err = anyhow::Error;
+
+    /// Parses `key=value` pairs separated by semicolons, e.g.
+    /// `stackSize=64Ki;memoryPages=256;maxModuleSize=16Mi`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = ModuleLimits::default();
+        for pair in s.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(i) => (pair[..i].trim(), pair[i + 1..].trim()),
+                None => {
+                    return Err(anyhow::anyhow!(
+                        "invalid limit {:?}, expected key=value",
+                        pair
+                    ))
+                }
+            };
+            let invalid = || anyhow::anyhow!("invalid value for limit {}: {:?}", key, value);
+            let parsed = parse_quantity(value)
+                .filter(|v| *v > 0)
+                .ok_or_else(invalid)?;
+            let small = || {
+                if parsed <= u64::from(u32::MAX) {
+                    Ok(parsed as u32)
+                } else {
+                    Err(invalid())
+                }
+            };
+            match key {
+                "stackSize" => limits.stack_size = Some(small()?),
+                "memoryPages" => limits.memory_pages = Some(small()?),
+                "maxModuleSize" => limits.max_module_size = Some(parsed),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "unknown limit {}, expected stackSize, memoryPages or maxModuleSize",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(limits)
+    }
+}
+
+/// Settings a pod picks by name with the `wasm3.krustlet.dev/profile`
+/// annotation, rather than setting each one itself.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RuntimeProfile {
+    /// Bytes of stack each module instance is created with
+    pub stack_size: Option<u32>,
+    /// The most 64KiB pages of linear memory a module may grow to
+    pub memory_pages: Option<u32>,
+    /// How long a single run of a module may take. wasm3 has no instruction
+    /// metering, so this is the budget runs are held to.
+    pub time_budget: Option<Duration>,
+    /// The WASI functions modules may import
+    pub wasi_profile: Option<WasiProfile>,
+}
+
+impl std::str::FromStr for RuntimeProfile {
+    type Err = anyhow::Error;
+
+    /// Parses `key=value` pairs separated by semicolons, e.g.
+    /// `stackSize=64Ki;memoryPages=256;timeBudgetSecs=60;wasiProfile=strict`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut profile = RuntimeProfile::default();
+        for pair in s.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(i) => (pair[..i].trim(), pair[i + 1..].trim()),
+                None => {
+                    return Err(anyhow::anyhow!(
+                        "invalid setting {:?}, expected key=value",
+                        pair
+                    ))
+                }
+            };
+            let invalid = || anyhow::anyhow!("invalid value for setting {}: {:?}", key, value);
+            let small = || {
+                parse_quantity(value)
+                    .filter(|v| *v > 0 && *v <= u64::from(u32::MAX))
+                    .map(|v| v as u32)
+                    .ok_or_else(invalid)
+            };
+            match key {
+                "stackSize" => profile.stack_size = Some(small()?),
+                "memoryPages" => profile.memory_pages = Some(small()?),
+                "timeBudgetSecs" => {
+                    profile.time_budget = match value.parse::<u64>() {
+                        Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+                        _ => return Err(invalid()),
+                    }
+                }
+                "wasiProfile" => profile.wasi_profile = Some(value.parse()?),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "unknown setting {}, expected stackSize, memoryPages, timeBudgetSecs or wasiProfile",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(profile)
+    }
+}
+
+/// The profiles every node has unless its configuration replaces them.
+fn builtin_runtime_profiles() -> BTreeMap<String, RuntimeProfile> {
+    let mut profiles = BTreeMap::new();
+    profiles.insert(
+        "small".to_owned(),
+        RuntimeProfile {
+            stack_size: Some(64 * 1024),
+            memory_pages: Some(256),
+            time_budget: None,
+            wasi_profile: Some(WasiProfile::Strict),
+        },
+    );
+    profiles.insert(
+        "standard".to_owned(),
+        RuntimeProfile {
+            stack_size: Some(256 * 1024),
+            memory_pages: Some(1024),
+            time_budget: None,
+            wasi_profile: Some(WasiProfile::Standard),
+        },
+    );
+    profiles.insert(
+        "compute".to_owned(),
+        RuntimeProfile {
+            stack_size: Some(1024 * 1024),
+            memory_pages: Some(16384),
+            time_budget: None,
+            wasi_profile: Some(WasiProfile::Permissive),
+        },
+    );
+    profiles
+}
+
+/// The namespace key of the limits used for namespaces without their own.
+pub const ANY_NAMESPACE: &str = "*";
+
+/// The default number of log files kept for each container: the current
+/// instance and the one before it.
+pub const DEFAULT_LOG_RETENTION: usize = 2;
+
+/// Configuration for the wasm3 provider.
+#[derive(Clone, Debug)]
+pub struct ProviderConfig {
+    /// Namespaces this provider accepts pods from. When empty, pods from all
+    /// namespaces are accepted.
+    pub namespaces: Vec<String>,
+    /// Address to serve health endpoints on. Health endpoints are disabled
+    /// when unset.
+    pub health_addr: Option<SocketAddr>,
+    /// Registry host checked by the readiness endpoint.
+    pub health_registry: Option<String>,
+    /// Certificate the health listener serves TLS with. Plain HTTP is served
+    /// when unset.
+    pub health_tls: Option<TlsFiles>,
+    /// How callers of `/metrics` are authenticated.
+    pub health_auth: EndpointAuth,
+    /// Path to a docker style configuration of registry credential helpers,
+    /// used when a pod has no image pull secret for a registry. See
+    /// [`CredentialConfig`](crate::credentials::CredentialConfig).
+    pub credential_config: Option<PathBuf>,
+    /// Where modules are loaded from.
+    pub module_source: ModuleSource,
+    /// Object store shared between nodes that modules pulled from registries
+    /// are cached in.
+    pub module_cache: Option<ModuleCache>,
+    /// Proxy URL used to reach registries.
+    pub registry_proxy: Option<String>,
+    /// Comma separated hosts that bypass `registry_proxy`.
+    pub registry_no_proxy: Option<String>,
+    /// PEM bundle of root CAs trusted when pulling from registries.
+    pub registry_ca_file: Option<PathBuf>,
+    /// File every container log request is appended to as a JSON line.
+    pub log_audit_file: Option<PathBuf>,
+    /// Keep modules of `restartPolicy: Always` pods parsed and linked after
+    /// they exit, so a restart only calls `_start` again. Linear memory is not
+    /// reset between runs.
+    pub memoize_modules: bool,
+    /// Limits on how long module setup may take.
+    pub setup_timeouts: SetupTimeouts,
+    /// Host environment variables pods may ask to inherit. Nothing is
+    /// forwarded when empty.
+    pub env_allowlist: Vec<String>,
+    /// Environment variables set in every module from the node's labels and
+    /// annotations. Variables the container sets itself take priority.
+    pub node_env: Vec<NodeEnvVar>,
+    /// How many log files to keep for each container, one per instance,
+    /// including the current one.
+    pub log_retention: usize,
+    /// Provide the `kv_get`, `kv_set` and `kv_delete` host functions, backed
+    /// by a store per pod under the data directory.
+    pub host_kv: bool,
+    /// Let modules watch Kubernetes resources in their pod's namespace with
+    /// the `k8s_watch` and `k8s_watch_next` host functions, as far as their
+    /// service account may.
+    pub host_watch: bool,
+    /// How long the handle of a pod whose containers have all exited is kept
+    /// before it is cleaned up.
+    pub finished_pod_ttl: Duration,
+    /// How long the logs of a deleted pod are kept on the node. They are
+    /// removed with the pod when unset.
+    pub deleted_pod_log_retention: Option<Duration>,
+    /// `registry/repository` patterns, where `*` matches any run of
+    /// characters, that images must match. All images are allowed when empty.
+    pub allowed_images: Vec<String>,
+    /// Bytes of available host memory below which the node reports
+    /// `MemoryPressure` and evicts pods. Eviction is disabled when unset.
+    pub eviction_memory_available: Option<u64>,
+    /// Stop `BestEffort` pods as soon as the node is cordoned, rather than
+    /// waiting for a drain to evict them.
+    pub drain_best_effort: bool,
+    /// Host files pods may ask to read with the `host_file_read` host
+    /// function. No files can be read when empty.
+    pub host_files: Vec<PathBuf>,
+    /// The largest module, in bytes, the provider will parse. Larger modules
+    /// fail validation with `ModuleTooLarge`. No limit when unset.
+    pub max_module_size: Option<u64>,
+    /// The largest stack, in bytes, a container is restarted with after its
+    /// module overflows the stack. Stacks are not raised when unset.
+    pub stack_autotune_max: Option<u32>,
+    /// The most host files each container may have open through WASI at
+    /// once. Opens past it fail with `EMFILE`. No limit when unset.
+    pub max_open_files: Option<usize>,
+    /// The most module instances that may be parsed, instantiated and
+    /// linked at once. Others wait their turn. No limit when unset.
+    pub max_concurrent_starts: Option<usize>,
+    /// Image of a companion module, such as a log shipper, started with every
+    /// pod and stopped with it.
+    pub sidecar_image: Option<String>,
+    /// Default module limits keyed by namespace, with [`ANY_NAMESPACE`] used
+    /// for namespaces that are not listed.
+    pub namespace_limits: HashMap<String, ModuleLimits>,
+    /// Runtime profiles pods can pick by name
+    pub runtime_profiles: BTreeMap<String, RuntimeProfile>,
+    /// Directory of static pod manifests, if static pods are run
+    pub pod_manifest_path: Option<PathBuf>,
+    /// Where the admin API listens, if it is served
+    pub admin_socket: Option<PathBuf>,
+    /// Tune the provider for small edge devices. See
+    /// [`ProviderConfig::apply_low_memory`].
+    pub low_memory: bool,
+    /// The node's zone. Looked up from `cloud_metadata` when unset.
+    pub topology_zone: Option<String>,
+    /// The node's region. Looked up from `cloud_metadata` when unset.
+    pub topology_region: Option<String>,
+    /// The node's instance type. Looked up from `cloud_metadata` when unset.
+    pub instance_type: Option<String>,
+    /// The metadata service to look up topology values that are not set
+    /// from. Nothing is looked up when unset.
+    pub cloud_metadata: Option<CloudMetadata>,
+    /// URL that pod started and container terminated events are POSTed to.
+    /// No events are sent when unset.
+    pub lifecycle_webhook: Option<String>,
+    /// Annotate running pods with a report of the modules they run and what
+    /// they may do.
+    pub attestation: bool,
+    /// Key the execution reports are signed with. Reports are not signed
+    /// when unset.
+    pub attestation_key_file: Option<PathBuf>,
+    /// Pull the images listed in `ModulePrewarm` resources ahead of time.
+    pub module_prewarm: bool,
+    /// Writes per second the provider may make to the Kubernetes API. Zero
+    /// means no limit.
+    pub api_qps: f64,
+    /// Writes the provider may make at once above `api_qps`.
+    pub api_burst: u32,
+    /// The least time between status patches for the same pod.
+    pub status_debounce: Duration,
+    /// How module output is buffered before it is written to the container
+    /// log. Pods can choose their own buffering.
+    pub stdout: StdoutPolicy,
+    /// Where module instances run.
+    pub executor: Executor,
+    /// Address the HTTP trigger gateway listens on. The gateway is disabled
+    /// when unset.
+    pub gateway_addr: Option<SocketAddr>,
+    /// How long the gateway waits for a handler to respond.
+    pub gateway_timeout: Duration,
+    /// The largest request or response body, in bytes, the gateway passes
+    /// on.
+    pub gateway_max_body: u64,
+}
+
+impl Default for ProviderConfig {
+    fn default() -> Self {
+        ProviderConfig {
+            namespaces: Vec::new(),
+            health_addr: None,
+            health_registry: None,
+            health_tls: None,
+            health_auth: EndpointAuth::default(),
+            credential_config: None,
+            module_source: ModuleSource::default(),
+            module_cache: None,
+            registry_proxy: None,
+            registry_no_proxy: None,
+            registry_ca_file: None,
+            log_audit_file: None,
+            memoize_modules: false,
+            setup_timeouts: SetupTimeouts::default(),
+            env_allowlist: Vec::new(),
+            node_env: Vec::new(),
+            log_retention: DEFAULT_LOG_RETENTION,
+            host_kv: false,
+            host_watch: false,
+            finished_pod_ttl: Duration::from_secs(300),
+            deleted_pod_log_retention: None,
+            allowed_images: Vec::new(),
+            eviction_memory_available: None,
+            drain_best_effort: false,
+            host_files: Vec::new(),
+            max_module_size: None,
+            stack_autotune_max: None,
+            max_open_files: None,
+            max_concurrent_starts: None,
+            sidecar_image: None,
+            namespace_limits: HashMap::new(),
+            runtime_profiles: builtin_runtime_profiles(),
+            pod_manifest_path: None,
+            admin_socket: None,
+            low_memory: false,
+            topology_zone: None,
+            topology_region: None,
+            instance_type: None,
+            cloud_metadata: None,
+            lifecycle_webhook: None,
+            attestation: false,
+            attestation_key_file: None,
+            module_prewarm: false,
+            api_qps: 50.0,
+            api_burst: 100,
+            status_debounce: Duration::from_millis(500),
+            stdout: StdoutPolicy::default(),
+            executor: Executor::default(),
+            gateway_addr: None,
+            gateway_timeout: Duration::from_secs(30),
+            gateway_max_body: 1 << 20,
+        }
+    }
+}
+
+/// The keys of the configuration file and the environment variable each one
+/// corresponds to.
+const FILE_KEYS: &[(&str, &str)] = &[
+    ("namespaces", NAMESPACES_ENV),
+    ("healthAddr", HEALTH_ADDR_ENV),
+    ("healthRegistry", HEALTH_REGISTRY_ENV),
+    ("healthTlsCertFile", HEALTH_TLS_CERT_ENV),
+    ("healthTlsKeyFile", HEALTH_TLS_KEY_ENV),
+    ("healthAuth", HEALTH_AUTH_ENV),
+    ("healthTokenFile", HEALTH_TOKEN_FILE_ENV),
+    ("credentialConfig", CREDENTIAL_CONFIG_ENV),
+    ("moduleSource", MODULE_SOURCE_ENV),
+    ("moduleCache", MODULE_CACHE_ENV),
+    ("moduleCacheTokenFile", MODULE_CACHE_TOKEN_FILE_ENV),
+    ("registryProxy", REGISTRY_PROXY_ENV),
+    ("registryNoProxy", REGISTRY_NO_PROXY_ENV),
+    ("registryCaFile", REGISTRY_CA_FILE_ENV),
+    ("logAuditFile", LOG_AUDIT_FILE_ENV),
+    ("memoizeModules", MEMOIZE_MODULES_ENV),
+    ("parseTimeoutSecs", PARSE_TIMEOUT_ENV),
+    ("instantiateTimeoutSecs", INSTANTIATE_TIMEOUT_ENV),
+    ("linkTimeoutSecs", LINK_TIMEOUT_ENV),
+    ("envAllowlist", ENV_ALLOWLIST_ENV),
+    ("nodeEnv", NODE_ENV_ENV),
+    ("logRetention", LOG_RETENTION_ENV),
+    ("hostKv", HOST_KV_ENV),
+    ("hostWatch", HOST_WATCH_ENV),
+    ("finishedPodTtlSecs", FINISHED_POD_TTL_ENV),
+    ("deletedPodLogRetentionSecs", DELETED_POD_LOG_RETENTION_ENV),
+    ("allowedImages", ALLOWED_IMAGES_ENV),
+    ("evictionMemoryAvailable", EVICTION_MEMORY_AVAILABLE_ENV),
+    ("drainBestEffort", DRAIN_BEST_EFFORT_ENV),
+    ("hostFiles", HOST_FILES_ENV),
+    ("maxModuleSize", MAX_MODULE_SIZE_ENV),
+    ("stackAutotuneMax", STACK_AUTOTUNE_MAX_ENV),
+    ("maxOpenFiles", MAX_OPEN_FILES_ENV),
+    ("maxConcurrentStarts", MAX_CONCURRENT_STARTS_ENV),
+    ("sidecarImage", SIDECAR_IMAGE_ENV),
+    ("namespaceLimits", NAMESPACE_LIMITS_ENV),
+    ("runtimeProfiles", RUNTIME_PROFILES_ENV),
+    ("podManifestPath", POD_MANIFEST_PATH_ENV),
+    ("adminSocket", ADMIN_SOCKET_ENV),
+    ("lowMemory", LOW_MEMORY_ENV),
+    ("topologyZone", TOPOLOGY_ZONE_ENV),
+    ("topologyRegion", TOPOLOGY_REGION_ENV),
+    ("instanceType", INSTANCE_TYPE_ENV),
+    ("cloudMetadata", CLOUD_METADATA_ENV),
+    ("lifecycleWebhook", LIFECYCLE_WEBHOOK_ENV),
+    ("attestation", ATTESTATION_ENV),
+    ("attestationKeyFile", ATTESTATION_KEY_FILE_ENV),
+    ("modulePrewarm", MODULE_PREWARM_ENV),
+    ("apiQps", API_QPS_ENV),
+    ("apiBurst", API_BURST_ENV),
+    ("statusDebounceMs", STATUS_DEBOUNCE_ENV),
+    ("stdoutBuffering", STDOUT_BUFFERING_ENV),
+    ("stdoutFlushIntervalMs", STDOUT_FLUSH_INTERVAL_ENV),
+    ("stdoutRateLimit", STDOUT_RATE_LIMIT_ENV),
+    ("containerLogFormat", CONTAINER_LOG_FORMAT_ENV),
+    ("executor", EXECUTOR_ENV),
+    ("executorCpus", EXECUTOR_CPUS_ENV),
+    ("gatewayAddr", GATEWAY_ADDR_ENV),
+    ("gatewayTimeoutSecs", GATEWAY_TIMEOUT_ENV),
+    ("gatewayMaxBody", GATEWAY_MAX_BODY_ENV),
+];
+
+/// Reads a configuration file into its values keyed by environment variable,
+/// so they can be parsed the same way. Lists are joined with commas.
+fn read_config_file(path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let raw = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("unable to read config file {}: {}", path.display(), e))?;
+    let file: HashMap<String, serde_yaml::Value> = serde_yaml::from_str(&raw)
+        .map_err(|e| anyhow::anyhow!("invalid config file {}: {}", path.display(), e))?;
+    let mut values = HashMap::new();
+    for (key, value) in file {
+        let env_key = FILE_KEYS
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, env_key)| *env_key)
+            .ok_or_else(|| {
+                anyhow::anyhow!("unknown setting {} in config file {}", key, path.display())
+            })?;
+        let value = match value {
+            serde_yaml::Value::Null => continue,
+            serde_yaml::Value::Sequence(items) => items
+                .iter()
+                .map(scalar_to_string)
+                .collect::<Option<Vec<_>>>()
+                .map(|items| items.join(",")),
+            value => scalar_to_string(&value),
+        }
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "invalid value for {} in config file {}",
+                key,
+                path.display()
+            )
+        })?;
+        values.insert(env_key.to_owned(), value);
+    }
+    Ok(values)
+}
+
+fn scalar_to_string(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(s) => Some(s.clone()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+impl ProviderConfig {
+    /// Builds a configuration from `WASM3_*` environment variables and the
+    /// file named by `WASM3_CONFIG_FILE`, using the default for anything that
+    /// is unset in both.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let file = match env_var(CONFIG_FILE_ENV)? {
+            Some(path) => read_config_file(Path::new(&path))?,
+            None => HashMap::new(),
+        };
+        let setting = |key: &str| -> anyhow::Result<Option<String>> {
+            match env_var(key)? {
+                Some(value) => Ok(Some(value)),
+                None => Ok(file.get(key).cloned()),
+            }
+        };
+        let mut config = ProviderConfig::default();
+        if let Some(namespaces) = setting(NAMESPACES_ENV)? {
+            config.namespaces = split_list(&namespaces);
+        }
+        if let Some(addr) = setting(HEALTH_ADDR_ENV)? {
+            let addr: SocketAddr = addr
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid value for {}: {}", HEALTH_ADDR_ENV, e))?;
+            config.health_addr = Some(addr);
+        }
+        config.health_registry = setting(HEALTH_REGISTRY_ENV)?;
+        config.health_tls = match (setting(HEALTH_TLS_CERT_ENV)?, setting(HEALTH_TLS_KEY_ENV)?) {
+            (Some(cert), Some(key)) => Some(TlsFiles {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "{} and {} must be set together",
+                    HEALTH_TLS_CERT_ENV,
+                    HEALTH_TLS_KEY_ENV
+                ))
+            }
+        };
+        if let Some(auth) = setting(HEALTH_AUTH_ENV)? {
+            config.health_auth = match auth.trim() {
+                "none" => EndpointAuth::None,
+                "token" => match setting(HEALTH_TOKEN_FILE_ENV)? {
+                    Some(path) => EndpointAuth::Token(PathBuf::from(path)),
+                    None => {
+                        return Err(anyhow::anyhow!(
+                            "{} is token but {} is not set",
+                            HEALTH_AUTH_ENV,
+                            HEALTH_TOKEN_FILE_ENV
+                        ))
+                    }
+                },
+                "webhook" => EndpointAuth::Webhook,
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "invalid value for {}: expected none, token or webhook",
+                        HEALTH_AUTH_ENV
+                    ))
+                }
+            };
+        }
+        config.credential_config = setting(CREDENTIAL_CONFIG_ENV)?.map(PathBuf::from);
+        if let Some(source) = setting(MODULE_SOURCE_ENV)? {
+            config.module_source = source.parse()?;
+        }
+        if let Some(cache) = setting(MODULE_CACHE_ENV)? {
+            let mut cache: ModuleCache = cache.parse()?;
+            if let ModuleCache::Http { token_file, .. } = &mut cache {
+                *token_file = setting(MODULE_CACHE_TOKEN_FILE_ENV)?.map(PathBuf::from);
+            }
+            config.module_cache = Some(cache);
+        }
+        config.registry_proxy = setting(REGISTRY_PROXY_ENV)?;
+        config.registry_no_proxy = setting(REGISTRY_NO_PROXY_ENV)?;
+        config.registry_ca_file = setting(REGISTRY_CA_FILE_ENV)?.map(PathBuf::from);
+        config.log_audit_file = setting(LOG_AUDIT_FILE_ENV)?.map(PathBuf::from);
+        if let Some(memoize) = setting(MEMOIZE_MODULES_ENV)? {
+            config.memoize_modules = parse_bool(MEMOIZE_MODULES_ENV, &memoize)?;
+        }
+        if let Some(secs) = setting(PARSE_TIMEOUT_ENV)? {
+            config.setup_timeouts.parse = parse_secs(PARSE_TIMEOUT_ENV, &secs)?;
+        }
+        if let Some(secs) = setting(INSTANTIATE_TIMEOUT_ENV)? {
+            config.setup_timeouts.instantiate = parse_secs(INSTANTIATE_TIMEOUT_ENV, &secs)?;
+        }
+        if let Some(secs) = setting(LINK_TIMEOUT_ENV)? {
+            config.setup_timeouts.link = parse_secs(LINK_TIMEOUT_ENV, &secs)?;
+        }
+        if let Some(allowlist) = setting(ENV_ALLOWLIST_ENV)? {
+            config.env_allowlist = split_list(&allowlist);
+        }
+        if let Some(vars) = setting(NODE_ENV_ENV)? {
+            config.node_env = split_list(&vars)
+                .iter()
+                .map(|v| v.parse())
+                .collect::<anyhow::Result<_>>()
+                .map_err(|e| anyhow::anyhow!("invalid value for {}: {}", NODE_ENV_ENV, e))?;
+        }
+        if let Some(retention) = setting(LOG_RETENTION_ENV)? {
+            config.log_retention = match retention.trim().parse() {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "invalid value for {}: expected a positive number",
+                        LOG_RETENTION_ENV
+                    ))
+                }
+            };
+        }
+        if let Some(kv) = setting(HOST_KV_ENV)? {
+            config.host_kv = parse_bool(HOST_KV_ENV, &kv)?;
+        }
+        if let Some(watch) = setting(HOST_WATCH_ENV)? {
+            config.host_watch = parse_bool(HOST_WATCH_ENV, &watch)?;
+        }
+        if let Some(secs) = setting(FINISHED_POD_TTL_ENV)? {
+            config.finished_pod_ttl = parse_secs(FINISHED_POD_TTL_ENV, &secs)?;
+        }
+        if let Some(secs) = setting(DELETED_POD_LOG_RETENTION_ENV)? {
+            config.deleted_pod_log_retention =
+                Some(parse_secs(DELETED_POD_LOG_RETENTION_ENV, &secs)?);
+        }
+        if let Some(allowed) = setting(ALLOWED_IMAGES_ENV)? {
+            config.allowed_images = split_list(&allowed);
+        }
+        if let Some(available) = setting(EVICTION_MEMORY_AVAILABLE_ENV)? {
+            config.eviction_memory_available =
+                Some(parse_bytes(EVICTION_MEMORY_AVAILABLE_ENV, &available)?);
+        }
+        if let Some(drain) = setting(DRAIN_BEST_EFFORT_ENV)? {
+            config.drain_best_effort = parse_bool(DRAIN_BEST_EFFORT_ENV, &drain)?;
+        }
+        if let Some(files) = setting(HOST_FILES_ENV)? {
+            config.host_files = split_list(&files).into_iter().map(PathBuf::from).collect();
+        }
+        if let Some(size) = setting(MAX_MODULE_SIZE_ENV)? {
+            config.max_module_size = Some(parse_bytes(MAX_MODULE_SIZE_ENV, &size)?);
+        }
+        if let Some(size) = setting(STACK_AUTOTUNE_MAX_ENV)? {
+            let size = parse_bytes(STACK_AUTOTUNE_MAX_ENV, &size)?;
+            config.stack_autotune_max = Some(u32::try_from(size).map_err(|_| {
+                anyhow::anyhow!("invalid value for {}: too large", STACK_AUTOTUNE_MAX_ENV)
+            })?);
+        }
+        if let Some(max) = setting(MAX_OPEN_FILES_ENV)? {
+            config.max_open_files = match max.trim().parse() {
+                Ok(n) if n > 0 => Some(n),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "invalid value for {}: expected a positive number",
+                        MAX_OPEN_FILES_ENV
+                    ))
+                }
+            };
+        }
+        if let Some(max) = setting(MAX_CONCURRENT_STARTS_ENV)? {
+            config.max_concurrent_starts = match max.trim().parse() {
+                Ok(n) if n > 0 => Some(n),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "invalid value for {}: expected a positive number",
+                        MAX_CONCURRENT_STARTS_ENV
+                    ))
+                }
+            };
+        }
+        config.sidecar_image = setting(SIDECAR_IMAGE_ENV)?;
+        if let Some(limits) = setting(NAMESPACE_LIMITS_ENV)? {
+            for entry in split_list(&limits) {
+                let (namespace, limits) = match entry.find(':') {
+                    Some(i) => (&entry[..i], &entry[i + 1..]),
+                    None => {
+                        return Err(anyhow::anyhow!(
+                            "invalid value for {}: expected namespace:key=value entries",
+                            NAMESPACE_LIMITS_ENV
+                        ))
+                    }
+                };
+                let limits = limits.parse().map_err(|e| {
+                    anyhow::anyhow!("invalid value for {}: {}", NAMESPACE_LIMITS_ENV, e)
+                })?;
+                config
+                    .namespace_limits
+                    .insert(namespace.trim().to_owned(), limits);
+            }
+        }
+        if let Some(profiles) = setting(RUNTIME_PROFILES_ENV)? {
+            for entry in split_list(&profiles) {
+                let (name, profile) = match entry.find(':') {
+                    Some(i) if !entry[..i].trim().is_empty() => (&entry[..i], &entry[i + 1..]),
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "invalid value for {}: expected name:key=value entries",
+                            RUNTIME_PROFILES_ENV
+                        ))
+                    }
+                };
+                let profile = profile.parse().map_err(|e| {
+                    anyhow::anyhow!("invalid value for {}: {}", RUNTIME_PROFILES_ENV, e)
+                })?;
+                config
+                    .runtime_profiles
+                    .insert(name.trim().to_owned(), profile);
+            }
+        }
+        config.pod_manifest_path = setting(POD_MANIFEST_PATH_ENV)?.map(PathBuf::from);
+        config.admin_socket = setting(ADMIN_SOCKET_ENV)?.map(PathBuf::from);
+        if let Some(low_memory) = setting(LOW_MEMORY_ENV)? {
+            if parse_bool(LOW_MEMORY_ENV, &low_memory)? {
+                config.apply_low_memory();
+            }
+        }
+        config.topology_zone = setting(TOPOLOGY_ZONE_ENV)?;
+        config.topology_region = setting(TOPOLOGY_REGION_ENV)?;
+        config.instance_type = setting(INSTANCE_TYPE_ENV)?;
+        if let Some(cloud) = setting(CLOUD_METADATA_ENV)? {
+            config.cloud_metadata = Some(cloud.parse()?);
+        }
+        config.lifecycle_webhook = setting(LIFECYCLE_WEBHOOK_ENV)?;
+        if let Some(attestation) = setting(ATTESTATION_ENV)? {
+            config.attestation = parse_bool(ATTESTATION_ENV, &attestation)?;
+        }
+        config.attestation_key_file = setting(ATTESTATION_KEY_FILE_ENV)?.map(PathBuf::from);
+        if config.attestation_key_file.is_some() && !config.attestation {
+            return Err(anyhow::anyhow!(
+                "{} is set, but {} is not",
+                ATTESTATION_KEY_FILE_ENV,
+                ATTESTATION_ENV
+            ));
+        }
+        if let Some(prewarm) = setting(MODULE_PREWARM_ENV)? {
+            config.module_prewarm = parse_bool(MODULE_PREWARM_ENV, &prewarm)?;
+        }
+        if let Some(qps) = setting(API_QPS_ENV)? {
+            config.api_qps = match qps.trim().parse::<f64>() {
+                Ok(qps) if qps >= 0.0 && qps.is_finite() => qps,
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "invalid value for {}: expected a number of writes per second",
+                        API_QPS_ENV
+                    ))
+                }
+            };
+        }
+        if let Some(burst) = setting(API_BURST_ENV)? {
+            config.api_burst = match burst.trim().parse() {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "invalid value for {}: expected a positive number",
+                        API_BURST_ENV
+                    ))
+                }
+            };
+        }
+        if let Some(debounce) = setting(STATUS_DEBOUNCE_ENV)? {
+            config.status_debounce = match debounce.trim().parse() {
+                Ok(ms) => Duration::from_millis(ms),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "invalid value for {}: expected a number of milliseconds",
+                        STATUS_DEBOUNCE_ENV
+                    ))
+                }
+            };
+        }
+        if let Some(buffering) = setting(STDOUT_BUFFERING_ENV)? {
+            config.stdout.buffering = buffering.parse().map_err(|e| {
+                anyhow::anyhow!("invalid value for {}: {}", STDOUT_BUFFERING_ENV, e)
+            })?;
+        }
+        if let Some(interval) = setting(STDOUT_FLUSH_INTERVAL_ENV)? {
+            config.stdout.flush_interval = match interval.trim().parse() {
+                Ok(ms) if ms > 0 => Duration::from_millis(ms),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "invalid value for {}: expected a positive number of milliseconds",
+                        STDOUT_FLUSH_INTERVAL_ENV
+                    ))
+                }
+            };
+        }
+        if let Some(limit) = setting(STDOUT_RATE_LIMIT_ENV)? {
+            config.stdout.rate_limit = Some(parse_bytes(STDOUT_RATE_LIMIT_ENV, &limit)?);
+        }
+        if let Some(format) = setting(CONTAINER_LOG_FORMAT_ENV)? {
+            config.stdout.format = format.parse().map_err(|e| {
+                anyhow::anyhow!("invalid value for {}: {}", CONTAINER_LOG_FORMAT_ENV, e)
+            })?;
+        }
+        if let Some(executor) = setting(EXECUTOR_ENV)? {
+            config.executor = match executor.trim() {
+                "shared" => Executor::Shared,
+                "pinned" => Executor::Pinned(Vec::new()),
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "invalid value for {}: expected shared or pinned",
+                        EXECUTOR_ENV
+                    ))
+                }
+            };
+        }
+        if let Some(cpus) = setting(EXECUTOR_CPUS_ENV)? {
+            match &mut config.executor {
+                Executor::Pinned(pinned) => *pinned = parse_cpus(EXECUTOR_CPUS_ENV, &cpus)?,
+                Executor::Shared => {
+                    return Err(anyhow::anyhow!(
+                        "{} is set but {} is not pinned",
+                        EXECUTOR_CPUS_ENV,
+                        EXECUTOR_ENV
+                    ))
+                }
+            }
+        }
+        if let Some(addr) = setting(GATEWAY_ADDR_ENV)? {
+            let addr: SocketAddr = addr
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid value for {}: {}", GATEWAY_ADDR_ENV, e))?;
+            config.gateway_addr = Some(addr);
+        }
+        if let Some(secs) = setting(GATEWAY_TIMEOUT_ENV)? {
+            config.gateway_timeout = parse_secs(GATEWAY_TIMEOUT_ENV, &secs)?;
+        }
+        if let Some(size) = setting(GATEWAY_MAX_BODY_ENV)? {
+            config.gateway_max_body = parse_bytes(GATEWAY_MAX_BODY_ENV, &size)?;
+        }
+        Ok(config)
+    }
+
+    /// Switches to the low memory mode, overriding settings that cost
+    /// memory: modules are not memoized or shared through a module cache,
+    /// only the current log file of each container is kept, stacks are
+    /// capped at [`LOW_MEMORY_MAX_STACK_SIZE`] and memory defaults to
+    /// [`LOW_MEMORY_MEMORY_PAGES`]. Pulled modules are also dropped once a pod
+    /// has started, rather than kept for restarts.
+    pub fn apply_low_memory(&mut self) {
+        self.low_memory = true;
+        self.memoize_modules = false;
+        self.module_cache = None;
+        self.log_retention = 1;
+    }
+
+    /// Returns true if pods in `namespace` may run on this provider.
+    pub fn allows_namespace(&self, namespace: &str) -> bool {
+        self.namespaces.is_empty() || self.namespaces.iter().any(|n| n == namespace)
+    }
+
+    /// Returns the default module limits for pods in `namespace`.
+    pub fn limits_for(&self, namespace: &str) -> ModuleLimits {
+        self.namespace_limits
+            .get(namespace)
+            .or_else(|| self.namespace_limits.get(ANY_NAMESPACE))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns true if images from `repository`, given as
+    /// `registry/repository`, may run on this provider.
+    pub fn allows_image(&self, repository: &str) -> bool {
+        self.allowed_images.is_empty()
+            || self
+                .allowed_images
+                .iter()
+                .any(|p| crate::credentials::wildcard_match(p, repository))
+    }
+}
+
+/// Reads an environment variable, treating unset and empty the same way.
+fn env_var(key: &str) -> anyhow::Result<Option<String>> {
+    match env::var(key) {
+        Ok(v) if v.trim().is_empty() => Ok(None),
+        Ok(v) => Ok(Some(v)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(anyhow::anyhow!("invalid value for {}: {}", key, e)),
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> anyhow::Result<bool> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid value for {}: expected true or false", key))
+}
+
+/// Parses a list of cores in the kernel's format, such as `0-3,6`.
+fn parse_cpus(key: &str, value: &str) -> anyhow::Result<Vec<usize>> {
+    let invalid = || anyhow::anyhow!("invalid value for {}: expected cores such as 0-3,6", key);
+    let mut cpus = Vec::new();
+    for part in split_list(value) {
+        match part.find('-') {
+            Some(i) => {
+                let first: usize = part[..i].trim().parse().map_err(|_| invalid())?;
+                let last: usize = part[i + 1..].trim().parse().map_err(|_| invalid())?;
+                if first > last {
+                    return Err(invalid());
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(part.trim().parse().map_err(|_| invalid())?),
+        }
+    }
+    if cpus.is_empty() {
+        return Err(invalid());
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+fn parse_secs(key: &str, value: &str) -> anyhow::Result<Duration> {
+    match value.trim().parse::<u64>() {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(anyhow::anyhow!(
+            "invalid value for {}: expected a positive number of seconds",
+            key
+        )),
+    }
+}
+
+/// Parses a byte count, either plain or with a `Ki`, `Mi` or `Gi` suffix as
+/// in Kubernetes quantities.
+fn parse_bytes(key: &str, value: &str) -> anyhow::Result<u64> {
+    match parse_quantity(value) {
+        Some(n) if n > 0 => Ok(n),
+        _ => Err(anyhow::anyhow!(
+            "invalid value for {}: expected a size such as 100Mi",
+            key
+        )),
+    }
+}
+
+/// Parses a whole Kubernetes quantity with an optional binary (`Ki`, `Mi`,
+/// `Gi`) or decimal (`k`, `M`, `G`) suffix.
+pub(crate) fn parse_quantity(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (digits, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, ""),
+    };
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "k" => 1_000,
+        "M" => 1_000_000,
+        "G" => 1_000_000_000,
+        "Ki" => 1 << 10,
+        "Mi" => 1 << 20,
+        "Gi" => 1 << 30,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Splits a comma separated list, dropping empty entries.
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
@@ -0,0 +1,156 @@
+//! Follows whether the node is cordoned, as `kubectl cordon` and `kubectl
+//! drain` do before evicting pods. While it is, the node admits no new pods
+//! except those that tolerate `node.kubernetes.io/unschedulable`, such as
+//! DaemonSet pods, and reports the `SchedulingDisabled` condition. With
+//! `WASM3_DRAIN_BEST_EFFORT` set, `BestEffort` pods are stopped as soon as the
+//! node is cordoned rather than waiting for the drain to evict them.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Node;
+use kube::api::Api;
+use kubelet::pod::{key_from_pod, Pod};
+use log::{error, info};
+
+use crate::admission;
+use crate::eviction::set_node_condition;
+use crate::qos::QosClass;
+use crate::SharedPodState;
+
+/// How often the node is checked.
+const MONITOR_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The taint the node lifecycle controller gives cordoned nodes.
+const UNSCHEDULABLE_TAINT: &str = "node.kubernetes.io/unschedulable";
+
+/// The condition the node reports while it is cordoned.
+const CONDITION: &str = "SchedulingDisabled";
+
+/// The reason pods are rejected and stopped with while the node is cordoned.
+pub(crate) const CORDONED_REASON: &str = "NodeCordoned";
+
+/// Tracks whether the node is cordoned.
+#[derive(Default)]
+pub(crate) struct Cordon(AtomicBool);
+
+impl Cordon {
+    pub(crate) fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Records the current state, returning true if it changed.
+    fn update(&self, cordoned: bool) -> bool {
+        self.0.swap(cordoned, Ordering::SeqCst) != cordoned
+    }
+}
+
+/// Returns true if the pod may still start on a cordoned node.
+pub(crate) fn tolerates_cordon(pod: &Pod) -> bool {
+    let tolerations = pod
+        .as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|s| s.tolerations.as_ref());
+    tolerations.map_or(false, |tolerations| {
+        tolerations.iter().any(|t| {
+            let key_matches = match t.key.as_deref() {
+                Some(key) => key == UNSCHEDULABLE_TAINT,
+                // An empty key with `Exists` tolerates every taint
+                None => t.operator.as_deref() == Some("Exists"),
+            };
+            key_matches && matches!(t.effect.as_deref(), None | Some("NoSchedule"))
+        })
+    })
+}
+
+fn is_cordoned(node: &Node) -> bool {
+    let spec = match &node.spec {
+        Some(spec) => spec,
+        None => return false,
+    };
+    spec.unschedulable == Some(true)
+        || spec.taints.as_ref().map_or(false, |taints| {
+            taints.iter().any(|t| t.key == UNSCHEDULABLE_TAINT)
+        })
+}
+
+/// Watches the node for being cordoned and uncordoned. Runs until the process
+/// exits.
+pub(crate) async fn cordon_loop(shared: SharedPodState) {
+    let api: Api<Node> = Api::all(kube::Client::new(shared.kubeconfig.clone()));
+    loop {
+        shared.clock.sleep(MONITOR_INTERVAL).await;
+        let cordoned = match api.get(&shared.node_name).await {
+            Ok(node) => is_cordoned(&node),
+            Err(e) => {
+                error!("Unable to check whether the node is cordoned: {:?}", e);
+                continue;
+            }
+        };
+        if !shared.cordon.update(cordoned) {
+            continue;
+        }
+        shared.metrics.set_gauge(
+            "wasm3_node_cordoned",
+            "1 while the node is cordoned and admits no new pods",
+            &[],
+            if cordoned { 1.0 } else { 0.0 },
+        );
+        let condition = if cordoned {
+            info!("Node was cordoned, no longer admitting pods");
+            set_node_condition(
+                &shared,
+                CONDITION,
+                true,
+                CORDONED_REASON,
+                "node is cordoned and admits no new pods",
+            )
+            .await
+        } else {
+            info!("Node was uncordoned, admitting pods again");
+            set_node_condition(
+                &shared,
+                CONDITION,
+                false,
+                "NodeSchedulable",
+                "node admits new pods",
+            )
+            .await
+        };
+        if let Err(e) = condition {
+            error!("Unable to update {} condition: {:?}", CONDITION, e);
+        }
+        if cordoned && shared.config.drain_best_effort {
+            stop_best_effort(&shared).await;
+        }
+    }
+}
+
+/// Stops every `BestEffort` pod, so its controller can replace it on another
+/// node straight away. Each pod keeps its slot until its instances have
+/// exited, see [`admission::stop_pod`].
+async fn stop_best_effort(shared: &SharedPodState) {
+    let pods = shared
+        .admission
+        .eviction_order()
+        .await
+        .into_iter()
+        .filter(|(pod, qos)| *qos == QosClass::BestEffort && !tolerates_cordon(pod));
+    // Stopped together, as each may take until its module returns
+    let stops = pods.map(|(pod, _)| async move {
+        info!(
+            "Stopping BestEffort pod {} as the node is cordoned",
+            key_from_pod(&pod)
+        );
+        admission::stop_pod(
+            shared,
+            &pod,
+            "Killing",
+            CORDONED_REASON,
+            "Stopping BestEffort pod as the node was cordoned",
+        )
+        .await;
+    });
+    futures::future::join_all(stops).await;
+}
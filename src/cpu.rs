@@ -0,0 +1,107 @@
+//! The pinned executor, for nodes with several cores. Each instance runs on a
+//! thread of its own, pinned to one core, with every instance of a pod on the
+//! same core. Pods are spread over the cores round robin, or put on the core
+//! their `wasm3.krustlet.dev/cpu` annotation asks for, so a busy pod only
+//! competes with the pods sharing its core and its modules stay in that
+//! core's caches.
+//!
+//! wasm3 environments cannot be shared between threads and a running module
+//! cannot be paused, so each instance keeps its own environment, as with the
+//! shared executor. What changes is which thread it runs on: the shared
+//! executor uses the async runtime's blocking pool, whose threads go wherever
+//! the scheduler puts them.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The cores pinned instances run on.
+pub(crate) struct CpuPool {
+    cpus: Vec<usize>,
+    next: AtomicUsize,
+}
+
+impl CpuPool {
+    /// Creates a pool of `cpus`, or of every core the provider may run on
+    /// when empty.
+    pub(crate) fn new(cpus: &[usize]) -> io::Result<Self> {
+        let cpus = if cpus.is_empty() {
+            allowed_cpus()?
+        } else {
+            cpus.to_vec()
+        };
+        if cpus.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "no cores to pin instances to",
+            ));
+        }
+        Ok(CpuPool {
+            cpus,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// The core a new pod runs on: `requested` if there is one, otherwise
+    /// the next one round robin. A requested core that is not in the pool is
+    /// returned as the error.
+    pub(crate) fn assign(&self, requested: Option<usize>) -> Result<usize, usize> {
+        match requested {
+            Some(cpu) if self.cpus.contains(&cpu) => Ok(cpu),
+            Some(cpu) => Err(cpu),
+            None => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                Ok(self.cpus[next % self.cpus.len()])
+            }
+        }
+    }
+
+    /// The cores in the pool.
+    pub(crate) fn cpus(&self) -> &[usize] {
+        &self.cpus
+    }
+}
+
+/// Pins the calling thread to `cpu`.
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    // Safety: the set is initialized before use and only read by the kernel
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pin_current_thread(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "pinning threads is only supported on Linux",
+    ))
+}
+
+/// The cores the process may run on, which respects cpusets.
+#[cfg(target_os = "linux")]
+fn allowed_cpus() -> io::Result<Vec<usize>> {
+    // Safety: the set is written by the kernel before it is read
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|cpu| libc::CPU_ISSET(*cpu, &set))
+            .collect())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allowed_cpus() -> io::Result<Vec<usize>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "pinning threads is only supported on Linux",
+    ))
+}
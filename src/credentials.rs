@@ -0,0 +1,122 @@
+//! Node level registry credentials, resolved through docker credential
+//! helpers (`docker-credential-<name>`) such as `ecr-login`, `gcr` or
+//! `acr-env`.
+//!
+//! The configuration file uses the same `credHelpers` and `credsStore` keys
+//! as a docker `config.json`:
+//!
+//! ```json
+//! {
+//!   "credHelpers": {
+//!     "*.dkr.ecr.us-west-2.amazonaws.com": "ecr-login",
+//!     "gcr.io": "gcr"
+//!   },
+//!   "credsStore": "desktop"
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+
+use log::debug;
+use oci_distribution::secrets::RegistryAuth;
+use serde_derive::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+const HELPER_PREFIX: &str = "docker-credential-";
+
+/// Credential helpers configured for this node.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialConfig {
+    /// Credential helper names keyed by registry host. Hosts may contain `*`
+    /// wildcards.
+    #[serde(default)]
+    pub cred_helpers: HashMap<String, String>,
+    /// A credential helper used for registries without a specific helper.
+    #[serde(default)]
+    pub creds_store: Option<String>,
+}
+
+/// The response of a credential helper `get` command.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+impl CredentialConfig {
+    /// Loads the configuration from a JSON file.
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = tokio::fs::read(path).await.map_err(|e| {
+            anyhow::anyhow!("unable to read credential config {}: {}", path.display(), e)
+        })?;
+        Ok(serde_json::from_slice(&raw)?)
+    }
+
+    fn helper_for(&self, registry: &str) -> Option<&str> {
+        self.cred_helpers
+            .iter()
+            .find(|(pattern, _)| wildcard_match(pattern, registry))
+            .map(|(_, helper)| helper.as_str())
+            .or_else(|| self.creds_store.as_deref())
+    }
+
+    /// Resolves credentials for `registry` through its configured helper.
+    /// Returns `None` if no helper is configured for it.
+    pub(crate) async fn resolve(&self, registry: &str) -> anyhow::Result<Option<RegistryAuth>> {
+        let helper = match self.helper_for(registry) {
+            Some(h) => format!("{}{}", HELPER_PREFIX, h),
+            None => return Ok(None),
+        };
+        debug!("Resolving credentials for {} with {}", registry, helper);
+
+        let mut child = tokio::process::Command::new(&helper)
+            .arg("get")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("unable to run credential helper {}: {}", helper, e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(registry.as_bytes()).await?;
+        }
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "credential helper {} failed for {}: {}",
+                helper,
+                registry,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let creds: HelperCredentials = serde_json::from_slice(&output.stdout)?;
+        Ok(Some(RegistryAuth::Basic(creds.username, creds.secret)))
+    }
+}
+
+/// Matches `value` against `pattern`, where `*` matches any run of
+/// characters.
+pub(crate) fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !value.starts_with(first) {
+        return false;
+    }
+    let mut rest = &value[first.len()..];
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(l) => l,
+        // No wildcard at all, so the pattern must match exactly
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
@@ -0,0 +1,135 @@
+//! The CRI container log format, which container runtimes write and log
+//! collectors such as Fluentd and Fluent Bit parse out of the box. Each line
+//! of the log is one record:
+//!
+//! ```text
+//! 2020-10-16T12:00:00.123456789Z stdout F a line of output
+//! ```
+//!
+//! The tag is `F` for a full line, or `P` for part of a line that continues
+//! in the next record of the same stream. A line is split into partial
+//! records when it grows past [`MAX_RECORD_LEN`], and when output is flushed
+//! before the line has ended.
+
+use std::sync::Arc;
+
+use chrono::SecondsFormat;
+
+use crate::clock::Clock;
+
+/// The longest content of one record, as containerd uses.
+const MAX_RECORD_LEN: usize = 16 * 1024;
+
+/// The stream output was written to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LogStream {
+    Stdout = 0,
+    Stderr = 1,
+}
+
+impl LogStream {
+    fn name(self) -> &'static str {
+        match self {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+        }
+    }
+}
+
+fn record(out: &mut Vec<u8>, timestamp: &str, stream: LogStream, tag: &str, content: &[u8]) {
+    out.extend_from_slice(timestamp.as_bytes());
+    out.push(b' ');
+    out.extend_from_slice(stream.name().as_bytes());
+    out.push(b' ');
+    out.extend_from_slice(tag.as_bytes());
+    out.push(b' ');
+    out.extend_from_slice(content);
+    out.push(b'\n');
+}
+
+/// Turns module output into records. The unfinished last line of each stream
+/// is held back until it ends or [`Encoder::flush_partial`] is called.
+pub(crate) struct Encoder {
+    partial: [Vec<u8>; 2],
+    /// What records are timestamped with
+    clock: Arc<dyn Clock>,
+}
+
+impl Encoder {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Encoder {
+            partial: Default::default(),
+            clock,
+        }
+    }
+
+    /// Returns the records for the lines `data` completes.
+    pub(crate) fn encode(&mut self, stream: LogStream, data: &[u8]) -> Vec<u8> {
+        let timestamp = self.clock.now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let pending = &mut self.partial[stream as usize];
+        pending.extend_from_slice(data);
+        let mut out = Vec::new();
+        while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            record(&mut out, &timestamp, stream, "F", &line[..end]);
+        }
+        while pending.len() > MAX_RECORD_LEN {
+            let part: Vec<u8> = pending.drain(..MAX_RECORD_LEN).collect();
+            record(&mut out, &timestamp, stream, "P", &part);
+        }
+        out
+    }
+
+    /// Returns the unfinished lines held back as partial records.
+    pub(crate) fn flush_partial(&mut self) -> Vec<u8> {
+        let timestamp = self.clock.now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let mut out = Vec::new();
+        for stream in [LogStream::Stdout, LogStream::Stderr].iter().copied() {
+            let pending = &mut self.partial[stream as usize];
+            if !pending.is_empty() {
+                record(&mut out, &timestamp, stream, "P", pending);
+                pending.clear();
+            }
+        }
+        out
+    }
+}
+
+/// Turns records back into the output they were made from, for
+/// `kubectl logs`. An incomplete record is held back until the rest is read.
+#[derive(Default)]
+pub(crate) struct Decoder {
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    /// Returns the output of the records `data` completes.
+    pub(crate) fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(data);
+        let mut out = Vec::new();
+        while let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = &line[..end];
+            let mut fields = line.splitn(4, |b| *b == b' ');
+            match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(_), Some(_), Some(tag), Some(content)) => {
+                    out.extend_from_slice(content);
+                    if tag != b"P" {
+                        out.push(b'\n');
+                    }
+                }
+                // Not a record, so it is passed on as it is
+                _ => {
+                    out.extend_from_slice(line);
+                    out.push(b'\n');
+                }
+            }
+        }
+        out
+    }
+
+    /// Forgets a record read in part, after seeking elsewhere in the log.
+    pub(crate) fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
@@ -0,0 +1,278 @@
+//! Dead letters: the inputs of calls on a pod's live instances that failed,
+//! kept for inspection and replay. A pod opts in with the
+//! `wasm3.krustlet.dev/dead-letter` annotation, and every `kubectl exec` call
+//! or HTTP trigger request whose handler traps, or that does not respond in
+//! time, is then written out with the error as a JSON record:
+//!
+//! * `dir` writes each record to a file under
+//!   `<data dir>/wasm3-dead-letters/<namespace>/<pod>/`, removed with the pod
+//! * `configmap:<name>` adds each record as a key of that ConfigMap in the
+//!   pod's namespace, creating it if needed, so it outlives the pod and can be
+//!   read from outside the node
+//!
+//! Records are named `<milliseconds since the epoch>-<pod>-<container>.json`
+//! so they sort in the order the calls failed. Only the newest
+//! [`MAX_LETTERS`] are kept per pod in a directory, or per ConfigMap, and
+//! request bodies stored in a ConfigMap are cut to [`MAX_CONFIG_MAP_BODY`] to
+//! stay within the API server's object size limit.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use k8s_openapi::api::core::v1::ConfigMap;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::api::{Api, PostParams};
+use kubelet::pod::Pod;
+use log::{debug, warn};
+
+use crate::gateway::HttpRequest;
+use crate::states::is_not_found;
+use crate::SharedPodState;
+
+/// Annotation naming where the pod's dead letters are kept: `dir` or
+/// `configmap:<name>`.
+const DEAD_LETTER_ANNOTATION: &str = "wasm3.krustlet.dev/dead-letter";
+
+/// The most dead letters kept per pod directory or ConfigMap.
+const MAX_LETTERS: usize = 50;
+
+/// The most bytes of a request body kept in a ConfigMap.
+const MAX_CONFIG_MAP_BODY: usize = 16 * 1024;
+
+/// How many times a ConfigMap update that raced another is tried again.
+const CONFLICT_RETRIES: usize = 3;
+
+/// Where a pod's dead letters are kept.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Sink {
+    /// A directory for the pod on the node
+    Dir,
+    /// A ConfigMap of this name in the pod's namespace
+    ConfigMap(String),
+}
+
+/// The sink the pod asked for, if any.
+pub(crate) fn sink(pod: &Pod) -> anyhow::Result<Option<Sink>> {
+    let value = match pod.annotations().get(DEAD_LETTER_ANNOTATION) {
+        Some(value) => value.trim(),
+        None => return Ok(None),
+    };
+    if value == "dir" {
+        return Ok(Some(Sink::Dir));
+    }
+    match value.strip_prefix("configmap:").map(str::trim) {
+        Some(name) if !name.is_empty() => Ok(Some(Sink::ConfigMap(name.to_owned()))),
+        _ => Err(anyhow::anyhow!(
+            "invalid {} annotation {:?}, expected dir or configmap:<name>",
+            DEAD_LETTER_ANNOTATION,
+            value
+        )),
+    }
+}
+
+/// The input of a failed call.
+pub(crate) enum Input<'a> {
+    /// A `kubectl exec` call, with the command as given
+    Exec(&'a str),
+    /// A request from the HTTP trigger gateway
+    Http(&'a HttpRequest),
+}
+
+/// The pod a failed call was made on.
+pub(crate) struct Target<'a> {
+    pub(crate) namespace: &'a str,
+    pub(crate) pod: &'a str,
+    pub(crate) container: &'a str,
+    /// The export that was called
+    pub(crate) export: &'a str,
+}
+
+/// The directory holding a pod's dead letters.
+fn pod_dir(dead_letter_path: &Path, namespace: &str, pod: &str) -> PathBuf {
+    dead_letter_path.join(namespace).join(pod)
+}
+
+/// Writes the dead letter of a failed call to `sink` in the background. A
+/// letter that cannot be written is logged and dropped.
+pub(crate) fn capture(
+    shared: &SharedPodState,
+    sink: Sink,
+    target: Target<'_>,
+    input: Input<'_>,
+    error: &str,
+) {
+    let now = chrono::Utc::now();
+    let name = format!(
+        "{:013}-{}-{}.json",
+        now.timestamp_millis(),
+        target.pod,
+        target.container
+    );
+    let body_limit = match sink {
+        Sink::Dir => usize::MAX,
+        Sink::ConfigMap(_) => MAX_CONFIG_MAP_BODY,
+    };
+    let input = match input {
+        Input::Exec(command) => serde_json::json!({
+            "trigger": "exec",
+            "command": command,
+        }),
+        Input::Http(request) => {
+            let body = &request.body[..request.body.len().min(body_limit)];
+            let mut input = serde_json::json!({
+                "trigger": "http",
+                "method": request.method,
+                "path": request.path,
+                "headers": request.headers.lines().collect::<Vec<_>>(),
+            });
+            // JSON cannot hold arbitrary bytes, so a body that is not text is
+            // kept hex encoded
+            match std::str::from_utf8(body) {
+                Ok(text) => input["body"] = text.into(),
+                Err(_) => input["bodyHex"] = hex::encode(body).into(),
+            }
+            if body.len() < request.body.len() {
+                input["bodyTruncated"] = true.into();
+            }
+            input
+        }
+    };
+    let letter = serde_json::json!({
+        "time": now.to_rfc3339(),
+        "namespace": target.namespace,
+        "pod": target.pod,
+        "container": target.container,
+        "export": target.export,
+        "error": error,
+        "input": input,
+    })
+    .to_string();
+    shared.metrics.inc_counter(
+        "wasm3_dead_letters_total",
+        "Inputs of failed calls kept as dead letters",
+        &[
+            ("namespace", target.namespace),
+            ("pod", target.pod),
+            ("container", target.container),
+        ],
+        1.0,
+    );
+    let namespace = target.namespace.to_owned();
+    let pod = target.pod.to_owned();
+    let shared = shared.clone();
+    tokio::spawn(async move {
+        let result = match &sink {
+            Sink::Dir => {
+                let dir = pod_dir(&shared.dead_letter_path, &namespace, &pod);
+                tokio::task::spawn_blocking(move || write_file(&dir, &name, &letter))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|r| r)
+            }
+            Sink::ConfigMap(config_map) => {
+                write_config_map(&shared, &namespace, config_map, name, letter).await
+            }
+        };
+        match result {
+            Ok(()) => debug!("Kept dead letter of pod {} in {:?}", pod, sink),
+            Err(e) => warn!("Unable to keep dead letter of pod {}: {:?}", pod, e),
+        }
+    });
+}
+
+/// Writes a letter to the pod's directory and removes the oldest past
+/// [`MAX_LETTERS`]. This does blocking IO.
+fn write_file(dir: &Path, name: &str, letter: &str) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    // Written whole and renamed, so a reader never sees half a letter
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    std::io::Write::write_all(&mut file, letter.as_bytes())?;
+    file.persist(dir.join(name))?;
+    let mut letters: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().map_or(false, |e| e == "json"))
+        .collect();
+    if letters.len() > MAX_LETTERS {
+        letters.sort();
+        for old in &letters[..letters.len() - MAX_LETTERS] {
+            std::fs::remove_file(old)?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds a letter to the ConfigMap, creating it if it does not exist, and
+/// removes the oldest past [`MAX_LETTERS`].
+async fn write_config_map(
+    shared: &SharedPodState,
+    namespace: &str,
+    name: &str,
+    key: String,
+    letter: String,
+) -> anyhow::Result<()> {
+    let api: Api<ConfigMap> =
+        Api::namespaced(kube::Client::new(shared.kubeconfig.clone()), namespace);
+    let mut attempt = 0;
+    loop {
+        let existing = match api.get(name).await {
+            Ok(config_map) => Some(config_map),
+            Err(e) if is_not_found(&e) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let result = match existing {
+            Some(mut config_map) => {
+                let data = config_map.data.get_or_insert_with(BTreeMap::new);
+                data.insert(key.clone(), letter.clone());
+                while data.len() > MAX_LETTERS {
+                    let oldest = data.keys().next().cloned().unwrap_or_default();
+                    data.remove(&oldest);
+                }
+                api.replace(name, &PostParams::default(), &config_map)
+                    .await
+                    .map(|_| ())
+            }
+            None => {
+                let mut data = BTreeMap::new();
+                data.insert(key.clone(), letter.clone());
+                let config_map = ConfigMap {
+                    metadata: ObjectMeta {
+                        name: Some(name.to_owned()),
+                        namespace: Some(namespace.to_owned()),
+                        ..Default::default()
+                    },
+                    data: Some(data),
+                    ..Default::default()
+                };
+                api.create(&PostParams::default(), &config_map)
+                    .await
+                    .map(|_| ())
+            }
+        };
+        match result {
+            Ok(()) => return Ok(()),
+            // Another letter got there first, so read the ConfigMap again
+            Err(kube::Error::Api(response))
+                if response.code == 409 && attempt < CONFLICT_RETRIES =>
+            {
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Removes a pod's dead letters once the pod is gone. Those kept in a
+/// ConfigMap stay.
+pub(crate) async fn remove_pod_letters(dead_letter_path: &Path, namespace: &str, pod: &str) {
+    let dir = pod_dir(dead_letter_path, namespace, pod);
+    match tokio::fs::remove_dir_all(&dir).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!(
+            "Unable to remove dead letters in {}: {:?}",
+            dir.display(),
+            e
+        ),
+    }
+}
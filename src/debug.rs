@@ -0,0 +1,228 @@
+//! State of a container's wasm3 instance that can be dumped on demand with
+//! `kubectl exec <pod> -c <container> -- wasm3-debug`, to see what a module
+//! that looks hung is doing without killing it.
+//!
+//! wasm3 cannot be paused or inspected while it runs, so the instance thread
+//! publishes what it knows as it goes: what it is running, how much linear
+//! memory the module had when it was last seen and the host functions it
+//! calls. The memory size is sampled whenever the module calls a `krustlet`
+//! host function and between runs, so a module stuck in a loop that calls
+//! nothing shows the size it had going in.
+//!
+//! The state also records when the module was last seen doing anything, by
+//! writing output or calling a host function, which the idle watch of a run
+//! checks.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::output::ContainerOutput;
+
+/// The exec command that dumps a container's state instead of calling an
+/// export.
+pub(crate) const DEBUG_COMMAND: &str = "wasm3-debug";
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// What an instance is doing.
+#[derive(Clone, Debug)]
+pub(crate) enum Activity {
+    /// Parsing, loading and linking the module
+    Setup,
+    /// Running `_initialize`
+    Initializing,
+    /// Running `_start`
+    Starting,
+    /// Running an export for a queued call
+    Calling(String),
+    /// Waiting for queued work after `_start` returned
+    Idle,
+    /// The instance is gone
+    Exited,
+}
+
+/// What the instance of one container last published about itself.
+pub(crate) struct DebugState {
+    /// When the current instance was created, and what it is doing since when
+    instance: Mutex<Option<(Instant, Activity, Instant)>>,
+    memory_bytes: AtomicU64,
+    runs: AtomicU64,
+    calls: AtomicU64,
+    host_calls: AtomicU64,
+    last_host_call: Mutex<Option<&'static str>>,
+    /// When the module last wrote output or called a host function
+    last_active: Mutex<Option<Instant>>,
+    /// The current instance's output and the log file it writes to, for
+    /// rotating the log
+    output: Mutex<Option<(Weak<ContainerOutput>, PathBuf)>>,
+    /// What how long things took is measured with
+    clock: Arc<dyn Clock>,
+}
+
+impl DebugState {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        DebugState {
+            instance: Default::default(),
+            memory_bytes: Default::default(),
+            runs: Default::default(),
+            calls: Default::default(),
+            host_calls: Default::default(),
+            last_host_call: Default::default(),
+            last_active: Default::default(),
+            output: Default::default(),
+            clock,
+        }
+    }
+
+    /// How long it is since `then` on the state's clock.
+    fn since(&self, then: Instant) -> Duration {
+        self.clock.instant().saturating_duration_since(then)
+    }
+
+    /// Records that a new instance is being set up.
+    pub(crate) fn instance_started(&self) {
+        let now = self.clock.instant();
+        *self.instance.lock().unwrap() = Some((now, Activity::Setup, now));
+        self.memory_bytes.store(0, Ordering::Relaxed);
+        *self.last_host_call.lock().unwrap() = None;
+        *self.last_active.lock().unwrap() = Some(now);
+    }
+
+    /// Records what the instance is doing now.
+    pub(crate) fn set_activity(&self, activity: Activity) {
+        match activity {
+            Activity::Starting => {
+                self.runs.fetch_add(1, Ordering::Relaxed);
+                self.active();
+            }
+            Activity::Calling(_) => {
+                self.calls.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => (),
+        }
+        if let Some((_, current, since)) = self.instance.lock().unwrap().as_mut() {
+            *current = activity;
+            *since = self.clock.instant();
+        }
+    }
+
+    /// Records the output of a new instance and the log file it writes to.
+    pub(crate) fn set_output(&self, output: &Arc<ContainerOutput>, log: &Path) {
+        *self.output.lock().unwrap() = Some((Arc::downgrade(output), log.to_owned()));
+    }
+
+    /// The current instance's output, if it still has one, and the log
+    /// file it was first opened on.
+    pub(crate) fn output(&self) -> Option<(Arc<ContainerOutput>, PathBuf)> {
+        let output = self.output.lock().unwrap();
+        let (output, log) = output.as_ref()?;
+        Some((output.upgrade()?, log.clone()))
+    }
+
+    /// Records the size of the module's linear memory.
+    pub(crate) fn set_memory_size(&self, bytes: usize) {
+        self.memory_bytes.store(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The size of the module's linear memory when it was last seen.
+    pub(crate) fn memory_size(&self) -> u64 {
+        self.memory_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Records a call to a host function.
+    pub(crate) fn host_call(&self, name: &'static str) {
+        self.host_calls.fetch_add(1, Ordering::Relaxed);
+        *self.last_host_call.lock().unwrap() = Some(name);
+        self.active();
+    }
+
+    /// Records that the module did something the host can see.
+    pub(crate) fn active(&self) {
+        *self.last_active.lock().unwrap() = Some(self.clock.instant());
+    }
+
+    /// How long it is since the module was last seen doing anything.
+    pub(crate) fn idle_for(&self) -> Duration {
+        self.last_active
+            .lock()
+            .unwrap()
+            .map_or_else(Duration::default, |last| self.since(last))
+    }
+
+    /// The state as lines of text for the exec session.
+    pub(crate) fn dump(&self, container: &str) -> Vec<String> {
+        let mut lines = vec![format!("container: {}", container)];
+        match self.instance.lock().unwrap().as_ref() {
+            Some((started, activity, since)) => {
+                lines.push(format!(
+                    "instance uptime: {:.1}s",
+                    self.since(*started).as_secs_f64()
+                ));
+                let since = self.since(*since).as_secs_f64();
+                lines.push(match activity {
+                    Activity::Setup => format!("activity: setting up for {:.1}s", since),
+                    Activity::Initializing => {
+                        format!("activity: running _initialize for {:.1}s", since)
+                    }
+                    Activity::Starting => format!("activity: running _start for {:.1}s", since),
+                    Activity::Calling(export) => {
+                        format!("activity: running {} for {:.1}s", export, since)
+                    }
+                    Activity::Idle => format!("activity: idle for {:.1}s", since),
+                    Activity::Exited => format!("activity: exited {:.1}s ago", since),
+                });
+            }
+            None => lines.push("instance uptime: not started".to_owned()),
+        }
+        let memory = self.memory_bytes.load(Ordering::Relaxed);
+        lines.push(format!(
+            "linear memory: {} bytes ({} pages)",
+            memory,
+            memory / WASM_PAGE_SIZE
+        ));
+        lines.push(format!(
+            "runs of _start: {}",
+            self.runs.load(Ordering::Relaxed)
+        ));
+        lines.push(format!(
+            "calls served: {}",
+            self.calls.load(Ordering::Relaxed)
+        ));
+        let last = self.last_host_call.lock().unwrap();
+        lines.push(format!(
+            "host calls: {}, last: {}",
+            self.host_calls.load(Ordering::Relaxed),
+            last.unwrap_or("none")
+        ));
+        if let Some(last) = *self.last_active.lock().unwrap() {
+            lines.push(format!(
+                "last output or host call: {:.1}s ago",
+                self.since(last).as_secs_f64()
+            ));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+
+    #[test]
+    fn idle_time_is_measured_on_the_clock() {
+        let clock = Arc::new(SimulatedClock::new(chrono::Utc::now()));
+        let state = DebugState::new(clock.clone());
+        assert_eq!(state.idle_for(), Duration::default());
+
+        state.instance_started();
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(state.idle_for(), Duration::from_secs(30));
+
+        state.host_call("log");
+        assert_eq!(state.idle_for(), Duration::default());
+    }
+}
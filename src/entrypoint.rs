@@ -0,0 +1,378 @@
+//! Typed entrypoints, for modules that are not WASI commands, such as
+//! numeric kernels that export a function taking and returning numbers.
+//!
+//! A pod names the function its modules are started with in the
+//! `wasm3.krustlet.dev/entrypoint` annotation, and its type in
+//! `wasm3.krustlet.dev/entrypoint-signature`, such as `(i32,i32)->i32`. The
+//! function is called with the container's arguments, parsed as its
+//! parameters, and the value it returns is put in the container's
+//! termination message.
+//!
+//! wasm3 is called through typed functions, so only signatures with up to
+//! [`MAX_PARAMS`] parameters, all of the same type, can be called.
+
+use std::fmt;
+
+use kubelet::pod::Pod;
+use wasm3::Module;
+
+use crate::sidecar;
+use crate::validation::{FuncType, VALUE_I32};
+
+/// Annotation naming the function the pod's modules are started with.
+const ENTRYPOINT_ANNOTATION: &str = "wasm3.krustlet.dev/entrypoint";
+
+/// Annotation holding the type of the entrypoint function, such as
+/// `(i32,i32)->i32`. Defaults to `()`, which takes and returns nothing.
+const SIGNATURE_ANNOTATION: &str = "wasm3.krustlet.dev/entrypoint-signature";
+
+/// The most parameters an entrypoint can take.
+pub(crate) const MAX_PARAMS: usize = 4;
+
+/// A WebAssembly number type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ValueType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+impl ValueType {
+    /// The type's encoding in a module's type section.
+    fn code(self) -> u8 {
+        match self {
+            ValueType::I32 => VALUE_I32,
+            ValueType::I64 => 0x7e,
+            ValueType::F32 => 0x7d,
+            ValueType::F64 => 0x7c,
+        }
+    }
+}
+
+impl std::str::FromStr for ValueType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "i32" => Ok(ValueType::I32),
+            "i64" => Ok(ValueType::I64),
+            "f32" => Ok(ValueType::F32),
+            "f64" => Ok(ValueType::F64),
+            _ => Err(anyhow::anyhow!(
+                "invalid type {:?}, expected i32, i64, f32 or f64",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ValueType::I32 => "i32",
+            ValueType::I64 => "i64",
+            ValueType::F32 => "f32",
+            ValueType::F64 => "f64",
+        })
+    }
+}
+
+/// The function a pod's modules are started with instead of `_start`.
+#[derive(Clone, Debug)]
+pub(crate) struct Entrypoint {
+    pub(crate) name: String,
+    params: Vec<ValueType>,
+    result: Option<ValueType>,
+}
+
+impl Entrypoint {
+    /// The type the module must export the function with.
+    pub(crate) fn func_type(&self) -> FuncType {
+        FuncType {
+            params: self.params.iter().map(|t| t.code()).collect(),
+            results: self.result.iter().map(|t| t.code()).collect(),
+        }
+    }
+
+    /// Parses `args` as the function's parameters, to call it with.
+    pub(crate) fn invocation(&self, args: &[String]) -> anyhow::Result<Invocation> {
+        if args.len() != self.params.len() {
+            return Err(anyhow::anyhow!(
+                "entrypoint {} takes {} parameters, but the container has {} arguments",
+                self.name,
+                self.params.len(),
+                args.len()
+            ));
+        }
+        let invalid = |arg: &str, t: ValueType| {
+            anyhow::anyhow!(
+                "invalid argument {:?} for entrypoint {}, expected an {}",
+                arg,
+                self.name,
+                t
+            )
+        };
+        fn parse_all<T: std::str::FromStr>(
+            args: &[String],
+            invalid: impl Fn(&str) -> anyhow::Error,
+        ) -> anyhow::Result<Vec<T>> {
+            args.iter()
+                .map(|a| a.trim().parse().map_err(|_| invalid(a)))
+                .collect()
+        }
+        // Signatures are only parsed with one parameter type
+        let args = match self.params.first() {
+            None => Args::I32(Vec::new()),
+            Some(ValueType::I32) => Args::I32(parse_all(args, |a| invalid(a, ValueType::I32))?),
+            Some(ValueType::I64) => Args::I64(parse_all(args, |a| invalid(a, ValueType::I64))?),
+            Some(ValueType::F32) => Args::F32(parse_all(args, |a| invalid(a, ValueType::F32))?),
+            Some(ValueType::F64) => Args::F64(parse_all(args, |a| invalid(a, ValueType::F64))?),
+        };
+        Ok(Invocation {
+            name: self.name.clone(),
+            result: self.result,
+            args,
+        })
+    }
+}
+
+impl fmt::Display for Entrypoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.name, self.func_type())
+    }
+}
+
+/// Parses a signature such as `(i32,i32)->i32`.
+fn parse_signature(value: &str) -> anyhow::Result<(Vec<ValueType>, Option<ValueType>)> {
+    let invalid = || {
+        anyhow::anyhow!(
+            "invalid {} annotation {:?}, expected a signature such as (i32,i32)->i32",
+            SIGNATURE_ANNOTATION,
+            value
+        )
+    };
+    let value = value.trim();
+    let close = value.find(')').ok_or_else(invalid)?;
+    let params = value
+        .strip_prefix('(')
+        .map(|_| &value[1..close])
+        .ok_or_else(invalid)?;
+    let params = params
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| p.parse().map_err(|_| invalid()))
+        .collect::<anyhow::Result<Vec<ValueType>>>()?;
+    let result = match value[close + 1..].trim() {
+        "" => None,
+        rest => Some(
+            rest.strip_prefix("->")
+                .ok_or_else(invalid)?
+                .parse()
+                .map_err(|_| invalid())?,
+        ),
+    };
+    if params.len() > MAX_PARAMS || params.windows(2).any(|w| w[0] != w[1]) {
+        return Err(anyhow::anyhow!(
+            "invalid {} annotation {:?}, entrypoints can only take up to {} parameters of the same type",
+            SIGNATURE_ANNOTATION,
+            value,
+            MAX_PARAMS
+        ));
+    }
+    Ok((params, result))
+}
+
+/// The entrypoint the pod sets for `container`, if it sets one. The node's
+/// sidecar is trusted by the node and started as usual.
+pub(crate) fn entrypoint(pod: &Pod, container: &str) -> anyhow::Result<Option<Entrypoint>> {
+    let name = pod
+        .annotations()
+        .get(ENTRYPOINT_ANNOTATION)
+        .map(|n| n.trim());
+    let signature = pod.annotations().get(SIGNATURE_ANNOTATION);
+    let name = match (name, signature) {
+        (Some(""), _) => {
+            return Err(anyhow::anyhow!(
+                "invalid {} annotation, expected a function name",
+                ENTRYPOINT_ANNOTATION
+            ))
+        }
+        (Some(name), _) => name,
+        (None, Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "{} annotation is set, but {} is not",
+                SIGNATURE_ANNOTATION,
+                ENTRYPOINT_ANNOTATION
+            ))
+        }
+        (None, None) => return Ok(None),
+    };
+    let (params, result) = match signature {
+        Some(signature) => parse_signature(signature)?,
+        None => (Vec::new(), None),
+    };
+    if sidecar::is_sidecar(container) {
+        return Ok(None);
+    }
+    Ok(Some(Entrypoint {
+        name: name.to_owned(),
+        params,
+        result,
+    }))
+}
+
+/// The value an entrypoint returned.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::I32(v) => write!(f, "{}", v),
+            Value::I64(v) => write!(f, "{}", v),
+            Value::F32(v) => write!(f, "{}", v),
+            Value::F64(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Converts what a typed wasm3 function returns into a [`Value`].
+trait IntoValue {
+    fn into_value(self) -> Option<Value>;
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Option<Value> {
+        None
+    }
+}
+
+macro_rules! into_value {
+    ($($t:ty => $variant:ident),*) => {
+        $(
+            impl IntoValue for $t {
+                fn into_value(self) -> Option<Value> {
+                    Some(Value::$variant(self))
+                }
+            }
+        )*
+    };
+}
+
+into_value!(i32 => I32, i64 => I64, f32 => F32, f64 => F64);
+
+/// The arguments of an entrypoint, all of one type.
+#[derive(Clone, Debug)]
+enum Args {
+    I32(Vec<i32>),
+    I64(Vec<i64>),
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+}
+
+/// Finds the function `$name` taking `$args` of type `$t` and returning
+/// `$ret`, and calls it with them if `$call` is true.
+macro_rules! find_and_call {
+    ($module:expr, $name:expr, $t:ty, $args:expr, $ret:ty, $call:expr) => {
+        match $args {
+            [] => $module.find_function::<(), $ret>($name).and_then(|f| {
+                if $call {
+                    f.call().map(IntoValue::into_value)
+                } else {
+                    Ok(None)
+                }
+            }),
+            [a] => $module.find_function::<$t, $ret>($name).and_then(|f| {
+                if $call {
+                    f.call(*a).map(IntoValue::into_value)
+                } else {
+                    Ok(None)
+                }
+            }),
+            [a, b] => $module
+                .find_function::<($t, $t), $ret>($name)
+                .and_then(|f| {
+                    if $call {
+                        f.call(*a, *b).map(IntoValue::into_value)
+                    } else {
+                        Ok(None)
+                    }
+                }),
+            [a, b, c] => $module
+                .find_function::<($t, $t, $t), $ret>($name)
+                .and_then(|f| {
+                    if $call {
+                        f.call(*a, *b, *c).map(IntoValue::into_value)
+                    } else {
+                        Ok(None)
+                    }
+                }),
+            [a, b, c, d] => $module
+                .find_function::<($t, $t, $t, $t), $ret>($name)
+                .and_then(|f| {
+                    if $call {
+                        f.call(*a, *b, *c, *d).map(IntoValue::into_value)
+                    } else {
+                        Ok(None)
+                    }
+                }),
+            _ => unreachable!("entrypoints take at most {} parameters", MAX_PARAMS),
+        }
+    };
+}
+
+/// [`find_and_call`] for each type the function can return.
+macro_rules! by_result {
+    ($module:expr, $name:expr, $t:ty, $args:expr, $result:expr, $call:expr) => {
+        match $result {
+            None => find_and_call!($module, $name, $t, $args, (), $call),
+            Some(ValueType::I32) => find_and_call!($module, $name, $t, $args, i32, $call),
+            Some(ValueType::I64) => find_and_call!($module, $name, $t, $args, i64, $call),
+            Some(ValueType::F32) => find_and_call!($module, $name, $t, $args, f32, $call),
+            Some(ValueType::F64) => find_and_call!($module, $name, $t, $args, f64, $call),
+        }
+    };
+}
+
+/// An entrypoint with the arguments it is called with.
+#[derive(Clone, Debug)]
+pub(crate) struct Invocation {
+    name: String,
+    result: Option<ValueType>,
+    args: Args,
+}
+
+impl Invocation {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Finds the function, and calls it if `call` is true. Returns the value
+    /// it returned, if any.
+    fn find(&self, module: &Module<'_>, call: bool) -> wasm3::error::Result<Option<Value>> {
+        let name = self.name.as_str();
+        match &self.args {
+            Args::I32(args) => by_result!(module, name, i32, args.as_slice(), self.result, call),
+            Args::I64(args) => by_result!(module, name, i64, args.as_slice(), self.result, call),
+            Args::F32(args) => by_result!(module, name, f32, args.as_slice(), self.result, call),
+            Args::F64(args) => by_result!(module, name, f64, args.as_slice(), self.result, call),
+        }
+    }
+
+    /// Checks that the module exports the function with its signature.
+    pub(crate) fn check(&self, module: &Module<'_>) -> wasm3::error::Result<()> {
+        self.find(module, false).map(|_| ())
+    }
+
+    /// Calls the function, returning the value it returned, if any.
+    pub(crate) fn call(&self, module: &Module<'_>) -> wasm3::error::Result<Option<Value>> {
+        self.find(module, true)
+    }
+}
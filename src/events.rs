@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use k8s_openapi::api::core::v1::{Event, EventSource, ObjectReference};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time};
+use kube::api::{Api, PostParams};
+use kubelet::pod::Pod;
+use log::{debug, error, info};
+
+use crate::logging::{self, Fields};
+use crate::outbox::Outbox;
+use crate::ratelimit::WriteLimiter;
+use crate::SharedPodState;
+
+const EVENT_SOURCE_COMPONENT: &str = "wasm3-provider";
+
+/// Records Kubernetes events against a single pod.
+#[derive(Clone)]
+pub(crate) struct EventRecorder {
+    /// Where events are created. Events are only logged when there is no
+    /// cluster, such as for `wasm3-provider run`.
+    client: Option<Api<Event>>,
+    limiter: Option<Arc<WriteLimiter>>,
+    /// Where events that could not reach the API server are queued
+    outbox: Option<Arc<Outbox>>,
+    pod_name: String,
+    namespace: String,
+    pod_uid: Option<String>,
+}
+
+impl EventRecorder {
+    pub(crate) fn new(client: kube::Client, pod: &Pod, shared: &SharedPodState) -> Self {
+        EventRecorder {
+            client: Some(Api::namespaced(client, pod.namespace())),
+            limiter: Some(shared.api_limiter.clone()),
+            outbox: Some(shared.outbox.clone()),
+            pod_name: pod.name().to_owned(),
+            namespace: pod.namespace().to_owned(),
+            pod_uid: pod.as_kube_pod().metadata.uid.clone(),
+        }
+    }
+
+    /// A recorder for a module run outside a cluster, which logs events.
+    pub(crate) fn local(name: &str) -> Self {
+        EventRecorder {
+            client: None,
+            limiter: None,
+            outbox: None,
+            pod_name: name.to_owned(),
+            namespace: String::new(),
+            pod_uid: None,
+        }
+    }
+
+    /// The fields that identify the pod in JSON logs.
+    pub(crate) fn log_fields(&self) -> Fields {
+        Fields::new(&self.pod_name, &self.namespace)
+    }
+
+    /// Records a `Normal` event for the pod.
+    pub(crate) async fn normal(&self, reason: &str, message: &str) {
+        self.record("Normal", reason, message).await
+    }
+
+    /// Records a `Warning` event for the pod.
+    pub(crate) async fn warning(&self, reason: &str, message: &str) {
+        self.record("Warning", reason, message).await
+    }
+
+    async fn record(&self, type_: &str, reason: &str, message: &str) {
+        let client = match &self.client {
+            Some(client) => client,
+            None => {
+                logging::with_fields(self.log_fields(), || {
+                    info!("{} {}: {}", type_, reason, message)
+                });
+                return;
+            }
+        };
+        if let Some(limiter) = &self.limiter {
+            if !limiter.event_write() {
+                logging::with_fields(self.log_fields(), || {
+                    debug!(
+                        "Dropped {} event for pod {} under the API rate limit: {}",
+                        reason, self.pod_name, message
+                    )
+                });
+                return;
+            }
+        }
+        let now = Time(chrono::Utc::now());
+        let event = Event {
+            metadata: ObjectMeta {
+                generate_name: Some(format!("{}.", self.pod_name)),
+                namespace: Some(self.namespace.clone()),
+                ..Default::default()
+            },
+            involved_object: ObjectReference {
+                api_version: Some("v1".to_owned()),
+                kind: Some("Pod".to_owned()),
+                name: Some(self.pod_name.clone()),
+                namespace: Some(self.namespace.clone()),
+                uid: self.pod_uid.clone(),
+                ..Default::default()
+            },
+            type_: Some(type_.to_owned()),
+            reason: Some(reason.to_owned()),
+            message: Some(message.to_owned()),
+            count: Some(1),
+            first_timestamp: Some(now.clone()),
+            last_timestamp: Some(now),
+            source: Some(EventSource {
+                component: Some(EVENT_SOURCE_COMPONENT.to_owned()),
+                host: None,
+            }),
+            ..Default::default()
+        };
+        // Events are best effort, so a failure here should never fail the pod
+        if let Err(e) = client.create(&PostParams::default(), &event).await {
+            let e = anyhow::Error::from(e);
+            if let Some(outbox) = &self.outbox {
+                if outbox.event(event, &e).await {
+                    logging::with_fields(self.log_fields(), || {
+                        debug!(
+                            "Queued {} event for pod {} until the API server is reachable: {:?}",
+                            reason, self.pod_name, e
+                        )
+                    });
+                    return;
+                }
+            }
+            logging::with_fields(self.log_fields(), || {
+                error!(
+                    "Unable to record {} event for pod {}: {:?}",
+                    reason, self.pod_name, e
+                )
+            });
+        }
+    }
+}
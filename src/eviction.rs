@@ -0,0 +1,188 @@
+//! Evicts pods when the node runs low on memory, following the kubelet
+//! eviction manager: the node reports the `MemoryPressure` condition, new
+//! `BestEffort` pods are rejected, and running pods are evicted one at a time
+//! in QoS order until memory recovers. `Guaranteed` pods are never evicted.
+//!
+//! An evicted pod's memory is only freed once its instances have exited, so
+//! the next pod is not evicted until then, and not within
+//! [`EVICTION_INTERVAL`] of the last eviction, so `MemAvailable` has caught
+//! up when pressure is checked again.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Node;
+use kube::api::{Api, PatchParams, PatchStrategy};
+use kubelet::pod::key_from_pod;
+use log::{debug, error, info, warn};
+
+use crate::admission;
+use crate::qos::QosClass;
+use crate::SharedPodState;
+
+/// How often host memory is checked.
+const MONITOR_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The least time between evictions.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(30);
+
+const MEMINFO_PATH: &str = "/proc/meminfo";
+
+/// Tracks whether the node is under memory pressure.
+#[derive(Default)]
+pub(crate) struct MemoryPressure(AtomicBool);
+
+impl MemoryPressure {
+    pub(crate) fn is_set(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Records the current state, returning true if it changed.
+    fn update(&self, pressure: bool) -> bool {
+        self.0.swap(pressure, Ordering::SeqCst) != pressure
+    }
+}
+
+/// Reads the memory available for new work from `/proc/meminfo`, in bytes.
+async fn memory_available() -> anyhow::Result<u64> {
+    let meminfo = tokio::fs::read_to_string(MEMINFO_PATH).await?;
+    meminfo
+        .lines()
+        .find_map(|line| {
+            let kib = line.strip_prefix("MemAvailable:")?;
+            kib.trim().trim_end_matches("kB").trim().parse::<u64>().ok()
+        })
+        .map(|kib| kib * 1024)
+        .ok_or_else(|| anyhow::anyhow!("no MemAvailable in {}", MEMINFO_PATH))
+}
+
+/// Watches host memory against `threshold` bytes and evicts pods while it is
+/// below. Runs until the process exits.
+pub(crate) async fn eviction_loop(shared: SharedPodState, threshold: u64) {
+    let mut last_eviction = None;
+    loop {
+        shared.clock.sleep(MONITOR_INTERVAL).await;
+        let available = match memory_available().await {
+            Ok(available) => available,
+            Err(e) => {
+                error!("Unable to read available memory: {:?}", e);
+                continue;
+            }
+        };
+        shared.metrics.set_gauge(
+            "wasm3_node_memory_available_bytes",
+            "Host memory available as seen by the eviction monitor",
+            &[],
+            available as f64,
+        );
+        let pressure = available < threshold;
+        if shared.memory_pressure.update(pressure) {
+            if pressure {
+                warn!(
+                    "Node is under memory pressure: {} bytes available, threshold {}",
+                    available, threshold
+                );
+            } else {
+                info!("Node is no longer under memory pressure");
+            }
+            let (reason, message) = if pressure {
+                (
+                    "KubeletHasInsufficientMemory",
+                    "kubelet has insufficient memory available",
+                )
+            } else {
+                (
+                    "KubeletHasSufficientMemory",
+                    "kubelet has sufficient memory available",
+                )
+            };
+            let condition =
+                set_node_condition(&shared, "MemoryPressure", pressure, reason, message).await;
+            if let Err(e) = condition {
+                error!("Unable to update MemoryPressure condition: {:?}", e);
+            }
+        }
+        if !pressure {
+            continue;
+        }
+        if shared.admission.any_stopping().await {
+            debug!(
+                "Under memory pressure, waiting for stopped pods to exit before evicting another"
+            );
+            continue;
+        }
+        let recent = last_eviction.map_or(false, |at| {
+            shared.clock.instant().saturating_duration_since(at) < EVICTION_INTERVAL
+        });
+        if !recent && evict_one(&shared, available, threshold).await {
+            last_eviction = Some(shared.clock.instant());
+        }
+    }
+}
+
+/// Evicts the pod that should go first, if any may be evicted, and returns
+/// whether one was. Only one pod is evicted per check so memory has a chance
+/// to recover before the next.
+async fn evict_one(shared: &SharedPodState, available: u64, threshold: u64) -> bool {
+    let victim = shared
+        .admission
+        .eviction_order()
+        .await
+        .into_iter()
+        .find(|(_, qos)| *qos != QosClass::Guaranteed);
+    let (victim, qos) = match victim {
+        Some(victim) => victim,
+        None => {
+            debug!("Under memory pressure but no pod can be evicted");
+            return false;
+        }
+    };
+    let key = key_from_pod(&victim);
+    info!(
+        "Evicting pod {} ({}) for memory pressure",
+        key,
+        qos.as_str()
+    );
+    let message = format!(
+        "The node was low on resource: memory. Threshold quantity: {}, available: {}Ki.",
+        threshold,
+        available / 1024
+    );
+    // The pod's slot, and its memory, are freed once its instances exit
+    if admission::stop_pod(shared, &victim, "Evicted", "Evicted", &message).await {
+        info!("Evicted pod {}", key);
+    }
+    true
+}
+
+/// Sets one of the node's conditions, such as `MemoryPressure`.
+pub(crate) async fn set_node_condition(
+    shared: &SharedPodState,
+    condition: &str,
+    status: bool,
+    reason: &str,
+    message: &str,
+) -> anyhow::Result<()> {
+    let now = shared.clock.now().to_rfc3339();
+    let patch = serde_json::json!({
+        "status": {
+            "conditions": [{
+                "type": condition,
+                "status": if status { "True" } else { "False" },
+                "reason": reason,
+                "message": message,
+                "lastHeartbeatTime": now,
+                "lastTransitionTime": now,
+            }]
+        }
+    });
+    let api: Api<Node> = Api::all(kube::Client::new(shared.kubeconfig.clone()));
+    // Conditions are merged by type, so a strategic merge leaves the others
+    let params = PatchParams {
+        patch_strategy: PatchStrategy::Strategic,
+        ..Default::default()
+    };
+    api.patch_status(&shared.node_name, &params, serde_json::to_vec(&patch)?)
+        .await?;
+    Ok(())
+}
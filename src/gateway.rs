@@ -0,0 +1,407 @@
+//! The HTTP trigger gateway, which turns incoming HTTP requests into calls to
+//! an exported handler of a module, for event driven pods that do nothing
+//! until a request comes in.
+//!
+//! A pod takes requests with the `wasm3.krustlet.dev/http-route` annotation,
+//! `[host]/path`, and names the handler with
+//! `wasm3.krustlet.dev/http-handler`, `[container:]export`. The container
+//! must be a reactor or memoized, so it has a live instance to call. The
+//! handler takes and returns nothing, and reads the request and writes the
+//! response with the `http_*` host functions, see [`host`](crate::host).
+//!
+//! Calls go through the instance's work queue like `kubectl exec` calls, so
+//! an instance handles one request at a time. A request that takes longer
+//! than the gateway timeout gets `504 Gateway Timeout`, but wasm3 cannot
+//! interrupt the module, so the call still holds up the requests queued
+//! behind it until it returns.
+
+use std::collections::HashMap;
+use std::convert::{Infallible, TryFrom};
+use std::net::SocketAddr;
+use std::sync::RwLock;
+
+use futures::{Stream, StreamExt};
+use kubelet::pod::Pod;
+use log::{debug, warn};
+use warp::http::header::{HeaderName, HeaderValue};
+use warp::http::{HeaderMap, Method, Response, StatusCode};
+use warp::path::FullPath;
+use warp::{Buf, Filter};
+
+use crate::dead_letter;
+use crate::metrics::ContainerMetrics;
+use crate::SharedPodState;
+
+/// Annotation holding the host and path prefix a pod takes requests for,
+/// such as `api.example.com/orders` or `/orders` for any host.
+const HTTP_ROUTE_ANNOTATION: &str = "wasm3.krustlet.dev/http-route";
+
+/// Annotation naming the export that handles requests, as `export` or
+/// `container:export`. Defaults to [`DEFAULT_HANDLER`] in the pod's first
+/// container.
+const HTTP_HANDLER_ANNOTATION: &str = "wasm3.krustlet.dev/http-handler";
+
+/// The export requests are handled by when the pod does not name one.
+const DEFAULT_HANDLER: &str = "handle_http";
+
+/// Where requests for a pod are sent.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Route {
+    /// The host the route is for, without a port. Any host when `None`.
+    host: Option<String>,
+    /// The path prefix, matched on whole segments
+    path: String,
+    namespace: String,
+    pod: String,
+    /// The container whose instance handles requests
+    pub(crate) container: String,
+    /// The export called for each request
+    export: String,
+    /// Where the requests the handler fails are kept, if anywhere
+    dead_letter: Option<dead_letter::Sink>,
+}
+
+impl Route {
+    /// Returns true if the route takes requests for `host` and `path`.
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match (&self.host, host) {
+            (None, _) => true,
+            (Some(route), Some(host)) => route.eq_ignore_ascii_case(host),
+            (Some(_), None) => false,
+        };
+        let prefix = self.path.trim_end_matches('/');
+        host_matches
+            && path.starts_with(prefix)
+            && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+    }
+}
+
+/// Reads the route a pod asked for, if any.
+pub(crate) fn route(pod: &Pod) -> anyhow::Result<Option<Route>> {
+    let annotations = pod.annotations();
+    let value = match annotations.get(HTTP_ROUTE_ANNOTATION) {
+        Some(value) => value.trim(),
+        None => return Ok(None),
+    };
+    let (host, path) = match value.find('/') {
+        Some(0) => (None, value),
+        Some(i) => (Some(value[..i].to_ascii_lowercase()), &value[i..]),
+        None => {
+            return Err(anyhow::anyhow!(
+                "invalid {} annotation {:?}, expected [host]/path",
+                HTTP_ROUTE_ANNOTATION,
+                value
+            ))
+        }
+    };
+    let handler = annotations
+        .get(HTTP_HANDLER_ANNOTATION)
+        .map(|h| h.trim())
+        .unwrap_or(DEFAULT_HANDLER);
+    let (container, export) = match handler.find(':') {
+        Some(i) => (handler[..i].to_owned(), &handler[i + 1..]),
+        None => match pod.containers().first() {
+            Some(container) => (container.name().to_owned(), handler),
+            None => {
+                return Err(anyhow::anyhow!(
+                    "pod has no containers to route requests to"
+                ))
+            }
+        },
+    };
+    if export.is_empty() {
+        return Err(anyhow::anyhow!(
+            "invalid {} annotation {:?}, expected [container:]export",
+            HTTP_HANDLER_ANNOTATION,
+            handler
+        ));
+    }
+    if !pod.containers().iter().any(|c| c.name() == container) {
+        return Err(anyhow::anyhow!(
+            "{} annotation names container {}, which the pod does not have",
+            HTTP_HANDLER_ANNOTATION,
+            container
+        ));
+    }
+    Ok(Some(Route {
+        host,
+        path: path.to_owned(),
+        namespace: pod.namespace().to_owned(),
+        pod: pod.name().to_owned(),
+        container,
+        export: export.to_owned(),
+        dead_letter: dead_letter::sink(pod)?,
+    }))
+}
+
+/// The routes of the running pods, keyed by pod key.
+#[derive(Default)]
+pub(crate) struct Routes {
+    routes: RwLock<HashMap<String, Route>>,
+}
+
+impl Routes {
+    pub(crate) fn insert(&self, key: &str, route: Route) {
+        self.routes.write().unwrap().insert(key.to_owned(), route);
+    }
+
+    pub(crate) fn remove(&self, key: &str) {
+        self.routes.write().unwrap().remove(key);
+    }
+
+    /// The route a request is sent on, with the key of its pod. Routes for
+    /// the request's host come before routes for any host, then longer paths
+    /// before shorter ones. Pods with the same route are told apart by key,
+    /// so the same one always wins.
+    fn find(&self, host: Option<&str>, path: &str) -> Option<(String, Route)> {
+        self.routes
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, route)| route.matches(host, path))
+            .max_by(|(a_key, a), (b_key, b)| {
+                (a.host.is_some(), a.path.len())
+                    .cmp(&(b.host.is_some(), b.path.len()))
+                    .then_with(|| b_key.cmp(a_key))
+            })
+            .map(|(key, route)| (key.clone(), route.clone()))
+    }
+}
+
+/// A request as the handler sees it.
+#[derive(Clone)]
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    /// The path, with the query if there is one
+    pub(crate) path: String,
+    /// `name: value` lines
+    pub(crate) headers: String,
+    pub(crate) body: Vec<u8>,
+}
+
+/// The response a handler builds up.
+pub(crate) struct HttpResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    /// The most bytes the body may grow to
+    max_body: usize,
+}
+
+impl HttpResponse {
+    pub(crate) fn new(max_body: usize) -> Self {
+        HttpResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            max_body,
+        }
+    }
+
+    /// Sets the status code. Returns false if it is not a valid one.
+    pub(crate) fn set_status(&mut self, status: i32) -> bool {
+        match u16::try_from(status).ok().map(StatusCode::from_u16) {
+            Some(Ok(status)) => {
+                self.status = status;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Adds a header. Returns false if the name or value is not valid.
+    pub(crate) fn add_header(&mut self, name: &[u8], value: &[u8]) -> bool {
+        match (HeaderName::from_bytes(name), HeaderValue::from_bytes(value)) {
+            (Ok(name), Ok(value)) => {
+                self.headers.append(name, value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Appends to the body. Returns false, writing nothing, if the body
+    /// would grow past its limit.
+    pub(crate) fn write(&mut self, data: &[u8]) -> bool {
+        if self.body.len() + data.len() > self.max_body {
+            return false;
+        }
+        self.body.extend_from_slice(data);
+        true
+    }
+
+    fn into_reply(self) -> Response<Vec<u8>> {
+        let mut response = Response::new(self.body);
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+fn error_reply(status: StatusCode, message: &str) -> Response<Vec<u8>> {
+    let mut response = Response::new(format!("{}\n", message).into_bytes());
+    *response.status_mut() = status;
+    response
+}
+
+/// The host a request was sent to, without a port.
+fn request_host(headers: &HeaderMap) -> Option<String> {
+    let host = headers.get("host")?.to_str().ok()?;
+    let host = match host.rfind(':') {
+        // Not the colons of an IPv6 address
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+/// Reads a request body of at most `max` bytes. `Content-Length` is not
+/// required, so the limit is checked as the body arrives.
+async fn read_body<S, B>(body: S, max: usize) -> Result<Vec<u8>, StatusCode>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    futures::pin_mut!(body);
+    let mut data = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if data.len() + chunk.remaining() > max {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        while chunk.has_remaining() {
+            let n = chunk.bytes().len();
+            data.extend_from_slice(chunk.bytes());
+            chunk.advance(n);
+        }
+    }
+    Ok(data)
+}
+
+async fn handle<S, B>(
+    shared: SharedPodState,
+    method: Method,
+    path: FullPath,
+    query: String,
+    headers: HeaderMap,
+    body: S,
+) -> Result<Response<Vec<u8>>, Infallible>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    let host = request_host(&headers);
+    let (key, route) = match shared.routes.find(host.as_deref(), path.as_str()) {
+        Some(found) => found,
+        None => {
+            return Ok(error_reply(
+                StatusCode::NOT_FOUND,
+                "No pod takes this route",
+            ))
+        }
+    };
+    let metrics = ContainerMetrics::new(
+        shared.metrics.clone(),
+        &route.namespace,
+        &route.pod,
+        &route.container,
+    );
+    let queue = shared
+        .work_queues
+        .read()
+        .await
+        .get(&key)
+        .and_then(|queues| queues.get(&route.container))
+        .cloned();
+    let queue = match queue {
+        Some(queue) => queue,
+        None => {
+            return Ok(error_reply(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The pod is not ready to take requests",
+            ))
+        }
+    };
+    let max_body = shared.config.gateway_max_body as usize;
+    let body = match read_body(body, max_body).await {
+        Ok(body) => body,
+        Err(status) => return Ok(error_reply(status, "Unable to read the request body")),
+    };
+    let request = HttpRequest {
+        method: method.to_string(),
+        path: if query.is_empty() {
+            path.as_str().to_owned()
+        } else {
+            format!("{}?{}", path.as_str(), query)
+        },
+        headers: headers
+            .iter()
+            .filter_map(|(name, value)| Some(format!("{}: {}\n", name, value.to_str().ok()?)))
+            .collect(),
+        body,
+    };
+    debug!(
+        "Sending {} {} to {} of pod {}",
+        request.method, request.path, route.export, key
+    );
+    let timeout = shared.config.gateway_timeout;
+    metrics.inc_counter(
+        "wasm3_gateway_requests_total",
+        "Requests the gateway sent to the container's handler",
+        1.0,
+    );
+    // The handler takes the request, so keep a copy in case it fails
+    let kept = route.dead_letter.as_ref().map(|_| request.clone());
+    let (reply, error) =
+        match tokio::time::timeout(timeout, queue.http(&route.export, request, max_body)).await {
+            Ok(Ok(response)) => return Ok(response.into_reply()),
+            Ok(Err(e)) => {
+                warn!("Handler {} of pod {} failed: {:#}", route.export, key, e);
+                (
+                    error_reply(StatusCode::INTERNAL_SERVER_ERROR, "The handler failed"),
+                    format!("{:#}", e),
+                )
+            }
+            Err(_) => (
+                error_reply(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "The handler did not respond in time",
+                ),
+                format!("handler did not respond within {}s", timeout.as_secs()),
+            ),
+        };
+    if let (Some(sink), Some(request)) = (route.dead_letter.clone(), kept) {
+        dead_letter::capture(
+            &shared,
+            sink,
+            dead_letter::Target {
+                namespace: &route.namespace,
+                pod: &route.pod,
+                container: &route.container,
+                export: &route.export,
+            },
+            dead_letter::Input::Http(&request),
+            &error,
+        );
+    }
+    metrics.inc_counter(
+        "wasm3_gateway_failures_total",
+        "Requests whose handler trapped or did not respond in time",
+        1.0,
+    );
+    Ok(reply)
+}
+
+/// Serves the gateway on `addr`.
+pub(crate) async fn serve(shared: SharedPodState, addr: SocketAddr) {
+    let with_shared = warp::any().map(move || shared.clone());
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+    let routes = with_shared
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(query)
+        .and(warp::header::headers_cloned())
+        .and(warp::body::stream())
+        .and_then(handle);
+    warp::serve(routes).run(addr).await
+}
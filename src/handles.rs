@@ -0,0 +1,235 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use kubelet::pod::Handle;
+use log::warn;
+use tokio::sync::{Mutex, MutexGuard, RwLock};
+
+use crate::clock::Clock;
+use crate::wasi_runtime::{HandleFactory, Runtime, Stopper};
+
+/// How long the instances of a pod the node stops are given to exit, the
+/// kubelet's default termination grace period.
+pub(crate) const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The handle to a running pod.
+pub(crate) type PodHandle = Handle<Runtime, HandleFactory>;
+
+/// A pod handle that can be shared outside of the map lock, with what
+/// stopping the pod's instances needs, so that stopping a pod does not lock
+/// its handle.
+#[derive(Clone)]
+pub(crate) struct SharedPodHandle {
+    handle: Arc<Mutex<PodHandle>>,
+    stoppers: Arc<[Stopper]>,
+}
+
+impl SharedPodHandle {
+    /// Locks the pod handle, for reading container logs through it.
+    pub(crate) async fn lock(&self) -> MutexGuard<'_, PodHandle> {
+        self.handle.lock().await
+    }
+}
+
+const SHARD_COUNT: usize = 16;
+
+/// A map of pod keys to pod handles, sharded so that operations on unrelated
+/// pods do not contend on a single lock. Shard locks are only held long enough
+/// to clone out the `Arc` of a handle, so slow operations on one pod (such as
+/// streaming logs) never block lifecycle operations on another.
+pub(crate) struct PodHandleMap {
+    shards: Vec<RwLock<HashMap<String, SharedPodHandle>>>,
+}
+
+impl Default for PodHandleMap {
+    fn default() -> Self {
+        PodHandleMap {
+            shards: (0..SHARD_COUNT).map(|_| Default::default()).collect(),
+        }
+    }
+}
+
+impl PodHandleMap {
+    fn shard(&self, key: &str) -> &RwLock<HashMap<String, SharedPodHandle>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Inserts the handle for a pod, with the stoppers of the instances
+    /// still running in it, replacing any existing one.
+    pub(crate) async fn insert(&self, key: String, handle: PodHandle, stoppers: Vec<Stopper>) {
+        let mut shard = self.shard(&key).write().await;
+        shard.insert(
+            key,
+            SharedPodHandle {
+                handle: Arc::new(Mutex::new(handle)),
+                stoppers: stoppers.into(),
+            },
+        );
+    }
+
+    /// Returns the handle for a pod, if one exists.
+    pub(crate) async fn get(&self, key: &str) -> Option<SharedPodHandle> {
+        let shard = self.shard(key).read().await;
+        shard.get(key).cloned()
+    }
+
+    /// Returns the keys of all pods with a handle.
+    pub(crate) async fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            keys.extend(shard.read().await.keys().cloned());
+        }
+        keys
+    }
+
+    /// Removes and returns the handle for a pod, if one exists.
+    pub(crate) async fn remove(&self, key: &str) -> Option<SharedPodHandle> {
+        let mut shard = self.shard(key).write().await;
+        shard.remove(key)
+    }
+
+    /// Removes the handle for a pod only if it is still `handle`, so a pod
+    /// recreated with the same name keeps its new handle.
+    async fn remove_if_same(&self, key: &str, handle: &SharedPodHandle) {
+        let mut shard = self.shard(key).write().await;
+        if shard
+            .get(key)
+            .map_or(false, |h| Arc::ptr_eq(&h.handle, &handle.handle))
+        {
+            shard.remove(key);
+        }
+    }
+}
+
+/// Removes the handle of a pod that has finished once `ttl` has passed on
+/// `clock`. Logs stay available until then.
+pub(crate) async fn expire(
+    handles: Arc<PodHandleMap>,
+    key: String,
+    ttl: Duration,
+    clock: &dyn Clock,
+) {
+    let handle = match handles.get(&key).await {
+        Some(handle) => handle,
+        None => return,
+    };
+    let expired = clock.sleep(ttl);
+    tokio::spawn(async move {
+        expired.await;
+        handles.remove_if_same(&key, &handle).await;
+    });
+}
+
+/// Stops the instances of the pod behind `handle`, waiting up to `timeout`
+/// for them to exit, and returns whether they did. wasm3 cannot interrupt a
+/// module, so instances still running a `_start` or a call then are left to
+/// exit in the background when it returns. `then` runs once every instance
+/// has exited, however long that takes. The pod's handle is not locked
+/// meanwhile, so its logs can still be read.
+pub(crate) async fn stop(
+    handle: SharedPodHandle,
+    key: &str,
+    timeout: Duration,
+    clock: &dyn Clock,
+    then: impl Future<Output = ()> + Send + 'static,
+) -> bool {
+    let stoppers = handle.stoppers.clone();
+    let mut stopping = tokio::spawn(async move {
+        futures::future::join_all(stoppers.iter().cloned().map(Stopper::stop)).await;
+        then.await;
+    });
+    let stopped = tokio::select! {
+        _ = &mut stopping => true,
+        _ = clock.sleep(timeout) => false,
+    };
+    if !stopped {
+        warn!(
+            "Pod {} is still running {}s after it was stopped, leaving it to exit when its module returns",
+            key,
+            timeout.as_secs()
+        );
+    }
+    stopped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+
+    async fn handle() -> PodHandle {
+        let pod: k8s_openapi::api::core::v1::Pod = serde_json::from_value(serde_json::json!({
+            "metadata": {"name": "hello", "namespace": "default"},
+            "spec": {"containers": []}
+        }))
+        .unwrap();
+        Handle::new(HashMap::new(), kubelet::pod::Pod::new(pod), None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_finished_pods_handle_is_removed_once_its_ttl_has_passed() {
+        let clock = SimulatedClock::new(chrono::Utc::now());
+        let handles = Arc::new(PodHandleMap::default());
+        let key = "default:hello".to_owned();
+        handles
+            .insert(key.clone(), handle().await, Vec::new())
+            .await;
+
+        expire(
+            handles.clone(),
+            key.clone(),
+            Duration::from_secs(60),
+            &clock,
+        )
+        .await;
+        clock.advance(Duration::from_secs(59));
+        tokio::task::yield_now().await;
+        assert!(handles.get(&key).await.is_some());
+
+        clock.advance(Duration::from_secs(1));
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(handles.get(&key).await.is_none());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn a_pods_handle_can_be_locked_while_it_is_stopping() {
+        let clock = SimulatedClock::new(chrono::Utc::now());
+        let handles = PodHandleMap::default();
+        let key = "default:hello".to_owned();
+        handles
+            .insert(key.clone(), handle().await, vec![Stopper::never()])
+            .await;
+        let handle = handles.get(&key).await.unwrap();
+
+        let stopping = stop(
+            handle.clone(),
+            &key,
+            Duration::from_secs(30),
+            &clock,
+            async {},
+        );
+        tokio::pin!(stopping);
+        // The instance never exits, so the stop is still waiting
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), &mut stopping)
+                .await
+                .is_err()
+        );
+        assert!(tokio::time::timeout(Duration::from_secs(5), handle.lock())
+            .await
+            .is_ok());
+
+        clock.advance(Duration::from_secs(30));
+        assert!(!stopping.await);
+    }
+}
@@ -0,0 +1,215 @@
+//! Health checks for the provider, served over HTTP on `/healthz` and
+//! `/readyz` and runnable locally through `wasm3-provider doctor`. The same
+//! listener serves provider metrics on `/metrics`, which can be put behind
+//! authentication, see [`auth`](crate::auth), and the listener behind TLS.
+
+use std::convert::Infallible;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::{Authenticator, Denied};
+use crate::config::TlsFiles;
+use crate::metrics::Metrics;
+
+/// The result of a single health check.
+pub struct CheckResult {
+    /// Name of the check
+    pub name: &'static str,
+    /// Why the check failed, if it did
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    fn from_result(name: &'static str, result: anyhow::Result<()>) -> Self {
+        CheckResult {
+            name,
+            error: result.err().map(|e| format!("{:#}", e)),
+        }
+    }
+
+    /// Returns true if the check passed.
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            None => write!(f, "[ OK ] {}", self.name),
+            Some(e) => write!(f, "[FAIL] {}: {}", self.name, e),
+        }
+    }
+}
+
+/// Runs the provider health checks.
+#[derive(Clone)]
+pub struct HealthChecker {
+    kubeconfig: kube::Config,
+    data_dir: PathBuf,
+    registry: Option<String>,
+}
+
+impl HealthChecker {
+    /// Creates a checker. `registry` is the host of a registry whose
+    /// reachability is part of readiness; it is skipped when `None`.
+    pub fn new(kubeconfig: kube::Config, data_dir: PathBuf, registry: Option<String>) -> Self {
+        HealthChecker {
+            kubeconfig,
+            data_dir,
+            registry,
+        }
+    }
+
+    /// Checks that only depend on the local node. A failure here means the
+    /// provider cannot run anything.
+    pub async fn liveness(&self) -> Vec<CheckResult> {
+        vec![
+            CheckResult::from_result("data_dir writable", self.check_data_dir().await),
+            CheckResult::from_result("wasm3 environment", check_wasm3().await),
+        ]
+    }
+
+    /// All checks, including those against remote services.
+    pub async fn readiness(&self) -> Vec<CheckResult> {
+        let mut results = self.liveness().await;
+        results.push(CheckResult::from_result(
+            "kubernetes API",
+            self.check_kube().await,
+        ));
+        if let Some(registry) = &self.registry {
+            results.push(CheckResult::from_result(
+                "registry reachable",
+                check_registry(registry).await,
+            ));
+        }
+        results
+    }
+
+    async fn check_data_dir(&self) -> anyhow::Result<()> {
+        let data_dir = self.data_dir.clone();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            tempfile::tempfile_in(&data_dir)
+                .map_err(|e| anyhow::anyhow!("cannot write to {}: {}", data_dir.display(), e))?;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn check_kube(&self) -> anyhow::Result<()> {
+        let client = kube::Client::new(self.kubeconfig.clone());
+        client.apiserver_version().await?;
+        Ok(())
+    }
+}
+
+async fn check_wasm3() -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(|| -> anyhow::Result<()> {
+        let env = wasm3::Environment::new()
+            .map_err(|e| anyhow::anyhow!("cannot create environment: {}", e))?;
+        env.create_runtime(1)
+            .map_err(|e| anyhow::anyhow!("cannot create runtime: {}", e))?;
+        Ok(())
+    })
+    .await?
+}
+
+async fn check_registry(registry: &str) -> anyhow::Result<()> {
+    // Any HTTP response, including 401, means the registry is reachable
+    reqwest::get(&format!("https://{}/v2/", registry)).await?;
+    Ok(())
+}
+
+fn report(results: Vec<CheckResult>) -> impl warp::Reply {
+    let status = if results.iter().all(CheckResult::is_ok) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = results
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n");
+    warp::reply::with_status(body, status)
+}
+
+/// Only lets requests through whose caller may get the node's
+/// `subresource`.
+fn authorized(
+    auth: Arc<Authenticator>,
+    subresource: &'static str,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let auth = auth.clone();
+            async move {
+                auth.check(header.as_deref(), subresource)
+                    .await
+                    .map_err(warp::reject::custom)
+            }
+        })
+        .untuple_one()
+}
+
+async fn denied(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    match rejection.find::<Denied>() {
+        Some(denied) => Ok(warp::reply::with_status(
+            denied.to_string(),
+            denied.status(),
+        )),
+        None => Err(rejection),
+    }
+}
+
+/// Serves `/healthz` (liveness), `/readyz` (readiness) and `/metrics` on
+/// `addr`, over TLS if `tls` is set. `/metrics` callers are checked by
+/// `auth`.
+pub(crate) async fn serve(
+    checker: HealthChecker,
+    metrics: Arc<Metrics>,
+    addr: SocketAddr,
+    auth: Arc<Authenticator>,
+    tls: Option<TlsFiles>,
+) {
+    let checker = Arc::new(checker);
+    let with_checker = warp::any().map(move || checker.clone());
+
+    let healthz = warp::path("healthz")
+        .and(warp::path::end())
+        .and(with_checker.clone())
+        .and_then(|checker: Arc<HealthChecker>| async move {
+            Ok::<_, Infallible>(report(checker.liveness().await))
+        });
+    let readyz = warp::path("readyz")
+        .and(warp::path::end())
+        .and(with_checker)
+        .and_then(|checker: Arc<HealthChecker>| async move {
+            Ok::<_, Infallible>(report(checker.readiness().await))
+        });
+
+    let metrics = warp::path("metrics")
+        .and(warp::path::end())
+        .and(authorized(auth, "metrics"))
+        .map(move || metrics.render());
+
+    let routes = warp::get()
+        .and(healthz.or(readyz).or(metrics))
+        .recover(denied);
+    match tls {
+        Some(tls) => {
+            warp::serve(routes)
+                .tls()
+                .cert_path(&tls.cert)
+                .key_path(&tls.key)
+                .run(addr)
+                .await
+        }
+        None => warp::serve(routes).run(addr).await,
+    }
+}
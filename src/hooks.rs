@@ -0,0 +1,104 @@
+//! Extension points for crates that embed the provider, registered with
+//! [`WasiProviderBuilder`](crate::WasiProviderBuilder).
+//!
+//! Hooks run on the provider's async executor, so they should be quick, and
+//! hand anything slow to a task of their own.
+
+use std::sync::Arc;
+
+use kubelet::pod::Pod;
+
+use crate::host;
+use crate::validation::WASI_MODULES;
+
+/// Called before a container's module starts, and before every restart, with
+/// the pod and the container's name. An error stops the container from
+/// starting, and is reported like any other failure to start it.
+pub type PreStartHook = Arc<dyn Fn(&Pod, &str) -> anyhow::Result<()> + Send + Sync>;
+
+/// Called after a container has stopped, with the pod and how the container
+/// stopped.
+pub type PostStopHook = Arc<dyn Fn(&Pod, &Stopped<'_>) + Send + Sync>;
+
+/// How a container stopped, as its terminated status reports it.
+#[derive(Debug)]
+pub struct Stopped<'a> {
+    /// The container's name
+    pub container: &'a str,
+    /// Whether the module trapped or exited with an error
+    pub failed: bool,
+    /// The message of the container's terminated status
+    pub message: &'a str,
+}
+
+/// Host functions provided to modules under an import module of their own,
+/// alongside WASI and the provider's own `krustlet` functions.
+///
+/// Modules are validated before they start, and an import nothing provides
+/// fails the pod, so [`provides`](HostExtension::provides) must name every
+/// function [`link`](HostExtension::link) can link. Functions are linked
+/// with wasm3's `Module::link_function`, so the extension must be built
+/// against the same wasm3 revision as the provider.
+pub trait HostExtension: Send + Sync {
+    /// The import module the functions are provided under. It may not be
+    /// `krustlet` or one of the WASI modules.
+    fn module(&self) -> &str;
+
+    /// Returns true if the extension provides the function `field`.
+    fn provides(&self, field: &str) -> bool;
+
+    /// Links the function `field`, which a module imports and the extension
+    /// provides.
+    fn link(&self, module: &mut wasm3::Module, field: &str) -> wasm3::error::Result<()>;
+}
+
+/// The hooks and host extensions registered for the provider.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    pub(crate) pre_start: Vec<PreStartHook>,
+    pub(crate) post_stop: Vec<PostStopHook>,
+    pub(crate) host_extensions: Vec<Arc<dyn HostExtension>>,
+}
+
+impl Hooks {
+    /// Checks that no host extension takes the name of a module the provider
+    /// links itself.
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        for extension in &self.host_extensions {
+            let module = extension.module();
+            if module == host::HOST_MODULE || WASI_MODULES.contains(&module) {
+                return Err(anyhow::anyhow!(
+                    "host extension cannot provide functions under {}, which the provider links",
+                    module
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the pre-start hooks, stopping at the first that fails.
+    pub(crate) fn pre_start(&self, pod: &Pod, container: &str) -> anyhow::Result<()> {
+        for hook in &self.pre_start {
+            hook(pod, container)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the post-stop hooks.
+    pub(crate) fn post_stop(&self, pod: &Pod, stopped: &Stopped<'_>) {
+        for hook in &self.post_stop {
+            hook(pod, stopped);
+        }
+    }
+}
+
+/// Returns the extension that provides `field` of `module`, if one does.
+pub(crate) fn find_extension<'a>(
+    extensions: &'a [Arc<dyn HostExtension>],
+    module: &str,
+    field: &str,
+) -> Option<&'a Arc<dyn HostExtension>> {
+    extensions
+        .iter()
+        .find(|e| e.module() == module && e.provides(field))
+}
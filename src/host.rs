@@ -0,0 +1,1542 @@
+//! Host functions modules can import from the `krustlet` module.
+//!
+//! wasm3 calls host functions through plain `extern "C"` functions, so the
+//! state they need is kept in a thread local set up by the instance thread
+//! before it runs the module.
+//!
+//! | Function | Signature |
+//! | --- | --- |
+//! | `metric_counter` | `(name_ptr: i32, name_len: i32, value: f64)` |
+//! | `metric_gauge` | `(name_ptr: i32, name_len: i32, value: f64)` |
+//! | `log` | `(level: i32, ptr: i32, len: i32)` |
+//! | `set_ready` | `(ready: i32)` |
+//!
+//! A container whose module imports `set_ready` is not ready until the module
+//! calls it with a non-zero value, and is not ready again once it calls it
+//! with zero. Every run starts out not ready. Other containers are ready
+//! while they run.
+//!
+//! When enabled, a per pod key-value store is also provided:
+//!
+//! | Function | Signature |
+//! | --- | --- |
+//! | `kv_get` | `(key_ptr: i32, key_len: i32, buf_ptr: i32, buf_len: i32) -> i32` |
+//! | `kv_set` | `(key_ptr: i32, key_len: i32, value_ptr: i32, value_len: i32) -> i32` |
+//! | `kv_delete` | `(key_ptr: i32, key_len: i32) -> i32` |
+//!
+//! `kv_get` returns the length of the value and copies as much of it as fits
+//! into the buffer. The key-value functions return [`KV_NOT_FOUND`],
+//! [`KV_INVALID`] or [`KV_FAILED`] on failure.
+//!
+//! Host files the node allows and the pod asks for can be read with:
+//!
+//! | Function | Signature |
+//! | --- | --- |
+//! | `host_file_read` | `(path_ptr: i32, path_len: i32, buf_ptr: i32, buf_len: i32) -> i32` |
+//!
+//! It reads from the start of the file into the buffer and returns the number
+//! of bytes read, or [`HOST_FILE_DENIED`], [`HOST_FILE_INVALID`] or
+//! [`HOST_FILE_FAILED`].
+//!
+//! The data of the ConfigMaps and Secrets the pod mounts can be read, and
+//! waited on, with:
+//!
+//! | Function | Signature |
+//! | --- | --- |
+//! | `config_get` | `(key_ptr: i32, key_len: i32, buf_ptr: i32, buf_len: i32) -> i32` |
+//! | `config_watch` | `(version: i64, timeout_ms: i32) -> i64` |
+//!
+//! `config_get` works like `kv_get`, returning [`CONFIG_NOT_FOUND`] or
+//! [`CONFIG_INVALID`] on failure. `config_watch` waits until the data's
+//! version differs from `version`, or the timeout passes, and returns the
+//! version. See [`crate::pod_config`].
+//!
+//! On nodes with `WASM3_HOST_WATCH` enabled, modules can watch Kubernetes
+//! resources in their pod's namespace, see [`crate::watch`], with:
+//!
+//! | Function | Signature |
+//! | --- | --- |
+//! | `k8s_watch` | `(resource_ptr: i32, resource_len: i32, selector_ptr: i32, selector_len: i32) -> i32` |
+//! | `k8s_watch_next` | `(id: i32, buf_ptr: i32, buf_len: i32, timeout_ms: i32) -> i32` |
+//!
+//! `k8s_watch` returns the ID of the new watch. `k8s_watch_next` waits for
+//! the watch's next event and returns its length, leaving it queued if it
+//! does not fit in the buffer, or 0 if the timeout passes first. They return
+//! [`WATCH_DISABLED`], [`WATCH_INVALID`], [`WATCH_LIMIT`] or [`WATCH_CLOSED`]
+//! on failure.
+//!
+//! A handler called by the HTTP trigger gateway, see [`crate::gateway`],
+//! reads the request and builds the response with:
+//!
+//! | Function | Signature |
+//! | --- | --- |
+//! | `http_request_read` | `(part: i32, buf_ptr: i32, buf_len: i32) -> i32` |
+//! | `http_response_status` | `(status: i32) -> i32` |
+//! | `http_response_header` | `(name_ptr: i32, name_len: i32, value_ptr: i32, value_len: i32) -> i32` |
+//! | `http_response_write` | `(ptr: i32, len: i32) -> i32` |
+//!
+//! `http_request_read` works like `kv_get` for the part of the request named
+//! by [`HTTP_METHOD`], [`HTTP_PATH`], [`HTTP_HEADERS`] or [`HTTP_BODY`].
+//! Headers are `name: value` lines. The response is `200` with no headers or
+//! body until the handler says otherwise. The functions return `0`, or the
+//! length for `http_request_read`, on success, and [`HTTP_NO_REQUEST`] when
+//! called outside a handler, [`HTTP_INVALID`] or [`HTTP_TOO_LARGE`] on
+//! failure.
+//!
+//! WASI's `fd_write` is replaced too, so that standard output and error go to
+//! the container log through [`ContainerOutput`] rather than to the
+//! provider's own output. Writes to other descriptors are passed through.
+//! For containers with a read-only root filesystem, or a cap on the files
+//! they may have open, WASI's `path_open` is replaced with one that refuses
+//! to open files for writing or past the cap, see [`FilePolicy`]. With a
+//! read-only root, every other WASI function that changes the filesystem is
+//! replaced with one that fails with `EROFS`, see [`READ_ONLY_FUNCTIONS`].
+//! With a cap, `fd_close` is replaced too, so closed files stop counting
+//! against it.
+//! `args_get`, `args_sizes_get`, `environ_get` and `environ_sizes_get` are
+//! always replaced, so the module is given the container's arguments, after
+//! its name as the program name, and environment rather than the provider's,
+//! see [`CommandLine`].
+//!
+//! Crates embedding the provider can link functions of their own under other
+//! import modules, see [`HostExtension`].
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ffi::c_void;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use wasm3::Module;
+use wasm3_sys as ffi;
+
+use crate::cri_log::LogStream;
+use crate::debug::DebugState;
+use crate::gateway::{HttpRequest, HttpResponse};
+use crate::hooks::{self, HostExtension};
+use crate::kv::{KvError, KvStore};
+use crate::metrics::ContainerMetrics;
+use crate::output::ContainerOutput;
+use crate::pod_config::PodConfig;
+use crate::status::StatusSender;
+use crate::validation::{ModuleInfo, WASI_MODULES};
+use crate::watch::{PodWatches, WatchError};
+
+/// The import module host functions are provided under.
+pub(crate) const HOST_MODULE: &str = "krustlet";
+
+/// The most distinct metric names a single container may report, so a module
+/// cannot grow the registry without bound.
+const MAX_MODULE_METRICS: usize = 100;
+
+/// The host function modules report their readiness with.
+pub(crate) const SET_READY: &str = "set_ready";
+
+/// Prefix added to metric names reported by modules.
+const MODULE_METRIC_PREFIX: &str = "wasm3_module_";
+
+/// Names of the host functions.
+const FUNCTIONS: &[&str] = &[
+    "metric_counter",
+    "metric_gauge",
+    "log",
+    "set_ready",
+    "sched_yield",
+    "host_file_read",
+    "config_get",
+    "config_watch",
+    "http_request_read",
+    "http_response_status",
+    "http_response_header",
+    "http_response_write",
+    "k8s_watch",
+    "k8s_watch_next",
+];
+
+/// Names of the key-value host functions.
+const KV_FUNCTIONS: &[&str] = &["kv_get", "kv_set", "kv_delete"];
+
+/// The key does not exist.
+pub(crate) const KV_NOT_FOUND: i32 = -1;
+/// The key, value or number of keys is over its limit, or a pointer is out of
+/// bounds.
+pub(crate) const KV_INVALID: i32 = -2;
+/// The store could not be read or written.
+pub(crate) const KV_FAILED: i32 = -3;
+
+/// The file is not one the pod may read.
+pub(crate) const HOST_FILE_DENIED: i32 = -1;
+/// The path is not valid UTF-8 or a pointer is out of bounds.
+pub(crate) const HOST_FILE_INVALID: i32 = -2;
+/// The file could not be read.
+pub(crate) const HOST_FILE_FAILED: i32 = -3;
+
+/// The pod has no config data under the key.
+pub(crate) const CONFIG_NOT_FOUND: i32 = -1;
+/// A pointer is out of bounds or the key is not valid UTF-8.
+pub(crate) const CONFIG_INVALID: i32 = -2;
+
+/// The node does not let modules watch resources.
+pub(crate) const WATCH_DISABLED: i32 = -1;
+/// The resource, selector or watch ID is not valid, or a pointer is out of
+/// bounds.
+pub(crate) const WATCH_INVALID: i32 = -2;
+/// The instance has as many watches as it may have.
+pub(crate) const WATCH_LIMIT: i32 = -3;
+/// The watch has ended and all its events have been taken.
+pub(crate) const WATCH_CLOSED: i32 = -4;
+
+/// The request's method, for `http_request_read`.
+pub(crate) const HTTP_METHOD: i32 = 0;
+/// The request's path and query, for `http_request_read`.
+pub(crate) const HTTP_PATH: i32 = 1;
+/// The request's headers, for `http_request_read`.
+pub(crate) const HTTP_HEADERS: i32 = 2;
+/// The request's body, for `http_request_read`.
+pub(crate) const HTTP_BODY: i32 = 3;
+
+/// The module is not handling a request.
+pub(crate) const HTTP_NO_REQUEST: i32 = -1;
+/// The part, status or header is not valid, or a pointer is out of bounds.
+pub(crate) const HTTP_INVALID: i32 = -2;
+/// The response body would grow past the gateway's limit.
+pub(crate) const HTTP_TOO_LARGE: i32 = -3;
+
+/// Returns true if `name` is a host function modules can import, given
+/// whether the key-value store is enabled.
+pub(crate) fn provides(name: &str, kv: bool) -> bool {
+    FUNCTIONS.contains(&name) || (kv && KV_FUNCTIONS.contains(&name))
+}
+
+/// How a container's module may open files. WASI's `path_open` is only
+/// replaced when there is something to enforce.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FilePolicy {
+    /// Files may only be opened for reading
+    pub(crate) read_only_root: bool,
+    /// The most files the module may have open at once
+    pub(crate) max_open_files: Option<usize>,
+}
+
+impl FilePolicy {
+    /// Returns true if WASI's `path_open` is replaced to enforce the policy.
+    pub(crate) fn replaces_path_open(self) -> bool {
+        self.read_only_root || self.max_open_files.is_some()
+    }
+}
+
+/// The arguments and environment the module is given through WASI, each held
+/// as the nul terminated string the module reads. Environment variables are
+/// `NAME=value`, sorted by name so every run sees them in the same order.
+#[derive(Default)]
+struct CommandLine {
+    args: Vec<Vec<u8>>,
+    env: Vec<Vec<u8>>,
+}
+
+impl CommandLine {
+    fn new(program: &str, args: &[String], env: &HashMap<String, String>) -> Self {
+        let terminated = |s: String| {
+            let mut bytes = s.into_bytes();
+            bytes.push(0);
+            bytes
+        };
+        let mut env: Vec<String> = env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        env.sort();
+        CommandLine {
+            args: std::iter::once(program.to_owned())
+                .chain(args.iter().cloned())
+                .map(terminated)
+                .collect(),
+            env: env.into_iter().map(terminated).collect(),
+        }
+    }
+}
+
+/// Descriptors the module opened with `path_open` and has not closed. Any
+/// left when the instance ends are closed then, so a module that leaks
+/// files cannot leak them past its instance.
+#[derive(Default)]
+struct OpenFiles(HashSet<i32>);
+
+impl Drop for OpenFiles {
+    fn drop(&mut self) {
+        for fd in self.0.drain() {
+            // Safety: the descriptor was opened by `path_open` and nothing
+            // else closes it
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+/// What host functions called from the current thread act on.
+pub(crate) struct HostContext {
+    metrics: ContainerMetrics,
+    metric_names: HashSet<String>,
+    /// The container's log file
+    output: Arc<ContainerOutput>,
+    /// The pod's key-value store, if enabled
+    kv: Option<KvStore>,
+    /// Host files the module may read
+    host_files: Vec<PathBuf>,
+    /// Where host calls are recorded for `wasm3-debug`
+    debug: Arc<DebugState>,
+    /// The pod's ConfigMap and Secret data
+    config: Arc<PodConfig>,
+    /// The gateway request being handled and the response to it, if any
+    http: Option<(HttpRequest, HttpResponse)>,
+    /// The instance's Kubernetes watches, if the node allows them
+    watches: Option<Arc<PodWatches>>,
+    /// How the module may open files
+    files: FilePolicy,
+    open_files: OpenFiles,
+    /// Where the module's readiness is reported, and the container's name
+    readiness: Option<(StatusSender, String)>,
+    /// The arguments and environment the module is given
+    command_line: CommandLine,
+}
+
+impl HostContext {
+    pub(crate) fn new(
+        metrics: ContainerMetrics,
+        output: Arc<ContainerOutput>,
+        kv: Option<KvStore>,
+        host_files: Vec<PathBuf>,
+        debug: Arc<DebugState>,
+        config: Arc<PodConfig>,
+        watches: Option<PodWatches>,
+    ) -> Self {
+        HostContext {
+            metrics,
+            metric_names: HashSet::new(),
+            output,
+            kv,
+            host_files,
+            debug,
+            config,
+            http: None,
+            watches: watches.map(Arc::new),
+            files: Default::default(),
+            open_files: Default::default(),
+            readiness: None,
+            command_line: Default::default(),
+        }
+    }
+
+    /// Sets how the module may open files.
+    pub(crate) fn with_files(mut self, files: FilePolicy) -> Self {
+        self.files = files;
+        self
+    }
+
+    /// Sets the arguments and environment the module is given, with
+    /// `program` as the first argument.
+    pub(crate) fn with_command_line(
+        mut self,
+        program: &str,
+        args: &[String],
+        env: &HashMap<String, String>,
+    ) -> Self {
+        self.command_line = CommandLine::new(program, args, env);
+        self
+    }
+
+    /// Sets where `set_ready` reports the readiness of `container`.
+    pub(crate) fn with_readiness(mut self, status_sender: StatusSender, container: &str) -> Self {
+        self.readiness = Some((status_sender, container.to_owned()));
+        self
+    }
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<HostContext>> = RefCell::new(None);
+}
+
+/// Clears the host context when dropped. Blocking threads are reused, so the
+/// context must not outlive the instance that set it.
+pub(crate) struct ContextGuard(());
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CONTEXT.with(|c| *c.borrow_mut() = None);
+    }
+}
+
+/// Sets the context for host functions called on this thread.
+pub(crate) fn enter(context: HostContext) -> ContextGuard {
+    CONTEXT.with(|c| *c.borrow_mut() = Some(context));
+    ContextGuard(())
+}
+
+/// Makes `request` the one the gateway handler about to be called on this
+/// thread handles.
+pub(crate) fn begin_http(request: HttpRequest, max_body: usize) {
+    CONTEXT.with(|c| {
+        if let Some(context) = c.borrow_mut().as_mut() {
+            context.http = Some((request, HttpResponse::new(max_body)));
+        }
+    });
+}
+
+/// Takes the response the gateway handler built, once it has returned.
+pub(crate) fn end_http() -> Option<HttpResponse> {
+    CONTEXT.with(|c| {
+        c.borrow_mut()
+            .as_mut()
+            .and_then(|c| c.http.take())
+            .map(|(_, response)| response)
+    })
+}
+
+/// Links the host functions `info` says the module imports. This must come
+/// after WASI is linked, so `fd_write` replaces the one wasm3 links.
+pub(crate) fn link(
+    module: &mut Module,
+    info: &ModuleInfo,
+    files: FilePolicy,
+    extensions: &[Arc<dyn HostExtension>],
+) -> wasm3::error::Result<()> {
+    for import in &info.imports {
+        if let Some(extension) = hooks::find_extension(extensions, &import.module, &import.field) {
+            extension.link(module, &import.field)?;
+        }
+    }
+    for import in info
+        .imports
+        .iter()
+        .filter(|i| WASI_MODULES.contains(&i.module.as_str()) && i.field == "fd_write")
+    {
+        module.link_function::<(i32, i32, i32, i32), i32>(&import.module, "fd_write", fd_write)?;
+    }
+    for import in info
+        .imports
+        .iter()
+        .filter(|i| WASI_MODULES.contains(&i.module.as_str()))
+    {
+        for (name, function) in COMMAND_LINE_FUNCTIONS {
+            if import.field == *name {
+                module.link_function::<(i32, i32), i32>(&import.module, name, *function)?;
+            }
+        }
+    }
+    for import in info.imports.iter().filter(|i| {
+        files.replaces_path_open()
+            && WASI_MODULES.contains(&i.module.as_str())
+            && i.field == "path_open"
+    }) {
+        module.link_function::<(i32, i32, i32, i32, i32, i64, i64, i32, i32), i32>(
+            &import.module,
+            "path_open",
+            path_open,
+        )?;
+    }
+    for import in info
+        .imports
+        .iter()
+        .filter(|i| files.read_only_root && WASI_MODULES.contains(&i.module.as_str()))
+    {
+        let (wasi, name) = (import.module.as_str(), import.field.as_str());
+        match name {
+            "fd_allocate" => module.link_function::<(i32, i64, i64), i32>(wasi, name, read_only)?,
+            "fd_filestat_set_size" => {
+                module.link_function::<(i32, i64), i32>(wasi, name, read_only)?
+            }
+            "fd_filestat_set_times" => {
+                module.link_function::<(i32, i64, i64, i32), i32>(wasi, name, read_only)?
+            }
+            "path_create_directory" | "path_remove_directory" | "path_unlink_file" => {
+                module.link_function::<(i32, i32, i32), i32>(wasi, name, read_only)?
+            }
+            "path_filestat_set_times" => module
+                .link_function::<(i32, i32, i32, i32, i64, i64, i32), i32>(wasi, name, read_only)?,
+            "path_link" => module
+                .link_function::<(i32, i32, i32, i32, i32, i32, i32), i32>(wasi, name, read_only)?,
+            "path_rename" => module
+                .link_function::<(i32, i32, i32, i32, i32, i32), i32>(wasi, name, read_only)?,
+            "path_symlink" => {
+                module.link_function::<(i32, i32, i32, i32, i32), i32>(wasi, name, read_only)?
+            }
+            _ => {}
+        }
+    }
+    for import in info.imports.iter().filter(|i| {
+        files.max_open_files.is_some()
+            && WASI_MODULES.contains(&i.module.as_str())
+            && i.field == "fd_close"
+    }) {
+        module.link_function::<(i32,), i32>(&import.module, "fd_close", fd_close)?;
+    }
+    for import in info.imports.iter().filter(|i| i.module == HOST_MODULE) {
+        match import.field.as_str() {
+            "metric_counter" => module.link_function::<(i32, i32, f64), ()>(
+                HOST_MODULE,
+                "metric_counter",
+                metric_counter,
+            )?,
+            "metric_gauge" => module.link_function::<(i32, i32, f64), ()>(
+                HOST_MODULE,
+                "metric_gauge",
+                metric_gauge,
+            )?,
+            "log" => module.link_function::<(i32, i32, i32), ()>(HOST_MODULE, "log", log)?,
+            "set_ready" => module.link_function::<(i32,), ()>(HOST_MODULE, SET_READY, set_ready)?,
+            "sched_yield" => {
+                module.link_function::<(), i32>(HOST_MODULE, "sched_yield", sched_yield)?
+            }
+            "kv_get" => {
+                module.link_function::<(i32, i32, i32, i32), i32>(HOST_MODULE, "kv_get", kv_get)?
+            }
+            "kv_set" => {
+                module.link_function::<(i32, i32, i32, i32), i32>(HOST_MODULE, "kv_set", kv_set)?
+            }
+            "kv_delete" => {
+                module.link_function::<(i32, i32), i32>(HOST_MODULE, "kv_delete", kv_delete)?
+            }
+            "host_file_read" => module.link_function::<(i32, i32, i32, i32), i32>(
+                HOST_MODULE,
+                "host_file_read",
+                host_file_read,
+            )?,
+            "config_get" => module.link_function::<(i32, i32, i32, i32), i32>(
+                HOST_MODULE,
+                "config_get",
+                config_get,
+            )?,
+            "config_watch" => module.link_function::<(i64, i32), i64>(
+                HOST_MODULE,
+                "config_watch",
+                config_watch,
+            )?,
+            "k8s_watch" => module.link_function::<(i32, i32, i32, i32), i32>(
+                HOST_MODULE,
+                "k8s_watch",
+                k8s_watch,
+            )?,
+            "k8s_watch_next" => module.link_function::<(i32, i32, i32, i32), i32>(
+                HOST_MODULE,
+                "k8s_watch_next",
+                k8s_watch_next,
+            )?,
+            "http_request_read" => module.link_function::<(i32, i32, i32), i32>(
+                HOST_MODULE,
+                "http_request_read",
+                http_request_read,
+            )?,
+            "http_response_status" => module.link_function::<(i32,), i32>(
+                HOST_MODULE,
+                "http_response_status",
+                http_response_status,
+            )?,
+            "http_response_header" => module.link_function::<(i32, i32, i32, i32), i32>(
+                HOST_MODULE,
+                "http_response_header",
+                http_response_header,
+            )?,
+            "http_response_write" => module.link_function::<(i32, i32), i32>(
+                HOST_MODULE,
+                "http_response_write",
+                http_response_write,
+            )?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Returns a pointer to `len` bytes at `ptr` in the module's linear memory,
+/// or `None` if the range is out of bounds. Arguments are taken straight from
+/// the wasm3 stack, where i32 values occupy the low bits of each slot.
+unsafe fn memory(runtime: ffi::IM3Runtime, ptr: u64, len: u64) -> Option<(*mut u8, usize)> {
+    let (ptr, len) = (ptr as u32, len as u32);
+    let mut size = 0u32;
+    let memory = ffi::m3_GetMemory(runtime, &mut size, 0);
+    if memory.is_null() || ptr.checked_add(len)? > size {
+        return None;
+    }
+    Some((memory.add(ptr as usize), len as usize))
+}
+
+unsafe fn read_memory<'a>(runtime: ffi::IM3Runtime, ptr: u64, len: u64) -> Option<&'a [u8]> {
+    let (ptr, len) = memory(runtime, ptr, len)?;
+    Some(std::slice::from_raw_parts(ptr, len))
+}
+
+unsafe fn write_memory<'a>(runtime: ffi::IM3Runtime, ptr: u64, len: u64) -> Option<&'a mut [u8]> {
+    let (ptr, len) = memory(runtime, ptr, len)?;
+    Some(std::slice::from_raw_parts_mut(ptr, len))
+}
+
+/// Records a call to the host function `name`, and the size of the module's
+/// linear memory at the time, for `wasm3-debug`.
+unsafe fn trace(runtime: ffi::IM3Runtime, name: &'static str) {
+    let mut size = 0u32;
+    ffi::m3_GetMemory(runtime, &mut size, 0);
+    CONTEXT.with(|c| {
+        if let Some(context) = c.borrow().as_ref() {
+            context.debug.host_call(name);
+            context.debug.set_memory_size(size as usize);
+        }
+    });
+}
+
+/// Reads a UTF-8 string argument from the module's linear memory.
+unsafe fn read_str<'a>(runtime: ffi::IM3Runtime, ptr: u64, len: u64) -> Option<&'a str> {
+    std::str::from_utf8(read_memory(runtime, ptr, len)?).ok()
+}
+
+/// Returns true if `name` can be used in a Prometheus metric name.
+fn valid_metric_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 128
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Records a metric reported by the module running on this thread.
+fn record_metric(name: Option<&str>, value: f64, counter: bool) {
+    let name = match name {
+        Some(name) if valid_metric_name(name) => name,
+        _ => {
+            warn!("Module reported a metric with an invalid name, ignoring");
+            return;
+        }
+    };
+    CONTEXT.with(|c| {
+        let mut context = c.borrow_mut();
+        let context = match context.as_mut() {
+            Some(context) => context,
+            None => return,
+        };
+        if !context.metric_names.contains(name) {
+            if context.metric_names.len() >= MAX_MODULE_METRICS {
+                warn!(
+                    "Module reported more than {} metrics, ignoring {}",
+                    MAX_MODULE_METRICS, name
+                );
+                return;
+            }
+            context.metric_names.insert(name.to_owned());
+        }
+        let full_name = format!("{}{}", MODULE_METRIC_PREFIX, name);
+        if counter {
+            // Counters only go up
+            if value >= 0.0 {
+                context
+                    .metrics
+                    .inc_counter(&full_name, "Counter reported by a module", value);
+            }
+        } else {
+            context
+                .metrics
+                .set_gauge(&full_name, "Gauge reported by a module", value);
+        }
+    });
+}
+
+/// The name a `log` level is written to the container log with. Levels follow
+/// the `log` crate, from 0 for trace to 4 for error.
+fn level_name(level: i32) -> &'static str {
+    match level {
+        i32::MIN..=0 => "TRACE",
+        1 => "DEBUG",
+        2 => "INFO",
+        3 => "WARN",
+        _ => "ERROR",
+    }
+}
+
+/// Writes a message logged by the module running on this thread to its
+/// container log, one line per line of the message.
+fn write_log(level: i32, message: Option<&str>) {
+    let message = match message {
+        Some(message) => message,
+        None => {
+            warn!("Module logged a message that is not valid UTF-8, ignoring");
+            return;
+        }
+    };
+    CONTEXT.with(|c| {
+        if let Some(context) = c.borrow().as_ref() {
+            let level = level_name(level);
+            for line in message.lines() {
+                let line = format!("[{}] {}\n", level, line);
+                if let Err(e) = context
+                    .output
+                    .write_limited(LogStream::Stderr, line.as_bytes())
+                {
+                    warn!("Unable to write module log line: {:?}", e);
+                    return;
+                }
+            }
+        }
+    });
+}
+
+unsafe extern "C" fn log(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "log");
+    let message = read_str(runtime, *sp.add(1), *sp.add(2));
+    write_log(*sp as i32, message);
+    std::ptr::null()
+}
+
+unsafe extern "C" fn set_ready(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "set_ready");
+    let ready = *sp as i32 != 0;
+    CONTEXT.with(|c| {
+        if let Some((status_sender, container)) =
+            c.borrow().as_ref().and_then(|c| c.readiness.as_ref())
+        {
+            status_sender.set_ready(container, Some(ready));
+        }
+    });
+    std::ptr::null()
+}
+
+/// Gives up the rest of the thread's time slice, so a module in a long loop
+/// lets the other instances sharing its core run. wasm3 cannot pause a
+/// module, so this is the only way one can make room for others.
+unsafe extern "C" fn sched_yield(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "sched_yield");
+    CONTEXT.with(|c| {
+        if let Some(context) = c.borrow().as_ref() {
+            context.metrics.inc_counter(
+                "wasm3_yields_total",
+                "Times the module yielded its thread with sched_yield",
+                1.0,
+            );
+        }
+    });
+    std::thread::yield_now();
+    *(sp as *mut i32) = 0;
+    std::ptr::null()
+}
+
+unsafe extern "C" fn metric_counter(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "metric_counter");
+    let name = read_str(runtime, *sp, *sp.add(1));
+    record_metric(name, f64::from_bits(*sp.add(2)), true);
+    std::ptr::null()
+}
+
+unsafe extern "C" fn metric_gauge(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "metric_gauge");
+    let name = read_str(runtime, *sp, *sp.add(1));
+    record_metric(name, f64::from_bits(*sp.add(2)), false);
+    std::ptr::null()
+}
+
+/// Runs a key-value operation against the store of the pod running on this
+/// thread, returning the status code for the module.
+fn with_kv(f: impl FnOnce(&KvStore) -> Result<i32, KvError>) -> i32 {
+    CONTEXT.with(|c| {
+        let context = c.borrow();
+        let kv = match context.as_ref().and_then(|c| c.kv.as_ref()) {
+            Some(kv) => kv,
+            None => return KV_FAILED,
+        };
+        match f(kv) {
+            Ok(code) => code,
+            Err(KvError::NotFound) => KV_NOT_FOUND,
+            Err(KvError::TooLarge) => KV_INVALID,
+            Err(KvError::Io(e)) => {
+                warn!("Key-value store operation failed: {:?}", e);
+                KV_FAILED
+            }
+        }
+    })
+}
+
+unsafe extern "C" fn kv_get(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "kv_get");
+    // The key is copied out as it may overlap the buffer being written
+    let key = read_memory(runtime, *sp.add(1), *sp.add(2)).map(<[u8]>::to_vec);
+    let code = match (key, write_memory(runtime, *sp.add(3), *sp.add(4))) {
+        (Some(key), Some(buf)) => with_kv(|kv| {
+            let value = kv.get(&key)?;
+            let n = value.len().min(buf.len());
+            buf[..n].copy_from_slice(&value[..n]);
+            Ok(value.len() as i32)
+        }),
+        _ => KV_INVALID,
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+unsafe extern "C" fn kv_set(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "kv_set");
+    let code = match (
+        read_memory(runtime, *sp.add(1), *sp.add(2)),
+        read_memory(runtime, *sp.add(3), *sp.add(4)),
+    ) {
+        (Some(key), Some(value)) => with_kv(|kv| kv.set(key, value).map(|()| 0)),
+        _ => KV_INVALID,
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+unsafe extern "C" fn kv_delete(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "kv_delete");
+    let code = match read_memory(runtime, *sp.add(1), *sp.add(2)) {
+        Some(key) => with_kv(|kv| kv.delete(key).map(|()| 0)),
+        None => KV_INVALID,
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+/// Reads the start of a host file into `buf` if the module running on this
+/// thread may read it. Paths are matched exactly, so `..` cannot reach other
+/// files.
+fn read_host_file(path: &str, buf: &mut [u8]) -> i32 {
+    let allowed = CONTEXT.with(|c| {
+        c.borrow()
+            .as_ref()
+            .map_or(false, |c| c.host_files.iter().any(|f| f == Path::new(path)))
+    });
+    if !allowed {
+        return HOST_FILE_DENIED;
+    }
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Unable to open host file {}: {:?}", path, e);
+            return HOST_FILE_FAILED;
+        }
+    };
+    // Devices such as /dev/urandom never end, so only read what fits
+    let mut read = 0;
+    let mut reader = file.take(buf.len() as u64);
+    loop {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => return read as i32,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => {
+                warn!("Unable to read host file {}: {:?}", path, e);
+                return HOST_FILE_FAILED;
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn host_file_read(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "host_file_read");
+    // The path is copied out as it may overlap the buffer being written
+    let path = read_str(runtime, *sp.add(1), *sp.add(2)).map(str::to_owned);
+    let code = match (path, write_memory(runtime, *sp.add(3), *sp.add(4))) {
+        (Some(path), Some(buf)) => read_host_file(&path, buf),
+        _ => HOST_FILE_INVALID,
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+/// The config data of the pod running on this thread.
+fn pod_config() -> Option<Arc<PodConfig>> {
+    CONTEXT.with(|c| c.borrow().as_ref().map(|c| c.config.clone()))
+}
+
+unsafe extern "C" fn config_get(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "config_get");
+    // The key is copied out as it may overlap the buffer being written
+    let key = read_str(runtime, *sp.add(1), *sp.add(2)).map(str::to_owned);
+    let code = match (key, write_memory(runtime, *sp.add(3), *sp.add(4))) {
+        (Some(key), Some(buf)) => pod_config()
+            .and_then(|config| {
+                config.with_value(&key, |value| {
+                    let n = value.len().min(buf.len());
+                    buf[..n].copy_from_slice(&value[..n]);
+                    value.len() as i32
+                })
+            })
+            .unwrap_or(CONFIG_NOT_FOUND),
+        _ => CONFIG_INVALID,
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+unsafe extern "C" fn config_watch(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "config_watch");
+    let seen = *sp.add(1);
+    let timeout = Duration::from_millis((*sp.add(2) as i32).max(0) as u64);
+    // The context is not borrowed while waiting, and the module is blocked
+    // until this returns, as with any other host call
+    let version = match pod_config() {
+        Some(config) => config.wait(seen, timeout),
+        None => 0,
+    };
+    *(sp as *mut i64) = version as i64;
+    std::ptr::null()
+}
+
+/// The status code for the module of a watch failure.
+fn watch_code(error: WatchError) -> i32 {
+    match error {
+        WatchError::Disabled => WATCH_DISABLED,
+        WatchError::Invalid => WATCH_INVALID,
+        WatchError::Limit => WATCH_LIMIT,
+        WatchError::Closed => WATCH_CLOSED,
+    }
+}
+
+/// The watches of the instance running on this thread.
+fn pod_watches() -> Result<Arc<PodWatches>, WatchError> {
+    CONTEXT.with(|c| {
+        c.borrow()
+            .as_ref()
+            .and_then(|c| c.watches.clone())
+            .ok_or(WatchError::Disabled)
+    })
+}
+
+unsafe extern "C" fn k8s_watch(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "k8s_watch");
+    let code = match (
+        read_str(runtime, *sp.add(1), *sp.add(2)),
+        read_str(runtime, *sp.add(3), *sp.add(4)),
+    ) {
+        (Some(resource), Some(selector)) => pod_watches()
+            .and_then(|watches| watches.start(resource, selector))
+            .unwrap_or_else(watch_code),
+        _ => WATCH_INVALID,
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+unsafe extern "C" fn k8s_watch_next(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "k8s_watch_next");
+    let id = *sp.add(1) as i32;
+    let timeout = Duration::from_millis((*sp.add(4) as i32).max(0) as u64);
+    // The context is not borrowed while waiting, as with `config_watch`
+    let code = match write_memory(runtime, *sp.add(2), *sp.add(3)) {
+        Some(buf) => pod_watches()
+            .and_then(|watches| watches.next(id, buf, timeout))
+            .map_or_else(watch_code, |len| len as i32),
+        None => WATCH_INVALID,
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+/// Runs `f` on the gateway request being handled on this thread and its
+/// response, returning the status code for the module.
+fn with_http(f: impl FnOnce(&HttpRequest, &mut HttpResponse) -> i32) -> i32 {
+    CONTEXT.with(
+        |c| match c.borrow_mut().as_mut().and_then(|c| c.http.as_mut()) {
+            Some((request, response)) => f(request, response),
+            None => HTTP_NO_REQUEST,
+        },
+    )
+}
+
+unsafe extern "C" fn http_request_read(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "http_request_read");
+    let part = *sp.add(1) as i32;
+    let code = match write_memory(runtime, *sp.add(2), *sp.add(3)) {
+        Some(buf) => with_http(|request, _| {
+            let value = match part {
+                HTTP_METHOD => request.method.as_bytes(),
+                HTTP_PATH => request.path.as_bytes(),
+                HTTP_HEADERS => request.headers.as_bytes(),
+                HTTP_BODY => &request.body[..],
+                _ => return HTTP_INVALID,
+            };
+            let n = value.len().min(buf.len());
+            buf[..n].copy_from_slice(&value[..n]);
+            value.len() as i32
+        }),
+        None => HTTP_INVALID,
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+unsafe extern "C" fn http_response_status(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "http_response_status");
+    let status = *sp.add(1) as i32;
+    let code = with_http(|_, response| {
+        if response.set_status(status) {
+            0
+        } else {
+            HTTP_INVALID
+        }
+    });
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+unsafe extern "C" fn http_response_header(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "http_response_header");
+    let code = match (
+        read_memory(runtime, *sp.add(1), *sp.add(2)),
+        read_memory(runtime, *sp.add(3), *sp.add(4)),
+    ) {
+        (Some(name), Some(value)) => with_http(|_, response| {
+            if response.add_header(name, value) {
+                0
+            } else {
+                HTTP_INVALID
+            }
+        }),
+        _ => HTTP_INVALID,
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+unsafe extern "C" fn http_response_write(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    trace(runtime, "http_response_write");
+    let code = match read_memory(runtime, *sp.add(1), *sp.add(2)) {
+        Some(data) => with_http(|_, response| {
+            if response.write(data) {
+                0
+            } else {
+                HTTP_TOO_LARGE
+            }
+        }),
+        None => HTTP_INVALID,
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+/// WASI errno values `fd_write` can return.
+const ERRNO_SUCCESS: i32 = 0;
+const ERRNO_BADF: i32 = 8;
+const ERRNO_FAULT: i32 = 21;
+const ERRNO_IO: i32 = 29;
+
+/// Writes to a descriptor for the module running on this thread. Standard
+/// output and error go to the container log, and wasm3 passes other
+/// descriptors straight through to the host, so the same is done here.
+fn write_fd(fd: i32, data: &[u8]) -> i32 {
+    let result = match fd {
+        1 | 2 => CONTEXT.with(|c| match c.borrow().as_ref() {
+            Some(context) => {
+                context.debug.active();
+                let stream = if fd == 1 {
+                    LogStream::Stdout
+                } else {
+                    LogStream::Stderr
+                };
+                context.output.write_limited(stream, data)
+            }
+            None => Ok(()),
+        }),
+        fd if fd > 2 => {
+            use std::os::unix::io::FromRawFd;
+            // Safety: the descriptor belongs to wasm3, which keeps it open,
+            // so the file must not close it when dropped
+            let mut file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+            file.write_all(data)
+        }
+        _ => return ERRNO_BADF,
+    };
+    match result {
+        Ok(()) => ERRNO_SUCCESS,
+        Err(e) => {
+            warn!(
+                "Unable to write module output to descriptor {}: {:?}",
+                fd, e
+            );
+            ERRNO_IO
+        }
+    }
+}
+
+/// `fd_write(fd, iovs, iovs_len, nwritten) -> errno`
+unsafe extern "C" fn fd_write(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    let fd = *sp.add(1) as i32;
+    let (iovs, iovs_len) = (*sp.add(2), *sp.add(3) as u32 as u64);
+    let code = match read_memory(runtime, iovs, iovs_len * 8) {
+        Some(iovs) => {
+            // Copied out, as writing may need the buffers one at a time
+            let iovs: Vec<(u64, u64)> = iovs
+                .chunks_exact(8)
+                .map(|iov| {
+                    let field = |i: usize| {
+                        u64::from(u32::from_le_bytes([
+                            iov[i],
+                            iov[i + 1],
+                            iov[i + 2],
+                            iov[i + 3],
+                        ]))
+                    };
+                    (field(0), field(4))
+                })
+                .collect();
+            let mut written = 0u32;
+            let mut code = ERRNO_SUCCESS;
+            for (ptr, len) in iovs {
+                match read_memory(runtime, ptr, len) {
+                    Some(data) => {
+                        code = write_fd(fd, data);
+                        if code != ERRNO_SUCCESS {
+                            break;
+                        }
+                        written = written.wrapping_add(data.len() as u32);
+                    }
+                    None => {
+                        code = ERRNO_FAULT;
+                        break;
+                    }
+                }
+            }
+            match write_memory(runtime, *sp.add(4), 4) {
+                Some(nwritten) => nwritten.copy_from_slice(&written.to_le_bytes()),
+                None if code == ERRNO_SUCCESS => code = ERRNO_FAULT,
+                None => (),
+            }
+            code
+        }
+        None => ERRNO_FAULT,
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+/// The signature of wasm3's raw host functions.
+pub(crate) type RawCall =
+    unsafe extern "C" fn(ffi::IM3Runtime, *mut u64, *mut c_void) -> *const c_void;
+
+/// The WASI functions replaced to give the module its [`CommandLine`].
+pub(crate) const COMMAND_LINE_FUNCTIONS: &[(&str, RawCall)] = &[
+    ("args_get", args_get),
+    ("args_sizes_get", args_sizes_get),
+    ("environ_get", environ_get),
+    ("environ_sizes_get", environ_sizes_get),
+];
+
+/// The wasm3 signature the [`COMMAND_LINE_FUNCTIONS`] share, for linking
+/// them with the raw API.
+pub(crate) const COMMAND_LINE_SIGNATURE: &[u8] = b"i(ii)\0";
+
+/// Calls `f` with the arguments, or the environment, of the module running
+/// on this thread.
+fn with_strings<T>(env: bool, f: impl FnOnce(&[Vec<u8>]) -> T) -> T {
+    CONTEXT.with(|c| {
+        let context = c.borrow();
+        let strings = match context.as_ref() {
+            Some(context) if env => &context.command_line.env[..],
+            Some(context) => &context.command_line.args[..],
+            None => &[],
+        };
+        f(strings)
+    })
+}
+
+/// How many strings there are and how many bytes they take, as
+/// `args_sizes_get` and `environ_sizes_get` report them.
+fn string_sizes(strings: &[Vec<u8>]) -> (u32, u32) {
+    let size = strings.iter().map(|s| s.len() as u32).sum();
+    (strings.len() as u32, size)
+}
+
+/// Lays `strings` out the way `args_get` and `environ_get` return them: a list
+/// of pointers to each string, and the strings one after another from
+/// `buf_ptr`.
+fn lay_out_strings(strings: &[Vec<u8>], buf_ptr: u32) -> (Vec<u8>, Vec<u8>) {
+    let mut list = Vec::with_capacity(strings.len() * 4);
+    let mut buf = Vec::new();
+    for string in strings {
+        list.extend_from_slice(&buf_ptr.wrapping_add(buf.len() as u32).to_le_bytes());
+        buf.extend_from_slice(string);
+    }
+    (list, buf)
+}
+
+/// Writes each of `values` to module memory at its pointer, failing with
+/// `EFAULT` if any is out of bounds.
+unsafe fn write_values(runtime: ffi::IM3Runtime, values: &[(u64, &[u8])]) -> i32 {
+    for (ptr, value) in values {
+        match write_memory(runtime, *ptr as u32 as u64, value.len() as u64) {
+            Some(out) => out.copy_from_slice(value),
+            None => return ERRNO_FAULT,
+        }
+    }
+    ERRNO_SUCCESS
+}
+
+unsafe fn strings_sizes_get(runtime: ffi::IM3Runtime, sp: *mut u64, env: bool) {
+    let (count, size) = with_strings(env, string_sizes);
+    let code = write_values(
+        runtime,
+        &[
+            (*sp.add(1), &count.to_le_bytes()[..]),
+            (*sp.add(2), &size.to_le_bytes()[..]),
+        ],
+    );
+    *(sp as *mut i32) = code;
+}
+
+unsafe fn strings_get(runtime: ffi::IM3Runtime, sp: *mut u64, env: bool) {
+    let (list_ptr, buf_ptr) = (*sp.add(1), *sp.add(2));
+    let (list, buf) = with_strings(env, |strings| lay_out_strings(strings, buf_ptr as u32));
+    let code = write_values(runtime, &[(list_ptr, &list[..]), (buf_ptr, &buf[..])]);
+    *(sp as *mut i32) = code;
+}
+
+/// `args_sizes_get(argc, argv_buf_size) -> errno`
+pub(crate) unsafe extern "C" fn args_sizes_get(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    strings_sizes_get(runtime, sp, false);
+    std::ptr::null()
+}
+
+/// `args_get(argv, argv_buf) -> errno`
+pub(crate) unsafe extern "C" fn args_get(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    strings_get(runtime, sp, false);
+    std::ptr::null()
+}
+
+/// `environ_sizes_get(environc, environ_buf_size) -> errno`
+pub(crate) unsafe extern "C" fn environ_sizes_get(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    strings_sizes_get(runtime, sp, true);
+    std::ptr::null()
+}
+
+/// `environ_get(environ, environ_buf) -> errno`
+pub(crate) unsafe extern "C" fn environ_get(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    strings_get(runtime, sp, true);
+    std::ptr::null()
+}
+
+/// WASI errno values `path_open` can return, besides those above.
+const ERRNO_ACCES: i32 = 2;
+const ERRNO_EXIST: i32 = 20;
+const ERRNO_INVAL: i32 = 28;
+const ERRNO_ISDIR: i32 = 31;
+const ERRNO_LOOP: i32 = 32;
+const ERRNO_MFILE: i32 = 33;
+const ERRNO_NAMETOOLONG: i32 = 37;
+const ERRNO_NOENT: i32 = 44;
+const ERRNO_NOTDIR: i32 = 54;
+const ERRNO_PERM: i32 = 63;
+const ERRNO_ROFS: i32 = 68;
+
+/// The `path_open` flags and rights that let the opened file be changed.
+const OFLAGS_CREAT: u64 = 1 << 0;
+const OFLAGS_TRUNC: u64 = 1 << 3;
+const FDFLAGS_APPEND: u64 = 1 << 0;
+const RIGHTS_FD_WRITE: u64 = 1 << 6;
+
+/// The other `path_open` flags and rights.
+const OFLAGS_DIRECTORY: u64 = 1 << 1;
+const OFLAGS_EXCL: u64 = 1 << 2;
+const FDFLAGS_DSYNC: u64 = 1 << 1;
+const FDFLAGS_NONBLOCK: u64 = 1 << 2;
+const FDFLAGS_RSYNC: u64 = 1 << 3;
+const FDFLAGS_SYNC: u64 = 1 << 4;
+const RIGHTS_FD_READ: u64 = 1 << 1;
+
+/// The descriptors wasm3's WASI preopens, `/` and `./`, both of which are
+/// the provider's working directory.
+const PREOPENED_FDS: std::ops::Range<i32> = 3..5;
+
+/// The WASI functions other than `path_open` that change the filesystem, and
+/// their wasm3 signatures, for linking [`read_only`] in their place with the
+/// raw API. `host::link` links the same functions.
+pub(crate) const READ_ONLY_FUNCTIONS: &[(&str, &[u8])] = &[
+    ("fd_allocate", b"i(iII)\0"),
+    ("fd_filestat_set_size", b"i(iI)\0"),
+    ("fd_filestat_set_times", b"i(iIIi)\0"),
+    ("path_create_directory", b"i(iii)\0"),
+    ("path_filestat_set_times", b"i(iiiiIIi)\0"),
+    ("path_link", b"i(iiiiiii)\0"),
+    ("path_remove_directory", b"i(iii)\0"),
+    ("path_rename", b"i(iiiiii)\0"),
+    ("path_symlink", b"i(iiiii)\0"),
+    ("path_unlink_file", b"i(iii)\0"),
+];
+
+/// The wasm3 signatures of `path_open` and `fd_close`, for linking them
+/// with the raw API.
+pub(crate) const PATH_OPEN_SIGNATURE: &[u8] = b"i(iiiiiIIii)\0";
+pub(crate) const FD_CLOSE_SIGNATURE: &[u8] = b"i(i)\0";
+
+/// Maps the host errno of a failed open to its WASI errno.
+fn wasi_errno(errno: i32) -> i32 {
+    match errno {
+        libc::EACCES => ERRNO_ACCES,
+        libc::EBADF => ERRNO_BADF,
+        libc::EEXIST => ERRNO_EXIST,
+        libc::EINVAL => ERRNO_INVAL,
+        libc::EISDIR => ERRNO_ISDIR,
+        libc::ELOOP => ERRNO_LOOP,
+        libc::EMFILE => ERRNO_MFILE,
+        libc::ENAMETOOLONG => ERRNO_NAMETOOLONG,
+        libc::ENOENT => ERRNO_NOENT,
+        libc::ENOTDIR => ERRNO_NOTDIR,
+        libc::EPERM => ERRNO_PERM,
+        _ => ERRNO_IO,
+    }
+}
+
+/// The host flags of an open with WASI's `oflags`, rights and `fdflags`,
+/// worked out the way wasm3 does.
+fn open_flags(oflags: u64, rights: u64, fdflags: u64) -> i32 {
+    let mut flags = match (rights & RIGHTS_FD_READ != 0, rights & RIGHTS_FD_WRITE != 0) {
+        (true, true) => libc::O_RDWR,
+        (false, true) => libc::O_WRONLY,
+        (_, false) => libc::O_RDONLY,
+    };
+    let bits = [
+        (oflags, OFLAGS_CREAT, libc::O_CREAT),
+        (oflags, OFLAGS_DIRECTORY, libc::O_DIRECTORY),
+        (oflags, OFLAGS_EXCL, libc::O_EXCL),
+        (oflags, OFLAGS_TRUNC, libc::O_TRUNC),
+        (fdflags, FDFLAGS_APPEND, libc::O_APPEND),
+        (fdflags, FDFLAGS_DSYNC, libc::O_DSYNC),
+        (fdflags, FDFLAGS_NONBLOCK, libc::O_NONBLOCK),
+        (fdflags, FDFLAGS_RSYNC, libc::O_RSYNC),
+        (fdflags, FDFLAGS_SYNC, libc::O_SYNC),
+    ];
+    for (set, bit, flag) in bits.iter().copied() {
+        if set & bit != 0 {
+            flags |= flag;
+        }
+    }
+    flags
+}
+
+/// `path_open(dirfd, dirflags, path, path_len, oflags, fs_rights_base,
+/// fs_rights_inheriting, fdflags, fd) -> errno`, linked in place of wasm3's
+/// to enforce the container's [`FilePolicy`]. With a read-only root, an open
+/// that could change the file fails with `EROFS`. With a cap on open files,
+/// an open past it fails with `EMFILE`. Anything else is opened the way
+/// wasm3 would, relative to the host directory behind `dirfd`.
+pub(crate) unsafe extern "C" fn path_open(
+    runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    let dirfd = *sp.add(1) as i32;
+    let (path, path_len) = (*sp.add(3) as u32 as u64, *sp.add(4) as u32 as u64);
+    let oflags = *sp.add(5) & 0xffff;
+    let rights = *sp.add(6);
+    let fdflags = *sp.add(8) & 0xffff;
+    let fd_ptr = *sp.add(9) as u32 as u64;
+    let writes = oflags & (OFLAGS_CREAT | OFLAGS_TRUNC) != 0
+        || fdflags & FDFLAGS_APPEND != 0
+        || rights & RIGHTS_FD_WRITE != 0;
+    let (files, open) = CONTEXT.with(|c| {
+        c.borrow().as_ref().map_or((FilePolicy::default(), 0), |c| {
+            (c.files, c.open_files.0.len())
+        })
+    });
+    let at_limit = files.max_open_files.map_or(false, |max| open >= max);
+    let path = read_memory(runtime, path, path_len).map(|p| std::ffi::CString::new(p.to_vec()));
+    let code = match path {
+        _ if writes && files.read_only_root => ERRNO_ROFS,
+        _ if at_limit => {
+            CONTEXT.with(|c| {
+                if let Some(context) = c.borrow().as_ref() {
+                    context.metrics.inc_counter(
+                        "wasm3_open_files_denied_total",
+                        "Opens refused because the module had as many files open as it may",
+                        1.0,
+                    );
+                }
+            });
+            ERRNO_MFILE
+        }
+        None => ERRNO_FAULT,
+        Some(Err(_)) => ERRNO_INVAL,
+        Some(Ok(path)) => {
+            let dir = if PREOPENED_FDS.contains(&dirfd) {
+                libc::AT_FDCWD
+            } else {
+                // wasm3 hands out host descriptors as they are
+                dirfd
+            };
+            let fd = libc::openat(
+                dir,
+                path.as_ptr(),
+                open_flags(oflags, rights, fdflags),
+                0o644,
+            );
+            if fd < 0 {
+                wasi_errno(std::io::Error::last_os_error().raw_os_error().unwrap_or(0))
+            } else {
+                match write_memory(runtime, fd_ptr, 4) {
+                    Some(out) => {
+                        out.copy_from_slice(&fd.to_le_bytes());
+                        CONTEXT.with(|c| {
+                            if let Some(context) = c.borrow_mut().as_mut() {
+                                context.open_files.0.insert(fd);
+                            }
+                        });
+                        ERRNO_SUCCESS
+                    }
+                    None => {
+                        libc::close(fd);
+                        ERRNO_FAULT
+                    }
+                }
+            }
+        }
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+/// Linked in place of each of the [`READ_ONLY_FUNCTIONS`] for containers with
+/// a read-only root filesystem. Each of them changes the filesystem, so they
+/// all fail with `EROFS` without looking at their arguments.
+pub(crate) unsafe extern "C" fn read_only(
+    _runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    *(sp as *mut i32) = ERRNO_ROFS;
+    std::ptr::null()
+}
+
+/// `fd_close(fd) -> errno`, linked in place of wasm3's when the container's
+/// open files are capped, so a closed file stops counting against the cap.
+/// Only descriptors the module opened with `path_open` can be closed, which
+/// also keeps it from closing the provider's own.
+pub(crate) unsafe extern "C" fn fd_close(
+    _runtime: ffi::IM3Runtime,
+    sp: *mut u64,
+    _mem: *mut c_void,
+) -> *const c_void {
+    let fd = *sp.add(1) as i32;
+    let opened = CONTEXT.with(|c| {
+        c.borrow_mut()
+            .as_mut()
+            .map_or(false, |context| context.open_files.0.remove(&fd))
+    });
+    let code = if !opened {
+        ERRNO_BADF
+    } else if libc::close(fd) == 0 {
+        ERRNO_SUCCESS
+    } else {
+        wasi_errno(std::io::Error::last_os_error().raw_os_error().unwrap_or(0))
+    };
+    *(sp as *mut i32) = code;
+    std::ptr::null()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_starts_with_the_program_and_sorts_the_environment() {
+        let env = vec![("B", "2"), ("A", "1")]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        let command_line = CommandLine::new("hello", &["--name=world".to_owned()], &env);
+        assert_eq!(
+            command_line.args,
+            vec![b"hello\0".to_vec(), b"--name=world\0".to_vec()]
+        );
+        assert_eq!(command_line.env, vec![b"A=1\0".to_vec(), b"B=2\0".to_vec()]);
+        assert_eq!(string_sizes(&command_line.args), (2, 19));
+    }
+
+    #[test]
+    fn strings_are_laid_out_after_the_buffer_pointer() {
+        let strings = vec![b"ab\0".to_vec(), b"c\0".to_vec()];
+        let (list, buf) = lay_out_strings(&strings, 100);
+        assert_eq!(list, [100u32.to_le_bytes(), 103u32.to_le_bytes()].concat());
+        assert_eq!(buf, b"ab\0c\0");
+    }
+}
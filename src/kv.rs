@@ -0,0 +1,98 @@
+//! Small durable key-value stores for pods, one per pod, kept under the data
+//! directory so state survives container restarts.
+//!
+//! Each key is stored as a file named after the hex encoding of the key. All
+//! operations do blocking IO and are only called from instance threads.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::error;
+
+/// The longest key a module may use.
+pub(crate) const MAX_KEY_LEN: usize = 256;
+
+/// The largest value a module may store.
+pub(crate) const MAX_VALUE_LEN: usize = 64 * 1024;
+
+/// The most keys a single pod may store.
+pub(crate) const MAX_KEYS: usize = 1024;
+
+/// Why a key-value operation failed.
+#[derive(Debug)]
+pub(crate) enum KvError {
+    NotFound,
+    /// The key, value or number of keys is over its limit
+    TooLarge,
+    Io(io::Error),
+}
+
+impl From<io::Error> for KvError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::NotFound {
+            KvError::NotFound
+        } else {
+            KvError::Io(e)
+        }
+    }
+}
+
+/// The directory holding a pod's store.
+pub(crate) fn pod_kv_dir(kv_path: &Path, namespace: &str, pod: &str) -> PathBuf {
+    kv_path.join(namespace).join(pod)
+}
+
+/// Removes a pod's store once the pod is gone.
+pub(crate) async fn remove_pod_kv(kv_path: &Path, namespace: &str, pod: &str) {
+    let dir = pod_kv_dir(kv_path, namespace, pod);
+    match tokio::fs::remove_dir_all(&dir).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => error!("Unable to remove state in {}: {:?}", dir.display(), e),
+    }
+}
+
+/// A single pod's key-value store.
+pub(crate) struct KvStore {
+    dir: PathBuf,
+}
+
+impl KvStore {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        KvStore { dir }
+    }
+
+    fn key_path(&self, key: &[u8]) -> Result<PathBuf, KvError> {
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return Err(KvError::TooLarge);
+        }
+        let name: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(self.dir.join(name))
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Vec<u8>, KvError> {
+        Ok(std::fs::read(self.key_path(key)?)?)
+    }
+
+    pub(crate) fn set(&self, key: &[u8], value: &[u8]) -> Result<(), KvError> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(KvError::TooLarge);
+        }
+        let path = self.key_path(key)?;
+        std::fs::create_dir_all(&self.dir)?;
+        if !path.exists() && std::fs::read_dir(&self.dir)?.count() >= MAX_KEYS {
+            return Err(KvError::TooLarge);
+        }
+        // Write then rename so a crash never leaves a partial value behind
+        let temp = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&temp)?;
+        file.write_all(value)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &path)?;
+        Ok(())
+    }
+
+    pub(crate) fn delete(&self, key: &[u8]) -> Result<(), KvError> {
+        Ok(std::fs::remove_file(self.key_path(key)?)?)
+    }
+}
@@ -8,11 +8,14 @@
 //! use kubelet::{Kubelet, config::Config};
 //! use kubelet::store::oci::FileStore;
 //! use std::sync::Arc;
-//! use wasi_provider::WasiProvider;
+//! use krustlet_wasm3::{configure_registry_network, ProviderConfig, WasiProvider};
 //!
 //! async {
 //!     // Get a configuration for the Kubelet
 //!     let kubelet_config = Config::default();
+//!     let provider_config = ProviderConfig::from_env().unwrap();
+//!     // Proxy and CA settings must be in place before the registry client exists
+//!     configure_registry_network(&provider_config).unwrap();
 //!     let client = oci_distribution::Client::default();
 //!     let store = Arc::new(FileStore::new(client, &std::path::PathBuf::from("")));
 //!
@@ -20,7 +23,23 @@
 //!     let kubeconfig = kube::Config::infer().await.unwrap();
 //!
 //!     // Instantiate the provider type
-//!     let provider = WasiProvider::new(store, &kubelet_config, kubeconfig.clone()).await.unwrap();
+//!     let provider = WasiProvider::builder()
+//!         .store(store)
+//!         .kubelet_config(&kubelet_config)
+//!         .kubeconfig(kubeconfig.clone())
+//!         .provider_config(provider_config)
+//!         .pre_start(|pod, container| {
+//!             println!("Starting {} of pod {}", container, pod.name());
+//!             Ok(())
+//!         })
+//!         .post_stop(|pod, stopped| {
+//!             if stopped.failed {
+//!                 eprintln!("{} of pod {} failed: {}", stopped.container, pod.name(), stopped.message);
+//!             }
+//!         })
+//!         .build()
+//!         .await
+//!         .unwrap();
 //!
 //!     // Instantiate the Kubelet
 //!     let kubelet = Kubelet::new(provider, kubeconfig, kubelet_config).await.unwrap();
@@ -31,21 +50,83 @@
 
 #![deny(missing_docs)]
 
+pub mod admin;
+mod admission;
+mod args;
+mod attestation;
+mod auth;
+pub mod build_info;
+mod builder;
+mod burst;
+mod cleanup;
+pub mod clock;
+mod compose;
+pub mod config;
+mod cordon;
+mod cpu;
+pub mod credentials;
+mod cri_log;
+mod dead_letter;
+mod debug;
+mod entrypoint;
+mod events;
+mod eviction;
+mod gateway;
+mod handles;
+pub mod health;
+pub mod hooks;
+mod host;
+mod kv;
+mod lifecycle_log;
+pub mod local;
+mod log_audit;
+mod log_files;
+mod log_stream;
+pub mod logging;
+pub mod metrics;
+mod module_error;
+mod node_env;
+mod outbox;
+mod output;
+mod pod_config;
+mod prewarm;
+mod qos;
+mod ratelimit;
+mod reconcile;
+mod recovery;
+pub mod registry;
+mod reload;
+mod sidecar;
+mod snapshot;
+mod stack_tuning;
+mod static_pods;
+mod status;
+pub mod store;
+mod topology;
+mod trap;
+mod validation;
 mod wasi_runtime;
+mod watch;
+mod webhook;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
 use std::path::PathBuf;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use kubelet::node::Builder;
-use kubelet::pod::{key_from_pod, pod_key, Handle, Pod};
+use kubelet::pod::{key_from_pod, pod_key, Pod};
 use kubelet::provider::{Provider, ProviderError};
 use kubelet::store::Store;
 use kubelet::volume::Ref;
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use log::{error, warn};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use tokio::sync::RwLock;
-use wasi_runtime::Runtime;
+
+pub use builder::WasiProviderBuilder;
+pub use config::ProviderConfig;
+pub use registry::configure_registry_network;
 
 mod states;
 
@@ -54,7 +135,12 @@ use states::terminated::Terminated;
 
 const TARGET_WASM32_WASI: &str = "wasm32-wasi";
 const LOG_DIR_NAME: &str = "wasi-logs";
+const RETAINED_LOG_DIR_NAME: &str = "wasi-logs-deleted";
 const VOLUME_DIR: &str = "volumes";
+const KV_DIR: &str = "kv";
+const SNAPSHOT_DIR: &str = "wasm3-snapshots";
+const DEAD_LETTER_DIR: &str = "wasm3-dead-letters";
+const OUTBOX_DIR: &str = "wasm3-outbox";
 
 /// WasiProvider provides a Kubelet runtime implementation that executes WASM
 /// binaries conforming to the WASI spec.
@@ -65,47 +151,175 @@ pub struct WasiProvider {
 
 #[derive(Clone)]
 struct SharedPodState {
-    handles: Arc<RwLock<HashMap<String, Handle<Runtime, wasi_runtime::HandleFactory>>>>,
+    handles: Arc<handles::PodHandleMap>,
+    /// The UID of the pod whose pod state owns each pod key, whether or not
+    /// it has started. A pod recreated with the same name takes the key over.
+    known_pods: Arc<RwLock<HashMap<String, String>>>,
+    /// UIDs of pods that are being deleted, or that the node stopped, so
+    /// their setup can stop early
+    deleted: Arc<RwLock<HashSet<String>>>,
+    /// Keys of handles whose pod was missing from the cluster on the last
+    /// reconcile. They are only collected if still missing on the next one.
+    orphans: Arc<RwLock<HashSet<String>>>,
+    admission: Arc<admission::Admission>,
+    memory_pressure: Arc<eviction::MemoryPressure>,
+    cordon: Arc<cordon::Cordon>,
+    /// The node's labels and annotations, for the variables modules are
+    /// given from them
+    node_metadata: Arc<node_env::NodeMetadata>,
+    /// The pods started from manifest files rather than the API server
+    static_pods: Arc<static_pods::StaticPods>,
+    /// Where to send the updated pod when a running pod asks to be reloaded,
+    /// keyed by pod key
+    reloads: Arc<RwLock<HashMap<String, UnboundedSender<Pod>>>>,
+    /// Queues for calls on the live instances of each pod, keyed by pod key
+    /// and then container name
+    work_queues: Arc<RwLock<HashMap<String, HashMap<String, wasi_runtime::WorkQueue>>>>,
+    /// The HTTP trigger gateway routes of running pods
+    routes: Arc<gateway::Routes>,
+    /// What the instance of each container publishes for `wasm3-debug`,
+    /// keyed by pod key and then container name
+    debug_states: Arc<RwLock<HashMap<String, HashMap<String, Arc<debug::DebugState>>>>>,
+    credentials: Arc<credentials::CredentialConfig>,
+    metrics: Arc<metrics::Metrics>,
+    /// Where pod lifecycle events are sent, if anywhere
+    webhook: Option<Arc<webhook::Notifier>>,
+    /// Work shared between pods that start at the same time
+    burst: Arc<burst::Burst>,
+    /// Writes execution reports to running pods, if the node attests them
+    attestor: Option<Arc<attestation::Attestor>>,
+    /// Paces status patches and events sent to the Kubernetes API
+    api_limiter: Arc<ratelimit::WriteLimiter>,
+    /// Status and event writes waiting for the API server to be reachable
+    outbox: Arc<outbox::Outbox>,
+    /// What was found at startup about pods left running when the provider
+    /// last stopped
+    recovery: Arc<recovery::Recovery>,
+    /// The cores instances are pinned to, if the pinned executor is used
+    cpus: Option<Arc<cpu::CpuPool>>,
     store: Arc<dyn Store + Sync + Send>,
     log_path: PathBuf,
+    /// Where the logs of deleted pods are kept, if they are kept
+    retained_log_path: PathBuf,
+    /// Wakes `kubectl logs -f` sessions when logs are written, if the
+    /// platform supports watching files
+    log_watcher: Option<Arc<log_stream::LogWatcher>>,
     kubeconfig: kube::Config,
     volume_path: PathBuf,
+    /// Root of the per pod key-value stores
+    kv_path: PathBuf,
+    /// Where module memory snapshots are kept
+    snapshot_path: PathBuf,
+    /// Root of the per pod dead letter directories
+    dead_letter_path: PathBuf,
+    node_name: String,
+    config: Arc<ProviderConfig>,
+    /// What restart backoff, module deadlines and container log timestamps
+    /// read the time from
+    clock: Arc<dyn clock::Clock>,
+    /// Hooks and host extensions set by the crate embedding the provider
+    hooks: Arc<hooks::Hooks>,
 }
 
 impl WasiProvider {
-    /// Create a new wasi provider from a module store and a kubelet config
+    /// Create a new wasi provider from a module store and a kubelet config.
+    /// Provider specific settings are read from the environment, see
+    /// [`ProviderConfig::from_env`].
     pub async fn new(
         store: Arc<dyn Store + Sync + Send>,
         config: &kubelet::config::Config,
         kubeconfig: kube::Config,
     ) -> anyhow::Result<Self> {
-        let log_path = config.data_dir.join(LOG_DIR_NAME);
-        let volume_path = config.data_dir.join(VOLUME_DIR);
-        tokio::fs::create_dir_all(&log_path).await?;
-        tokio::fs::create_dir_all(&volume_path).await?;
-        Ok(Self {
-            shared: SharedPodState {
-                handles: Default::default(),
-                store,
-                log_path,
-                volume_path,
-                kubeconfig,
-            },
-        })
+        Self::builder()
+            .store(store)
+            .kubelet_config(config)
+            .kubeconfig(kubeconfig)
+            .build()
+            .await
+    }
+
+    /// Create a new wasi provider from a module store, a kubelet config and
+    /// provider specific configuration
+    pub async fn new_with_config(
+        store: Arc<dyn Store + Sync + Send>,
+        config: &kubelet::config::Config,
+        kubeconfig: kube::Config,
+        provider_config: ProviderConfig,
+    ) -> anyhow::Result<Self> {
+        Self::builder()
+            .store(store)
+            .kubelet_config(config)
+            .kubeconfig(kubeconfig)
+            .provider_config(provider_config)
+            .build()
+            .await
+    }
+
+    /// Starts building a wasi provider, for crates that embed the provider
+    /// and want to set where it keeps logs, link host functions of their own
+    /// or run hooks around each container, see [`WasiProviderBuilder`].
+    pub fn builder() -> WasiProviderBuilder {
+        Default::default()
     }
 }
 
 struct ModuleRunContext {
-    modules: HashMap<String, Vec<u8>>,
+    /// Modules keyed by container name. These are kept for the lifetime of
+    /// the pod state so restarts do not pull them again.
+    modules: HashMap<String, wasi_runtime::ModuleData>,
+    /// The image each entry in `modules` was pulled from
+    module_images: HashMap<String, String>,
+    /// The digest each container's image was pinned to when it was first
+    /// pulled, keyed by container name. Kept when modules are dropped, so
+    /// they are pulled again by digest.
+    pinned_images: HashMap<String, registry::PinnedImage>,
+    /// Library modules the pod's modules are linked with, keyed by the
+    /// import module name they are linked under
+    libraries: HashMap<String, wasi_runtime::ModuleData>,
+    /// The image each entry in `libraries` was pulled from
+    library_images: HashMap<String, String>,
+    /// How many times each container has been restarted, keyed by container
+    /// name
+    restart_counts: HashMap<String, i32>,
+    /// Stacks raised for containers whose module overflowed the stack
+    raised_stacks: stack_tuning::RaisedStacks,
+    /// Runtimes kept alive between restarts, keyed by container name
+    memoized: HashMap<String, wasi_runtime::WasiRuntime>,
     volumes: HashMap<String, Ref>,
-    status_sender: Sender<(String, kubelet::container::Status)>,
-    status_recv: Receiver<(String, kubelet::container::Status)>,
+    /// Data of the ConfigMaps and Secrets the pod mounts, for the config host
+    /// functions
+    pod_config: Arc<pod_config::PodConfig>,
+    /// The core the pod's instances are pinned to, once one has started
+    cpu: Option<usize>,
+    status_sender: status::StatusSender,
+    status_recv: status::StatusReceiver,
+    /// Updated pods whose modules should be reloaded in place
+    reload_recv: UnboundedReceiver<Pod>,
+}
+
+impl ModuleRunContext {
+    /// How many times a container has been restarted
+    fn restart_count(&self, container_name: &str) -> i32 {
+        self.restart_counts
+            .get(container_name)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// The UID of a pod, which unlike its key is never reused.
+fn pod_uid(pod: &Pod) -> String {
+    pod.as_kube_pod().metadata.uid.clone().unwrap_or_default()
 }
 
 /// State that is shared between pod state handlers.
 pub struct PodState {
     key: String,
+    uid: String,
+    namespace: String,
+    name: String,
     run_context: ModuleRunContext,
+    /// Failures in a row, which set the restart backoff
     errors: usize,
     shared: SharedPodState,
 }
@@ -114,10 +328,38 @@ pub struct PodState {
 #[async_trait]
 impl kubelet::state::AsyncDrop for PodState {
     async fn async_drop(self) {
+        self.shared.deleted.write().await.remove(&self.uid);
         {
-            let mut handles = self.shared.handles.write().await;
-            handles.remove(&self.key);
+            let mut known = self.shared.known_pods.write().await;
+            // Everything else is keyed by name, and belongs to the new pod if
+            // the pod was recreated before this one was cleaned up
+            if known.get(&self.key) != Some(&self.uid) {
+                return;
+            }
+            known.remove(&self.key);
         }
+        self.shared.handles.remove(&self.key).await;
+        self.shared.reloads.write().await.remove(&self.key);
+        self.shared.work_queues.write().await.remove(&self.key);
+        self.shared.routes.remove(&self.key);
+        self.shared.debug_states.write().await.remove(&self.key);
+        self.shared.api_limiter.forget(&self.key);
+        self.shared.admission.release(&self.key).await;
+        self.shared.metrics.remove_pod(&self.namespace, &self.name);
+        let volumes = self
+            .run_context
+            .volumes
+            .values()
+            .map(|v| v.deref().clone())
+            .collect();
+        cleanup::remove_pod(
+            &self.shared,
+            &self.namespace,
+            &self.name,
+            &self.uid,
+            volumes,
+        )
+        .await;
     }
 }
 
@@ -132,26 +374,144 @@ impl Provider for WasiProvider {
     async fn node(&self, builder: &mut Builder) -> anyhow::Result<()> {
         builder.set_architecture("wasm-wasi");
         builder.add_taint("NoExecute", "kubernetes.io/arch", Self::ARCH);
+        for (key, value) in build_info::node_labels() {
+            builder.add_label(&key, &value);
+        }
+        for (key, value) in build_info::node_annotations() {
+            builder.add_annotation(&key, &value);
+        }
+        for (key, value) in topology::resolve(&self.shared.config).await.labels() {
+            builder.add_label(&key, &value);
+        }
         Ok(())
     }
 
     async fn initialize_pod_state(&self, pod: &Pod) -> anyhow::Result<Self::PodState> {
-        let (tx, rx) = mpsc::channel(pod.all_containers().len());
+        // The static pod it mirrors is already running from its manifest
+        if static_pods::is_mirror(pod) {
+            return Err(anyhow::anyhow!(
+                "pod {} is the mirror of a static pod, ignoring it",
+                key_from_pod(pod)
+            ));
+        }
+        let (tx, rx) = status::channel();
+        let (reload_tx, reload_rx) = mpsc::unbounded_channel();
         let run_context = ModuleRunContext {
             modules: Default::default(),
+            module_images: Default::default(),
+            pinned_images: Default::default(),
+            libraries: Default::default(),
+            library_images: Default::default(),
+            restart_counts: self.shared.recovery.take_restart_counts(&pod_uid(pod)),
+            raised_stacks: Default::default(),
+            memoized: Default::default(),
             volumes: Default::default(),
+            pod_config: Default::default(),
+            cpu: None,
             status_sender: tx,
             status_recv: rx,
+            reload_recv: reload_rx,
         };
         let key = key_from_pod(pod);
+        let uid = pod_uid(pod);
+        {
+            let mut known = self.shared.known_pods.write().await;
+            // The kubelet can deliver an add again, for example after a
+            // resync. The pod state already running it carries on.
+            if known.get(&key) == Some(&uid) {
+                return Err(anyhow::anyhow!(
+                    "pod {} is already running, ignoring repeated add",
+                    key
+                ));
+            }
+            known.insert(key.clone(), uid.clone());
+        }
+        self.shared
+            .reloads
+            .write()
+            .await
+            .insert(key.clone(), reload_tx);
         Ok(PodState {
             key,
+            uid,
+            namespace: pod.namespace().to_owned(),
+            name: pod.name().to_owned(),
             run_context,
             errors: 0,
             shared: self.shared.clone(),
         })
     }
 
+    /// Calls the exported function named by the command on the container's
+    /// live instance, waiting for any work queued ahead of it. The
+    /// `wasm3-debug` command dumps the state of the container's instance
+    /// instead, without waiting.
+    async fn exec(
+        &self,
+        pod: Pod,
+        container_name: String,
+        command: String,
+    ) -> anyhow::Result<Vec<String>> {
+        let key = key_from_pod(&pod);
+        let export = command
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("expected the name of an exported function to call"))?;
+        if export == debug::DEBUG_COMMAND {
+            let state = self
+                .shared
+                .debug_states
+                .read()
+                .await
+                .get(&key)
+                .and_then(|states| states.get(&container_name))
+                .cloned()
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "container {} of pod {} has not been started",
+                        container_name,
+                        key
+                    )
+                })?;
+            return Ok(state.dump(&container_name));
+        }
+        let queue = self
+            .shared
+            .work_queues
+            .read()
+            .await
+            .get(&key)
+            .and_then(|queues| queues.get(&container_name))
+            .cloned()
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "container {} of pod {} does not take calls, it must be a reactor or memoized",
+                    container_name,
+                    key
+                )
+            })?;
+        if let Err(e) = queue.call(export).await {
+            match dead_letter::sink(&pod) {
+                Ok(Some(sink)) => dead_letter::capture(
+                    &self.shared,
+                    sink,
+                    dead_letter::Target {
+                        namespace: pod.namespace(),
+                        pod: pod.name(),
+                        container: &container_name,
+                        export,
+                    },
+                    dead_letter::Input::Exec(&command),
+                    &format!("{:#}", e),
+                ),
+                Ok(None) => (),
+                Err(e) => warn!("Unable to keep dead letter of pod {}: {}", key, e),
+            }
+            return Err(e);
+        }
+        Ok(vec![format!("{} returned", export)])
+    }
+
     async fn logs(
         &self,
         namespace: String,
@@ -159,12 +519,60 @@ impl Provider for WasiProvider {
         container_name: String,
         sender: kubelet::log::Sender,
     ) -> anyhow::Result<()> {
-        let mut handles = self.shared.handles.write().await;
-        let handle = handles
-            .get_mut(&pod_key(&namespace, &pod_name))
+        self.shared.metrics.inc_counter(
+            "wasm3_log_requests_total",
+            "Container log requests served",
+            &[
+                ("namespace", namespace.as_str()),
+                ("pod", pod_name.as_str()),
+                ("container", container_name.as_str()),
+            ],
+            1.0,
+        );
+        if let Some(path) = &self.shared.config.log_audit_file {
+            // A failed audit write must not hide logs from the user, but it
+            // does need to be visible to operators
+            if let Err(e) =
+                log_audit::record_log_access(path, &namespace, &pod_name, &container_name).await
+            {
+                error!("Unable to write log access audit record: {:?}", e);
+            }
+        }
+        let handle = self
+            .shared
+            .handles
+            .get(&pod_key(&namespace, &pod_name))
+            .await
             .ok_or_else(|| ProviderError::PodNotFound {
                 pod_name: pod_name.clone(),
             })?;
-        handle.output(&container_name, sender).await
+        let watcher = match &self.shared.log_watcher {
+            Some(watcher) => watcher,
+            // Fall back to the kubelet's polling reader
+            None => {
+                let mut handle = handle.lock().await;
+                return handle.output(&container_name, sender).await;
+            }
+        };
+        // The pod handle is not needed while streaming, so don't keep it
+        // locked for the whole session
+        drop(handle);
+        let dir = log_files::container_log_dir(
+            &self.shared.log_path,
+            &namespace,
+            &pod_name,
+            &container_name,
+        );
+        let path = tokio::task::spawn_blocking(move || log_files::latest_instance_log(&dir))
+            .await??
+            .ok_or_else(|| anyhow::anyhow!("container {} has no logs", container_name))?;
+        let metrics = metrics::ContainerMetrics::new(
+            self.shared.metrics.clone(),
+            &namespace,
+            &pod_name,
+            &container_name,
+        );
+        let format = self.shared.config.stdout.format;
+        log_stream::stream(watcher, &path, sender, format, &metrics).await
     }
 }
@@ -0,0 +1,148 @@
+//! A per pod record of each container's lifecycle on the node, for looking
+//! into what happened after the pod's Kubernetes events have expired.
+//!
+//! Each step is appended as a JSON line to `events.jsonl` in the pod's log
+//! directory, `<log dir>/<namespace>/<pod>/`, so the file is kept and removed
+//! along with the pod's logs, including for the time logs of deleted pods are
+//! retained. Past [`MAX_SIZE`] the file is moved to `events.jsonl.1`,
+//! replacing the one before, and a new one started.
+//!
+//! ```json
+//! {"time":"2020-10-16T12:00:00.123Z","container":"hello","step":"trapped","detail":"unreachable executed"}
+//! ```
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::warn;
+use serde_derive::Serialize;
+
+use crate::clock::Clock;
+
+/// The name of the file in the pod's log directory.
+const EVENTS_FILE: &str = "events.jsonl";
+
+/// How large the file grows before it is moved aside.
+const MAX_SIZE: u64 = 1024 * 1024;
+
+/// A step in a container's lifecycle.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Step {
+    /// The module was pulled or read from the module source
+    Pulled,
+    /// wasm3 parsed the module
+    Parsed,
+    /// The module's imports were linked and its entrypoint found
+    Linked,
+    /// A run of the module's entrypoint started
+    Started,
+    /// The entrypoint of a reactor returned, and the instance stays to serve
+    /// calls
+    Yielded,
+    /// The module trapped, in its entrypoint or in a call
+    Trapped,
+    /// The entrypoint returned
+    Exited,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: chrono::DateTime<chrono::Utc>,
+    container: &'a str,
+    step: Step,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+}
+
+/// The file of a pod whose logs are in `pod_log_dir`.
+pub(crate) fn events_file(pod_log_dir: &Path) -> PathBuf {
+    pod_log_dir.join(EVENTS_FILE)
+}
+
+/// Records the lifecycle of one container.
+#[derive(Clone)]
+pub(crate) struct LifecycleLog {
+    path: PathBuf,
+    container: String,
+    clock: Arc<dyn Clock>,
+}
+
+impl LifecycleLog {
+    /// The log of `container` in pod `pod`, whose logs are below `log_path`.
+    pub(crate) fn new(
+        log_path: &Path,
+        namespace: &str,
+        pod: &str,
+        container: &str,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        LifecycleLog {
+            path: events_file(&log_path.join(namespace).join(pod)),
+            container: container.to_owned(),
+            clock,
+        }
+    }
+
+    /// Appends a step. A step that cannot be written is logged and dropped.
+    /// This does blocking IO.
+    pub(crate) fn record(&self, step: Step, detail: Option<&str>) {
+        let record = Record {
+            time: self.clock.now(),
+            container: &self.container,
+            step,
+            detail,
+        };
+        if let Err(e) = self.append(&record) {
+            warn!(
+                "Unable to record {:?} of container {} in {}: {:?}",
+                step,
+                self.container,
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    fn append(&self, record: &Record<'_>) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        if let Ok(metadata) = std::fs::metadata(&self.path) {
+            if metadata.len() >= MAX_SIZE {
+                std::fs::rename(&self.path, self.path.with_extension("jsonl.1"))?;
+            }
+        }
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // Each step is one append, so the steps of a pod's containers do not
+        // interleave
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Reads the steps recorded in `file`, oldest first, including those moved
+/// aside. This does blocking IO.
+pub(crate) fn read(file: &Path) -> std::io::Result<Vec<serde_json::Value>> {
+    let mut records = Vec::new();
+    for path in &[file.with_extension("jsonl.1"), file.to_owned()] {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        // A line cut short by a crash is skipped
+        records.extend(
+            contents
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok()),
+        );
+    }
+    Ok(records)
+}
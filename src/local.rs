@@ -0,0 +1,205 @@
+//! Runs a single module outside a cluster, for `wasm3-provider run`. Modules
+//! are loaded through the same stores, checked by the same validation and run
+//! by the same runtime as on a node, so a module that fails on a node can be
+//! debugged on a workstation.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use kubelet::container::Status;
+use kubelet::store::oci::FileStore;
+use kubelet::store::{PullPolicy, Store};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use tokio::io::AsyncReadExt;
+
+use crate::clock::SystemClock;
+use crate::config::{ContainerLogFormat, ModuleSource, ProviderConfig, StdoutPolicy};
+use crate::credentials::CredentialConfig;
+use crate::events::EventRecorder;
+use crate::host::FilePolicy;
+use crate::metrics::ContainerMetrics;
+use crate::status;
+use crate::store::DirectoryStore;
+use crate::validation;
+use crate::wasi_runtime::{self, ModuleData, WasiRuntime};
+
+/// The container name local runs use in logs and metrics.
+const LOCAL_NAME: &str = "local";
+
+/// How often new module output is read.
+const OUTPUT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// What to run and how.
+pub struct LocalRun {
+    /// An image reference, or the path of a `.wasm` file
+    pub module: String,
+    /// Environment variables for the module
+    pub env: HashMap<String, String>,
+    /// Arguments for the module
+    pub args: Vec<String>,
+    /// Where pulled modules are cached
+    pub data_dir: PathBuf,
+    /// Provider settings, for the module source, credentials and timeouts
+    pub config: ProviderConfig,
+}
+
+/// Something that happened during a local run.
+pub enum LocalEvent {
+    /// The run moved on to a new step, or the module exited
+    Status(String),
+    /// The module wrote output
+    Output(String),
+}
+
+/// Loads, checks and runs a module, reporting each step and the module's
+/// output to `report`. Returns an error if the module could not be started or
+/// exited with a failure.
+pub async fn run(options: LocalRun, mut report: impl FnMut(LocalEvent)) -> anyhow::Result<()> {
+    report(LocalEvent::Status(format!("Loading {}", options.module)));
+    let module = load_module(&options).await?;
+
+    report(LocalEvent::Status("Validating".to_owned()));
+    let kv = options.config.host_kv;
+    let check = module.clone();
+    let max_size = options.config.max_module_size;
+    let info = tokio::task::spawn_blocking(move || {
+        validation::validate(&check, "_start", kv, max_size, Default::default(), &[], &[])
+    })
+    .await?
+    .map_err(|e| anyhow::anyhow!("{}: {}", e.reason(), e))?;
+    report(LocalEvent::Status(format!(
+        "WASI imports: {}",
+        info.wasi_imports().join(", ")
+    )));
+
+    let log = tempfile::NamedTempFile::new()?.into_temp_path();
+    let kv_dir = if kv {
+        Some(options.data_dir.join("kv").join(LOCAL_NAME))
+    } else {
+        None
+    };
+    let (status_sender, mut status_recv) = status::channel();
+    let runtime = WasiRuntime::new(
+        LOCAL_NAME.to_owned(),
+        module,
+        options.env,
+        options.args,
+        None,
+        FilePolicy {
+            read_only_root: false,
+            max_open_files: options.config.max_open_files,
+        },
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+        log.to_path_buf(),
+        status_sender,
+        EventRecorder::local(LOCAL_NAME),
+        None,
+        ContainerMetrics::new(Default::default(), "", LOCAL_NAME, LOCAL_NAME),
+        false,
+        false,
+        options.config.setup_timeouts,
+        // The log is forwarded to the terminal as it is
+        StdoutPolicy {
+            format: ContainerLogFormat::Plain,
+            ..options.config.stdout
+        },
+        kv_dir,
+        Default::default(),
+        None,
+        wasi_runtime::DEFAULT_STACK_SIZE,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Arc::new(SystemClock),
+    );
+    report(LocalEvent::Status("Starting".to_owned()));
+    let _handle = runtime.start().await?;
+    report(LocalEvent::Status("Running".to_owned()));
+
+    let mut output = tokio::fs::File::open(&log).await?;
+    let mut buf = Vec::new();
+    loop {
+        let status = tokio::select! {
+            status = status_recv.recv() => status,
+            _ = tokio::time::delay_for(OUTPUT_POLL_INTERVAL) => {
+                forward_output(&mut output, &mut buf, &mut report).await?;
+                continue;
+            }
+        };
+        forward_output(&mut output, &mut buf, &mut report).await?;
+        match status {
+            Some((
+                _,
+                Status::Terminated {
+                    message, failed, ..
+                },
+            )) => {
+                report(LocalEvent::Status(format!("Terminated: {}", message)));
+                return if failed {
+                    Err(anyhow::anyhow!(message))
+                } else {
+                    Ok(())
+                };
+            }
+            Some((_, Status::Waiting { message, .. })) => {
+                report(LocalEvent::Status(format!("Waiting: {}", message)))
+            }
+            Some(_) => (),
+            None => return Err(anyhow::anyhow!("module exited without reporting a status")),
+        }
+    }
+}
+
+/// Reads a `.wasm` file, or pulls an image through the configured store.
+async fn load_module(options: &LocalRun) -> anyhow::Result<ModuleData> {
+    let path = Path::new(&options.module);
+    if path.is_file() {
+        return Ok(tokio::fs::read(path).await?.into());
+    }
+    let reference = Reference::try_from(options.module.as_str()).map_err(|e| {
+        anyhow::anyhow!("{} is not a file or image reference: {}", options.module, e)
+    })?;
+    let store: Arc<dyn Store + Send + Sync> = match &options.config.module_source {
+        ModuleSource::Registry => Arc::new(FileStore::new(
+            oci_distribution::Client::default(),
+            &options.data_dir,
+        )),
+        ModuleSource::Directory(root) => Arc::new(DirectoryStore::new(root)),
+    };
+    let auth = match &options.config.credential_config {
+        Some(path) => CredentialConfig::load(path)
+            .await?
+            .resolve(reference.registry())
+            .await?
+            .unwrap_or(RegistryAuth::Anonymous),
+        None => RegistryAuth::Anonymous,
+    };
+    let bytes = store
+        .get(&reference, PullPolicy::IfNotPresent, &auth)
+        .await?;
+    Ok(bytes.into())
+}
+
+/// Reports whatever the module has written since the last call.
+async fn forward_output(
+    output: &mut tokio::fs::File,
+    buf: &mut Vec<u8>,
+    report: &mut impl FnMut(LocalEvent),
+) -> anyhow::Result<()> {
+    buf.clear();
+    output.read_to_end(buf).await?;
+    if !buf.is_empty() {
+        report(LocalEvent::Output(
+            String::from_utf8_lossy(buf).into_owned(),
+        ));
+    }
+    Ok(())
+}
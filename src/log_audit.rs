@@ -0,0 +1,38 @@
+use std::path::Path;
+
+use serde_derive::Serialize;
+use tokio::io::AsyncWriteExt;
+
+/// A single container log access. The kubelet does not pass the identity of
+/// the requester to providers, so the record covers what was read and when.
+#[derive(Serialize)]
+struct LogAccessRecord<'a> {
+    timestamp: chrono::DateTime<chrono::Utc>,
+    namespace: &'a str,
+    pod: &'a str,
+    container: &'a str,
+}
+
+/// Appends a log access record to the audit file as a JSON line.
+pub(crate) async fn record_log_access(
+    path: &Path,
+    namespace: &str,
+    pod: &str,
+    container: &str,
+) -> anyhow::Result<()> {
+    let record = LogAccessRecord {
+        timestamp: chrono::Utc::now(),
+        namespace,
+        pod,
+        container,
+    };
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(&line).await?;
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! Per-instance container log files.
+//!
+//! Every time a container is started it writes to a new file named after its
+//! instance number, `<log dir>/<namespace>/<pod>/<container>/<n>.log`, so the
+//! output of earlier runs survives a restart. Only the newest files are kept.
+//! A shadow instance, which tries a new module before a reload replaces the
+//! running one, writes to `shadow.log` in the same directory instead.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{error, warn};
+
+const LOG_EXTENSION: &str = "log";
+
+/// The log file of a container's shadow instance. Its name is not an
+/// instance number, so it is never taken for an instance's log.
+const SHADOW_LOG: &str = "shadow.log";
+
+/// The directory holding the log files of a container.
+pub(crate) fn container_log_dir(
+    log_path: &Path,
+    namespace: &str,
+    pod: &str,
+    container: &str,
+) -> PathBuf {
+    log_path.join(namespace).join(pod).join(container)
+}
+
+/// Creates the log file for the next instance of a container, keeping at
+/// most `retention` files including the new one. This does blocking IO.
+pub(crate) fn create_instance_log(dir: &Path, retention: usize) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let mut instances = instances(dir)?;
+    let next = instances.last().map(|n| n + 1).unwrap_or(0);
+    let path = instance_path(dir, next);
+    std::fs::File::create(&path)?;
+    instances.push(next);
+
+    let excess = instances.len().saturating_sub(retention.max(1));
+    for old in &instances[..excess] {
+        if let Err(e) = std::fs::remove_file(instance_path(dir, *old)) {
+            warn!("Unable to remove old container log: {:?}", e);
+        }
+    }
+    Ok(path)
+}
+
+/// Creates the log file of a container's shadow instance, replacing the log
+/// of the last one. This does blocking IO.
+pub(crate) fn create_shadow_log(dir: &Path) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(SHADOW_LOG);
+    std::fs::File::create(&path)?;
+    Ok(path)
+}
+
+/// Returns the log file of the newest instance of a container, if it has one.
+/// This does blocking IO.
+pub(crate) fn latest_instance_log(dir: &Path) -> io::Result<Option<PathBuf>> {
+    match instances(dir) {
+        Ok(instances) => Ok(instances.last().map(|n| instance_path(dir, *n))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes the logs of every container in a pod once the pod is gone.
+pub(crate) async fn remove_pod_logs(log_path: &Path, namespace: &str, pod: &str) {
+    let dir = log_path.join(namespace).join(pod);
+    match tokio::fs::remove_dir_all(&dir).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => error!("Unable to remove logs in {}: {:?}", dir.display(), e),
+    }
+}
+
+fn instance_path(dir: &Path, instance: u64) -> PathBuf {
+    dir.join(format!("{}.{}", instance, LOG_EXTENSION))
+}
+
+/// The instance numbers of existing log files, oldest first.
+fn instances(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut instances = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(LOG_EXTENSION) {
+            continue;
+        }
+        if let Some(n) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse().ok())
+        {
+            instances.push(n);
+        }
+    }
+    instances.sort_unstable();
+    Ok(instances)
+}
@@ -0,0 +1,455 @@
+//! Streams container logs to `kubectl logs`, following them with filesystem
+//! notifications rather than polling.
+//!
+//! A single [`LogWatcher`] watches the whole log directory, so the number of
+//! open follow sessions does not change how many watches or threads are used.
+//! Each session reads the file through its own handle, at its own offset, so
+//! any number of them can follow a container while it writes. A session is
+//! woken at most once for the changes made while it was busy, so a slow
+//! client costs the watcher nothing.
+
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, error};
+use notify::{Op, RawEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+use crate::config::ContainerLogFormat;
+use crate::cri_log::Decoder;
+use crate::log_files;
+use crate::metrics::ContainerMetrics;
+
+/// The most bytes read from a log file at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The size of the blocks read backwards when looking for the start of the
+/// last lines.
+const TAIL_BLOCK_SIZE: u64 = 8 * 1024;
+
+const LOG_BYTES_METRIC: &str = "wasm3_log_bytes_streamed_total";
+
+type Subscribers = Arc<Mutex<HashMap<PathBuf, Vec<Sender<()>>>>>;
+
+/// Wakes the subscribers of `path`. One already due a wakeup is not sent
+/// another, and ones that have gone away are dropped.
+fn wake(subscribers: &mut HashMap<PathBuf, Vec<Sender<()>>>, path: &Path) {
+    let senders = match subscribers.get_mut(path) {
+        Some(senders) => senders,
+        None => return,
+    };
+    let mut open = Vec::with_capacity(senders.len());
+    for mut sender in senders.drain(..) {
+        if !matches!(sender.try_send(()), Err(TrySendError::Closed(_))) {
+            open.push(sender);
+        }
+    }
+    if open.is_empty() {
+        subscribers.remove(path);
+    } else {
+        *senders = open;
+    }
+}
+
+/// Watches the log directory and wakes followers of files that change.
+pub(crate) struct LogWatcher {
+    subscribers: Subscribers,
+    // Kept alive for as long as the watcher is in use
+    _watcher: Mutex<RecommendedWatcher>,
+}
+
+impl LogWatcher {
+    /// Watches every log file under `log_path`, including ones created later.
+    pub(crate) fn new(log_path: &Path) -> anyhow::Result<Self> {
+        let (tx, rx) = std::sync::mpsc::channel::<RawEvent>();
+        let mut watcher = notify::raw_watcher(tx)?;
+        watcher.watch(log_path, RecursiveMode::Recursive)?;
+        let subscribers: Subscribers = Default::default();
+        let dispatch = subscribers.clone();
+        std::thread::Builder::new()
+            .name("log-watcher".into())
+            .spawn(move || {
+                // Ends when the watcher, and with it the sending side, is
+                // dropped
+                for event in rx {
+                    let path = match event.path {
+                        Some(path) => path,
+                        None => continue,
+                    };
+                    let created = event.op.map_or(false, |op| op.contains(Op::CREATE));
+                    let mut subscribers = dispatch.lock().unwrap();
+                    wake(&mut subscribers, &path);
+                    // A new file in a container's log directory is a new
+                    // instance, which followers of the old one need to know
+                    if created {
+                        if let Some(dir) = path.parent() {
+                            wake(&mut subscribers, dir);
+                        }
+                    }
+                }
+            })?;
+        Ok(LogWatcher {
+            subscribers,
+            _watcher: Mutex::new(watcher),
+        })
+    }
+
+    /// Returns a receiver woken whenever `path` changes, or for a directory,
+    /// whenever a file is created in it.
+    fn subscribe(&self, path: &Path) -> Receiver<()> {
+        let (tx, rx) = mpsc::channel(1);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .entry(path.to_owned())
+            .or_default()
+            .push(tx);
+        rx
+    }
+}
+
+/// Sends the log at `path` to `sender`, starting `tail` lines from the end if
+/// set, then keeps sending new output if `follow` is set, until the file is
+/// removed, the container starts a new instance with a log of its own, or
+/// the client goes away. A log in the CRI `format` is sent as the output it
+/// was made from, and `tail` counts its records.
+pub(crate) async fn stream(
+    watcher: &LogWatcher,
+    path: &Path,
+    mut sender: kubelet::log::Sender,
+    format: ContainerLogFormat,
+    metrics: &ContainerMetrics,
+) -> anyhow::Result<()> {
+    let dir = path.parent().unwrap_or(path);
+    // Subscribe before the first read so no write in between is missed
+    let mut updates = if sender.follow() {
+        Some((watcher.subscribe(path), watcher.subscribe(dir)))
+    } else {
+        None
+    };
+    let mut file = tokio::fs::File::open(path).await?;
+    if let Some(lines) = sender.tail() {
+        let offset = tail_offset(&mut file, lines).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+    }
+
+    let mut reader = Reader {
+        pending: Vec::new(),
+        decoder: match format {
+            ContainerLogFormat::Plain => None,
+            ContainerLogFormat::Cri => Some(Decoder::default()),
+        },
+    };
+    if !send_available(&mut file, &mut reader, &mut sender, metrics).await? {
+        return Ok(());
+    }
+    let (updates, instances) = match updates.as_mut() {
+        Some(updates) => updates,
+        None => return Ok(()),
+    };
+    loop {
+        tokio::select! {
+            update = updates.recv() => if update.is_none() {
+                break;
+            },
+            instance = instances.recv() => {
+                if instance.is_none() {
+                    break;
+                }
+                if !replaced(dir, path).await? {
+                    continue;
+                }
+                // The old instance has stopped writing, so what is left of
+                // its log is all there will be
+                send_available(&mut file, &mut reader, &mut sender, metrics).await?;
+                debug!("Log {} was replaced by a new instance, ending follow", path.display());
+                break;
+            }
+        }
+        if tokio::fs::metadata(path).await.is_err() {
+            debug!("Log {} was removed, ending follow", path.display());
+            break;
+        }
+        if !send_available(&mut file, &mut reader, &mut sender, metrics).await? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if the container whose log directory is `dir` has started an
+/// instance newer than the one that writes to `path`.
+async fn replaced(dir: &Path, path: &Path) -> anyhow::Result<bool> {
+    let dir = dir.to_owned();
+    let latest =
+        tokio::task::spawn_blocking(move || log_files::latest_instance_log(&dir)).await??;
+    Ok(latest.map_or(false, |latest| latest != path))
+}
+
+/// What is left over from one read of a log for the next.
+struct Reader {
+    /// Output ending in an incomplete UTF-8 sequence
+    pending: Vec<u8>,
+    /// Set for the CRI log format
+    decoder: Option<Decoder>,
+}
+
+/// Sends everything from the current position to the end of the file.
+/// Incomplete UTF-8 sequences and CRI records at the end of a read are kept
+/// in `reader` until the rest arrives. Returns false once the client has gone
+/// away.
+async fn send_available(
+    file: &mut tokio::fs::File,
+    reader: &mut Reader,
+    sender: &mut kubelet::log::Sender,
+    metrics: &ContainerMetrics,
+) -> anyhow::Result<bool> {
+    let mut buf = vec![0; CHUNK_SIZE];
+    let pending = &mut reader.pending;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(true);
+        }
+        match reader.decoder.as_mut() {
+            Some(decoder) => pending.extend_from_slice(&decoder.decode(&buf[..n])),
+            None => pending.extend_from_slice(&buf[..n]),
+        }
+        let valid = match std::str::from_utf8(pending) {
+            Ok(_) => pending.len(),
+            // An invalid sequence will never become valid, so send it as is
+            Err(e) if e.error_len().is_some() => pending.len(),
+            Err(e) => e.valid_up_to(),
+        };
+        let chunk: Vec<u8> = pending.drain(..valid).collect();
+        if chunk.is_empty() {
+            continue;
+        }
+        metrics.inc_counter(
+            LOG_BYTES_METRIC,
+            "Bytes of container log streamed to log consumers",
+            chunk.len() as f64,
+        );
+        if let Err(e) = sender
+            .send(String::from_utf8_lossy(&chunk).into_owned())
+            .await
+        {
+            debug!("Log client went away: {:?}", e);
+            return Ok(false);
+        }
+    }
+}
+
+/// Finds the offset of the start of the last `lines` lines of the file,
+/// reading backwards from the end so large logs are not read in full.
+async fn tail_offset(file: &mut tokio::fs::File, lines: usize) -> anyhow::Result<u64> {
+    let len = file.metadata().await?.len();
+    if lines == 0 {
+        return Ok(len);
+    }
+    let mut end = len;
+    let mut newlines = 0;
+    let mut block = vec![0; TAIL_BLOCK_SIZE as usize];
+    while end > 0 {
+        let start = end.saturating_sub(TAIL_BLOCK_SIZE);
+        let size = (end - start) as usize;
+        file.seek(SeekFrom::Start(start)).await?;
+        file.read_exact(&mut block[..size]).await?;
+        for (i, b) in block[..size].iter().enumerate().rev() {
+            // A newline ending the file does not start another line
+            if *b != b'\n' || start + i as u64 == len - 1 {
+                continue;
+            }
+            newlines += 1;
+            if newlines == lines {
+                return Ok(start + i as u64 + 1);
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// Creates the log watcher, logging rather than failing if the platform
+/// cannot watch files, since logs can still be read without following.
+pub(crate) fn watch(log_path: &Path) -> Option<Arc<LogWatcher>> {
+    match LogWatcher::new(log_path) {
+        Ok(watcher) => Some(Arc::new(watcher)),
+        Err(e) => {
+            error!(
+                "Unable to watch container logs, follow is disabled: {:?}",
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tokio::task::JoinHandle;
+    use warp::hyper::Body;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// A `kubectl logs -f` session.
+    struct Follower {
+        body: Body,
+        received: String,
+        session: JoinHandle<anyhow::Result<()>>,
+    }
+
+    impl Follower {
+        fn start(watcher: &Arc<LogWatcher>, path: &Path) -> Self {
+            let (tx, body) = Body::channel();
+            let options = serde_json::from_value(serde_json::json!({ "follow": true })).unwrap();
+            let sender = kubelet::log::Sender::new(tx, options);
+            let (watcher, path) = (watcher.clone(), path.to_owned());
+            let session = tokio::spawn(async move {
+                let metrics = ContainerMetrics::new(Default::default(), "default", "test", "test");
+                stream(&watcher, &path, sender, ContainerLogFormat::Plain, &metrics).await
+            });
+            Follower {
+                body,
+                received: String::new(),
+                session,
+            }
+        }
+
+        /// Reads until everything received ends with `expected`.
+        async fn read_until(&mut self, expected: &str) {
+            let body = &mut self.body;
+            let received = &mut self.received;
+            let read = tokio::time::timeout(TIMEOUT, async {
+                while !received.ends_with(expected) {
+                    let chunk = body.next().await.expect("the session ended").unwrap();
+                    received.push_str(std::str::from_utf8(&chunk).unwrap());
+                }
+            })
+            .await;
+            assert!(
+                read.is_ok(),
+                "{:?} never arrived in {:?}",
+                expected,
+                self.received
+            );
+        }
+
+        /// Reads what is left once the session ends, and returns everything
+        /// received.
+        async fn read_to_end(mut self) -> String {
+            let rest = tokio::time::timeout(TIMEOUT, warp::hyper::body::to_bytes(self.body))
+                .await
+                .expect("the session did not end")
+                .unwrap();
+            self.received.push_str(std::str::from_utf8(&rest).unwrap());
+            self.session.await.unwrap().unwrap();
+            self.received
+        }
+    }
+
+    fn append(path: &Path, text: &str) {
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    /// A watcher of a fresh log directory, and the log of a container's first
+    /// instance in it.
+    fn log() -> (tempfile::TempDir, Arc<LogWatcher>, PathBuf) {
+        let root = tempfile::tempdir().unwrap();
+        let watcher = Arc::new(LogWatcher::new(root.path()).unwrap());
+        let dir = log_files::container_log_dir(root.path(), "default", "test", "test");
+        let path = log_files::create_instance_log(&dir, 5).unwrap();
+        (root, watcher, path)
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn every_follower_receives_everything_even_one_that_lags() {
+        let (_root, watcher, path) = log();
+        let mut first = Follower::start(&watcher, &path);
+        let mut second = Follower::start(&watcher, &path);
+        // Not read until the container has finished writing, so its session
+        // is held up sending
+        let lagging = Follower::start(&watcher, &path);
+
+        let mut written = String::new();
+        for i in 0..100 {
+            let line = format!("line {}\n", i);
+            append(&path, &line);
+            written.push_str(&line);
+            first.read_until(&line).await;
+            second.read_until(&line).await;
+        }
+        assert_eq!(first.received, written);
+        assert_eq!(second.received, written);
+
+        // Starting the next instance ends every session once it has sent the
+        // rest of this one's log
+        log_files::create_instance_log(path.parent().unwrap(), 5).unwrap();
+        assert_eq!(lagging.read_to_end().await, written);
+        assert_eq!(first.read_to_end().await, written);
+        assert_eq!(second.read_to_end().await, written);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn followers_of_a_replaced_instance_get_the_rest_of_its_log_and_no_more() {
+        let (_root, watcher, old) = log();
+        let dir = old.parent().unwrap().to_owned();
+        let mut first = Follower::start(&watcher, &old);
+        let mut second = Follower::start(&watcher, &old);
+        let lagging = Follower::start(&watcher, &old);
+        append(&old, "old 1\n");
+        first.read_until("old 1\n").await;
+        second.read_until("old 1\n").await;
+
+        // Written after the last read, so it is only sent while the
+        // sessions find out about the new instance
+        append(&old, "old 2\n");
+        let new = log_files::create_instance_log(&dir, 5).unwrap();
+        append(&new, "new 1\n");
+        for follower in vec![first, second, lagging] {
+            assert_eq!(follower.read_to_end().await, "old 1\nold 2\n");
+        }
+
+        // The new instance's output is followed from its own log
+        let mut next = Follower::start(&watcher, &new);
+        next.read_until("new 1\n").await;
+        append(&new, "new 2\n");
+        next.read_until("new 2\n").await;
+        log_files::create_instance_log(&dir, 5).unwrap();
+        assert_eq!(next.read_to_end().await, "new 1\nnew 2\n");
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn a_follower_that_disconnects_leaves_the_others_following() {
+        let (_root, watcher, path) = log();
+        let mut staying = Follower::start(&watcher, &path);
+        let mut leaving = Follower::start(&watcher, &path);
+
+        append(&path, "before\n");
+        staying.read_until("before\n").await;
+        leaving.read_until("before\n").await;
+
+        drop(leaving.body);
+        append(&path, "after\n");
+        staying.read_until("after\n").await;
+        // The session ends the next time it has something to send
+        tokio::time::timeout(TIMEOUT, leaving.session)
+            .await
+            .expect("the session did not end")
+            .unwrap()
+            .unwrap();
+
+        append(&path, "last\n");
+        staying.read_until("last\n").await;
+        assert_eq!(staying.received, "before\nafter\nlast\n");
+    }
+}
@@ -0,0 +1,149 @@
+//! Provider log output. Logs are written through env_logger, either as its
+//! usual text lines or, for log pipelines such as Fluent Bit or Loki, as one
+//! JSON object per line. In JSON mode, lines about a pod carry its name and
+//! namespace, and where known the container and the state the pod is in, as
+//! separate fields.
+
+use std::cell::RefCell;
+use std::io::Write;
+
+use kubelet::pod::Pod;
+
+/// Environment variable selecting the log format, `text` or `json`.
+pub const LOG_FORMAT_ENV: &str = "WASM3_LOG_FORMAT";
+
+/// How provider logs are written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// env_logger's text lines
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::anyhow!(
+                "invalid log format {:?}, expected text or json",
+                s
+            )),
+        }
+    }
+}
+
+impl LogFormat {
+    /// Reads the format from [`LOG_FORMAT_ENV`], defaulting to text.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(LOG_FORMAT_ENV) {
+            Ok(format) if !format.trim().is_empty() => format.parse(),
+            _ => Ok(LogFormat::default()),
+        }
+    }
+}
+
+/// Installs the process logger. Levels are still read from `RUST_LOG`. Call
+/// this once, before the provider is created.
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+    }
+    builder.init();
+}
+
+fn json_line(record: &log::Record<'_>) -> serde_json::Value {
+    let mut line = serde_json::json!({
+        "time": chrono::Utc::now().to_rfc3339(),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    FIELDS.with(|fields| {
+        if let Some(fields) = &*fields.borrow() {
+            fields.add_to(&mut line);
+        }
+    });
+    line
+}
+
+/// What a log line is about.
+#[derive(Clone, Debug)]
+pub(crate) struct Fields {
+    pod: String,
+    namespace: String,
+    container: Option<String>,
+    phase: Option<&'static str>,
+}
+
+impl Fields {
+    pub(crate) fn new(pod: &str, namespace: &str) -> Self {
+        Fields {
+            pod: pod.to_owned(),
+            namespace: namespace.to_owned(),
+            container: None,
+            phase: None,
+        }
+    }
+
+    pub(crate) fn pod(pod: &Pod) -> Self {
+        Fields::new(pod.name(), pod.namespace())
+    }
+
+    pub(crate) fn container(mut self, container: &str) -> Self {
+        self.container = Some(container.to_owned());
+        self
+    }
+
+    /// The state the pod is in, such as `ImagePull` or `Running`.
+    pub(crate) fn phase(mut self, phase: &'static str) -> Self {
+        self.phase = Some(phase);
+        self
+    }
+
+    fn add_to(&self, line: &mut serde_json::Value) {
+        line["pod"] = self.pod.clone().into();
+        if !self.namespace.is_empty() {
+            line["namespace"] = self.namespace.clone().into();
+        }
+        if let Some(container) = &self.container {
+            line["container"] = container.clone().into();
+        }
+        if let Some(phase) = self.phase {
+            line["phase"] = phase.into();
+        }
+    }
+}
+
+thread_local! {
+    static FIELDS: RefCell<Option<Fields>> = RefCell::new(None);
+}
+
+/// Puts back the fields that were set before [`with_fields`], even if the
+/// function panics.
+struct Restore(Option<Fields>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        FIELDS.with(|fields| *fields.borrow_mut() = previous);
+    }
+}
+
+/// Runs `f` with `fields` attached to everything it logs on this thread. `f`
+/// must not wait on other tasks, which could log on the same thread, so async
+/// code wraps just the log call.
+pub(crate) fn with_fields<T>(fields: Fields, f: impl FnOnce() -> T) -> T {
+    let _restore = Restore(FIELDS.with(|current| current.replace(Some(fields))));
+    f()
+}
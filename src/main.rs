@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use krustlet_wasm3::admin::{self, Request};
+use krustlet_wasm3::build_info;
+use krustlet_wasm3::health::HealthChecker;
+use krustlet_wasm3::local::{self, LocalEvent, LocalRun};
+use krustlet_wasm3::logging::{self, LogFormat};
+use krustlet_wasm3::{configure_registry_network, ProviderConfig};
+use structopt::StructOpt;
+
+/// Tools for operating the krustlet wasm3 provider
+#[derive(StructOpt, Debug)]
+#[structopt(name = "wasm3-provider")]
+struct Options {
+    /// How logs are written, text or json
+    #[structopt(long, env = "WASM3_LOG_FORMAT", default_value = "text")]
+    log_format: LogFormat,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Runs the provider health checks locally and reports the results
+    Doctor {
+        /// The kubelet data directory
+        #[structopt(long, env = "KRUSTLET_DATA_DIR")]
+        data_dir: Option<PathBuf>,
+
+        /// A registry host to check reachability of, e.g. webassembly.azurecr.io
+        #[structopt(long, env = "WASM3_HEALTH_REGISTRY")]
+        registry: Option<String>,
+    },
+    /// Prints the provider's version and the wasm3 build options it was
+    /// built with
+    BuildInfo {
+        /// Print the build options as the Markdown table in the README
+        #[structopt(long)]
+        markdown: bool,
+    },
+    /// Manages a running provider through its admin socket
+    Ctl {
+        /// The provider's admin socket
+        #[structopt(long, env = "WASM3_ADMIN_SOCKET")]
+        socket: PathBuf,
+
+        #[structopt(subcommand)]
+        command: CtlCommand,
+    },
+    /// Runs a module locally, without a cluster, using the provider's module
+    /// store, validation and runtime
+    Run {
+        /// An image reference, or the path of a .wasm file
+        module: String,
+
+        /// An environment variable for the module, as NAME=VALUE
+        #[structopt(long = "env", short = "e", number_of_values = 1)]
+        env: Vec<String>,
+
+        /// An argument for the module
+        #[structopt(long = "arg", number_of_values = 1)]
+        args: Vec<String>,
+
+        /// The kubelet data directory, where pulled modules are cached
+        #[structopt(long, env = "KRUSTLET_DATA_DIR")]
+        data_dir: Option<PathBuf>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum CtlCommand {
+    /// Lists the pods the provider knows about
+    Pods,
+    /// Dumps the state of each running container's instance
+    Handles {
+        /// Only this pod, as NAMESPACE/NAME
+        pod: Option<String>,
+    },
+    /// Reconciles the provider's pods with the API server now
+    Reconcile,
+    /// Starts a new log file for each running container
+    RotateLogs {
+        /// Only this pod, as NAMESPACE/NAME
+        pod: Option<String>,
+    },
+    /// Lists the lifecycle steps recorded for a pod, including a deleted one
+    /// whose logs are retained
+    Events {
+        /// The pod, as NAMESPACE/NAME
+        pod: String,
+    },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let options = Options::from_args();
+    logging::init(options.log_format);
+
+    match options.command {
+        Command::Doctor { data_dir, registry } => doctor(data_dir, registry).await,
+        Command::Ctl { socket, command } => ctl(socket, command).await,
+        Command::BuildInfo { markdown } => {
+            print_build_info(markdown);
+            Ok(())
+        }
+        Command::Run {
+            module,
+            env,
+            args,
+            data_dir,
+        } => run(module, env, args, data_dir).await,
+    }
+}
+
+async fn doctor(data_dir: Option<PathBuf>, registry: Option<String>) -> anyhow::Result<()> {
+    let data_dir = match data_dir {
+        Some(d) => d,
+        None => default_data_dir()?,
+    };
+    let kubeconfig = kube::Config::infer().await?;
+    let checker = HealthChecker::new(kubeconfig, data_dir, registry);
+
+    let results = checker.readiness().await;
+    for result in results.iter() {
+        println!("{}", result);
+    }
+    if results.iter().all(|r| r.is_ok()) {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("one or more checks failed"))
+    }
+}
+
+async fn ctl(socket: PathBuf, command: CtlCommand) -> anyhow::Result<()> {
+    let request = match command {
+        CtlCommand::Pods => Request::Pods,
+        CtlCommand::Handles { pod } => Request::Handles { pod },
+        CtlCommand::Reconcile => Request::Reconcile,
+        CtlCommand::RotateLogs { pod } => Request::RotateLogs { pod },
+        CtlCommand::Events { pod } => Request::Events { pod },
+    };
+    let result = admin::call(&socket, &request).await?;
+    match (&request, &result) {
+        (Request::Pods, serde_json::Value::Array(pods)) => {
+            println!("{:<50} {:<8} STATIC", "POD", "RUNNING");
+            for pod in pods {
+                println!(
+                    "{:<50} {:<8} {}",
+                    pod["key"].as_str().unwrap_or_default(),
+                    pod["running"].as_bool().unwrap_or_default(),
+                    pod["static"].as_bool().unwrap_or_default()
+                );
+            }
+        }
+        (Request::Handles { .. }, serde_json::Value::Object(pods)) => {
+            for (pod, containers) in pods {
+                println!("pod: {}", pod);
+                for lines in containers.as_object().into_iter().flat_map(|c| c.values()) {
+                    for line in lines.as_array().into_iter().flatten() {
+                        println!("  {}", line.as_str().unwrap_or_default());
+                    }
+                    println!();
+                }
+            }
+        }
+        (Request::Events { .. }, serde_json::Value::Array(records)) => {
+            println!("{:<30} {:<20} {:<8} DETAIL", "TIME", "CONTAINER", "STEP");
+            for record in records {
+                println!(
+                    "{:<30} {:<20} {:<8} {}",
+                    record["time"].as_str().unwrap_or_default(),
+                    record["container"].as_str().unwrap_or_default(),
+                    record["step"].as_str().unwrap_or_default(),
+                    record["detail"].as_str().unwrap_or_default()
+                );
+            }
+        }
+        (_, serde_json::Value::Null) => println!("done"),
+        (_, result) => println!("{}", serde_json::to_string_pretty(result)?),
+    }
+    Ok(())
+}
+
+fn print_build_info(markdown: bool) {
+    if markdown {
+        println!("| Option | Default | Description |");
+        println!("| --- | --- | --- |");
+        for option in build_info::BUILD_OPTIONS {
+            println!(
+                "| `{}` | `{}` | {} |",
+                option.name, option.default, option.description
+            );
+        }
+        return;
+    }
+    println!("provider version: {}", build_info::PROVIDER_VERSION);
+    println!("wasm3 version:    {}", build_info::WASM3_VERSION);
+    println!("wasi version:     {}", build_info::WASI_VERSION);
+    println!("target:           {}", build_info::BUILD_TARGET);
+    println!();
+    for (option, value, set) in build_info::build_options() {
+        let source = if set { "set" } else { "default" };
+        println!("{:<28} {:<8} ({})", option.name, value, source);
+    }
+}
+
+async fn run(
+    module: String,
+    env: Vec<String>,
+    args: Vec<String>,
+    data_dir: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let data_dir = match data_dir {
+        Some(d) => d,
+        None => default_data_dir()?,
+    };
+    let env = env
+        .iter()
+        .map(|pair| match pair.find('=') {
+            Some(i) => Ok((pair[..i].to_owned(), pair[i + 1..].to_owned())),
+            None => Err(anyhow::anyhow!(
+                "invalid --env {:?}, expected NAME=VALUE",
+                pair
+            )),
+        })
+        .collect::<anyhow::Result<HashMap<_, _>>>()?;
+    let config = ProviderConfig::from_env()?;
+    configure_registry_network(&config)?;
+    let options = LocalRun {
+        module,
+        env,
+        args,
+        data_dir,
+        config,
+    };
+    // Status goes to stderr so the module's output can be piped on its own
+    local::run(options, |event| match event {
+        LocalEvent::Status(status) => eprintln!("==> {}", status),
+        LocalEvent::Output(output) => print!("{}", output),
+    })
+    .await
+}
+
+/// The kubelet's default data directory, `$HOME/.krustlet`.
+fn default_data_dir() -> anyhow::Result<PathBuf> {
+    let home = std::env::var("HOME")
+        .map_err(|_| anyhow::anyhow!("unable to find home directory, pass --data-dir"))?;
+    Ok(PathBuf::from(home).join(".krustlet"))
+}
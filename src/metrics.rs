@@ -0,0 +1,153 @@
+//! A minimal metrics registry rendered in the Prometheus text format.
+//!
+//! Metrics are updated from both async tasks and the blocking threads wasm3
+//! runs on, so the registry uses a std mutex and never holds it across an
+//! await.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        }
+    }
+}
+
+type Labels = Vec<(String, String)>;
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    samples: BTreeMap<Labels, f64>,
+}
+
+/// Metrics exposed by the provider.
+#[derive(Default)]
+pub struct Metrics {
+    families: Mutex<BTreeMap<String, Family>>,
+}
+
+impl Metrics {
+    fn update(
+        &self,
+        name: &str,
+        help: &'static str,
+        kind: Kind,
+        labels: &[(&str, &str)],
+        f: impl FnOnce(&mut f64),
+    ) {
+        let labels: Labels = labels
+            .iter()
+            .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+            .collect();
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(name.to_owned()).or_insert_with(|| Family {
+            help,
+            kind,
+            samples: BTreeMap::new(),
+        });
+        f(family.samples.entry(labels).or_insert(0.0));
+    }
+
+    /// Increments a counter by `by`.
+    pub fn inc_counter(&self, name: &str, help: &'static str, labels: &[(&str, &str)], by: f64) {
+        self.update(name, help, Kind::Counter, labels, |v| *v += by)
+    }
+
+    /// Sets a gauge to `value`.
+    pub fn set_gauge(&self, name: &str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+        self.update(name, help, Kind::Gauge, labels, |v| *v = value)
+    }
+
+    /// Removes every sample labelled with the given pod, once it is gone.
+    pub fn remove_pod(&self, namespace: &str, pod: &str) {
+        let has = |labels: &Labels, key: &str, value: &str| {
+            labels.iter().any(|(k, v)| k == key && v == value)
+        };
+        let mut families = self.families.lock().unwrap();
+        for family in families.values_mut() {
+            family.samples.retain(|labels, _| {
+                !(has(labels, "namespace", namespace) && has(labels, "pod", pod))
+            });
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, family.kind.as_str());
+            for (labels, value) in family.samples.iter() {
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{} {}", name, value);
+                } else {
+                    let labels = labels
+                        .iter()
+                        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+                }
+            }
+        }
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Metrics handle scoped to a single container, labelled with its namespace,
+/// pod and container name.
+#[derive(Clone)]
+pub(crate) struct ContainerMetrics {
+    metrics: Arc<Metrics>,
+    namespace: String,
+    pod: String,
+    container: String,
+}
+
+impl ContainerMetrics {
+    pub(crate) fn new(metrics: Arc<Metrics>, namespace: &str, pod: &str, container: &str) -> Self {
+        ContainerMetrics {
+            metrics,
+            namespace: namespace.to_owned(),
+            pod: pod.to_owned(),
+            container: container.to_owned(),
+        }
+    }
+
+    fn labels(&self) -> [(&str, &str); 3] {
+        [
+            ("namespace", self.namespace.as_str()),
+            ("pod", self.pod.as_str()),
+            ("container", self.container.as_str()),
+        ]
+    }
+
+    /// Increments a per container counter.
+    pub(crate) fn inc_counter(&self, name: &str, help: &'static str, by: f64) {
+        self.metrics.inc_counter(name, help, &self.labels(), by)
+    }
+
+    /// Sets a per container gauge.
+    pub(crate) fn set_gauge(&self, name: &str, help: &'static str, value: f64) {
+        self.metrics.set_gauge(name, help, &self.labels(), value)
+    }
+}
@@ -0,0 +1,84 @@
+//! The ways getting a module to run can fail. Each kind maps to one reason,
+//! which is used for the container status, the event recorded on the pod and
+//! the log line, so tooling can act on a failure without parsing messages.
+
+use std::fmt;
+
+use kubelet::container::Status;
+
+use crate::trap::{self, TrapDetails, MAX_TERMINATION_MESSAGE_LEN};
+
+/// A failure to pull, set up or run a module.
+#[derive(Debug)]
+pub(crate) enum ModuleError {
+    /// The image could not be pulled
+    Pull(String),
+    /// wasm3 could not create a runtime for the module, or parse or load it
+    Parse(String),
+    /// WASI or host functions could not be linked, or the entrypoint is
+    /// missing
+    Link(String),
+    /// The module trapped while running
+    Trap(TrapDetails),
+    /// A setup phase took longer than its timeout
+    SetupTimeout(String),
+    /// A run went over the pod's time budget
+    DeadlineExceeded(String),
+    /// A run wrote no output and called no host functions for the pod's idle
+    /// timeout
+    Idle(String),
+    /// The node could not provide something the module needed, such as its
+    /// log file
+    Host(String),
+}
+
+impl ModuleError {
+    /// A CamelCase reason for the container status and events.
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            ModuleError::Pull(_) => "ErrImagePull",
+            ModuleError::Parse(_) => "ParseError",
+            ModuleError::Link(_) => "LinkError",
+            ModuleError::Trap(trap) => trap.kind.reason(),
+            ModuleError::SetupTimeout(_) => "CreateContainerError",
+            ModuleError::DeadlineExceeded(_) => "DeadlineExceeded",
+            ModuleError::Idle(_) => "ModuleIdle",
+            ModuleError::Host(_) => "HostError",
+        }
+    }
+
+    /// The status of a container that failed with this error.
+    pub(crate) fn terminated(&self) -> Status {
+        Status::Terminated {
+            failed: true,
+            message: trap::truncate(&self.to_string(), MAX_TERMINATION_MESSAGE_LEN),
+            timestamp: chrono::Utc::now(),
+        }
+    }
+
+    /// The message for the warning event recorded against the pod.
+    /// Traps include the full wasm3 details, which the status leaves out.
+    pub(crate) fn event_message(&self, container: &str) -> String {
+        match self {
+            ModuleError::Trap(trap) => format!("Container {} {}", container, trap),
+            error => format!("Container {}: {}", container, error),
+        }
+    }
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModuleError::Trap(trap) => f.write_str(&trap.termination_message()),
+            ModuleError::Pull(message)
+            | ModuleError::Parse(message)
+            | ModuleError::Link(message)
+            | ModuleError::SetupTimeout(message)
+            | ModuleError::DeadlineExceeded(message)
+            | ModuleError::Idle(message)
+            | ModuleError::Host(message) => write!(f, "{}: {}", self.reason(), message),
+        }
+    }
+}
+
+impl std::error::Error for ModuleError {}
@@ -0,0 +1,123 @@
+//! Environment variables filled in from the node's labels and annotations,
+//! as named by `WASM3_NODE_ENV`, so that modules can adapt to where they
+//! were placed, such as reading the region from `topology.kubernetes.io/region`.
+//! They reach the module through the host's `environ_get`, see
+//! [`crate::host`].
+//!
+//! The node is read when a container starts, and what was read is reused for
+//! a short while so that a burst of pods does not read it once each.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use k8s_openapi::api::core::v1::Node;
+use kube::api::Api;
+use log::warn;
+use tokio::sync::Mutex;
+
+use crate::config::{NodeEnvVar, NodeField};
+use crate::SharedPodState;
+
+/// How long the node's metadata is reused before it is read again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The node's labels and annotations as last read.
+struct Metadata {
+    read: Instant,
+    labels: BTreeMap<String, String>,
+    annotations: BTreeMap<String, String>,
+}
+
+/// The node's metadata, shared by every pod on the node.
+#[derive(Default)]
+pub(crate) struct NodeMetadata(Mutex<Option<Metadata>>);
+
+/// The variables `WASM3_NODE_ENV` names, with the values the node has for
+/// them. A label or annotation the node does not have leaves its variable
+/// unset. If the node cannot be read, the values last read are used, and it
+/// is an error only if it has never been read.
+pub(crate) async fn node_env(shared: &SharedPodState) -> anyhow::Result<HashMap<String, String>> {
+    let vars = &shared.config.node_env;
+    if vars.is_empty() {
+        return Ok(HashMap::new());
+    }
+    // Held while the node is read, so that pods starting together wait for
+    // one read
+    let mut metadata = shared.node_metadata.0.lock().await;
+    let stale = metadata
+        .as_ref()
+        .map_or(true, |m| m.read.elapsed() >= REFRESH_INTERVAL);
+    if stale {
+        let api: Api<Node> = Api::all(kube::Client::new(shared.kubeconfig.clone()));
+        match api.get(&shared.node_name).await {
+            Ok(node) => {
+                *metadata = Some(Metadata {
+                    read: Instant::now(),
+                    labels: node.metadata.labels.unwrap_or_default(),
+                    annotations: node.metadata.annotations.unwrap_or_default(),
+                })
+            }
+            Err(e) if metadata.is_some() => {
+                warn!(
+                    "Unable to read the node's labels, using those last read: {:?}",
+                    e
+                )
+            }
+            Err(e) => {
+                return Err(anyhow::anyhow!(
+                    "unable to read the node's labels for {}: {}",
+                    crate::config::NODE_ENV_ENV,
+                    e
+                ))
+            }
+        }
+    }
+    Ok(match metadata.as_ref() {
+        Some(metadata) => values(vars, metadata),
+        None => HashMap::new(),
+    })
+}
+
+fn values(vars: &[NodeEnvVar], metadata: &Metadata) -> HashMap<String, String> {
+    vars.iter()
+        .filter_map(|var| {
+            let value = match &var.field {
+                NodeField::Label(key) => metadata.labels.get(key),
+                NodeField::Annotation(key) => metadata.annotations.get(key),
+            };
+            value.map(|value| (var.name.clone(), value.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_come_from_labels_and_annotations_the_node_has() {
+        let vars: Vec<NodeEnvVar> = [
+            "NODE_REGION=label:topology.kubernetes.io/region",
+            "NODE_RACK=annotation:example.com/rack",
+            "NODE_ZONE=label:topology.kubernetes.io/zone",
+        ]
+        .iter()
+        .map(|v| v.parse().unwrap())
+        .collect();
+        let metadata = Metadata {
+            read: Instant::now(),
+            labels: vec![("topology.kubernetes.io/region", "eu-west")]
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+            annotations: vec![("example.com/rack", "r7")]
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+        };
+        let env = values(&vars, &metadata);
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["NODE_REGION"], "eu-west");
+        assert_eq!(env["NODE_RACK"], "r7");
+    }
+}
@@ -0,0 +1,415 @@
+//! Status and event writes that could not reach the API server, kept on disk
+//! until they can. Without this, a container that terminates while the API
+//! server is unreachable leaves its pod showing the phase it had before, as
+//! nothing writes its status again.
+//!
+//! A write that fails because the API server could not be reached, or was
+//! overloaded, is queued as a file under `<data dir>/wasm3-outbox/` and
+//! replayed every [`REPLAY_INTERVAL`] until it goes through, so queued writes
+//! also survive the provider restarting. Only the latest status of each
+//! container, and of each pod, is kept, and a status write that succeeds
+//! discards the one queued before it, so an old status is never replayed
+//! over a newer one. Writes for a pod that was deleted, or recreated under
+//! the same name, are dropped, as are writes queued for longer than
+//! [`MAX_AGE`].
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::{ContainerStatus, Event, Pod as KubePod};
+use kube::api::{Api, PatchParams, PostParams};
+use kubelet::pod::Pod;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::metrics::Metrics;
+use crate::states::is_not_found;
+use crate::states::running::merge_container_status;
+
+/// How often queued writes are tried again.
+const REPLAY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a write is kept before it is given up on.
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The most events kept. The oldest are dropped past it, as events matter
+/// less than statuses the longer they wait.
+const MAX_EVENTS: usize = 500;
+
+/// A write to the API server.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+enum Write {
+    /// The status of one container, merged into the pod's statuses
+    ContainerStatus {
+        namespace: String,
+        pod: String,
+        uid: String,
+        status: ContainerStatus,
+    },
+    /// A patch of the pod's status, such as its phase
+    PodStatus {
+        namespace: String,
+        pod: String,
+        uid: String,
+        patch: serde_json::Value,
+    },
+    Event {
+        event: Event,
+    },
+}
+
+/// A write as it is kept on disk.
+#[derive(Deserialize, Serialize)]
+struct Queued {
+    queued: DateTime<Utc>,
+    #[serde(flatten)]
+    write: Write,
+}
+
+/// Returns true if a write failed in a way that trying again later can fix:
+/// the API server could not be reached, or it was overloaded.
+fn is_transient(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<kube::Error>() {
+        Some(kube::Error::Api(response)) => response.code == 429 || response.code >= 500,
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Writes to the API server that are waiting for it to be reachable.
+pub(crate) struct Outbox {
+    dir: PathBuf,
+    /// The names of the queued files. Also held while files are written or
+    /// removed, so a write that was replaced while it was being sent is not
+    /// removed.
+    queued: Arc<Mutex<HashSet<String>>>,
+    /// Tells apart events queued in the same millisecond
+    next_event: AtomicU64,
+    kubeconfig: kube::Config,
+    metrics: Arc<Metrics>,
+}
+
+impl Outbox {
+    /// Opens the outbox in `dir`, picking up writes queued before the
+    /// provider last stopped. This does blocking IO.
+    pub(crate) fn open(dir: PathBuf, kubeconfig: kube::Config, metrics: Arc<Metrics>) -> Self {
+        let queued: HashSet<String> = match list(&dir) {
+            Ok(names) => names.into_iter().collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => {
+                warn!(
+                    "Unable to read queued API writes in {}: {:?}",
+                    dir.display(),
+                    e
+                );
+                HashSet::new()
+            }
+        };
+        if !queued.is_empty() {
+            info!("Found {} API writes queued before restarting", queued.len());
+        }
+        let outbox = Outbox {
+            dir,
+            queued: Arc::new(Mutex::new(queued)),
+            next_event: AtomicU64::new(0),
+            kubeconfig,
+            metrics,
+        };
+        outbox.count();
+        outbox
+    }
+
+    /// Merges the status of one of `pod`'s containers into the pod's status,
+    /// queueing it if the API server cannot be reached.
+    pub(crate) async fn container_status(
+        &self,
+        pod: &Pod,
+        status: ContainerStatus,
+    ) -> anyhow::Result<()> {
+        let uid = crate::pod_uid(pod);
+        let name = format!("status-{}-{}.json", uid, status.name);
+        let write = Write::ContainerStatus {
+            namespace: pod.namespace().to_owned(),
+            pod: pod.name().to_owned(),
+            uid,
+            status,
+        };
+        self.send(name, write).await
+    }
+
+    /// Patches `pod`'s status, queueing the patch if the API server cannot
+    /// be reached.
+    pub(crate) async fn pod_status(
+        &self,
+        pod: &Pod,
+        patch: serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let uid = crate::pod_uid(pod);
+        let name = format!("phase-{}.json", uid);
+        let write = Write::PodStatus {
+            namespace: pod.namespace().to_owned(),
+            pod: pod.name().to_owned(),
+            uid,
+            patch,
+        };
+        self.send(name, write).await
+    }
+
+    /// Queues an event whose creation failed, if it failed in a way that
+    /// trying again can fix. Returns whether it was queued.
+    pub(crate) async fn event(&self, event: Event, error: &anyhow::Error) -> bool {
+        if !is_transient(error) {
+            return false;
+        }
+        let name = format!(
+            "event-{:013}-{}.json",
+            Utc::now().timestamp_millis(),
+            self.next_event.fetch_add(1, Ordering::Relaxed)
+        );
+        match self.queue(name, Write::Event { event }).await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Unable to queue event: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Makes a status write, replacing any queued for the same container or
+    /// pod, and queues it if it fails in a way that trying again can fix.
+    async fn send(&self, name: String, write: Write) -> anyhow::Result<()> {
+        match self.deliver(&write).await {
+            Ok(()) => {
+                let queued = self.queued.lock().unwrap().contains(&name);
+                if queued {
+                    self.remove(name, None).await;
+                }
+                Ok(())
+            }
+            Err(e) if is_transient(&e) => {
+                debug!(
+                    "Queueing status write {} until the API server is reachable: {:?}",
+                    name, e
+                );
+                self.queue(name, write).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes `write` to the file `name`, replacing what was there.
+    async fn queue(&self, name: String, write: Write) -> anyhow::Result<()> {
+        let contents = serde_json::to_vec(&Queued {
+            queued: Utc::now(),
+            write,
+        })?;
+        let dir = self.dir.clone();
+        let queued = self.queued.clone();
+        let is_event = name.starts_with("event-");
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let mut queued = queued.lock().unwrap();
+            std::fs::create_dir_all(&dir)?;
+            // Written whole and renamed, so a crash never leaves half a write
+            let mut file = tempfile::NamedTempFile::new_in(&dir)?;
+            std::io::Write::write_all(&mut file, &contents)?;
+            file.persist(dir.join(&name))?;
+            queued.insert(name);
+            if is_event {
+                let mut events: Vec<String> = queued
+                    .iter()
+                    .filter(|n| n.starts_with("event-"))
+                    .cloned()
+                    .collect();
+                if events.len() > MAX_EVENTS {
+                    events.sort();
+                    for old in &events[..events.len() - MAX_EVENTS] {
+                        std::fs::remove_file(dir.join(old))?;
+                        queued.remove(old);
+                    }
+                }
+            }
+            Ok(())
+        })
+        .await??;
+        self.count();
+        Ok(())
+    }
+
+    /// Removes the queued file `name`, unless `sent` is given and the file
+    /// no longer holds it because a newer write replaced it.
+    async fn remove(&self, name: String, sent: Option<Vec<u8>>) {
+        let dir = self.dir.clone();
+        let queued = self.queued.clone();
+        let result = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+            let mut queued = queued.lock().unwrap();
+            let path = dir.join(&name);
+            if let Some(sent) = sent {
+                match std::fs::read(&path) {
+                    Ok(current) if current != sent => return Ok(()),
+                    Ok(_) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            queued.remove(&name);
+            Ok(())
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => self.count(),
+            Ok(Err(e)) => warn!("Unable to remove queued API write: {:?}", e),
+            Err(e) => warn!("Unable to remove queued API write: {:?}", e),
+        }
+    }
+
+    /// Makes a write. Status writes for a pod that is gone, or was recreated,
+    /// are dropped.
+    async fn deliver(&self, write: &Write) -> anyhow::Result<()> {
+        let client = kube::Client::new(self.kubeconfig.clone());
+        match write {
+            Write::ContainerStatus {
+                namespace,
+                pod,
+                uid,
+                status,
+            } => {
+                let api: Api<KubePod> = Api::namespaced(client, namespace);
+                merge_container_status(&api, pod, Some(uid), status.clone()).await
+            }
+            Write::PodStatus {
+                namespace,
+                pod,
+                uid,
+                patch,
+            } => {
+                let api: Api<KubePod> = Api::namespaced(client, namespace);
+                match api.get(pod).await {
+                    Ok(current) if current.metadata.uid.as_deref() == Some(uid.as_str()) => {}
+                    Ok(_) => return Ok(()),
+                    Err(e) if is_not_found(&e) => return Ok(()),
+                    Err(e) => return Err(e.into()),
+                }
+                api.patch_status(pod, &PatchParams::default(), serde_json::to_vec(patch)?)
+                    .await?;
+                Ok(())
+            }
+            Write::Event { event } => {
+                let namespace = event.metadata.namespace.clone().unwrap_or_default();
+                let api: Api<Event> = Api::namespaced(client, &namespace);
+                api.create(&PostParams::default(), event).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Tries each queued write again, oldest first, until one fails in a
+    /// way that suggests the API server is still unreachable.
+    async fn replay(&self) {
+        let mut names: Vec<String> = self.queued.lock().unwrap().iter().cloned().collect();
+        // Events are named by when they were queued, so they go in order
+        names.sort();
+        for name in names {
+            let path = self.dir.join(&name);
+            let contents = match tokio::fs::read(&path).await {
+                Ok(contents) => contents,
+                Err(e) => {
+                    warn!(
+                        "Unable to read queued API write {}: {:?}",
+                        path.display(),
+                        e
+                    );
+                    self.remove(name, None).await;
+                    continue;
+                }
+            };
+            let queued: Queued = match serde_json::from_slice(&contents) {
+                Ok(queued) => queued,
+                Err(e) => {
+                    warn!(
+                        "Dropping invalid queued API write {}: {}",
+                        path.display(),
+                        e
+                    );
+                    self.remove(name, None).await;
+                    continue;
+                }
+            };
+            let age = Utc::now().signed_duration_since(queued.queued);
+            if age.to_std().map_or(false, |age| age > MAX_AGE) {
+                warn!("Dropping API write {} queued since {}", name, queued.queued);
+                self.count_replayed("expired");
+                self.remove(name, Some(contents)).await;
+                continue;
+            }
+            match self.deliver(&queued.write).await {
+                Ok(()) => {
+                    debug!("Replayed queued API write {}", name);
+                    self.count_replayed("sent");
+                    self.remove(name, Some(contents)).await;
+                }
+                Err(e) if is_transient(&e) => {
+                    debug!(
+                        "API server still unreachable, will retry queued writes: {:?}",
+                        e
+                    );
+                    return;
+                }
+                Err(e) => {
+                    warn!("Dropping queued API write {}: {:?}", name, e);
+                    self.count_replayed("failed");
+                    self.remove(name, Some(contents)).await;
+                }
+            }
+        }
+    }
+
+    fn count(&self) {
+        let queued = self.queued.lock().unwrap().len();
+        self.metrics.set_gauge(
+            "wasm3_queued_api_writes",
+            "Status and event writes waiting for the API server to be reachable",
+            &[],
+            queued as f64,
+        );
+    }
+
+    fn count_replayed(&self, result: &str) {
+        self.metrics.inc_counter(
+            "wasm3_replayed_api_writes_total",
+            "Queued status and event writes tried again, by how it ended",
+            &[("result", result)],
+            1.0,
+        );
+    }
+}
+
+/// The names of the queued writes in `dir`. This does blocking IO.
+fn list(dir: &Path) -> std::io::Result<Vec<String>> {
+    Ok(std::fs::read_dir(dir)?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|n| n.ends_with(".json"))
+        .collect())
+}
+
+/// Replays queued writes until the process exits.
+pub(crate) async fn replay_loop(outbox: Arc<Outbox>, clock: Arc<dyn Clock>) {
+    loop {
+        clock.sleep(REPLAY_INTERVAL).await;
+        let empty = outbox.queued.lock().unwrap().is_empty();
+        if !empty {
+            outbox.replay().await;
+        }
+    }
+}
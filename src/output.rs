@@ -0,0 +1,260 @@
+//! A container's log file as modules write to it. Standard output and error,
+//! lines from the `log` host function and trap details all go through the
+//! same buffer, so they reach the log in the order they were written.
+//!
+//! Many small writes straight to the file cost a system call each, so output
+//! is buffered as the [`StdoutBuffering`] policy says. A buffer is flushed
+//! when it is full, every flush interval, so a module that goes quiet still
+//! has its output seen, and before the instance reports that it terminated.
+//!
+//! With the CRI log format, output is turned into records as it is written,
+//! and a line not yet ended when the output is flushed is written as a
+//! partial record.
+//!
+//! When the policy has a rate limit, what the module writes past it in any
+//! one second is dropped, so a module stuck printing in a loop cannot fill
+//! the node's disk. A marker line saying how much was dropped is written
+//! once the next second starts, or when the output is dropped.
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::clock::Clock;
+use crate::config::{ContainerLogFormat, StdoutBuffering, StdoutPolicy};
+use crate::cri_log::{Encoder, LogStream};
+
+/// How much output is buffered before it is written regardless of policy.
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Output written to a container's log file.
+pub(crate) struct ContainerOutput {
+    buffering: StdoutBuffering,
+    clock: Arc<dyn Clock>,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    file: std::fs::File,
+    buf: Vec<u8>,
+    /// Set for the CRI log format
+    encoder: Option<Encoder>,
+    /// Set when the policy has a rate limit
+    limit: Option<RateLimit>,
+}
+
+/// How much a module has written in the current second.
+struct RateLimit {
+    bytes_per_sec: u64,
+    window_start: Instant,
+    written: u64,
+    dropped: u64,
+    /// Whether the last byte written ended a line, so the marker starts on
+    /// one of its own
+    line_ended: bool,
+}
+
+impl RateLimit {
+    /// How much of `len` bytes written at `now` fits under the limit, and,
+    /// if a new second has started since the last write, the marker for what
+    /// the last second dropped.
+    fn allow(&mut self, now: Instant, len: usize) -> (usize, Option<String>) {
+        let mut marker = None;
+        if now.saturating_duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.written = 0;
+            marker = self.take_marker();
+        }
+        let allowed = self
+            .bytes_per_sec
+            .saturating_sub(self.written)
+            .min(len as u64);
+        self.written += allowed;
+        self.dropped += len as u64 - allowed;
+        (allowed as usize, marker)
+    }
+
+    /// The line reporting the output dropped since the last one, if any was.
+    fn take_marker(&mut self) -> Option<String> {
+        if self.dropped == 0 {
+            return None;
+        }
+        let marker = format!(
+            "{}[wasm3] dropped {} bytes of output over the limit of {} bytes per second\n",
+            if self.line_ended { "" } else { "\n" },
+            self.dropped,
+            self.bytes_per_sec
+        );
+        self.dropped = 0;
+        self.line_ended = true;
+        Some(marker)
+    }
+}
+
+impl Inner {
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.file.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+impl ContainerOutput {
+    /// Wraps `file` and, for buffered policies, starts flushing it every
+    /// flush interval on `runtime` until the output is dropped. CRI records
+    /// are timestamped with `clock`.
+    pub(crate) fn new(
+        file: std::fs::File,
+        policy: StdoutPolicy,
+        clock: Arc<dyn Clock>,
+        runtime: &tokio::runtime::Handle,
+    ) -> Arc<Self> {
+        let output = Arc::new(ContainerOutput {
+            buffering: policy.buffering,
+            inner: Mutex::new(Inner {
+                file,
+                buf: Vec::new(),
+                encoder: match policy.format {
+                    ContainerLogFormat::Plain => None,
+                    ContainerLogFormat::Cri => Some(Encoder::new(clock.clone())),
+                },
+                limit: policy.rate_limit.map(|bytes_per_sec| RateLimit {
+                    bytes_per_sec,
+                    window_start: clock.instant(),
+                    written: 0,
+                    dropped: 0,
+                    line_ended: true,
+                }),
+            }),
+            clock,
+        });
+        // Partial lines are held back in the CRI format, so they need
+        // flushing even without a buffer
+        if policy.buffering != StdoutBuffering::Unbuffered
+            || policy.format == ContainerLogFormat::Cri
+        {
+            runtime.spawn(flush_loop(Arc::downgrade(&output), policy));
+        }
+        output
+    }
+
+    /// Writes `data`, which the module wrote to `stream`, dropping what is
+    /// over the policy's rate limit.
+    pub(crate) fn write_limited(&self, stream: LogStream, data: &[u8]) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let limit = match inner.limit.as_mut() {
+            Some(limit) => limit,
+            None => return self.write_locked(&mut inner, stream, data),
+        };
+        let (allowed, marker) = limit.allow(self.clock.instant(), data.len());
+        if allowed > 0 {
+            limit.line_ended = data[allowed - 1] == b'\n';
+        }
+        // What the last second dropped is reported before anything else
+        if let Some(marker) = marker {
+            self.write_locked(&mut inner, LogStream::Stderr, marker.as_bytes())?;
+        }
+        self.write_locked(&mut inner, stream, &data[..allowed])
+    }
+
+    /// Writes `data`, which the module wrote to `stream`, buffering it as the
+    /// policy says. Output from the provider, such as trap details, is never
+    /// limited.
+    pub(crate) fn write(&self, stream: LogStream, data: &[u8]) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        self.write_locked(&mut inner, stream, data)
+    }
+
+    fn write_locked(&self, inner: &mut Inner, stream: LogStream, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let encoded;
+        let data = match inner.encoder.as_mut() {
+            Some(encoder) => {
+                encoded = encoder.encode(stream, data);
+                &encoded[..]
+            }
+            None => data,
+        };
+        match self.buffering {
+            StdoutBuffering::Unbuffered => inner.file.write_all(data),
+            StdoutBuffering::Line => {
+                inner.buf.extend_from_slice(data);
+                if inner.buf.len() >= BLOCK_SIZE {
+                    return inner.flush();
+                }
+                // Everything up to the last complete line is written
+                if let Some(end) = inner.buf.iter().rposition(|b| *b == b'\n') {
+                    let Inner { file, buf, .. } = &mut *inner;
+                    file.write_all(&buf[..=end])?;
+                    buf.drain(..=end);
+                }
+                Ok(())
+            }
+            StdoutBuffering::Block => {
+                inner.buf.extend_from_slice(data);
+                if inner.buf.len() >= BLOCK_SIZE {
+                    inner.flush()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes out anything buffered to the current file and writes to `file`
+    /// from then on, to rotate the log of a running instance. This does
+    /// blocking IO.
+    pub(crate) fn rotate(&self, file: std::fs::File) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(partial) = inner.encoder.as_mut().map(Encoder::flush_partial) {
+            inner.buf.extend_from_slice(&partial);
+        }
+        inner.flush()?;
+        inner.file = file;
+        Ok(())
+    }
+
+    /// Writes out anything buffered, including unfinished lines.
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(partial) = inner.encoder.as_mut().map(Encoder::flush_partial) {
+            inner.buf.extend_from_slice(&partial);
+        }
+        inner.flush()
+    }
+}
+
+impl Drop for ContainerOutput {
+    fn drop(&mut self) {
+        let marker = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.limit.as_mut().and_then(RateLimit::take_marker)
+        };
+        if let Some(marker) = marker {
+            if let Err(e) = self.write(LogStream::Stderr, marker.as_bytes()) {
+                warn!("Unable to write container output: {:?}", e);
+            }
+        }
+        if let Err(e) = self.flush() {
+            warn!("Unable to flush container output: {:?}", e);
+        }
+    }
+}
+
+async fn flush_loop(output: Weak<ContainerOutput>, policy: StdoutPolicy) {
+    loop {
+        tokio::time::delay_for(policy.flush_interval).await;
+        let output = match output.upgrade() {
+            Some(output) => output,
+            None => return,
+        };
+        if let Err(e) = output.flush() {
+            warn!("Unable to flush container output: {:?}", e);
+        }
+    }
+}
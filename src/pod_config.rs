@@ -0,0 +1,209 @@
+//! ConfigMap and Secret data for the `config_get` and `config_watch` host
+//! functions. The data of the ConfigMaps and Secrets a pod mounts as volumes
+//! is kept in memory, keyed by `<volume>/<key>`, and refreshed on the
+//! kubelet's sync period. Each change bumps a version that `config_watch`
+//! waits on, and live instances that export `config_changed` have it called,
+//! so modules can follow updates without polling files.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
+use kube::api::Api;
+use kubelet::pod::Pod;
+use log::{debug, info, warn};
+
+use crate::SharedPodState;
+
+/// How often the data is read again, the kubelet's default sync period.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The export called on live instances when the data changes.
+pub(crate) const CONFIG_CHANGED: &str = "config_changed";
+
+/// Where a pod's config data comes from: volume name and object name.
+enum Source {
+    ConfigMap(String, String),
+    Secret(String, String),
+}
+
+/// The values and how many times they have changed.
+#[derive(Default)]
+struct Values {
+    version: u64,
+    values: HashMap<String, Vec<u8>>,
+}
+
+/// The config data of one pod.
+#[derive(Default)]
+pub(crate) struct PodConfig {
+    values: Mutex<Values>,
+    changed: Condvar,
+    /// Set once the refresh loop has been started
+    watching: AtomicBool,
+}
+
+impl PodConfig {
+    /// Runs `f` on the value of `key`, if there is one.
+    pub(crate) fn with_value<T>(&self, key: &str, f: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        self.values.lock().unwrap().values.get(key).map(|v| f(v))
+    }
+
+    /// Waits up to `timeout` for the version to move on from `seen`, and
+    /// returns the version.
+    pub(crate) fn wait(&self, seen: u64, timeout: Duration) -> u64 {
+        let values = self.values.lock().unwrap();
+        let (values, _) = self
+            .changed
+            .wait_timeout_while(values, timeout, |v| v.version == seen)
+            .unwrap();
+        values.version
+    }
+
+    /// Replaces the values, returning true if they changed.
+    fn replace(&self, values: HashMap<String, Vec<u8>>) -> bool {
+        let mut current = self.values.lock().unwrap();
+        if current.values == values {
+            return false;
+        }
+        current.values = values;
+        current.version += 1;
+        self.changed.notify_all();
+        true
+    }
+}
+
+/// The ConfigMaps and Secrets the pod mounts as volumes.
+fn sources(pod: &Pod) -> Vec<Source> {
+    let volumes = pod
+        .as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|s| s.volumes.as_ref());
+    volumes
+        .into_iter()
+        .flatten()
+        .filter_map(|v| {
+            if let Some(name) = v.config_map.as_ref().and_then(|c| c.name.clone()) {
+                Some(Source::ConfigMap(v.name.clone(), name))
+            } else if let Some(name) = v.secret.as_ref().and_then(|s| s.secret_name.clone()) {
+                Some(Source::Secret(v.name.clone(), name))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Reads the current data of every source. A source that does not exist is
+/// left out, as the kubelet has already checked it is optional.
+async fn load(
+    client: &kube::Client,
+    pod: &Pod,
+    sources: &[Source],
+) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+    let mut values = HashMap::new();
+    for source in sources {
+        match source {
+            Source::ConfigMap(volume, name) => {
+                let api: Api<ConfigMap> = Api::namespaced(client.clone(), pod.namespace());
+                let config_map = match api.get(name).await {
+                    Err(e) if crate::states::is_not_found(&e) => continue,
+                    result => result?,
+                };
+                for (key, value) in config_map.data.unwrap_or_default() {
+                    values.insert(format!("{}/{}", volume, key), value.into_bytes());
+                }
+                for (key, value) in config_map.binary_data.unwrap_or_default() {
+                    values.insert(format!("{}/{}", volume, key), value.0);
+                }
+            }
+            Source::Secret(volume, name) => {
+                let api: Api<Secret> = Api::namespaced(client.clone(), pod.namespace());
+                let secret = match api.get(name).await {
+                    Err(e) if crate::states::is_not_found(&e) => continue,
+                    result => result?,
+                };
+                for (key, value) in secret.data.unwrap_or_default() {
+                    values.insert(format!("{}/{}", volume, key), value.0);
+                }
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// Loads the pod's config data and, the first time, starts refreshing it.
+/// Does nothing for pods that mount no ConfigMaps or Secrets.
+pub(crate) async fn start(
+    shared: &SharedPodState,
+    key: &str,
+    pod: &Pod,
+    config: &Arc<PodConfig>,
+) -> anyhow::Result<()> {
+    let sources = sources(pod);
+    if sources.is_empty() {
+        return Ok(());
+    }
+    let client = kube::Client::new(shared.kubeconfig.clone());
+    config.replace(load(&client, pod, &sources).await?);
+    if !config.watching.swap(true, Ordering::SeqCst) {
+        tokio::spawn(refresh_loop(
+            shared.clone(),
+            key.to_owned(),
+            pod.clone(),
+            sources,
+            Arc::downgrade(config),
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the data again every [`REFRESH_INTERVAL`] until the pod state is
+/// dropped, telling live instances when it changes.
+async fn refresh_loop(
+    shared: SharedPodState,
+    key: String,
+    pod: Pod,
+    sources: Vec<Source>,
+    config: Weak<PodConfig>,
+) {
+    let client = kube::Client::new(shared.kubeconfig.clone());
+    loop {
+        shared.clock.sleep(REFRESH_INTERVAL).await;
+        let config = match config.upgrade() {
+            Some(config) => config,
+            None => return,
+        };
+        let values = match load(&client, &pod, &sources).await {
+            Ok(values) => values,
+            Err(e) => {
+                warn!("Unable to refresh config data of pod {}: {:?}", key, e);
+                continue;
+            }
+        };
+        if !config.replace(values) {
+            continue;
+        }
+        info!("Config data of pod {} changed", key);
+        let queues: Vec<_> = match shared.work_queues.read().await.get(&key) {
+            Some(queues) => queues.iter().map(|(n, q)| (n.clone(), q.clone())).collect(),
+            None => continue,
+        };
+        // A call waits for the work queued ahead of it, so one busy
+        // instance must not hold up the others or the next refresh
+        for (container, queue) in queues {
+            let key = key.clone();
+            tokio::spawn(async move {
+                if let Err(e) = queue.call(CONFIG_CHANGED).await {
+                    debug!(
+                        "Did not call {} on container {} of pod {}: {}",
+                        CONFIG_CHANGED, container, key, e
+                    );
+                }
+            });
+        }
+    }
+}
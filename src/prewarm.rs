@@ -0,0 +1,204 @@
+//! Module pre-warming. A cluster scoped `ModulePrewarm` custom resource lists
+//! images that every wasm3 node pulls into its module store ahead of time, so
+//! pods using them start without waiting on the registry. Each node checks
+//! that the modules parse and reports how it got on under its own name in the
+//! resource's status.
+//!
+//! wasm3 modules are tied to the thread and environment they were parsed in,
+//! so only the pull and the check are done ahead of time. Modules are still
+//! parsed when a pod starts, which is quick next to a pull.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::api::{Api, ListParams, PatchParams, WatchEvent};
+use kubelet::store::PullPolicy;
+use log::{error, info, warn};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::registry::describe_pull_error;
+use crate::validation;
+use crate::SharedPodState;
+
+/// How long to wait before watching again after the watch fails.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A list of images to pull onto every wasm3 node ahead of time.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ModulePrewarm {
+    metadata: ObjectMeta,
+    spec: ModulePrewarmSpec,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct ModulePrewarmSpec {
+    #[serde(default)]
+    images: Vec<String>,
+}
+
+impl k8s_openapi::Resource for ModulePrewarm {
+    const API_VERSION: &'static str = "wasm3.krustlet.dev/v1alpha1";
+    const GROUP: &'static str = "wasm3.krustlet.dev";
+    const KIND: &'static str = "ModulePrewarm";
+    const VERSION: &'static str = "v1alpha1";
+}
+
+impl k8s_openapi::Metadata for ModulePrewarm {
+    type Ty = ObjectMeta;
+
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn metadata_mut(&mut self) -> &mut ObjectMeta {
+        &mut self.metadata
+    }
+}
+
+/// Watches `ModulePrewarm` resources and pre-warms the images they list.
+/// Runs until the process exits.
+pub(crate) async fn prewarm_loop(shared: SharedPodState) {
+    // The generation of each resource that was last pre-warmed, keyed by
+    // name, so status updates do not pull everything again
+    let mut seen = HashMap::new();
+    loop {
+        if let Err(e) = watch_prewarms(&shared, &mut seen).await {
+            error!("Unable to watch module prewarms, will retry: {:?}", e);
+        }
+        shared.clock.sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// Lists the resources and then watches them until the watch ends.
+async fn watch_prewarms(
+    shared: &SharedPodState,
+    seen: &mut HashMap<String, i64>,
+) -> anyhow::Result<()> {
+    let api: Api<ModulePrewarm> = Api::all(kube::Client::new(shared.kubeconfig.clone()));
+    let params = ListParams::default();
+    let list = api.list(&params).await?;
+    let version = list.metadata.resource_version.clone().unwrap_or_default();
+    for prewarm in list.items {
+        check(shared, &api, seen, prewarm).await;
+    }
+
+    let events = api.watch(&params, &version).await?;
+    tokio::pin!(events);
+    while let Some(event) = events.try_next().await? {
+        match event {
+            WatchEvent::Added(prewarm) | WatchEvent::Modified(prewarm) => {
+                check(shared, &api, seen, prewarm).await
+            }
+            WatchEvent::Deleted(prewarm) => {
+                seen.remove(&prewarm.metadata.name.unwrap_or_default());
+            }
+            WatchEvent::Error(e) => {
+                return Err(anyhow::anyhow!("module prewarm watch failed: {:?}", e))
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Pre-warms a resource's images if its spec changed since it was last seen,
+/// then reports the outcome for this node.
+async fn check(
+    shared: &SharedPodState,
+    api: &Api<ModulePrewarm>,
+    seen: &mut HashMap<String, i64>,
+    prewarm: ModulePrewarm,
+) {
+    let name = prewarm.metadata.name.clone().unwrap_or_default();
+    let generation = prewarm.metadata.generation.unwrap_or_default();
+    if seen.get(&name) == Some(&generation) {
+        return;
+    }
+    seen.insert(name.clone(), generation);
+
+    info!(
+        "Pre-warming {} modules for {}",
+        prewarm.spec.images.len(),
+        name
+    );
+    let mut images = Vec::new();
+    for image in &prewarm.spec.images {
+        let status = match prewarm_image(shared, image).await {
+            Ok(()) => serde_json::json!({ "image": image, "ready": true }),
+            Err(e) => {
+                warn!("Unable to pre-warm {} for {}: {:#}", image, name, e);
+                serde_json::json!({ "image": image, "ready": false, "message": format!("{:#}", e) })
+            }
+        };
+        images.push(status);
+    }
+
+    // A merge patch only touches this node's entry, so nodes reporting at
+    // the same time do not overwrite each other
+    let patch = serde_json::json!({
+        "status": {
+            "nodes": {
+                shared.node_name.as_str(): {
+                    "observedGeneration": generation,
+                    "time": chrono::Utc::now().to_rfc3339(),
+                    "images": images,
+                }
+            }
+        }
+    });
+    let result = match serde_json::to_vec(&patch) {
+        Ok(patch) => api
+            .patch_status(&name, &PatchParams::default(), patch)
+            .await
+            .map(|_| ())
+            .map_err(anyhow::Error::from),
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        error!("Unable to report pre-warm status for {}: {:?}", name, e);
+    }
+}
+
+/// Pulls an image into the module store, unless it is already there, and
+/// checks that it is a module the node could run.
+async fn prewarm_image(shared: &SharedPodState, image: &str) -> anyhow::Result<()> {
+    let reference = Reference::try_from(image)
+        .map_err(|e| anyhow::anyhow!("invalid image reference: {}", e))?;
+    let repository = format!("{}/{}", reference.registry(), reference.repository());
+    if !shared.config.allows_image(&repository) {
+        return Err(anyhow::anyhow!(
+            "images from {} are not allowed on this node",
+            repository
+        ));
+    }
+    // There is no pod to take image pull secrets from, so only the node's
+    // own credentials are used
+    let auth = shared
+        .credentials
+        .resolve(reference.registry())
+        .await?
+        .unwrap_or(RegistryAuth::Anonymous);
+    let bytes = shared
+        .store
+        .get(&reference, PullPolicy::IfNotPresent, &auth)
+        .await
+        .map_err(|e| anyhow::anyhow!("{}", describe_pull_error(&e)))?;
+    if let Some(limit) = shared.config.max_module_size {
+        if bytes.len() as u64 > limit {
+            return Err(validation::ValidationError::ModuleTooLarge {
+                size: bytes.len() as u64,
+                limit,
+            }
+            .into());
+        }
+    }
+    validation::parse_module_info(&bytes)?;
+    Ok(())
+}
@@ -0,0 +1,105 @@
+//! Works out the Kubernetes quality of service class of a pod from the CPU and
+//! memory requests and limits of its containers, following the same rules as
+//! the kubelet. The class decides which pods are evicted or preempted first.
+
+use std::collections::BTreeMap;
+
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use kubelet::pod::Pod;
+
+use crate::config;
+
+/// The resources that decide a pod's QoS class.
+const QOS_RESOURCES: &[&str] = &["cpu", "memory"];
+
+/// The Kubernetes quality of service class of a pod, in the order pods are
+/// evicted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum QosClass {
+    BestEffort,
+    Burstable,
+    Guaranteed,
+}
+
+impl QosClass {
+    /// The name Kubernetes uses for the class in `status.qosClass`.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            QosClass::BestEffort => "BestEffort",
+            QosClass::Burstable => "Burstable",
+            QosClass::Guaranteed => "Guaranteed",
+        }
+    }
+}
+
+/// Returns the QoS class of a pod. A pod is `Guaranteed` when every container
+/// has CPU and memory limits equal to its requests, `BestEffort` when no
+/// container has any CPU or memory request or limit, and `Burstable`
+/// otherwise.
+pub(crate) fn qos_class(pod: &Pod) -> QosClass {
+    let spec = match pod.as_kube_pod().spec.as_ref() {
+        Some(spec) => spec,
+        None => return QosClass::BestEffort,
+    };
+    let mut any_resources = false;
+    let mut guaranteed = true;
+    for container in spec
+        .containers
+        .iter()
+        .chain(spec.init_containers.iter().flatten())
+    {
+        let resources = container.resources.as_ref();
+        let requests = resources.and_then(|r| r.requests.as_ref());
+        let limits = resources.and_then(|r| r.limits.as_ref());
+        for resource in QOS_RESOURCES {
+            let limit = get(limits, resource);
+            // A request defaults to the limit when only the limit is set
+            let request = get(requests, resource).or(limit);
+            if request.is_some() || limit.is_some() {
+                any_resources = true;
+            }
+            match (request, limit) {
+                (Some(request), Some(limit)) if same_quantity(resource, request, limit) => (),
+                _ => guaranteed = false,
+            }
+        }
+    }
+    if !any_resources {
+        QosClass::BestEffort
+    } else if guaranteed {
+        QosClass::Guaranteed
+    } else {
+        QosClass::Burstable
+    }
+}
+
+fn get<'a>(quantities: Option<&'a BTreeMap<String, Quantity>>, resource: &str) -> Option<&'a str> {
+    quantities
+        .and_then(|q| q.get(resource))
+        .map(|q| q.0.as_str())
+}
+
+/// Compares two quantities by value, so that `1` and `1000m` CPU are the same.
+/// Quantities that cannot be parsed are compared as written.
+fn same_quantity(resource: &str, a: &str, b: &str) -> bool {
+    let value = |q: &str| {
+        if resource == "cpu" {
+            millicores(q)
+        } else {
+            config::parse_quantity(q)
+        }
+    };
+    match (value(a), value(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.trim() == b.trim(),
+    }
+}
+
+/// Parses a CPU quantity such as `500m` or `2` into thousandths of a core.
+fn millicores(value: &str) -> Option<u64> {
+    let value = value.trim();
+    match value.strip_suffix('m') {
+        Some(millis) => millis.parse().ok(),
+        None => config::parse_quantity(value)?.checked_mul(1000),
+    }
+}
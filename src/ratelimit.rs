@@ -0,0 +1,131 @@
+//! Limits on how fast the provider writes to the Kubernetes API, so a node
+//! full of crash looping pods cannot flood the API server with status updates
+//! and events. Writes draw from a node wide token bucket that refills at the
+//! configured QPS up to the configured burst. Status patches wait for a
+//! token, and each pod's are spaced at least the debounce interval apart.
+//! Events are best effort, so one that finds the bucket empty is dropped
+//! rather than held up.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::metrics::Metrics;
+
+const THROTTLED_METRIC: &str = "wasm3_api_writes_throttled_total";
+const THROTTLED_HELP: &str =
+    "Kubernetes API writes delayed, coalesced or dropped by the rate limit";
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Paces the provider's writes to the Kubernetes API.
+pub(crate) struct WriteLimiter {
+    /// Tokens added per second. Zero turns the node wide limit off.
+    qps: f64,
+    burst: f64,
+    debounce: Duration,
+    bucket: Mutex<Bucket>,
+    /// When each pod, by pod key, may next have its status patched
+    next_status: Mutex<HashMap<String, Instant>>,
+    metrics: Arc<Metrics>,
+}
+
+impl WriteLimiter {
+    pub(crate) fn new(qps: f64, burst: u32, debounce: Duration, metrics: Arc<Metrics>) -> Self {
+        let burst = f64::from(burst.max(1));
+        WriteLimiter {
+            qps,
+            burst,
+            debounce,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+            next_status: Default::default(),
+            metrics,
+        }
+    }
+
+    /// Takes a token if there is one. Otherwise returns how long until there
+    /// will be.
+    fn take(&self) -> Option<Duration> {
+        if self.qps <= 0.0 {
+            return None;
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * self.qps;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / self.qps))
+        }
+    }
+
+    /// Waits until a status patch for the pod with `pod_key` may be sent.
+    pub(crate) async fn status_write(&self, pod_key: &str) {
+        let wait = {
+            let mut next_status = self.next_status.lock().unwrap();
+            let now = Instant::now();
+            let at = match next_status.get(pod_key) {
+                Some(next) if *next > now => *next,
+                _ => now,
+            };
+            next_status.insert(pod_key.to_owned(), at + self.debounce);
+            at - now
+        };
+        let mut delayed = wait > Duration::from_secs(0);
+        if delayed {
+            tokio::time::delay_for(wait).await;
+        }
+        while let Some(wait) = self.take() {
+            delayed = true;
+            tokio::time::delay_for(wait).await;
+        }
+        if delayed {
+            debug!(
+                "Delayed status patch for pod {} by the API rate limit",
+                pod_key
+            );
+            self.count("status", "delayed");
+        }
+    }
+
+    /// Returns true if an event may be sent now. Events that may not are
+    /// dropped by the caller.
+    pub(crate) fn event_write(&self) -> bool {
+        if self.take().is_none() {
+            return true;
+        }
+        self.count("event", "dropped");
+        false
+    }
+
+    /// Records that a status patch was skipped because a newer status for
+    /// the same container arrived while it waited.
+    pub(crate) fn coalesced(&self) {
+        self.count("status", "coalesced");
+    }
+
+    /// Drops what is known about a pod that is gone.
+    pub(crate) fn forget(&self, pod_key: &str) {
+        self.next_status.lock().unwrap().remove(pod_key);
+    }
+
+    fn count(&self, write: &str, outcome: &str) {
+        self.metrics.inc_counter(
+            THROTTLED_METRIC,
+            THROTTLED_HELP,
+            &[("write", write), ("outcome", outcome)],
+            1.0,
+        );
+    }
+}
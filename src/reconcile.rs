@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, ListParams, PatchParams};
+use kubelet::pod::{key_from_pod, Pod};
+use log::{debug, error, info, warn};
+
+use crate::handles;
+use crate::SharedPodState;
+
+/// How often provider state is reconciled against the cluster.
+pub(crate) const RECONCILE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Annotation bumped on pods the provider does not know about so that the
+/// kubelet receives a fresh watch event and starts them.
+const RESYNC_ANNOTATION: &str = "wasm3.krustlet.dev/resync";
+
+/// Periodically reconciles provider state with the pods assigned to this node
+/// in case a watch event was dropped. Runs until the process exits.
+pub(crate) async fn reconcile_loop(shared: SharedPodState) {
+    loop {
+        shared.clock.sleep(RECONCILE_INTERVAL).await;
+        if let Err(e) = reconcile(&shared).await {
+            error!("Unable to reconcile pods, will retry: {:?}", e);
+        }
+    }
+}
+
+pub(crate) async fn reconcile(shared: &SharedPodState) -> anyhow::Result<()> {
+    let client: Api<KubePod> = Api::all(kube::Client::new(shared.kubeconfig.clone()));
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", shared.node_name));
+    let pods: Vec<Pod> = client
+        .list(&params)
+        .await?
+        .items
+        .into_iter()
+        .map(Pod::new)
+        .collect();
+    let cluster_keys: HashSet<String> = pods.iter().map(key_from_pod).collect();
+    debug!("Reconciling {} pods assigned to this node", pods.len());
+
+    // Handles for pods that no longer exist in the cluster will never get a
+    // delete event, so stop and drop them here. A pod added after the list
+    // was taken has a handle but is not in the list, so a handle is only
+    // collected once it has been missing on two reconciles in a row.
+    let mut orphans = shared.orphans.write().await;
+    let previous = std::mem::take(&mut *orphans);
+    for key in shared.handles.keys().await {
+        // Static pods run whether or not they have a mirror
+        if cluster_keys.contains(&key) || shared.static_pods.contains(&key) {
+            continue;
+        }
+        if !previous.contains(&key) {
+            debug!(
+                "Pod {} is not in the cluster, collecting it next time if still missing",
+                key
+            );
+            orphans.insert(key);
+            continue;
+        }
+        warn!("Garbage collecting handle for missing pod {}", key);
+        if let Some(handle) = shared.handles.remove(&key).await {
+            handles::stop(
+                handle,
+                &key,
+                handles::STOP_TIMEOUT,
+                shared.clock.as_ref(),
+                async {},
+            )
+            .await;
+        }
+    }
+    drop(orphans);
+
+    // Pods we have never seen need a new watch event to be started. A pod
+    // recreated under a known name is new too.
+    let known = shared.known_pods.read().await.clone();
+    for pod in pods
+        .iter()
+        .filter(|p| known.get(&key_from_pod(p)) != Some(&crate::pod_uid(p)))
+    {
+        if pod.deletion_timestamp().is_some()
+            || is_terminal(pod)
+            || crate::static_pods::is_mirror(pod)
+            || !shared.config.allows_namespace(pod.namespace())
+        {
+            continue;
+        }
+        info!(
+            "Pod {} in namespace {} is assigned to this node but not running, resyncing",
+            pod.name(),
+            pod.namespace()
+        );
+        let api: Api<KubePod> = Api::namespaced(
+            kube::Client::new(shared.kubeconfig.clone()),
+            pod.namespace(),
+        );
+        let patch = serde_json::json!({
+            "metadata": {
+                "annotations": {
+                    RESYNC_ANNOTATION: shared.clock.now().to_rfc3339(),
+                }
+            }
+        });
+        if let Err(e) = api
+            .patch(
+                pod.name(),
+                &PatchParams::default(),
+                serde_json::to_vec(&patch)?,
+            )
+            .await
+        {
+            error!("Unable to resync pod {}: {:?}", pod.name(), e);
+        }
+    }
+
+    let cluster_pods = pods
+        .iter()
+        .map(|p| (p.namespace().to_owned(), p.name().to_owned()))
+        .collect();
+    crate::cleanup::sweep(shared, cluster_pods).await;
+    Ok(())
+}
+
+fn is_terminal(pod: &Pod) -> bool {
+    let phase = pod
+        .as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|s| s.phase.as_deref());
+    matches!(phase, Some("Succeeded") | Some("Failed"))
+}
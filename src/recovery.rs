@@ -0,0 +1,175 @@
+//! Recovery of pods the provider was running when it stopped.
+//!
+//! Instances do not outlive the provider, so when it starts, any pod bound to
+//! this node that the API server still shows as `Running` has nothing
+//! running it. The kubelet starts such pods again from scratch. That is what
+//! `Always` and `OnFailure` pods would do after a crash anyway, so they keep
+//! counting restarts from where they were. A `Never` pod must not run twice,
+//! so it is marked `Failed` with reason `NodeRestart` instead.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use k8s_openapi::api::core::v1::{ContainerState, ContainerStateTerminated, Pod as KubePod};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::api::{Api, ListParams, PatchParams};
+use kubelet::pod::Pod;
+use log::{error, info, warn};
+
+use crate::events::EventRecorder;
+use crate::SharedPodState;
+
+/// The reason pods that could not be recovered fail with.
+pub(crate) const NODE_RESTART_REASON: &str = "NodeRestart";
+
+const NODE_RESTART_MESSAGE: &str = "The node's provider restarted while the pod was running";
+
+/// What was found about pods the provider was running before it started,
+/// keyed by pod UID, until the kubelet hands them over.
+#[derive(Default)]
+pub(crate) struct Recovery {
+    /// Restart counts of the containers of pods that are started again
+    restarts: Mutex<HashMap<String, HashMap<String, i32>>>,
+    /// Pods that were marked failed
+    failed: Mutex<HashSet<String>>,
+}
+
+impl Recovery {
+    /// The restart counts a recovered pod's containers continue from.
+    pub(crate) fn take_restart_counts(&self, uid: &str) -> HashMap<String, i32> {
+        self.restarts
+            .lock()
+            .unwrap()
+            .remove(uid)
+            .unwrap_or_default()
+    }
+
+    /// Returns true if the pod was marked failed because the provider
+    /// restarted.
+    pub(crate) fn is_failed(&self, uid: &str) -> bool {
+        self.failed.lock().unwrap().contains(uid)
+    }
+
+    /// Forgets a failed pod once the kubelet has handed it over.
+    pub(crate) fn forget_failed(&self, uid: &str) {
+        self.failed.lock().unwrap().remove(uid);
+    }
+}
+
+/// Finds the pods bound to this node that the API server shows as running
+/// and either marks them failed or records their restart counts. Runs once,
+/// before the kubelet starts any pods.
+pub(crate) async fn recover(shared: &SharedPodState) {
+    let client = kube::Client::new(shared.kubeconfig.clone());
+    let api: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", shared.node_name));
+    let pods = match api.list(&params).await {
+        Ok(pods) => pods.items,
+        Err(e) => {
+            warn!(
+                "Unable to look for pods left running, skipping recovery: {:?}",
+                e
+            );
+            return;
+        }
+    };
+    for pod in pods.into_iter().map(Pod::new) {
+        let phase = pod
+            .as_kube_pod()
+            .status
+            .as_ref()
+            .and_then(|s| s.phase.as_deref());
+        if phase != Some("Running") || pod.deletion_timestamp().is_some() {
+            continue;
+        }
+        let uid = crate::pod_uid(&pod);
+        if crate::states::restart_policy(&pod) == "Never" {
+            info!(
+                "Pod {} in namespace {} was running when the provider stopped, marking it failed",
+                pod.name(),
+                pod.namespace()
+            );
+            shared.recovery.failed.lock().unwrap().insert(uid);
+            mark_failed(shared, &client, &pod).await;
+        } else {
+            info!(
+                "Pod {} in namespace {} was running when the provider stopped, restarting it",
+                pod.name(),
+                pod.namespace()
+            );
+            let counts = container_statuses(&pod)
+                .iter()
+                .map(|s| (s.name.clone(), s.restart_count))
+                .collect();
+            shared.recovery.restarts.lock().unwrap().insert(uid, counts);
+            EventRecorder::new(client.clone(), &pod, shared)
+                .normal(
+                    NODE_RESTART_REASON,
+                    "Restarting containers after the provider restarted",
+                )
+                .await;
+        }
+    }
+}
+
+fn container_statuses(pod: &Pod) -> Vec<k8s_openapi::api::core::v1::ContainerStatus> {
+    pod.as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|s| s.container_statuses.clone())
+        .unwrap_or_default()
+}
+
+/// Marks a pod failed, with the containers that were running terminated.
+async fn mark_failed(shared: &SharedPodState, client: &kube::Client, pod: &Pod) {
+    EventRecorder::new(client.clone(), pod, shared)
+        .warning(NODE_RESTART_REASON, NODE_RESTART_MESSAGE)
+        .await;
+    let now = Time(chrono::Utc::now());
+    let statuses: Vec<_> = container_statuses(pod)
+        .into_iter()
+        .map(|mut status| {
+            let running = status.state.as_ref().and_then(|s| s.running.as_ref());
+            if let Some(started_at) = running.map(|r| r.started_at.clone()) {
+                status.state = Some(ContainerState {
+                    terminated: Some(ContainerStateTerminated {
+                        exit_code: 1,
+                        reason: Some(NODE_RESTART_REASON.to_owned()),
+                        message: Some(NODE_RESTART_MESSAGE.to_owned()),
+                        started_at,
+                        finished_at: Some(now.clone()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                });
+                status.ready = false;
+            }
+            status
+        })
+        .collect();
+    let patch = serde_json::json!({
+        "metadata": {
+            "resourceVersion": "",
+        },
+        "status": {
+            "phase": "Failed",
+            "reason": NODE_RESTART_REASON,
+            "message": NODE_RESTART_MESSAGE,
+            "containerStatuses": statuses,
+        }
+    });
+    let patch = match serde_json::to_vec(&patch) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Unable to serialize status for pod {}: {:?}", pod.name(), e);
+            return;
+        }
+    };
+    let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+    if let Err(e) = api
+        .patch_status(pod.name(), &PatchParams::default(), patch)
+        .await
+    {
+        error!("Unable to mark pod {} failed: {:?}", pod.name(), e);
+    }
+}
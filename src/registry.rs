@@ -0,0 +1,123 @@
+//! Network settings for pulling modules from OCI registries.
+//!
+//! The registry client does not expose its HTTP client, but it does honor the
+//! standard proxy variables and OpenSSL's `SSL_CERT_FILE`, so settings are
+//! applied by exporting those. [`configure_registry_network`] must therefore
+//! be called before the `oci_distribution::Client` is created.
+
+use std::convert::TryFrom;
+use std::path::Path;
+
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+
+use crate::config::ProviderConfig;
+
+const PEM_CERTIFICATE_HEADER: &str = "-----BEGIN CERTIFICATE-----";
+
+/// Applies the registry proxy and CA settings from `config` to this process.
+pub fn configure_registry_network(config: &ProviderConfig) -> anyhow::Result<()> {
+    if let Some(proxy) = &config.registry_proxy {
+        std::env::set_var("HTTPS_PROXY", proxy);
+        std::env::set_var("HTTP_PROXY", proxy);
+    }
+    if let Some(no_proxy) = &config.registry_no_proxy {
+        std::env::set_var("NO_PROXY", no_proxy);
+    }
+    if let Some(ca_file) = &config.registry_ca_file {
+        validate_ca_file(ca_file)?;
+        std::env::set_var("SSL_CERT_FILE", ca_file);
+    }
+    Ok(())
+}
+
+/// Fails early with a clear message instead of on the first pull.
+fn validate_ca_file(path: &Path) -> anyhow::Result<()> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("unable to read CA bundle {}: {}", path.display(), e))?;
+    if !contents.contains(PEM_CERTIFICATE_HEADER) {
+        return Err(anyhow::anyhow!(
+            "CA bundle {} does not contain any PEM certificates",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Describes a pull error, adding a hint for TLS and proxy failures, which
+/// are otherwise hard to act on.
+pub(crate) fn describe_pull_error(error: &anyhow::Error) -> String {
+    let message = format!("{:#}", error);
+    let lower = message.to_lowercase();
+    let hint = if lower.contains("certificate") || lower.contains("tls") || lower.contains("ssl") {
+        Some(format!(
+            "TLS verification failed; if the registry uses a private CA, set {}",
+            crate::config::REGISTRY_CA_FILE_ENV
+        ))
+    } else if lower.contains("proxy") || lower.contains("tunnel") {
+        Some(format!(
+            "the proxy rejected the connection; check {} and {}",
+            crate::config::REGISTRY_PROXY_ENV,
+            crate::config::REGISTRY_NO_PROXY_ENV
+        ))
+    } else {
+        None
+    };
+    match hint {
+        Some(hint) => format!("{} ({})", message, hint),
+        None => message,
+    }
+}
+
+/// The digest a container's image was resolved to when its module was
+/// pulled.
+#[derive(Clone, Debug)]
+pub(crate) struct PinnedImage {
+    /// The image as the container names it
+    pub(crate) image: String,
+    /// The image by digest, `registry/repository@sha256:...`, which is
+    /// reported as the container's `imageID`
+    pub(crate) image_id: String,
+}
+
+impl PinnedImage {
+    /// Pins an image that already names its digest.
+    pub(crate) fn from_digest(reference: &Reference) -> Option<Self> {
+        reference.digest().map(|digest| PinnedImage {
+            image: reference.whole().to_owned(),
+            image_id: format!(
+                "{}/{}@{}",
+                reference.registry(),
+                reference.repository(),
+                digest
+            ),
+        })
+    }
+
+    /// The reference to pull the pinned module by.
+    pub(crate) fn reference(&self) -> anyhow::Result<Reference> {
+        Reference::try_from(self.image_id.as_str())
+            .map_err(|e| anyhow::anyhow!("invalid pinned image {}: {}", self.image_id, e))
+    }
+}
+
+/// Asks the registry which digest an image's tag points at now.
+pub(crate) async fn resolve_digest(
+    reference: &Reference,
+    auth: &RegistryAuth,
+) -> anyhow::Result<PinnedImage> {
+    if let Some(pinned) = PinnedImage::from_digest(reference) {
+        return Ok(pinned);
+    }
+    let mut client = oci_distribution::Client::default();
+    let digest = client.fetch_manifest_digest(reference, auth).await?;
+    Ok(PinnedImage {
+        image: reference.whole().to_owned(),
+        image_id: format!(
+            "{}/{}@{}",
+            reference.registry(),
+            reference.repository(),
+            digest
+        ),
+    })
+}
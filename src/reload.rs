@@ -0,0 +1,141 @@
+//! Reloads the modules of a running pod in place when its reload annotation
+//! changes, so a new build pushed under the same tag can be tried without
+//! recreating the pod, or when the image of one of its app containers is
+//! changed, for example with `kubectl set image`.
+//!
+//! The kubelet does not hand pod updates to providers, so pods assigned to
+//! this node are watched here and changes are passed to the pod's state
+//! machine, which does the reload in its `Reloading` state. Deletions are
+//! recorded too, so a pod deleted while it is starting stops starting.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, ListParams, WatchEvent};
+use kubelet::pod::{key_from_pod, Pod};
+use log::{debug, error, info};
+
+use crate::SharedPodState;
+
+/// Annotation whose value is changed, for example to a timestamp, to reload
+/// the pod's modules.
+const RELOAD_ANNOTATION: &str = "wasm3.krustlet.dev/reload";
+
+/// How long to wait before watching again after the watch fails.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a pod is reloaded on changes of.
+struct Seen {
+    annotation: String,
+    /// The images of the app containers
+    images: Vec<Option<String>>,
+}
+
+impl Seen {
+    fn of(pod: &Pod) -> Self {
+        Seen {
+            annotation: pod
+                .annotations()
+                .get(RELOAD_ANNOTATION)
+                .cloned()
+                .unwrap_or_default(),
+            images: pod
+                .containers()
+                .iter()
+                .map(|c| c.image().ok().flatten().map(|i| i.whole().to_owned()))
+                .collect(),
+        }
+    }
+
+    /// Returns true if the pod should be reloaded, having been seen as
+    /// `previous`.
+    fn reloads(&self, previous: &Seen) -> bool {
+        (self.annotation != previous.annotation && !self.annotation.is_empty())
+            || self.images != previous.images
+    }
+}
+
+/// Watches pods on this node for reload requests. Runs until the process
+/// exits.
+pub(crate) async fn reload_loop(shared: SharedPodState) {
+    // What was last seen of each pod, keyed by pod key
+    let mut seen = HashMap::new();
+    loop {
+        if let Err(e) = watch_reloads(&shared, &mut seen).await {
+            error!("Unable to watch pods for reloads, will retry: {:?}", e);
+        }
+        shared.clock.sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// Lists the pods on this node and then watches them until the watch ends.
+async fn watch_reloads(
+    shared: &SharedPodState,
+    seen: &mut HashMap<String, Seen>,
+) -> anyhow::Result<()> {
+    let api: Api<KubePod> = Api::all(kube::Client::new(shared.kubeconfig.clone()));
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", shared.node_name));
+    let list = api.list(&params).await?;
+    let version = list.metadata.resource_version.clone().unwrap_or_default();
+    let pods: Vec<Pod> = list.items.into_iter().map(Pod::new).collect();
+    let keys: HashSet<String> = pods.iter().map(key_from_pod).collect();
+    seen.retain(|key, _| keys.contains(key));
+    for pod in pods {
+        check(shared, seen, pod).await;
+    }
+
+    let events = api.watch(&params, &version).await?;
+    tokio::pin!(events);
+    while let Some(event) = events.try_next().await? {
+        match event {
+            WatchEvent::Added(pod) | WatchEvent::Modified(pod) => {
+                check(shared, seen, Pod::new(pod)).await
+            }
+            WatchEvent::Deleted(pod) => {
+                let pod = Pod::new(pod);
+                seen.remove(&key_from_pod(&pod));
+                shared.deleted.write().await.remove(&crate::pod_uid(&pod));
+            }
+            WatchEvent::Error(e) => return Err(anyhow::anyhow!("pod watch failed: {:?}", e)),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Asks for the pod to be reloaded if its reload annotation or container
+/// images changed since it was last seen. What a pod has when it is first
+/// seen is what it started with, so it does not cause a reload.
+async fn check(shared: &SharedPodState, seen: &mut HashMap<String, Seen>, pod: Pod) {
+    let key = key_from_pod(&pod);
+    if pod.deletion_timestamp().is_some() {
+        let uid = crate::pod_uid(&pod);
+        // Only pods with pod state here are cleaned up, so don't collect the
+        // rest
+        if shared.known_pods.read().await.get(&key) == Some(&uid) {
+            shared.deleted.write().await.insert(uid);
+        }
+    }
+    let current = Seen::of(&pod);
+    let reloads = seen
+        .get(&key)
+        .map_or(false, |previous| current.reloads(previous));
+    seen.insert(key.clone(), current);
+    if !reloads {
+        return;
+    }
+    if pod.deletion_timestamp().is_some() {
+        return;
+    }
+    match shared.reloads.read().await.get(&key) {
+        Some(reload) => {
+            info!("Reload of pod {} requested", key);
+            if reload.send(pod).is_err() {
+                debug!("Pod {} went away before it could be reloaded", key);
+            }
+        }
+        None => debug!("Ignoring reload of pod {} that is not running here", key),
+    }
+}
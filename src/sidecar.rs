@@ -0,0 +1,29 @@
+//! A companion module, such as a log shipper or metrics agent, that the node
+//! runs alongside every pod. It is pulled, validated and started with the
+//! pod's own containers, logs like them and shares the pod's key-value store,
+//! but it is not part of the pod spec, so its exit never changes the pod's
+//! status.
+
+use k8s_openapi::api::core::v1::Container as KubeContainer;
+use kubelet::container::Container;
+
+use crate::config::ProviderConfig;
+
+/// The container name the sidecar runs under, for example for
+/// `kubectl logs -c wasm3-sidecar`.
+pub(crate) const SIDECAR_CONTAINER_NAME: &str = "wasm3-sidecar";
+
+/// The sidecar container to add to every pod, if one is configured.
+pub(crate) fn container(config: &ProviderConfig) -> Option<Container> {
+    let image = config.sidecar_image.as_ref()?;
+    Some(Container::new(&KubeContainer {
+        name: SIDECAR_CONTAINER_NAME.to_owned(),
+        image: Some(image.clone()),
+        ..Default::default()
+    }))
+}
+
+/// Returns true if `name` is the sidecar's container.
+pub(crate) fn is_sidecar(name: &str) -> bool {
+    name == SIDECAR_CONTAINER_NAME
+}
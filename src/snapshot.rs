@@ -0,0 +1,159 @@
+//! Snapshots of a module's linear memory taken after `_initialize` returns, so
+//! later starts of the same module with the same configuration can restore
+//! the memory instead of running an expensive initialization again.
+//!
+//! wasm3 can only read and write linear memory, not globals, so a snapshot
+//! is only taken for modules whose globals are back where they started once
+//! `_initialize` returns. See [`can_snapshot`].
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use sha2::{Digest, Sha256};
+use wasm3::Runtime;
+
+use crate::validation::ModuleInfo;
+
+/// The export run once to initialize a module before `_start`.
+pub(crate) const INITIALIZE: &str = "_initialize";
+
+/// The most snapshots kept on a node. The oldest are removed first.
+const MAX_SNAPSHOTS: usize = 32;
+
+const SNAPSHOT_EXTENSION: &str = "mem";
+
+const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+/// Returns true if restoring linear memory alone reproduces the state
+/// `_initialize` leaves behind. That holds when the module imports no mutable
+/// globals and defines at most one, which in LLVM output is the stack
+/// pointer: calls leave it where they found it.
+pub(crate) fn can_snapshot(info: &ModuleInfo) -> bool {
+    info.imported_mutable_globals == 0 && info.defined_mutable_globals <= 1
+}
+
+/// Identifies a snapshot by the module and everything that can change what
+/// `_initialize` does.
+pub(crate) fn snapshot_key(
+    module: &[u8],
+    env: &std::collections::HashMap<String, String>,
+    args: &[String],
+    stack_size: u32,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(Sha256::digest(module));
+    // Sorted so the key does not depend on map order
+    let env: BTreeMap<_, _> = env.iter().collect();
+    for (name, value) in env {
+        hasher.update(name.as_bytes());
+        hasher.update(b"=");
+        hasher.update(value.as_bytes());
+        hasher.update(b"\0");
+    }
+    for arg in args {
+        hasher.update(arg.as_bytes());
+        hasher.update(b"\0");
+    }
+    hasher.update(stack_size.to_le_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Snapshots kept in a directory, one file per key.
+pub(crate) struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    pub(crate) fn new(dir: impl AsRef<Path>) -> Self {
+        SnapshotStore {
+            dir: dir.as_ref().to_owned(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", key, SNAPSHOT_EXTENSION))
+    }
+
+    /// Returns the snapshot saved under `key`, if there is one.
+    pub(crate) fn load(&self, key: &str) -> Option<Vec<u8>> {
+        std::fs::read(self.path(key)).ok()
+    }
+
+    /// Saves `memory` under `key`, then removes the oldest snapshots over
+    /// [`MAX_SNAPSHOTS`]. The file is written under a temporary name first so
+    /// a concurrent start never reads half a snapshot.
+    pub(crate) fn save(&self, key: &str, memory: &[u8]) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let mut temp = tempfile::NamedTempFile::new_in(&self.dir)?;
+        std::io::Write::write_all(&mut temp, memory)?;
+        temp.persist(self.path(key)).map_err(|e| e.error)?;
+        self.prune();
+        Ok(())
+    }
+
+    fn prune(&self) {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Unable to list module snapshots: {:?}", e);
+                return;
+            }
+        };
+        let mut snapshots: Vec<_> = entries
+            .filter_map(Result::ok)
+            .filter(|e| {
+                e.path()
+                    .extension()
+                    .map_or(false, |x| x == SNAPSHOT_EXTENSION)
+            })
+            .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+            .collect();
+        if snapshots.len() <= MAX_SNAPSHOTS {
+            return;
+        }
+        snapshots.sort();
+        let excess = snapshots.len() - MAX_SNAPSHOTS;
+        for (_, path) in snapshots.into_iter().take(excess) {
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!(
+                    "Unable to remove module snapshot {}: {:?}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Returns a copy of the runtime's linear memory.
+pub(crate) fn capture(rt: &Runtime) -> Vec<u8> {
+    // Safety: the runtime is not running, so nothing else uses its memory
+    unsafe { (*rt.memory()).to_vec() }
+}
+
+/// Replaces the runtime's linear memory with `snapshot`, growing it first if
+/// needed. Returns false, without copying anything, if the memory cannot be
+/// made the snapshot's size.
+pub(crate) fn restore(rt: &Runtime, snapshot: &[u8]) -> bool {
+    if snapshot.len() % WASM_PAGE_SIZE != 0 {
+        return false;
+    }
+    // Safety: the runtime is not running, so nothing else uses its memory,
+    // and the slice is fetched again after the memory is resized
+    unsafe {
+        if (*rt.memory()).len() < snapshot.len()
+            && rt
+                .resize_memory((snapshot.len() / WASM_PAGE_SIZE) as u32)
+                .is_err()
+        {
+            return false;
+        }
+        let memory = &mut *rt.memory_mut();
+        if memory.len() != snapshot.len() {
+            return false;
+        }
+        memory.copy_from_slice(snapshot);
+    }
+    true
+}
@@ -0,0 +1,145 @@
+//! Stack sizes set by pods, and raised for containers whose module ran out
+//! of stack.
+//!
+//! A pod sets the stack its modules get with the
+//! `wasm3.krustlet.dev/stack-size` annotation, either one size for every
+//! container or `container=size` entries separated by commas. With
+//! `WASM3_STACK_AUTOTUNE_MAX` set, a container that traps with a stack
+//! overflow is restarted with double the stack, up to that cap. Once the
+//! container has run with the raised size for as long as it takes the
+//! restart backoff to reset, or has completed, the size is written back to
+//! the annotation, so it can be copied into the pod's template.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, PatchParams};
+use kubelet::pod::Pod;
+
+use crate::config;
+use crate::trap::TrapKind;
+
+/// Annotation holding the stack size of the pod's modules.
+const STACK_SIZE_ANNOTATION: &str = "wasm3.krustlet.dev/stack-size";
+
+/// The smallest stack a raise gives, so the first raise from the wasm3
+/// default is a useful one.
+const MIN_RAISED_STACK_SIZE: u32 = 64 * 1024;
+
+/// The stack sizes set by the annotation: one for every container under the
+/// empty name, or one for each container named.
+fn annotated_sizes(pod: &Pod) -> anyhow::Result<BTreeMap<String, u32>> {
+    let value = match pod.annotations().get(STACK_SIZE_ANNOTATION) {
+        Some(value) => value,
+        None => return Ok(BTreeMap::new()),
+    };
+    let invalid = || {
+        anyhow::anyhow!(
+            "invalid {} annotation {:?}, expected a size or container=size entries",
+            STACK_SIZE_ANNOTATION,
+            value
+        )
+    };
+    let mut sizes = BTreeMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (container, size) = match entry.find('=') {
+            Some(i) => (entry[..i].trim(), entry[i + 1..].trim()),
+            None => ("", entry),
+        };
+        let size = config::parse_quantity(size)
+            .filter(|s| *s > 0 && *s <= u64::from(u32::MAX))
+            .ok_or_else(invalid)?;
+        sizes.insert(container.to_owned(), size as u32);
+    }
+    Ok(sizes)
+}
+
+/// Checks that the pod's stack size annotation can be parsed.
+pub(crate) fn validate(pod: &Pod) -> anyhow::Result<()> {
+    annotated_sizes(pod).map(|_| ())
+}
+
+/// The stack size the pod sets for `container`, if any.
+pub(crate) fn annotated_size(pod: &Pod, container: &str) -> Option<u32> {
+    let sizes = annotated_sizes(pod).ok()?;
+    sizes.get(container).or_else(|| sizes.get("")).copied()
+}
+
+/// Returns true if a container terminated with `message` because its module
+/// ran out of stack.
+pub(crate) fn is_stack_overflow(message: &str) -> bool {
+    message
+        .strip_prefix(TrapKind::StackOverflow.reason())
+        .map_or(false, |rest| rest.starts_with(':'))
+}
+
+/// The stack sizes raised for a pod's containers.
+#[derive(Default)]
+pub(crate) struct RaisedStacks {
+    sizes: HashMap<String, u32>,
+    /// Containers whose raised size has not been written to the pod yet
+    unrecorded: HashSet<String>,
+}
+
+impl RaisedStacks {
+    /// The size `container`'s stack was raised to, if it was.
+    pub(crate) fn get(&self, container: &str) -> Option<u32> {
+        self.sizes.get(container).copied()
+    }
+
+    /// Doubles the stack of `container` from `current`, up to `cap`.
+    /// Returns the new size, or `None` if the stack is already at the cap.
+    pub(crate) fn raise(&mut self, container: &str, current: u32, cap: u32) -> Option<u32> {
+        let raised = current
+            .saturating_mul(2)
+            .max(MIN_RAISED_STACK_SIZE)
+            .min(cap);
+        if raised <= current {
+            return None;
+        }
+        self.sizes.insert(container.to_owned(), raised);
+        self.unrecorded.insert(container.to_owned());
+        Some(raised)
+    }
+
+    /// Returns true if a raised size has yet to be written to the pod.
+    pub(crate) fn has_unrecorded(&self) -> bool {
+        !self.unrecorded.is_empty()
+    }
+
+    /// Writes the raised sizes to the pod's annotation, keeping the sizes it
+    /// sets for other containers. A write that fails is not tried again
+    /// until a stack is raised again.
+    pub(crate) async fn record(&mut self, client: &Api<KubePod>, pod: &Pod) -> anyhow::Result<()> {
+        if std::mem::take(&mut self.unrecorded).is_empty() {
+            return Ok(());
+        }
+        let mut sizes = annotated_sizes(pod).unwrap_or_default();
+        for (container, size) in &self.sizes {
+            sizes.insert(container.clone(), *size);
+        }
+        let value = sizes
+            .iter()
+            .map(|(container, size)| match container.as_str() {
+                "" => size.to_string(),
+                container => format!("{}={}", container, size),
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let patch = serde_json::json!({
+            "metadata": {
+                "annotations": {
+                    STACK_SIZE_ANNOTATION: value,
+                }
+            }
+        });
+        client
+            .patch(
+                pod.name(),
+                &PatchParams::default(),
+                serde_json::to_vec(&patch)?,
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -1,11 +1,218 @@
 pub(crate) mod completed;
 pub(crate) mod crash_loop_backoff;
 pub(crate) mod error;
+pub(crate) mod failed;
 pub(crate) mod image_pull;
 pub(crate) mod image_pull_backoff;
 pub(crate) mod initializing;
 pub(crate) mod registered;
+pub(crate) mod rejected;
+pub(crate) mod reloading;
 pub(crate) mod running;
 pub(crate) mod starting;
 pub(crate) mod terminated;
+pub(crate) mod validating;
 pub(crate) mod volume_mount;
+
+use std::time::{Duration, Instant};
+
+use kubelet::pod::Pod;
+use log::info;
+
+use crate::clock::Clock;
+use crate::config::{self, ModuleLimits, ProviderConfig, RuntimeProfile};
+use crate::{handles, sidecar, stack_tuning, PodState};
+
+/// The wait before the first restart after a failure. It doubles with each
+/// failure in a row, up to [`CRASH_BACKOFF_MAX`], as on other kubelets.
+const CRASH_BACKOFF_BASE: Duration = Duration::from_secs(10);
+
+/// The longest wait between restarts.
+const CRASH_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+/// How long a pod has to run without failing for its failure count, and with
+/// it the restart backoff, to be reset.
+pub(crate) const CRASH_BACKOFF_RESET: Duration = Duration::from_secs(600);
+
+/// The wait before restarting a pod that has failed `failures` times in a
+/// row.
+pub(crate) fn crash_backoff(failures: usize) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16) as u32;
+    (CRASH_BACKOFF_BASE * 2u32.pow(doublings)).min(CRASH_BACKOFF_MAX)
+}
+
+/// The failures in a row to count for a pod that failed after running since
+/// `started`. A pod that ran for [`CRASH_BACKOFF_RESET`] starts again from
+/// the shortest backoff.
+pub(crate) fn failures_in_a_row(failures: usize, started: Instant, clock: &dyn Clock) -> usize {
+    if clock.instant().saturating_duration_since(started) >= CRASH_BACKOFF_RESET {
+        0
+    } else {
+        failures
+    }
+}
+
+/// Bytes in a page of linear memory.
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// The pod's restart policy, defaulting to `Always` like Kubernetes does.
+pub(crate) fn restart_policy(pod: &Pod) -> &str {
+    pod.as_kube_pod()
+        .spec
+        .as_ref()
+        .and_then(|s| s.restart_policy.as_deref())
+        .unwrap_or("Always")
+}
+
+/// Annotation naming the runtime profile the pod's modules run with, such as
+/// `small`, `standard` or `compute`.
+const PROFILE_ANNOTATION: &str = "wasm3.krustlet.dev/profile";
+
+/// The runtime profile the pod picked for `container`, if any. The node's
+/// sidecar is not the pod's, so the pod's profile does not apply to it.
+pub(crate) fn runtime_profile(
+    config: &ProviderConfig,
+    pod: &Pod,
+    container: &str,
+) -> anyhow::Result<Option<RuntimeProfile>> {
+    let name = match pod.annotations().get(PROFILE_ANNOTATION) {
+        Some(name) => name.trim(),
+        None => return Ok(None),
+    };
+    match config.runtime_profiles.get(name) {
+        Some(_) if sidecar::is_sidecar(container) => Ok(None),
+        Some(profile) => Ok(Some(*profile)),
+        None => Err(anyhow::anyhow!(
+            "invalid {} annotation {:?}, expected one of {}",
+            PROFILE_ANNOTATION,
+            name,
+            config
+                .runtime_profiles
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// The limits for a container's module: the namespace defaults, overridden
+/// by the pod's runtime profile, with the container's memory limit taking
+/// priority over the memory pages, and the pod's stack size, or the stack it
+/// was raised to, over the stack size.
+pub(crate) fn module_limits(pod_state: &PodState, pod: &Pod, container: &str) -> ModuleLimits {
+    let mut limits = pod_state.shared.config.limits_for(pod.namespace());
+    // An invalid profile has already failed the pod in validation
+    if let Ok(Some(profile)) = runtime_profile(&pod_state.shared.config, pod, container) {
+        limits.stack_size = profile.stack_size.or(limits.stack_size);
+        limits.memory_pages = profile.memory_pages.or(limits.memory_pages);
+    }
+    limits.max_module_size = limits
+        .max_module_size
+        .or(pod_state.shared.config.max_module_size);
+    let memory_limit = pod
+        .as_kube_pod()
+        .spec
+        .iter()
+        .flat_map(|s| {
+            s.containers
+                .iter()
+                .chain(s.init_containers.iter().flatten())
+        })
+        .find(|c| c.name == container)
+        .and_then(|c| c.resources.as_ref())
+        .and_then(|r| r.limits.as_ref())
+        .and_then(|l| l.get("memory"))
+        .and_then(|q| config::parse_quantity(&q.0));
+    if let Some(bytes) = memory_limit {
+        let pages = (bytes / WASM_PAGE_SIZE).max(1).min(u64::from(u32::MAX));
+        limits.memory_pages = Some(pages as u32);
+    }
+    if let Some(size) = pod_state
+        .run_context
+        .raised_stacks
+        .get(container)
+        .or_else(|| stack_tuning::annotated_size(pod, container))
+    {
+        limits.stack_size = Some(size);
+    }
+    if pod_state.shared.config.low_memory {
+        limits.stack_size = limits
+            .stack_size
+            .map(|s| s.min(config::LOW_MEMORY_MAX_STACK_SIZE));
+        limits
+            .memory_pages
+            .get_or_insert(config::LOW_MEMORY_MEMORY_PAGES);
+    }
+    limits
+}
+
+/// Returns true if the pod was deleted while it was being set up, so the
+/// remaining setup should be skipped.
+pub(crate) async fn deleted_during_setup(pod_state: &PodState) -> bool {
+    let deleted = pod_state
+        .shared
+        .deleted
+        .read()
+        .await
+        .contains(&pod_state.uid);
+    if deleted {
+        info!(
+            "Pod {} was deleted while starting, abandoning setup",
+            pod_state.key
+        );
+    }
+    deleted
+}
+
+/// Returns true if an API call failed because the object does not exist.
+pub(crate) fn is_not_found(e: &kube::Error) -> bool {
+    matches!(e, kube::Error::Api(response) if response.code == 404)
+}
+
+/// Frees what a pod that has finished running holds: its admission slot
+/// straight away, and its handle once the finished pod TTL has passed.
+pub(crate) async fn release_finished(pod_state: &PodState) {
+    pod_state.shared.admission.release(&pod_state.key).await;
+    handles::expire(
+        pod_state.shared.handles.clone(),
+        pod_state.key.clone(),
+        pod_state.shared.config.finished_pod_ttl,
+        pod_state.shared.clock.as_ref(),
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+    use futures::FutureExt;
+
+    #[test]
+    fn backoff_doubles_with_each_failure_up_to_the_max() {
+        let backoffs: Vec<u64> = (1..=7).map(|n| crash_backoff(n).as_secs()).collect();
+        assert_eq!(backoffs, vec![10, 20, 40, 80, 160, 300, 300]);
+        assert_eq!(crash_backoff(1000), CRASH_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn a_restart_waits_out_its_backoff_on_the_clock() {
+        let clock = SimulatedClock::new(chrono::Utc::now());
+        let mut backoff = clock.sleep(crash_backoff(2));
+        clock.advance(Duration::from_secs(19));
+        assert!((&mut backoff).now_or_never().is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(backoff.now_or_never().is_some());
+    }
+
+    #[test]
+    fn failures_reset_once_a_pod_has_run_for_the_reset_time() {
+        let clock = SimulatedClock::new(chrono::Utc::now());
+        let started = clock.instant();
+        clock.advance(CRASH_BACKOFF_RESET - Duration::from_secs(1));
+        assert_eq!(failures_in_a_row(4, started, &clock), 4);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(failures_in_a_row(4, started, &clock), 0);
+    }
+}
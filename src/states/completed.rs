@@ -1,7 +1,8 @@
 use crate::PodState;
 use kubelet::state::prelude::*;
+use log::error;
 
-/// Pod was deleted.
+/// All of the Pod's containers completed successfully.
 #[derive(Default, Debug)]
 pub struct Completed;
 
@@ -9,9 +10,20 @@ pub struct Completed;
 impl State<PodState> for Completed {
     async fn next(
         self: Box<Self>,
-        _pod_state: &mut PodState,
-        _pod: &Pod,
+        pod_state: &mut PodState,
+        pod: &Pod,
     ) -> anyhow::Result<Transition<PodState>> {
+        // The kubelet patches the phase once, so it is queued here in case
+        // the API server was unreachable then
+        let status = make_status(Phase::Succeeded, "Completed")?;
+        if let Err(e) = pod_state.shared.outbox.pod_status(pod, status).await {
+            error!(
+                "Unable to patch final status of pod {}: {:?}",
+                pod.name(),
+                e
+            );
+        }
+        super::release_finished(pod_state).await;
         Ok(Transition::Complete(Ok(())))
     }
 
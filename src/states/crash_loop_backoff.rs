@@ -10,10 +10,11 @@ pub struct CrashLoopBackoff;
 impl State<PodState> for CrashLoopBackoff {
     async fn next(
         self: Box<Self>,
-        _pod_state: &mut PodState,
+        pod_state: &mut PodState,
         _pod: &Pod,
     ) -> anyhow::Result<Transition<PodState>> {
-        tokio::time::delay_for(std::time::Duration::from_secs(60)).await;
+        let backoff = super::crash_backoff(pod_state.errors);
+        pod_state.shared.clock.sleep(backoff).await;
         Ok(Transition::next(self, Registered))
     }
 
@@ -19,11 +19,13 @@ impl State<PodState> for Error {
         _pod: &Pod,
     ) -> anyhow::Result<Transition<PodState>> {
         pod_state.errors += 1;
+        // The first few failures wait here so the status keeps showing why
+        // the pod failed. After that the pod is reported as crash looping.
         if pod_state.errors > 3 {
-            pod_state.errors = 0;
             Ok(Transition::next(self, CrashLoopBackoff))
         } else {
-            tokio::time::delay_for(std::time::Duration::from_secs(5)).await;
+            let backoff = super::crash_backoff(pod_state.errors);
+            pod_state.shared.clock.sleep(backoff).await;
             Ok(Transition::next(self, Registered))
         }
     }
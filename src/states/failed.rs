@@ -0,0 +1,39 @@
+use crate::PodState;
+use kubelet::state::prelude::*;
+use log::error;
+
+/// A container of a Pod that is never restarted failed.
+#[derive(Default, Debug)]
+pub struct Failed {
+    pub message: String,
+}
+
+#[async_trait::async_trait]
+impl State<PodState> for Failed {
+    async fn next(
+        self: Box<Self>,
+        pod_state: &mut PodState,
+        pod: &Pod,
+    ) -> anyhow::Result<Transition<PodState>> {
+        // The kubelet patches the phase once, so it is queued here in case
+        // the API server was unreachable then
+        let status = make_status(Phase::Failed, &self.message)?;
+        if let Err(e) = pod_state.shared.outbox.pod_status(pod, status).await {
+            error!(
+                "Unable to patch final status of pod {}: {:?}",
+                pod.name(),
+                e
+            );
+        }
+        super::release_finished(pod_state).await;
+        Ok(Transition::Complete(Ok(())))
+    }
+
+    async fn json_status(
+        &self,
+        _pod_state: &mut PodState,
+        _pod: &Pod,
+    ) -> anyhow::Result<serde_json::Value> {
+        make_status(Phase::Failed, &self.message)
+    }
+}
@@ -1,10 +1,307 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, FutureExt};
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::Api;
+use kubelet::container::Container;
+use kubelet::container::Status;
+use kubelet::pod::key_from_pod;
+use kubelet::secret::RegistryAuthResolver;
 use kubelet::state::prelude::*;
-use log::error;
+use kubelet::store::PullPolicy;
+use log::{error, info, warn};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
 
+use crate::compose;
+use crate::config::ModuleSource;
+use crate::events::EventRecorder;
+use crate::lifecycle_log::{LifecycleLog, Step};
+use crate::logging::{self, Fields};
+use crate::module_error::ModuleError;
+use crate::registry::{describe_pull_error, resolve_digest, PinnedImage};
+use crate::sidecar;
+use crate::wasi_runtime::ModuleData;
 use crate::PodState;
 
+use super::error::Error;
 use super::image_pull_backoff::ImagePullBackoff;
-use super::volume_mount::VolumeMount;
+use super::running::patch_container_status;
+use super::terminated::Terminated;
+use super::validating::Validating;
+
+/// Returns the containers whose modules the pod needs: its own and the
+/// node's sidecar, if there is one.
+fn pod_containers(pod_state: &PodState, pod: &Pod) -> Vec<Container> {
+    let mut containers = pod.all_containers();
+    containers.extend(sidecar::container(&pod_state.shared.config));
+    containers
+}
+
+/// Returns the image of every container in the pod, keyed by container name.
+fn pod_images(containers: &[Container]) -> anyhow::Result<HashMap<String, String>> {
+    let mut images = HashMap::new();
+    for container in containers {
+        if let Some(image) = container.image()? {
+            images.insert(container.name().to_owned(), image.whole().to_owned());
+        }
+    }
+    Ok(images)
+}
+
+/// How often a pull that is still in progress is reported.
+const PULL_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Reports that a pull is still in progress, both as an event and as the
+/// container's waiting message, so a slow pull can be told apart from a hang.
+/// The store only returns a module once it is complete, so progress is
+/// reported as time elapsed rather than bytes.
+async fn report_pull_progress(
+    client: &Api<KubePod>,
+    events: &EventRecorder,
+    pod: &Pod,
+    container: &Container,
+    image: &str,
+    elapsed: Duration,
+    restart_count: i32,
+) {
+    let message = format!("Still pulling image {} after {}s", image, elapsed.as_secs());
+    events.normal("Pulling", &message).await;
+    let status = Status::Waiting {
+        timestamp: chrono::Utc::now(),
+        message,
+    };
+    if let Err(e) = patch_container_status(
+        client,
+        pod.name(),
+        container.name().to_owned(),
+        &status,
+        None,
+        None,
+        restart_count,
+    )
+    .await
+    {
+        error!("Unable to patch pull progress: {:?}", e);
+    }
+}
+
+/// Image pull secrets take priority; node level credential helpers are used
+/// for registries without one.
+async fn resolve_auth(
+    pod_state: &PodState,
+    auth_resolver: &RegistryAuthResolver,
+    reference: &Reference,
+) -> anyhow::Result<RegistryAuth> {
+    Ok(
+        match auth_resolver.resolve_registry_auth(reference).await? {
+            RegistryAuth::Anonymous => pod_state
+                .shared
+                .credentials
+                .resolve(reference.registry())
+                .await?
+                .unwrap_or(RegistryAuth::Anonymous),
+            auth => auth,
+        },
+    )
+}
+
+/// Pulls the module of one of the pod's libraries, with the same credentials
+/// as its containers. Like a container image, an image without a digest that
+/// is untagged or tagged `latest` is always pulled.
+async fn fetch_library(
+    pod_state: &PodState,
+    auth_resolver: &RegistryAuthResolver,
+    name: &str,
+    image: &str,
+) -> anyhow::Result<(String, ModuleData)> {
+    let reference = Reference::try_from(image).map_err(|e| {
+        ModuleError::Pull(format!("library {}: invalid image {}: {}", name, image, e))
+    })?;
+    let pull_policy =
+        if reference.digest().is_none() && matches!(reference.tag(), None | Some("latest")) {
+            PullPolicy::Always
+        } else {
+            PullPolicy::IfNotPresent
+        };
+    let auth = resolve_auth(pod_state, auth_resolver, &reference).await?;
+    let bytes = pod_state
+        .shared
+        .store
+        .get(&reference, pull_policy, &auth)
+        .await
+        .map_err(|e| {
+            ModuleError::Pull(format!(
+                "library {} image {}: {}",
+                name,
+                image,
+                describe_pull_error(&e)
+            ))
+        })?;
+    Ok((name.to_owned(), bytes.into()))
+}
+
+/// Works out the digest to pull a container's module by, so the code it runs
+/// does not change under a tag that has moved. Restarts reuse the digest the
+/// container was pulled by before, unless `force_pull` asks for the image to
+/// be pulled again. Returns `None` if the digest cannot be known, in which
+/// case the module is pulled by tag.
+async fn pin_image(
+    pod_state: &PodState,
+    container: &str,
+    reference: &Reference,
+    pull_policy: PullPolicy,
+    force_pull: bool,
+    auth: &RegistryAuth,
+) -> Option<PinnedImage> {
+    if let Some(pinned) = PinnedImage::from_digest(reference) {
+        return Some(pinned);
+    }
+    // A module directory has no registry to ask, and a pod that never pulls
+    // must not reach one
+    if pod_state.shared.config.module_source != ModuleSource::Registry
+        || matches!(pull_policy, PullPolicy::Never)
+    {
+        return None;
+    }
+    if !force_pull {
+        match pod_state.run_context.pinned_images.get(container) {
+            Some(pinned) if pinned.image == reference.whole() => return Some(pinned.clone()),
+            _ => (),
+        }
+    }
+    match resolve_digest(reference, auth).await {
+        Ok(pinned) => Some(pinned),
+        Err(e) => {
+            warn!(
+                "Unable to resolve image {} to a digest, pulling it by tag: {}",
+                reference.whole(),
+                describe_pull_error(&e)
+            );
+            None
+        }
+    }
+}
+
+/// Pulls the module for a single container, along with the digest it was
+/// pinned to. Image pull secrets take priority; node level credential
+/// helpers are used for registries without one. With `force_pull` the image
+/// is pulled again whatever its pull policy says.
+pub(super) async fn fetch_module(
+    pod_state: &PodState,
+    pod: &Pod,
+    auth_resolver: &RegistryAuthResolver,
+    container: &Container,
+    force_pull: bool,
+) -> anyhow::Result<(String, ModuleData, Option<PinnedImage>)> {
+    let reference = container
+        .image()?
+        .ok_or_else(|| anyhow::anyhow!("container {} has no image", container.name()))?;
+    let pull_policy = if force_pull {
+        PullPolicy::Always
+    } else {
+        container.effective_pull_policy()?
+    };
+    let auth = resolve_auth(pod_state, auth_resolver, &reference).await?;
+    let pinned = pin_image(
+        pod_state,
+        container.name(),
+        &reference,
+        pull_policy,
+        force_pull,
+        &auth,
+    )
+    .await;
+    // A digest always names the same module, so once the tag is resolved
+    // there is nothing to pull again if the module is already here, and
+    // pods pulling it at the same time can share the pull
+    let (pull_reference, pull_policy, by_digest) = match &pinned {
+        Some(pinned)
+            if pod_state.shared.config.module_source == ModuleSource::Registry
+                && !matches!(pull_policy, PullPolicy::Never) =>
+        {
+            (pinned.reference()?, PullPolicy::IfNotPresent, true)
+        }
+        _ => (reference.clone(), pull_policy, false),
+    };
+    let key = pull_reference.whole().to_owned();
+    let store = pod_state.shared.store.clone();
+    let get = async move {
+        store
+            .get(&pull_reference, pull_policy, &auth)
+            .await
+            .map(ModuleData::from)
+            .map_err(|e| describe_pull_error(&e))
+    };
+    let pull = if by_digest {
+        pod_state.shared.burst.pull(&key, get).boxed()
+    } else {
+        get.boxed()
+    };
+    tokio::pin!(pull);
+    let started = Instant::now();
+    let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+    let events = EventRecorder::new(client.clone(), pod, &pod_state.shared);
+    let pods: Api<KubePod> = Api::namespaced(client, pod.namespace());
+    let bytes = loop {
+        tokio::select! {
+            result = &mut pull => break result.map_err(|e| {
+                ModuleError::Pull(format!("image {}: {}", reference.whole(), e))
+            })?,
+            _ = tokio::time::delay_for(PULL_PROGRESS_INTERVAL) => {
+                pod_state.shared.api_limiter.status_write(&key_from_pod(pod)).await;
+                report_pull_progress(
+                    &pods,
+                    &events,
+                    pod,
+                    container,
+                    reference.whole(),
+                    started.elapsed(),
+                    pod_state.run_context.restart_count(container.name()),
+                )
+                .await;
+            }
+        }
+    };
+    let lifecycle = LifecycleLog::new(
+        &pod_state.shared.log_path,
+        pod.namespace(),
+        pod.name(),
+        container.name(),
+        pod_state.shared.clock.clone(),
+    );
+    let detail = match &pinned {
+        Some(pinned) => pinned.image_id.clone(),
+        None => reference.whole().to_owned(),
+    };
+    if let Err(e) =
+        tokio::task::spawn_blocking(move || lifecycle.record(Step::Pulled, Some(&detail))).await
+    {
+        warn!("Unable to record pull of {}: {:?}", container.name(), e);
+    }
+    Ok((container.name().to_owned(), bytes, pinned))
+}
+
+/// Records the digests pulled modules were pinned to, for restarts and the
+/// containers' `imageID`, and returns the modules keyed by container name.
+pub(super) fn record_pins(
+    pod_state: &mut PodState,
+    pulled: Vec<(String, ModuleData, Option<PinnedImage>)>,
+) -> Vec<(String, ModuleData)> {
+    pulled
+        .into_iter()
+        .map(|(container, data, pinned)| {
+            let pins = &mut pod_state.run_context.pinned_images;
+            match pinned {
+                Some(pinned) => pins.insert(container.clone(), pinned),
+                None => pins.remove(&container),
+            };
+            (container, data)
+        })
+        .collect()
+}
 
 /// Kubelet is pulling container images.
 #[derive(Default, Debug)]
@@ -17,21 +314,87 @@ impl State<PodState> for ImagePull {
         pod_state: &mut PodState,
         pod: &Pod,
     ) -> anyhow::Result<Transition<PodState>> {
-        let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
-        let auth_resolver = kubelet::secret::RegistryAuthResolver::new(client, &pod);
-        pod_state.run_context.modules = match pod_state
-            .shared
-            .store
-            .fetch_pod_modules(&pod, &auth_resolver)
-            .await
+        let containers = pod_containers(pod_state, pod);
+        let images = pod_images(&containers)?;
+        let libraries = match compose::libraries(pod) {
+            Ok(libraries) => libraries,
+            Err(e) => {
+                let message = e.to_string();
+                return Ok(Transition::next(self, Error { message }));
+            }
+        };
+        for (name, image) in libraries.iter() {
+            let allowed = Reference::try_from(image.as_str()).map_or(true, |r| {
+                let repository = format!("{}/{}", r.registry(), r.repository());
+                pod_state.shared.config.allows_image(&repository)
+            });
+            if !allowed {
+                let message = format!(
+                    "image {} of library {} is not allowed on this node",
+                    image, name
+                );
+                return Ok(Transition::next(self, Error { message }));
+            }
+        }
+        let library_images: HashMap<String, String> = libraries.iter().cloned().collect();
+        // Modules are kept across restarts, so only pull again if the
+        // containers now point at different images
+        if !pod_state.run_context.modules.is_empty()
+            && pod_state.run_context.module_images == images
+            && pod_state.run_context.library_images == library_images
         {
-            Ok(modules) => modules,
+            logging::with_fields(Fields::pod(pod).phase("ImagePull"), || {
+                info!("Reusing pulled modules for pod {}", pod.name())
+            });
+            return Ok(Transition::next(self, Validating));
+        }
+
+        let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+        let auth_resolver = RegistryAuthResolver::new(client.clone(), &pod);
+        let state: &PodState = pod_state;
+        let auth_resolver = &auth_resolver;
+        let fetches = containers
+            .iter()
+            .map(move |c| fetch_module(state, pod, auth_resolver, c, false));
+        let library_fetches = libraries
+            .iter()
+            .map(move |(name, image)| fetch_library(state, auth_resolver, name, image));
+        // Modules go into shared storage once; runtimes only ever get a
+        // reference to them
+        let pulled = future::try_join(
+            future::try_join_all(fetches),
+            future::try_join_all(library_fetches),
+        )
+        .await;
+        let (modules, library_modules) = match pulled {
+            Ok((modules, library_modules)) => (
+                record_pins(pod_state, modules).into_iter().collect(),
+                library_modules.into_iter().collect(),
+            ),
             Err(e) => {
-                error!("{:?}", e);
+                let error = match e.downcast::<ModuleError>() {
+                    Ok(error) => error,
+                    Err(e) => ModuleError::Pull(describe_pull_error(&e)),
+                };
+                logging::with_fields(Fields::pod(pod).phase("ImagePull"), || {
+                    error!("Unable to pull modules for pod {}: {}", pod.name(), error)
+                });
+                EventRecorder::new(client, pod, &pod_state.shared)
+                    .warning(error.reason(), &error.to_string())
+                    .await;
                 return Ok(Transition::next(self, ImagePullBackoff));
             }
         };
-        Ok(Transition::next(self, VolumeMount))
+        if super::deleted_during_setup(pod_state).await {
+            return Ok(Transition::next(self, Terminated));
+        }
+        pod_state.run_context.modules = modules;
+        pod_state.run_context.libraries = library_modules;
+        // Memoized instances run the old modules, so they cannot be reused
+        pod_state.run_context.memoized.clear();
+        pod_state.run_context.module_images = images;
+        pod_state.run_context.library_images = library_images;
+        Ok(Transition::next(self, Validating))
     }
 
     async fn json_status(
@@ -43,5 +406,7 @@ impl State<PodState> for ImagePull {
     }
 }
 
-impl TransitionTo<VolumeMount> for ImagePull {}
+impl TransitionTo<Validating> for ImagePull {}
 impl TransitionTo<ImagePullBackoff> for ImagePull {}
+impl TransitionTo<Terminated> for ImagePull {}
+impl TransitionTo<Error> for ImagePull {}
@@ -10,10 +10,14 @@ pub struct ImagePullBackoff;
 impl State<PodState> for ImagePullBackoff {
     async fn next(
         self: Box<Self>,
-        _pod_state: &mut PodState,
+        pod_state: &mut PodState,
         _pod: &Pod,
     ) -> anyhow::Result<Transition<PodState>> {
-        tokio::time::delay_for(std::time::Duration::from_secs(60)).await;
+        pod_state
+            .shared
+            .clock
+            .sleep(std::time::Duration::from_secs(60))
+            .await;
         Ok(Transition::next(self, ImagePull))
     }
 
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 
-use log::{error, info};
+use log::{debug, error, info};
 
+use crate::logging::{self, Fields};
 use crate::PodState;
 use k8s_openapi::api::core::v1::Pod as KubePod;
 use kube::api::{Api, PatchParams};
@@ -11,15 +12,22 @@ use kubelet::state::prelude::*;
 
 use super::error::Error;
 use super::starting::{start_container, ContainerHandleMap, Starting};
+use super::terminated::Terminated;
 
 async fn patch_init_status(
     client: &Api<KubePod>,
     pod_name: &str,
     name: String,
     status: &ContainerStatus,
+    restart_count: i32,
 ) -> anyhow::Result<()> {
     // We need to fetch the current status because there is no way to merge with a strategic merge patch here
     let mut init_container_statuses = match client.get(pod_name).await {
+        // A pod that was force deleted has no status left to update
+        Err(e) if super::is_not_found(&e) => {
+            debug!("Pod {} is gone, not patching its status", pod_name);
+            return Ok(());
+        }
         Ok(p) => match p.status {
             Some(s) => s.init_container_statuses.unwrap_or_default(),
             None => {
@@ -38,12 +46,17 @@ async fn patch_init_status(
             Vec::default()
         }
     };
-    match init_container_statuses.iter().position(|s| s.name == name) {
+    let mut container_status = status.to_kubernetes(name);
+    container_status.restart_count = restart_count;
+    match init_container_statuses
+        .iter()
+        .position(|s| s.name == container_status.name)
+    {
         Some(i) => {
-            init_container_statuses[i] = status.to_kubernetes(name);
+            init_container_statuses[i] = container_status;
         }
         None => {
-            init_container_statuses.push(status.to_kubernetes(name));
+            init_container_statuses.push(container_status);
         }
     };
     let s = serde_json::json!({
@@ -77,13 +90,29 @@ impl State<PodState> for Initializing {
         let mut container_handles: ContainerHandleMap = HashMap::new();
 
         for init_container in pod.init_containers() {
-            info!(
-                "Starting init container {:?} for pod {:?}",
-                init_container.name(),
-                pod.name()
+            if super::deleted_during_setup(pod_state).await {
+                return Ok(Transition::next(self, Terminated));
+            }
+            logging::with_fields(
+                Fields::pod(pod)
+                    .container(init_container.name())
+                    .phase("Initializing"),
+                || {
+                    info!(
+                        "Starting init container {:?} for pod {:?}",
+                        init_container.name(),
+                        pod.name()
+                    )
+                },
             );
 
-            let handle = start_container(pod_state, pod, &init_container).await?;
+            let handle = match start_container(pod_state, pod, &init_container).await {
+                Ok((handle, _)) => handle,
+                Err(e) => {
+                    let message = e.to_string();
+                    return Ok(Transition::next(self, Error { message }));
+                }
+            };
 
             container_handles.insert(
                 ContainerKey::Init(init_container.name().to_string()),
@@ -91,9 +120,37 @@ impl State<PodState> for Initializing {
             );
 
             while let Some((name, status)) = pod_state.run_context.status_recv.recv().await {
-                if let Err(e) = patch_init_status(&client, &pod.name(), name.clone(), &status).await
+                let restart_count = pod_state.run_context.restart_count(&name);
+                // Only app containers report readiness
+                if pod_state
+                    .run_context
+                    .status_recv
+                    .changed(&name, &status, None)
                 {
-                    error!("Unable to patch status, will retry on next update: {:?}", e);
+                    pod_state
+                        .shared
+                        .api_limiter
+                        .status_write(&key_from_pod(pod))
+                        .await;
+                    match patch_init_status(
+                        &client,
+                        &pod.name(),
+                        name.clone(),
+                        &status,
+                        restart_count,
+                    )
+                    .await
+                    {
+                        Ok(()) => pod_state
+                            .run_context
+                            .status_recv
+                            .patched(&name, &status, None),
+                        Err(e) => {
+                            logging::with_fields(Fields::pod(pod).phase("Initializing"), || {
+                                error!("Unable to patch status, will retry on next update: {:?}", e)
+                            })
+                        }
+                    }
                 }
                 if let ContainerStatus::Terminated {
                     timestamp: _,
@@ -116,10 +173,12 @@ impl State<PodState> for Initializing {
                         // into a pod handle so they are available for future log fetching
                         let pod_handle = Handle::new(container_handles, pod.clone(), None).await?;
                         let pod_key = key_from_pod(&pod);
-                        {
-                            let mut handles = pod_state.shared.handles.write().await;
-                            handles.insert(pod_key, pod_handle);
-                        }
+                        // They have all exited, so there is nothing to stop
+                        pod_state
+                            .shared
+                            .handles
+                            .insert(pod_key, pod_handle, Vec::new())
+                            .await;
                         client
                             .patch_status(
                                 pod.name(),
@@ -134,7 +193,9 @@ impl State<PodState> for Initializing {
                 }
             }
         }
-        info!("Finished init containers for pod {:?}", pod.name());
+        logging::with_fields(Fields::pod(pod).phase("Initializing"), || {
+            info!("Finished init containers for pod {:?}", pod.name())
+        });
         Ok(Transition::next(self, Starting::new(container_handles)))
     }
 
@@ -149,3 +210,4 @@ impl State<PodState> for Initializing {
 
 impl TransitionTo<Error> for Initializing {}
 impl TransitionTo<Starting> for Initializing {}
+impl TransitionTo<Terminated> for Initializing {}
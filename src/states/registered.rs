@@ -1,11 +1,43 @@
 use log::{error, info};
 
 use super::error::Error;
+use super::failed::Failed;
 use super::image_pull::ImagePull;
+use super::rejected::Rejected;
+use crate::admission;
+use crate::cordon;
+use crate::events::EventRecorder;
+use crate::handles;
+use crate::logging::{self, Fields};
+use crate::qos::{self, QosClass};
+use crate::recovery;
+use crate::sidecar;
 use crate::PodState;
 use kubelet::container::Container;
 use kubelet::state::prelude::*;
 
+/// Admits the pod, preempting lower priority pods to make room if it has to.
+/// A preempted pod's slot is only free once its instances have exited, so
+/// the pod is rejected if one does not exit in time.
+async fn admit(pod_state: &PodState, pod: &Pod) -> Result<(), String> {
+    loop {
+        match pod_state.shared.admission.admit(pod).await {
+            Ok(None) => return Ok(()),
+            Ok(Some(victim)) => {
+                let victim_key = victim.key();
+                if !admission::preempt(&pod_state.shared, victim, pod).await {
+                    return Err(format!(
+                        "Preempted pod {} did not exit within {}s to make room",
+                        victim_key,
+                        handles::STOP_TIMEOUT.as_secs()
+                    ));
+                }
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
 fn validate_pod_runnable(pod: &Pod) -> anyhow::Result<()> {
     for container in pod.containers() {
         validate_not_kube_proxy(&container)?;
@@ -22,6 +54,104 @@ fn validate_not_kube_proxy(container: &Container) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Returns the first image of the pod that the image policy does not allow,
+/// with the container using it.
+fn disallowed_image(pod_state: &PodState, pod: &Pod) -> anyhow::Result<Option<(String, String)>> {
+    for container in pod.all_containers() {
+        if let Some(image) = container.image()? {
+            let repository = format!("{}/{}", image.registry(), image.repository());
+            if !pod_state.shared.config.allows_image(&repository) {
+                return Ok(Some((
+                    container.name().to_owned(),
+                    image.whole().to_owned(),
+                )));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the containers of the pod that ask for a host port, with the port.
+fn host_ports(pod: &Pod) -> Vec<(String, i32)> {
+    let spec = match pod.as_kube_pod().spec.as_ref() {
+        Some(spec) => spec,
+        None => return Vec::new(),
+    };
+    spec.containers
+        .iter()
+        .chain(spec.init_containers.iter().flatten())
+        .flat_map(|c| {
+            c.ports
+                .iter()
+                .flatten()
+                .filter_map(move |p| p.host_port.map(|port| (c.name.clone(), port)))
+        })
+        .collect()
+}
+
+/// Returns the first container of the pod with a volume mount that uses
+/// `subPath` or `subPathExpr`, with the mount path.
+fn sub_path_mount(pod: &Pod) -> Option<(String, String)> {
+    let spec = pod.as_kube_pod().spec.as_ref()?;
+    spec.containers
+        .iter()
+        .chain(spec.init_containers.iter().flatten())
+        .find_map(|c| {
+            c.volume_mounts
+                .iter()
+                .flatten()
+                .find(|m| m.sub_path.is_some() || m.sub_path_expr.is_some())
+                .map(|m| (c.name.clone(), m.mount_path.clone()))
+        })
+}
+
+/// Returns why the pod's security context cannot be honored, if it cannot.
+/// wasm3 modules have no Linux privileges, so asking for any is rejected
+/// rather than silently ignored.
+fn unsupported_security_context(pod: &Pod) -> Option<String> {
+    let spec = pod.as_kube_pod().spec.as_ref()?;
+    let pod_context = spec.security_context.as_ref();
+    for container in spec
+        .containers
+        .iter()
+        .chain(spec.init_containers.iter().flatten())
+    {
+        let context = container.security_context.as_ref();
+        if context.and_then(|s| s.privileged) == Some(true) {
+            return Some(format!(
+                "Container {} asks to be privileged, but wasm3 modules cannot have host privileges",
+                container.name
+            ));
+        }
+        let added = context
+            .and_then(|s| s.capabilities.as_ref())
+            .and_then(|c| c.add.as_ref())
+            .filter(|add| !add.is_empty());
+        if let Some(added) = added {
+            return Some(format!(
+                "Container {} asks for capabilities {}, but wasm3 modules have no Linux capabilities",
+                container.name,
+                added.join(", ")
+            ));
+        }
+        // Modules do not run as a user, so runAsNonRoot always holds, but a
+        // spec that contradicts itself is still an error
+        let non_root = context
+            .and_then(|s| s.run_as_non_root)
+            .or_else(|| pod_context.and_then(|s| s.run_as_non_root));
+        let user = context
+            .and_then(|s| s.run_as_user)
+            .or_else(|| pod_context.and_then(|s| s.run_as_user));
+        if non_root == Some(true) && user == Some(0) {
+            return Some(format!(
+                "Container {} sets runAsNonRoot but runAsUser is 0",
+                container.name
+            ));
+        }
+    }
+    None
+}
+
 /// The Kubelet is aware of the Pod.
 #[derive(Default, Debug)]
 pub struct Registered;
@@ -30,29 +160,185 @@ pub struct Registered;
 impl State<PodState> for Registered {
     async fn next(
         self: Box<Self>,
-        _pod_state: &mut PodState,
+        pod_state: &mut PodState,
         pod: &Pod,
     ) -> anyhow::Result<Transition<PodState>> {
+        // A pod that must not run twice and was running when the provider
+        // stopped has already been marked failed
+        if pod_state.shared.recovery.is_failed(&pod_state.uid) {
+            pod_state.shared.recovery.forget_failed(&pod_state.uid);
+            let message = recovery::NODE_RESTART_REASON.to_owned();
+            return Ok(Transition::next(self, Failed { message }));
+        }
+        if !pod_state.shared.config.allows_namespace(pod.namespace()) {
+            let message = format!(
+                "Namespace {} is not served by this provider",
+                pod.namespace()
+            );
+            logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+                error!("Rejecting pod {}: {}", pod.name(), message)
+            });
+            let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+            EventRecorder::new(client, pod, &pod_state.shared)
+                .warning("Rejected", &message)
+                .await;
+            return Ok(Transition::next(self, Rejected { message }));
+        }
+        // WASI modules have no sockets, so there is nothing a host port could
+        // forward to
+        if let Some((container, port)) = host_ports(pod).into_iter().next() {
+            let message = format!(
+                "Container {} requests host port {}, but wasm3 modules have no network access",
+                container, port
+            );
+            logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+                error!("Rejecting pod {}: {}", pod.name(), message)
+            });
+            let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+            EventRecorder::new(client, pod, &pod_state.shared)
+                .warning("HostPortUnsupported", &message)
+                .await;
+            return Ok(Transition::next(self, Rejected { message }));
+        }
+        // Modules get no volume mounts, so there is no volume to map part of
+        if let Some((container, path)) = sub_path_mount(pod) {
+            let message = format!(
+                "Container {} mounts a subPath at {}, but wasm3 modules get no volume mounts",
+                container, path
+            );
+            logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+                error!("Rejecting pod {}: {}", pod.name(), message)
+            });
+            let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+            EventRecorder::new(client, pod, &pod_state.shared)
+                .warning("SubPathUnsupported", &message)
+                .await;
+            return Ok(Transition::next(self, Rejected { message }));
+        }
+        if let Some(message) = unsupported_security_context(pod) {
+            logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+                error!("Rejecting pod {}: {}", pod.name(), message)
+            });
+            let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+            EventRecorder::new(client, pod, &pod_state.shared)
+                .warning("SecurityContextUnsupported", &message)
+                .await;
+            return Ok(Transition::next(self, Rejected { message }));
+        }
+        if pod_state.shared.config.sidecar_image.is_some()
+            && pod
+                .all_containers()
+                .iter()
+                .any(|c| sidecar::is_sidecar(c.name()))
+        {
+            let message = format!(
+                "Container name {} is reserved for the node's sidecar",
+                sidecar::SIDECAR_CONTAINER_NAME
+            );
+            logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+                error!("Rejecting pod {}: {}", pod.name(), message)
+            });
+            let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+            EventRecorder::new(client, pod, &pod_state.shared)
+                .warning("Rejected", &message)
+                .await;
+            return Ok(Transition::next(self, Rejected { message }));
+        }
+        match disallowed_image(pod_state, pod) {
+            Ok(None) => (),
+            Ok(Some((container, image))) => {
+                let message = format!(
+                    "Image {} of container {} is not from an allowed registry",
+                    image, container
+                );
+                logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+                    error!("Rejecting pod {}: {}", pod.name(), message)
+                });
+                let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+                EventRecorder::new(client, pod, &pod_state.shared)
+                    .warning("PolicyViolation", &message)
+                    .await;
+                return Ok(Transition::next(self, Rejected { message }));
+            }
+            Err(e) => {
+                let message = format!("{:?}", e);
+                logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+                    error!("{}", message)
+                });
+                return Ok(Transition::next(self, Error { message }));
+            }
+        }
+        // A pod being restarted was admitted already, and it is up to the
+        // eviction loop whether it keeps running under memory pressure
+        let restarting = pod_state.shared.admission.is_admitted(&pod_state.key).await;
+        if !restarting
+            && pod_state.shared.memory_pressure.is_set()
+            && qos::qos_class(pod) == QosClass::BestEffort
+        {
+            let message = "The node had condition: [MemoryPressure].".to_owned();
+            logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+                error!("Rejecting pod {}: {}", pod.name(), message)
+            });
+            let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+            EventRecorder::new(client, pod, &pod_state.shared)
+                .warning("Evicted", &message)
+                .await;
+            return Ok(Transition::next(self, Rejected { message }));
+        }
+        if !restarting && pod_state.shared.cordon.is_set() && !cordon::tolerates_cordon(pod) {
+            let message = "The node is cordoned and admits no new pods".to_owned();
+            logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+                error!("Rejecting pod {}: {}", pod.name(), message)
+            });
+            let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+            EventRecorder::new(client, pod, &pod_state.shared)
+                .warning(cordon::CORDONED_REASON, &message)
+                .await;
+            return Ok(Transition::next(self, Rejected { message }));
+        }
         match validate_pod_runnable(&pod) {
             Ok(_) => (),
             Err(e) => {
                 let message = format!("{:?}", e);
-                error!("{}", message);
+                logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+                    error!("{}", message)
+                });
                 return Ok(Transition::next(self, Error { message }));
             }
         }
-        info!("Pod added: {}.", pod.name());
+        if let Err(message) = admit(pod_state, pod).await {
+            logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+                error!("Rejecting pod {}: {}", pod.name(), message)
+            });
+            let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+            EventRecorder::new(client, pod, &pod_state.shared)
+                .warning("OutOfpods", &message)
+                .await;
+            return Ok(Transition::next(self, Rejected { message }));
+        }
+        logging::with_fields(Fields::pod(pod).phase("Registered"), || {
+            info!("Pod added: {}.", pod.name())
+        });
         Ok(Transition::next(self, ImagePull))
     }
 
     async fn json_status(
         &self,
-        _pod_state: &mut PodState,
-        _pod: &Pod,
+        pod_state: &mut PodState,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Pending, "Registered")
+        // Do not flip a pod that was marked failed back to pending
+        if pod_state.shared.recovery.is_failed(&pod_state.uid) {
+            return make_status(Phase::Failed, recovery::NODE_RESTART_REASON);
+        }
+        let mut status = make_status(Phase::Pending, "Registered")?;
+        // Report the class this node will treat the pod as, like the kubelet
+        status["status"]["qosClass"] = qos::qos_class(pod).as_str().into();
+        Ok(status)
     }
 }
 
 impl TransitionTo<ImagePull> for Registered {}
 impl TransitionTo<Error> for Registered {}
+impl TransitionTo<Failed> for Registered {}
+impl TransitionTo<Rejected> for Registered {}
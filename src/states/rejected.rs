@@ -0,0 +1,27 @@
+use crate::PodState;
+use kubelet::state::prelude::*;
+
+/// The provider refused to run the Pod.
+#[derive(Default, Debug)]
+pub struct Rejected {
+    pub message: String,
+}
+
+#[async_trait::async_trait]
+impl State<PodState> for Rejected {
+    async fn next(
+        self: Box<Self>,
+        _pod_state: &mut PodState,
+        _pod: &Pod,
+    ) -> anyhow::Result<Transition<PodState>> {
+        Ok(Transition::Complete(Ok(())))
+    }
+
+    async fn json_status(
+        &self,
+        _pod_state: &mut PodState,
+        _pod: &Pod,
+    ) -> anyhow::Result<serde_json::Value> {
+        make_status(Phase::Failed, &self.message)
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::future;
+use kubelet::container::{ContainerKey, Status};
+use kubelet::pod::Handle;
+use kubelet::secret::RegistryAuthResolver;
+use kubelet::state::prelude::*;
+use log::{error, info};
+
+use crate::entrypoint;
+use crate::events::EventRecorder;
+use crate::handles;
+use crate::logging::{self, Fields};
+use crate::module_error::ModuleError;
+use crate::registry::describe_pull_error;
+use crate::status;
+use crate::wasi_runtime::ModuleData;
+use crate::PodState;
+
+use super::error::Error;
+use super::image_pull::{fetch_module, record_pins};
+use super::running::Running;
+use super::starting::{
+    attest, start_container, start_order, start_shadow, wait_for_dependencies, ContainerHandleMap,
+};
+use super::validating;
+
+/// Annotation holding how many seconds the new modules of a reload run in
+/// shadow, next to the running ones, before they replace them.
+const SHADOW_ANNOTATION: &str = "wasm3.krustlet.dev/shadow-secs";
+
+/// How long the pod's new modules run in shadow on a reload, if they do.
+pub(crate) fn shadow_window(pod: &Pod) -> anyhow::Result<Option<Duration>> {
+    let value = match pod.annotations().get(SHADOW_ANNOTATION) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match value.trim().parse::<u64>() {
+        Ok(0) => Ok(None),
+        Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+        Err(_) => Err(anyhow::anyhow!(
+            "invalid {} annotation {:?}, expected a number of seconds",
+            SHADOW_ANNOTATION,
+            value
+        )),
+    }
+}
+
+/// Runs the new modules as shadow instances for up to `window`, while the
+/// running instances carry on. Returns why the reload should not go ahead if
+/// a shadow instance could not start or failed. Shadows that are still
+/// running when the window ends are left to return on their own, as wasm3
+/// cannot interrupt them, and nothing they report is looked at again.
+async fn shadow(
+    pod_state: &PodState,
+    pod: &Pod,
+    modules: &[(String, ModuleData)],
+    window: Duration,
+) -> anyhow::Result<Option<String>> {
+    let (status_sender, mut status_recv) = status::channel();
+    let mut running = 0;
+    for container in pod.containers() {
+        let module = match modules.iter().find(|(name, _)| name == container.name()) {
+            Some((_, module)) => module.clone(),
+            None => continue,
+        };
+        if let Err(e) =
+            start_shadow(pod_state, pod, &container, module, status_sender.clone()).await
+        {
+            return Ok(Some(format!(
+                "container {} could not start in shadow: {}",
+                container.name(),
+                e
+            )));
+        }
+        running += 1;
+    }
+    drop(status_sender);
+    let deadline = tokio::time::delay_for(window);
+    tokio::pin!(deadline);
+    while running > 0 {
+        tokio::select! {
+            status = status_recv.recv() => match status {
+                Some((name, Status::Terminated { failed: true, message, .. })) => {
+                    return Ok(Some(format!("container {} failed in shadow: {}", name, message)));
+                }
+                Some((_, Status::Terminated { .. })) => running -= 1,
+                Some(_) => (),
+                None => break,
+            },
+            _ = &mut deadline => break,
+        }
+    }
+    Ok(None)
+}
+
+/// Kubelet is replacing the modules of a running pod because its reload
+/// annotation or the image of one of its containers changed. The new modules
+/// are pulled, checked and, if the pod asks for it, run in shadow while the
+/// old ones keep running, so a bad image leaves the pod as it was.
+#[derive(Debug)]
+pub struct Reloading {
+    /// The pod as it was when the reload was asked for
+    pub(crate) pod: Pod,
+}
+
+impl Reloading {
+    /// Records why the reload did not happen and goes back to the running
+    /// modules.
+    async fn abandon(
+        self: Box<Self>,
+        events: &EventRecorder,
+        message: &str,
+    ) -> Transition<PodState> {
+        logging::with_fields(Fields::pod(&self.pod).phase("Reloading"), || {
+            error!("Unable to reload pod {}: {}", self.pod.name(), message)
+        });
+        events.warning("ReloadFailed", message).await;
+        Transition::next(self, Running)
+    }
+}
+
+#[async_trait::async_trait]
+impl State<PodState> for Reloading {
+    async fn next(
+        self: Box<Self>,
+        pod_state: &mut PodState,
+        _pod: &Pod,
+    ) -> anyhow::Result<Transition<PodState>> {
+        let pod = self.pod.clone();
+        logging::with_fields(Fields::pod(&pod).phase("Reloading"), || {
+            info!("Reloading modules for pod {}", pod.name())
+        });
+        let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+        let events = EventRecorder::new(client.clone(), &pod, &pod_state.shared);
+        let auth_resolver = RegistryAuthResolver::new(client, &pod);
+        // Init containers have already run, so only app containers reload
+        let containers = pod.containers();
+        let state: &PodState = pod_state;
+        let fetches = containers
+            .iter()
+            .map(|c| fetch_module(state, &pod, &auth_resolver, c, true));
+        let mut modules = match future::try_join_all(fetches).await {
+            Ok(modules) => record_pins(pod_state, modules),
+            Err(e) => {
+                let message = match e.downcast::<ModuleError>() {
+                    Ok(error) => error.to_string(),
+                    Err(e) => ModuleError::Pull(describe_pull_error(&e)).to_string(),
+                };
+                return Ok(self.abandon(&events, &message).await);
+            }
+        };
+        for (container, data) in modules.iter_mut() {
+            let limits = super::module_limits(pod_state, &pod, container);
+            let profile = match validating::wasi_profile(&pod_state.shared.config, &pod, container)
+            {
+                Ok(profile) => profile,
+                Err(e) => return Ok(self.abandon(&events, &e.to_string()).await),
+            };
+            let entrypoint = match entrypoint::entrypoint(&pod, container) {
+                Ok(entrypoint) => entrypoint,
+                Err(e) => return Ok(self.abandon(&events, &e.to_string()).await),
+            };
+            let libraries = validating::libraries_for(pod_state, container);
+            match validating::prepare(
+                pod_state,
+                data.clone(),
+                limits,
+                profile,
+                entrypoint,
+                libraries,
+            )
+            .await?
+            {
+                Ok(Some(module)) => *data = module,
+                Ok(None) => (),
+                Err(e) => {
+                    let message = format!("{}: container {}: {}", e.reason(), container, e);
+                    return Ok(self.abandon(&events, &message).await);
+                }
+            }
+        }
+        let window = match shadow_window(&pod) {
+            Ok(window) => window,
+            Err(e) => return Ok(self.abandon(&events, &e.to_string()).await),
+        };
+        if let Some(window) = window {
+            logging::with_fields(Fields::pod(&pod).phase("Reloading"), || {
+                info!(
+                    "Running new modules of pod {} in shadow for {}s",
+                    pod.name(),
+                    window.as_secs()
+                )
+            });
+            if let Some(message) = shadow(pod_state, &pod, &modules, window).await? {
+                return Ok(self.abandon(&events, &message).await);
+            }
+        }
+
+        for container in containers.iter() {
+            if let Some(image) = container.image()? {
+                pod_state
+                    .run_context
+                    .module_images
+                    .insert(container.name().to_owned(), image.whole().to_owned());
+            }
+        }
+        pod_state.run_context.modules.extend(modules);
+        // Memoized instances run the old modules, and dropping them lets
+        // their threads exit
+        pod_state.run_context.memoized.clear();
+        // The old instances keep the old status channel, so nothing they
+        // report once they are replaced is mistaken for the new ones
+        let (tx, rx) = status::channel();
+        pod_state.run_context.status_sender = tx;
+        pod_state.run_context.status_recv = rx;
+
+        let containers = match start_order(&pod) {
+            Ok(containers) => containers,
+            Err(e) => {
+                let message = e.to_string();
+                return Ok(Transition::next(self, Error { message }));
+            }
+        };
+        let mut container_handles: ContainerHandleMap = HashMap::new();
+        let mut stoppers = Vec::new();
+        for (container, dependencies) in containers {
+            if let Err(e) = wait_for_dependencies(pod_state, container.name(), &dependencies).await
+            {
+                let message = e.to_string();
+                return Ok(Transition::next(self, Error { message }));
+            }
+            match start_container(pod_state, &pod, &container).await {
+                Ok((handle, stopper)) => {
+                    stoppers.push(stopper);
+                    container_handles
+                        .insert(ContainerKey::App(container.name().to_string()), handle);
+                }
+                Err(e) => {
+                    logging::with_fields(
+                        Fields::pod(&pod)
+                            .container(container.name())
+                            .phase("Reloading"),
+                        || error!("Unable to start container {}: {:?}", container.name(), e),
+                    );
+                    let message = e.to_string();
+                    return Ok(Transition::next(self, Error { message }));
+                }
+            }
+        }
+
+        // Switch over to the new instances before stopping the old ones
+        let old = pod_state.shared.handles.get(&pod_state.key).await;
+        let pod_handle = Handle::new(container_handles, pod.clone(), None).await?;
+        pod_state
+            .shared
+            .handles
+            .insert(pod_state.key.clone(), pod_handle, stoppers)
+            .await;
+        if let Some(old) = old {
+            handles::stop(
+                old,
+                &pod_state.key,
+                handles::STOP_TIMEOUT,
+                pod_state.shared.clock.as_ref(),
+                async {},
+            )
+            .await;
+        }
+        events
+            .normal("Reloaded", "Modules were pulled again and restarted")
+            .await;
+        attest(pod_state, &pod);
+        Ok(Transition::next(self, Running))
+    }
+
+    async fn json_status(
+        &self,
+        _pod_state: &mut PodState,
+        _pod: &Pod,
+    ) -> anyhow::Result<serde_json::Value> {
+        make_status(Phase::Running, "Reloading")
+    }
+}
+
+impl TransitionTo<Running> for Reloading {}
+impl TransitionTo<Error> for Reloading {}
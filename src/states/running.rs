@@ -1,23 +1,92 @@
-use k8s_openapi::api::core::v1::Pod as KubePod;
+use k8s_openapi::api::core::v1::{ContainerStatus, Pod as KubePod, PodCondition};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use kube::api::{Api, PatchParams};
+
 use kubelet::container::Status;
+use kubelet::pod::key_from_pod;
 use kubelet::state::prelude::*;
-use log::error;
+use log::{debug, error, info};
 
 use super::completed::Completed;
 use super::error::Error;
+use super::failed::Failed;
+use super::reloading::Reloading;
+use crate::config;
+use crate::events::EventRecorder;
+use crate::hooks::Stopped;
+use crate::logging::{self, Fields};
+use crate::sidecar;
+use crate::stack_tuning;
+use crate::wasi_runtime;
+use crate::webhook::LifecycleEvent;
 use crate::PodState;
 
-async fn patch_container_status(
+/// Patches the status of container `name`, along with the pod's `Ready` and
+/// `ContainersReady` conditions. `ready` is the readiness the container's
+/// module reported, if it reports readiness; otherwise the container is
+/// ready while it runs.
+pub(crate) async fn patch_container_status(
     client: &Api<KubePod>,
     pod_name: &str,
     name: String,
     status: &Status,
+    ready: Option<bool>,
+    image_id: Option<&str>,
+    restart_count: i32,
+) -> anyhow::Result<()> {
+    let container_status = container_status(name, status, ready, image_id, restart_count);
+    merge_container_status(client, pod_name, None, container_status).await
+}
+
+/// The Kubernetes status of container `name`.
+pub(crate) fn container_status(
+    name: String,
+    status: &Status,
+    ready: Option<bool>,
+    image_id: Option<&str>,
+    restart_count: i32,
+) -> ContainerStatus {
+    let mut container_status = status.to_kubernetes(name);
+    container_status.restart_count = restart_count;
+    if let (Some(ready), Status::Running { .. }) = (ready, status) {
+        container_status.ready = ready;
+    }
+    if let Some(image_id) = image_id {
+        container_status.image_id = image_id.to_owned();
+    }
+    container_status
+}
+
+/// Merges `container_status` into the statuses of pod `pod_name` and updates
+/// its `Ready` and `ContainersReady` conditions. When the pod's `uid` is
+/// given, a pod that was recreated under the same name is left alone, and a
+/// failure to read the pod is returned rather than patched over.
+pub(crate) async fn merge_container_status(
+    client: &Api<KubePod>,
+    pod_name: &str,
+    uid: Option<&str>,
+    container_status: ContainerStatus,
 ) -> anyhow::Result<()> {
     // We need to fetch the current status because there is no way to merge with a strategic merge patch ere
-    let mut container_statuses = match client.get(pod_name).await {
+    let (mut container_statuses, conditions) = match client.get(pod_name).await {
+        // A pod that was force deleted has no status left to update
+        Err(e) if super::is_not_found(&e) => {
+            debug!("Pod {} is gone, not patching its status", pod_name);
+            return Ok(());
+        }
+        Ok(p) if uid.is_some() && p.metadata.uid.as_deref() != uid => {
+            debug!("Pod {} was recreated, not patching its status", pod_name);
+            return Ok(());
+        }
+        Err(e) if uid.is_some() => return Err(e.into()),
         Ok(p) => match p.status {
-            Some(s) => s.container_statuses.unwrap_or_default(),
+            Some(s) => (
+                s.container_statuses.unwrap_or_default(),
+                Some((
+                    s.conditions.unwrap_or_default(),
+                    p.spec.map_or(0, |s| s.containers.len()),
+                )),
+            ),
             None => {
                 return Err(anyhow::anyhow!(
                     "Pod is missing status information. This should not occur"
@@ -30,19 +99,23 @@ async fn patch_container_status(
             // randomly abort the whole process due to an error
             // fetching the current status. We should probably have
             // some sort of retry mechanism, but that is another
-            // task for another day
-            Vec::default()
+            // task for another day. The conditions are left alone, as
+            // the patch replaces the whole list.
+            (Vec::default(), None)
         }
     };
-    match container_statuses.iter().position(|s| s.name == name) {
+    match container_statuses
+        .iter()
+        .position(|s| s.name == container_status.name)
+    {
         Some(i) => {
-            container_statuses[i] = status.to_kubernetes(name);
+            container_statuses[i] = container_status;
         }
         None => {
-            container_statuses.push(status.to_kubernetes(name));
+            container_statuses.push(container_status);
         }
     };
-    let s = serde_json::json!({
+    let mut s = serde_json::json!({
         "metadata": {
             "resourceVersion": "",
         },
@@ -50,12 +123,120 @@ async fn patch_container_status(
             "containerStatuses": container_statuses,
         }
     });
+    if let Some((mut conditions, containers)) = conditions {
+        // A container that has not reported yet is not ready
+        let all_ready =
+            container_statuses.len() >= containers && container_statuses.iter().all(|s| s.ready);
+        for condition in &["ContainersReady", "Ready"] {
+            set_condition(&mut conditions, condition, all_ready);
+        }
+        s["status"]["conditions"] = serde_json::to_value(&conditions)?;
+    }
     client
         .patch_status(pod_name, &PatchParams::default(), serde_json::to_vec(&s)?)
         .await?;
     Ok(())
 }
 
+/// Sets a pod condition to whether the pod's containers are all ready,
+/// keeping its transition time unless its status changes.
+fn set_condition(conditions: &mut Vec<PodCondition>, type_: &str, ready: bool) {
+    let status = if ready { "True" } else { "False" };
+    let reason = if ready {
+        None
+    } else {
+        Some("ContainersNotReady".to_owned())
+    };
+    match conditions.iter_mut().find(|c| c.type_ == type_) {
+        Some(condition) if condition.status == status => condition.reason = reason,
+        Some(condition) => {
+            condition.status = status.to_owned();
+            condition.reason = reason;
+            condition.last_transition_time = Some(Time(chrono::Utc::now()));
+        }
+        None => conditions.push(PodCondition {
+            type_: type_.to_owned(),
+            status: status.to_owned(),
+            reason,
+            last_transition_time: Some(Time(chrono::Utc::now())),
+            ..Default::default()
+        }),
+    }
+}
+
+/// Raises the stack of a container whose module overflowed it, if the node
+/// tunes stacks, so that its next run gets double the stack.
+async fn raise_stack(pod_state: &mut PodState, pod: &Pod, container: &str) {
+    let node_config = &pod_state.shared.config;
+    let cap = match node_config.stack_autotune_max {
+        Some(cap) if node_config.low_memory => cap.min(config::LOW_MEMORY_MAX_STACK_SIZE),
+        Some(cap) => cap,
+        None => return,
+    };
+    let current = super::module_limits(pod_state, pod, container)
+        .stack_size
+        .unwrap_or(wasi_runtime::DEFAULT_STACK_SIZE);
+    let events = EventRecorder::new(
+        kube::Client::new(pod_state.shared.kubeconfig.clone()),
+        pod,
+        &pod_state.shared,
+    );
+    match pod_state
+        .run_context
+        .raised_stacks
+        .raise(container, current, cap)
+    {
+        Some(size) => {
+            // A memoized runtime keeps the stack it was created with
+            pod_state.run_context.memoized.remove(container);
+            logging::with_fields(
+                Fields::pod(pod).container(container).phase("Running"),
+                || {
+                    info!(
+                        "Raising stack of container {} to {} bytes after a stack overflow",
+                        container, size
+                    )
+                },
+            );
+            events
+                .normal(
+                    "StackSizeRaised",
+                    &format!(
+                        "Container {} ran out of stack, restarting it with {} bytes",
+                        container, size
+                    ),
+                )
+                .await;
+        }
+        None => {
+            events
+                .warning(
+                    "StackSizeAtLimit",
+                    &format!(
+                        "Container {} ran out of stack with {} bytes, the most it may be given",
+                        container, current
+                    ),
+                )
+                .await
+        }
+    }
+}
+
+/// Writes the stack sizes raised for the pod's containers to its
+/// annotation.
+async fn record_stacks(pod_state: &mut PodState, client: &Api<KubePod>, pod: &Pod) {
+    if let Err(e) = pod_state
+        .run_context
+        .raised_stacks
+        .record(client, pod)
+        .await
+    {
+        logging::with_fields(Fields::pod(pod).phase("Running"), || {
+            error!("Unable to record raised stack sizes: {:?}", e)
+        });
+    }
+}
+
 /// The Kubelet is running the Pod.
 #[derive(Default, Debug)]
 pub struct Running;
@@ -73,11 +254,80 @@ impl State<PodState> for Running {
         );
         let mut completed = 0;
         let total_containers = pod.containers().len();
+        let clock = pod_state.shared.clock.clone();
+        let started = clock.instant();
+        let pod_key = key_from_pod(pod);
 
-        while let Some((name, status)) = pod_state.run_context.status_recv.recv().await {
+        loop {
+            let (name, status) = tokio::select! {
+                status = pod_state.run_context.status_recv.recv() => match status {
+                    Some(status) => status,
+                    None => break,
+                },
+                Some(pod) = pod_state.run_context.reload_recv.recv() => {
+                    return Ok(Transition::next(self, Reloading { pod }));
+                }
+                // A raised stack has worked once the pod has run for as long
+                // as it takes the backoff to reset
+                _ = clock.sleep((started + super::CRASH_BACKOFF_RESET).saturating_duration_since(clock.instant())),
+                    if pod_state.run_context.raised_stacks.has_unrecorded() => {
+                    record_stacks(pod_state, &client, pod).await;
+                    continue;
+                }
+            };
+            // The sidecar is not in the pod spec, so it has no status to
+            // patch and does not count towards the pod finishing
+            if sidecar::is_sidecar(&name) {
+                logging::with_fields(Fields::pod(pod).container(&name).phase("Running"), || {
+                    info!("Sidecar of pod {} is now {:?}", pod.name(), status)
+                });
+                continue;
+            }
             // TODO: implement a container state machine such that it will self-update the Kubernetes API as it transitions through these stages.
-            if let Err(e) = patch_container_status(&client, &pod.name(), name, &status).await {
-                error!("Unable to patch status, will retry on next update: {:?}", e);
+            let restart_count = pod_state.run_context.restart_count(&name);
+            let ready = pod_state.run_context.status_recv.ready(&name);
+            // Nothing is patched for a status the API server already has
+            if pod_state
+                .run_context
+                .status_recv
+                .changed(&name, &status, ready)
+            {
+                pod_state.shared.api_limiter.status_write(&pod_key).await;
+                // A newer status for the same container arrived while this one
+                // waited, so only that one is sent. Terminations are always
+                // sent as they end the container.
+                let terminated = matches!(status, Status::Terminated { .. });
+                if !terminated && pod_state.run_context.status_recv.has_newer(&name) {
+                    pod_state.shared.api_limiter.coalesced();
+                } else {
+                    let container_status = container_status(
+                        name.clone(),
+                        &status,
+                        ready,
+                        pod_state
+                            .run_context
+                            .pinned_images
+                            .get(&name)
+                            .map(|p| p.image_id.as_str()),
+                        restart_count,
+                    );
+                    // Queued until the API server is reachable if it is not,
+                    // so a termination is never lost to an outage
+                    match pod_state
+                        .shared
+                        .outbox
+                        .container_status(pod, container_status)
+                        .await
+                    {
+                        Ok(()) => pod_state
+                            .run_context
+                            .status_recv
+                            .patched(&name, &status, ready),
+                        Err(e) => logging::with_fields(Fields::pod(pod).phase("Running"), || {
+                            error!("Unable to patch status, will retry on next update: {:?}", e)
+                        }),
+                    }
+                }
             }
             if let Status::Terminated {
                 timestamp: _,
@@ -85,9 +335,44 @@ impl State<PodState> for Running {
                 failed,
             } = status
             {
+                if let Some(webhook) = &pod_state.shared.webhook {
+                    // wasm3 does not pass on the code a module exits with,
+                    // so this follows the container status
+                    webhook.notify(
+                        pod,
+                        LifecycleEvent::ContainerTerminated {
+                            container: name.clone(),
+                            exit_code: if failed { 1 } else { 0 },
+                            message: message.clone(),
+                        },
+                    );
+                }
+                pod_state.shared.hooks.post_stop(
+                    pod,
+                    &Stopped {
+                        container: &name,
+                        failed,
+                        message: &message,
+                    },
+                );
                 if failed {
+                    if stack_tuning::is_stack_overflow(&message) {
+                        raise_stack(pod_state, pod, &name).await;
+                    }
+                    // A pod that ran for long enough before failing starts
+                    // again from the shortest backoff
+                    pod_state.errors =
+                        super::failures_in_a_row(pod_state.errors, started, clock.as_ref());
+                    // Pods that are never restarted, such as most Job pods,
+                    // fail for good instead of being retried
+                    if super::restart_policy(pod) == "Never" {
+                        return Ok(Transition::next(self, Failed { message }));
+                    }
                     return Ok(Transition::next(self, Error { message }));
                 } else {
+                    if pod_state.run_context.raised_stacks.get(&name).is_some() {
+                        record_stacks(pod_state, &client, pod).await;
+                    }
                     completed += 1;
                     if completed == total_containers {
                         return Ok(Transition::next(self, Completed));
@@ -109,3 +394,5 @@ impl State<PodState> for Running {
 
 impl TransitionTo<Completed> for Running {}
 impl TransitionTo<Error> for Running {}
+impl TransitionTo<Failed> for Running {}
+impl TransitionTo<Reloading> for Running {}
@@ -1,81 +1,851 @@
 use std::collections::HashMap;
-use std::ops::Deref;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use tokio::sync::Mutex;
 
 use kubelet::container::{Container, ContainerKey};
 use kubelet::pod::{key_from_pod, Handle};
 use kubelet::provider;
 use kubelet::state::prelude::*;
-use kubelet::volume::Ref;
 
-use crate::wasi_runtime::{self, HandleFactory, Runtime, WasiRuntime};
+use crate::attestation::ContainerReport;
+use crate::config::{self, StdoutPolicy};
+use crate::entrypoint;
+use crate::events::EventRecorder;
+use crate::gateway;
+use crate::host::FilePolicy;
+use crate::kv;
+use crate::lifecycle_log::LifecycleLog;
+use crate::log_files;
+use crate::logging::{self, Fields};
+use crate::metrics::ContainerMetrics;
+use crate::node_env;
+use crate::sidecar;
+use crate::status::StatusSender;
+use crate::wasi_runtime::{self, HandleFactory, IdleWatch, Runtime, Stopper, WasiRuntime};
+use crate::watch::WatchScope;
+use crate::webhook::LifecycleEvent;
 use crate::PodState;
 
+use super::error::Error;
 use super::running::Running;
+use super::terminated::Terminated;
 
-fn volume_path_map(
+/// Annotation holding a comma separated list of host files the pod's modules
+/// may read.
+const HOST_FILES_ANNOTATION: &str = "wasm3.krustlet.dev/host-files";
+
+/// Returns the host files the pod asked to read that the node allows, along
+/// with the paths that were refused.
+fn host_files(pod_state: &PodState, pod: &Pod) -> (Vec<PathBuf>, Vec<String>) {
+    let mut files = Vec::new();
+    let mut refused = Vec::new();
+    let requested = match pod.annotations().get(HOST_FILES_ANNOTATION) {
+        Some(requested) => requested,
+        None => return (files, refused),
+    };
+    let allowlist = &pod_state.shared.config.host_files;
+    for path in requested
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+    {
+        let path = PathBuf::from(path);
+        if allowlist.contains(&path) {
+            files.push(path);
+        } else {
+            refused.push(path.display().to_string());
+        }
+    }
+    (files, refused)
+}
+
+/// Annotation asking for the root filesystem of the pod's modules to be
+/// read-only, set to `true`, as `readOnlyRootFilesystem` does for a single
+/// container.
+const READ_ONLY_ROOT_ANNOTATION: &str = "wasm3.krustlet.dev/read-only-root";
+
+/// Whether the container's root filesystem is read-only, because the pod's
+/// annotation or the container's `readOnlyRootFilesystem` asks for it. The
+/// node's sidecar is trusted by the node, so the pod's annotation does not
+/// apply to it.
+fn read_only_root(pod: &Pod, container: &Container) -> anyhow::Result<bool> {
+    if sidecar::is_sidecar(container.name()) {
+        return Ok(false);
+    }
+    let annotated = match pod
+        .annotations()
+        .get(READ_ONLY_ROOT_ANNOTATION)
+        .map(|v| v.trim())
+    {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(value) => {
+            return Err(anyhow::anyhow!(
+                "invalid {} annotation {:?}, expected true or false",
+                READ_ONLY_ROOT_ANNOTATION,
+                value
+            ))
+        }
+    };
+    let requested = pod
+        .as_kube_pod()
+        .spec
+        .iter()
+        .flat_map(|s| {
+            s.containers
+                .iter()
+                .chain(s.init_containers.iter().flatten())
+        })
+        .find(|c| c.name == container.name())
+        .and_then(|c| c.security_context.as_ref())
+        .and_then(|s| s.read_only_root_filesystem)
+        .unwrap_or(false);
+    Ok(annotated || requested)
+}
+
+/// Annotation holding the most host files each of the pod's modules may have
+/// open at once. It can lower the node's `WASM3_MAX_OPEN_FILES` but not
+/// raise it.
+const MAX_OPEN_FILES_ANNOTATION: &str = "wasm3.krustlet.dev/max-open-files";
+
+/// The most files the container's module may have open at once, if it is
+/// capped. As with the read-only root, the pod's annotation does not apply
+/// to the node's sidecar.
+fn max_open_files(
+    pod_state: &PodState,
+    pod: &Pod,
     container: &Container,
-    volumes: &HashMap<String, Ref>,
-) -> anyhow::Result<HashMap<PathBuf, Option<PathBuf>>> {
-    if let Some(volume_mounts) = container.volume_mounts().as_ref() {
-        volume_mounts
+) -> anyhow::Result<Option<usize>> {
+    let node = pod_state.shared.config.max_open_files;
+    let value = match pod.annotations().get(MAX_OPEN_FILES_ANNOTATION) {
+        Some(_) if sidecar::is_sidecar(container.name()) => return Ok(node),
+        Some(value) => value,
+        None => return Ok(node),
+    };
+    match value.trim().parse::<usize>() {
+        Ok(max) if max > 0 => Ok(Some(node.map_or(max, |node| node.min(max)))),
+        _ => Err(anyhow::anyhow!(
+            "invalid {} annotation {:?}, expected a positive number",
+            MAX_OPEN_FILES_ANNOTATION,
+            value
+        )),
+    }
+}
+
+/// Annotation holding a comma separated list of host environment variables to
+/// pass into the pod's modules.
+const INHERIT_ENV_ANNOTATION: &str = "wasm3.krustlet.dev/inherit-env";
+
+/// Returns the host environment variables the pod asked to inherit that the
+/// node allows, along with the names that were refused.
+fn inherited_env(pod_state: &PodState, pod: &Pod) -> (HashMap<String, String>, Vec<String>) {
+    let mut env = HashMap::new();
+    let mut refused = Vec::new();
+    let requested = match pod.annotations().get(INHERIT_ENV_ANNOTATION) {
+        Some(requested) => requested,
+        None => return (env, refused),
+    };
+    let allowlist = &pod_state.shared.config.env_allowlist;
+    for name in requested
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+    {
+        if !allowlist.iter().any(|a| a == name) {
+            refused.push(name.to_owned());
+        } else if let Ok(value) = std::env::var(name) {
+            env.insert(name.to_owned(), value);
+        }
+    }
+    (env, refused)
+}
+
+/// Annotation asking for the memory a module's `_initialize` leaves behind
+/// to be snapshotted and restored on later starts, set to `true`.
+const SNAPSHOT_ANNOTATION: &str = "wasm3.krustlet.dev/snapshot";
+
+/// Whether the pod asked for initialization snapshots.
+fn snapshot(pod: &Pod) -> anyhow::Result<bool> {
+    match pod.annotations().get(SNAPSHOT_ANNOTATION).map(|v| v.trim()) {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(value) => Err(anyhow::anyhow!(
+            "invalid {} annotation {:?}, expected true or false",
+            SNAPSHOT_ANNOTATION,
+            value
+        )),
+    }
+}
+
+/// Annotation asking for the pod's modules to be run as reactors, set to
+/// `true`. A reactor's `_start` sets it up, after which the container keeps
+/// running and its exports can be called with `kubectl exec`.
+const REACTOR_ANNOTATION: &str = "wasm3.krustlet.dev/reactor";
+
+/// Whether the pod's modules are reactors.
+fn reactor(pod: &Pod) -> anyhow::Result<bool> {
+    match pod.annotations().get(REACTOR_ANNOTATION).map(|v| v.trim()) {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(value) => Err(anyhow::anyhow!(
+            "invalid {} annotation {:?}, expected true or false",
+            REACTOR_ANNOTATION,
+            value
+        )),
+    }
+}
+
+/// Annotation asking for pod metadata fields in the containers' arguments,
+/// such as `{podName}`, to be expanded, set to `true`.
+const ARG_TEMPLATES_ANNOTATION: &str = "wasm3.krustlet.dev/arg-templates";
+
+/// Whether the pod's arguments are templates.
+fn arg_templates(pod: &Pod) -> anyhow::Result<bool> {
+    match pod
+        .annotations()
+        .get(ARG_TEMPLATES_ANNOTATION)
+        .map(|v| v.trim())
+    {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(value) => Err(anyhow::anyhow!(
+            "invalid {} annotation {:?}, expected true or false",
+            ARG_TEMPLATES_ANNOTATION,
+            value
+        )),
+    }
+}
+
+/// Annotation holding the number of seconds a single run of a module may
+/// take before it is stopped with `DeadlineExceeded`.
+const TIME_BUDGET_ANNOTATION: &str = "wasm3.krustlet.dev/time-budget-secs";
+
+/// The time budget the pod asked for, or else the one of its runtime
+/// profile, if any.
+fn time_budget(
+    pod_state: &PodState,
+    pod: &Pod,
+    container: &str,
+) -> anyhow::Result<Option<Duration>> {
+    let value = match pod.annotations().get(TIME_BUDGET_ANNOTATION) {
+        Some(value) => value,
+        None => {
+            return Ok(
+                super::runtime_profile(&pod_state.shared.config, pod, container)?
+                    .and_then(|p| p.time_budget),
+            )
+        }
+    };
+    match value.trim().parse::<u64>() {
+        Ok(secs) if secs > 0 => Ok(Some(Duration::from_secs(secs))),
+        _ => Err(anyhow::anyhow!(
+            "invalid {} annotation {:?}, expected a positive number of seconds",
+            TIME_BUDGET_ANNOTATION,
+            value
+        )),
+    }
+}
+
+/// Annotation holding the number of seconds a run of one of the pod's modules
+/// may go without writing output or calling a host function before it is
+/// reported as idle.
+const IDLE_TIMEOUT_ANNOTATION: &str = "wasm3.krustlet.dev/idle-timeout-secs";
+
+/// Annotation choosing what happens to an idle run: `warn`, the default, or
+/// `restart`.
+const IDLE_ACTION_ANNOTATION: &str = "wasm3.krustlet.dev/idle-action";
+
+/// How the pod asked for idle runs of `container` to be handled, if it did.
+/// The node's sidecar is not the pod's, so it is not watched.
+fn idle_watch(pod: &Pod, container: &Container) -> anyhow::Result<Option<IdleWatch>> {
+    let restart = match pod
+        .annotations()
+        .get(IDLE_ACTION_ANNOTATION)
+        .map(|v| v.trim())
+    {
+        None | Some("warn") => false,
+        Some("restart") => true,
+        Some(value) => {
+            return Err(anyhow::anyhow!(
+                "invalid {} annotation {:?}, expected warn or restart",
+                IDLE_ACTION_ANNOTATION,
+                value
+            ))
+        }
+    };
+    let value = match pod.annotations().get(IDLE_TIMEOUT_ANNOTATION) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let timeout = match value.trim().parse::<u64>() {
+        Ok(secs) if secs > 0 => Duration::from_secs(secs),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "invalid {} annotation {:?}, expected a positive number of seconds",
+                IDLE_TIMEOUT_ANNOTATION,
+                value
+            ))
+        }
+    };
+    if sidecar::is_sidecar(container.name()) {
+        return Ok(None);
+    }
+    Ok(Some(IdleWatch { timeout, restart }))
+}
+
+/// Annotation choosing how the pod's module output is buffered: `line`,
+/// `block` or `unbuffered`.
+const STDOUT_BUFFERING_ANNOTATION: &str = "wasm3.krustlet.dev/stdout-buffering";
+
+/// Annotation setting the most bytes per second each of the pod's containers
+/// may write, such as `16Ki`. It can only lower the node's limit.
+const STDOUT_RATE_LIMIT_ANNOTATION: &str = "wasm3.krustlet.dev/stdout-rate-limit";
+
+/// The node's output policy with the buffering and rate limit the pod asked
+/// for, if any.
+fn stdout_policy(pod_state: &PodState, pod: &Pod) -> anyhow::Result<StdoutPolicy> {
+    let mut policy = pod_state.shared.config.stdout;
+    if let Some(value) = pod.annotations().get(STDOUT_BUFFERING_ANNOTATION) {
+        policy.buffering = value.parse().map_err(|e| {
+            anyhow::anyhow!("invalid {} annotation: {}", STDOUT_BUFFERING_ANNOTATION, e)
+        })?;
+    }
+    if let Some(value) = pod.annotations().get(STDOUT_RATE_LIMIT_ANNOTATION) {
+        let limit = match config::parse_quantity(value) {
+            Some(limit) if limit > 0 => limit,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "invalid {} annotation {:?}, expected a size such as 16Ki",
+                    STDOUT_RATE_LIMIT_ANNOTATION,
+                    value
+                ))
+            }
+        };
+        policy.rate_limit = Some(policy.rate_limit.map_or(limit, |node| node.min(limit)));
+    }
+    Ok(policy)
+}
+
+/// Annotation asking for the core the pod's instances are pinned to when the
+/// node runs the pinned executor.
+const CPU_ANNOTATION: &str = "wasm3.krustlet.dev/cpu";
+
+/// The core the pod's instances are pinned to, if the node pins them. It is
+/// assigned when the pod's first container starts and kept for the others
+/// and for restarts.
+fn pinned_cpu(pod_state: &mut PodState, pod: &Pod) -> anyhow::Result<Option<usize>> {
+    let pool = match &pod_state.shared.cpus {
+        Some(pool) => pool,
+        None => return Ok(None),
+    };
+    if let Some(cpu) = pod_state.run_context.cpu {
+        return Ok(Some(cpu));
+    }
+    let requested = match pod.annotations().get(CPU_ANNOTATION) {
+        Some(value) => Some(value.trim().parse::<usize>().map_err(|_| {
+            anyhow::anyhow!(
+                "invalid {} annotation {:?}, expected a core number",
+                CPU_ANNOTATION,
+                value
+            )
+        })?),
+        None => None,
+    };
+    let cpu = pool.assign(requested).map_err(|cpu| {
+        anyhow::anyhow!(
+            "core {} asked for by the {} annotation is not one of the node's cores {:?}",
+            cpu,
+            CPU_ANNOTATION,
+            pool.cpus()
+        )
+    })?;
+    pod_state.run_context.cpu = Some(cpu);
+    Ok(Some(cpu))
+}
+
+/// Prefix of the annotations declaring the containers a container depends on,
+/// e.g. `wasm3.krustlet.dev/depends-on.app: config-writer`.
+const DEPENDS_ON_ANNOTATION_PREFIX: &str = "wasm3.krustlet.dev/depends-on.";
+
+/// How long a container waits for each of its dependencies to be ready
+/// before the pod fails.
+const DEPENDENCY_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Orders the pod's containers so that every container comes after the
+/// containers it depends on, keeping declaration order otherwise. Each
+/// container is returned with the names of the containers it depends on,
+/// which [`wait_for_dependencies`] waits for before it is started.
+pub(super) fn start_order(pod: &Pod) -> anyhow::Result<Vec<(Container, Vec<String>)>> {
+    let mut pending = pod.containers();
+    let mut dependencies: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, value) in pod.annotations() {
+        let dependent = match key.strip_prefix(DEPENDS_ON_ANNOTATION_PREFIX) {
+            Some(dependent) => dependent,
+            None => continue,
+        };
+        let deps: Vec<String> = value
+            .split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+            .map(str::to_owned)
+            .collect();
+        for name in deps
             .iter()
-            .map(|vm| -> anyhow::Result<(PathBuf, Option<PathBuf>)> {
-                // Check the volume exists first
-                let vol = volumes.get(&vm.name).ok_or_else(|| {
-                    anyhow::anyhow!(
-                        "no volume with the name of {} found for container {}",
-                        vm.name,
-                        container.name()
-                    )
-                })?;
-                let mut guest_path = PathBuf::from(&vm.mount_path);
-                if let Some(sub_path) = &vm.sub_path {
-                    guest_path.push(sub_path);
-                }
-                // We can safely assume that this should be valid UTF-8 because it would have
-                // been validated by the k8s API
-                Ok((vol.deref().clone(), Some(guest_path)))
+            .map(String::as_str)
+            .chain(std::iter::once(dependent))
+        {
+            if !pending.iter().any(|c| c.name() == name) {
+                return Err(anyhow::anyhow!(
+                    "{}{} refers to unknown container {}",
+                    DEPENDS_ON_ANNOTATION_PREFIX,
+                    dependent,
+                    name
+                ));
+            }
+        }
+        dependencies.insert(dependent.to_owned(), deps);
+    }
+
+    let mut ordered: Vec<(Container, Vec<String>)> = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|c| {
+            dependencies.get(c.name()).map_or(true, |deps| {
+                deps.iter()
+                    .all(|d| ordered.iter().any(|(o, _)| o.name() == d))
             })
-            .collect::<anyhow::Result<HashMap<PathBuf, Option<PathBuf>>>>()
-    } else {
-        Ok(HashMap::default())
+        });
+        match ready {
+            Some(i) => {
+                let container = pending.remove(i);
+                let deps = dependencies.remove(container.name()).unwrap_or_default();
+                ordered.push((container, deps));
+            }
+            None => {
+                let names: Vec<&str> = pending.iter().map(|c| c.name()).collect();
+                return Err(anyhow::anyhow!(
+                    "container dependencies form a cycle between {}",
+                    names.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(ordered)
+}
+
+/// Waits for each container `container` depends on to be running and ready,
+/// or to have run to completion, for at most [`DEPENDENCY_TIMEOUT`] each. A
+/// dependency whose module imports `set_ready` is only ready once it says so.
+/// Fails if a dependency fails or does not become ready in time.
+pub(super) async fn wait_for_dependencies(
+    pod_state: &mut PodState,
+    container: &str,
+    dependencies: &[String],
+) -> anyhow::Result<()> {
+    for dependency in dependencies {
+        let clock = pod_state.shared.clock.clone();
+        let started = tokio::select! {
+            started = pod_state.run_context.status_recv.started(dependency) => Some(started),
+            _ = clock.sleep(DEPENDENCY_TIMEOUT) => None,
+        };
+        match started {
+            Some(Ok(())) => {}
+            Some(Err(message)) => {
+                return Err(anyhow::anyhow!(
+                    "container {} depends on {}, which failed: {}",
+                    container,
+                    dependency,
+                    message
+                ))
+            }
+            None => {
+                return Err(anyhow::anyhow!(
+                    "container {} depends on {}, which was not ready within {}s",
+                    container,
+                    dependency,
+                    DEPENDENCY_TIMEOUT.as_secs()
+                ))
+            }
+        }
     }
+    // Only statuses of the run about to start count for its own dependents
+    pod_state.run_context.status_recv.forget(container);
+    Ok(())
+}
+
+/// Whether the pod's containers should be memoized between restarts. A
+/// reactor's instance is always kept, since it serves calls after `_start`.
+fn memoize(pod_state: &PodState, pod: &Pod) -> anyhow::Result<bool> {
+    Ok(reactor(pod)?
+        || (pod_state.shared.config.memoize_modules && super::restart_policy(pod) == "Always"))
 }
 
+/// Starts `container`, returning its handle and the stopper of its instance.
 pub(crate) async fn start_container(
     pod_state: &mut PodState,
     pod: &Pod,
     container: &Container,
-) -> anyhow::Result<kubelet::container::Handle<wasi_runtime::Runtime, wasi_runtime::HandleFactory>>
-{
+) -> anyhow::Result<(
+    kubelet::container::Handle<wasi_runtime::Runtime, wasi_runtime::HandleFactory>,
+    Stopper,
+)> {
+    pod_state.shared.hooks.pre_start(pod, container.name())?;
+    pod_state
+        .run_context
+        .restart_counts
+        .entry(container.name().to_owned())
+        .and_modify(|n| *n += 1)
+        .or_insert(0);
+    if let Some(runtime) = pod_state.run_context.memoized.get(container.name()) {
+        logging::with_fields(Fields::pod(pod).container(container.name()), || {
+            debug!("Restarting memoized container {}", container.name())
+        });
+        let handle = runtime.start().await?;
+        return Ok((handle, runtime.stopper()));
+    }
+
     let module_data = pod_state
         .run_context
         .modules
-        .remove(container.name())
+        .get(container.name())
+        .cloned()
         .expect("FATAL ERROR: module map not properly populated");
+    let log_dir = log_files::container_log_dir(
+        &pod_state.shared.log_path,
+        pod.namespace(),
+        pod.name(),
+        container.name(),
+    );
+    let retention = pod_state.shared.config.log_retention;
+    let log_file =
+        tokio::task::spawn_blocking(move || log_files::create_instance_log(&log_dir, retention))
+            .await??;
+    let status_sender = pod_state.run_context.status_sender.clone();
+    let runtime = container_runtime(
+        pod_state,
+        pod,
+        container,
+        module_data,
+        log_file,
+        status_sender,
+        false,
+    )
+    .await?;
+
+    logging::with_fields(Fields::pod(pod).container(container.name()), || {
+        debug!("Starting container {} on thread", container.name())
+    });
+    let handle = runtime.start().await?;
+    let stopper = runtime.stopper();
+    pod_state
+        .shared
+        .debug_states
+        .write()
+        .await
+        .entry(pod_state.key.clone())
+        .or_default()
+        .insert(container.name().to_owned(), runtime.debug_state());
+    if let Some(queue) = runtime.work_queue() {
+        pod_state
+            .shared
+            .work_queues
+            .write()
+            .await
+            .entry(pod_state.key.clone())
+            .or_default()
+            .insert(container.name().to_owned(), queue);
+    }
+    if memoize(pod_state, pod)? {
+        pod_state
+            .run_context
+            .memoized
+            .insert(container.name().to_owned(), runtime);
+    }
+    Ok((handle, stopper))
+}
+
+/// Starts a shadow instance of `container` running `module_data`, to try a
+/// new module next to the running one before it replaces it. The instance
+/// reports to `status_sender` and writes its output to the container's
+/// shadow log rather than to the container log, and its metrics are not
+/// published. Nothing else is told about it, so requests and calls still go
+/// to the running instance.
+pub(crate) async fn start_shadow(
+    pod_state: &PodState,
+    pod: &Pod,
+    container: &Container,
+    module_data: wasi_runtime::ModuleData,
+    status_sender: StatusSender,
+) -> anyhow::Result<kubelet::container::Handle<wasi_runtime::Runtime, wasi_runtime::HandleFactory>>
+{
+    let log_dir = log_files::container_log_dir(
+        &pod_state.shared.log_path,
+        pod.namespace(),
+        pod.name(),
+        container.name(),
+    );
+    let log_file =
+        tokio::task::spawn_blocking(move || log_files::create_shadow_log(&log_dir)).await??;
+    let runtime = container_runtime(
+        pod_state,
+        pod,
+        container,
+        module_data,
+        log_file,
+        status_sender,
+        true,
+    )
+    .await?;
+    logging::with_fields(Fields::pod(pod).container(container.name()), || {
+        debug!(
+            "Starting shadow of container {} on thread",
+            container.name()
+        )
+    });
+    runtime.start().await
+}
+
+/// Sets up an instance of `container` running `module_data`. A `shadow`
+/// instance is never memoized, takes no snapshots and publishes no metrics.
+async fn container_runtime(
+    pod_state: &PodState,
+    pod: &Pod,
+    container: &Container,
+    module_data: wasi_runtime::ModuleData,
+    log_file: PathBuf,
+    status_sender: StatusSender,
+    shadow: bool,
+) -> anyhow::Result<WasiRuntime> {
     let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
-    let env = provider::env_vars(&container, pod, &client).await;
-    let args = container.args().clone().unwrap_or_default();
-    let container_volumes = volume_path_map(container, &pod_state.run_context.volumes)?;
+    let events = EventRecorder::new(client.clone(), pod, &pod_state.shared);
+    // Variables set on the container take priority over the node's, which
+    // take priority over inherited ones
+    let (mut env, refused) = inherited_env(pod_state, pod);
+    if !refused.is_empty() {
+        events
+            .warning(
+                "EnvNotAllowed",
+                &format!(
+                    "Host environment variables not allowed on this node were not inherited: {}",
+                    refused.join(", ")
+                ),
+            )
+            .await;
+    }
+    // wasm3's WASI only preopens the working directory, so volumes cannot
+    // be mounted. Say so once rather than on every restart.
+    let mounts: Vec<&str> = container
+        .volume_mounts()
+        .iter()
+        .flatten()
+        .map(|mount| mount.mount_path.as_str())
+        .collect();
+    if !mounts.is_empty() && pod_state.run_context.restart_count(container.name()) == 0 {
+        events
+            .warning(
+                "VolumeMountsNotProvided",
+                &format!(
+                    "Container {} mounts volumes at {}, but wasm3 modules get no volume mounts, so they are not provided",
+                    container.name(),
+                    mounts.join(", ")
+                ),
+            )
+            .await;
+    }
+    let (host_files, refused) = host_files(pod_state, pod);
+    if !refused.is_empty() {
+        events
+            .warning(
+                "HostFileNotAllowed",
+                &format!(
+                    "Host files not allowed on this node cannot be read: {}",
+                    refused.join(", ")
+                ),
+            )
+            .await;
+    }
+    env.extend(node_env::node_env(&pod_state.shared).await?);
+    env.extend(provider::env_vars(&container, pod, &client).await);
+    let mut args = container.args().clone().unwrap_or_default();
+    if arg_templates(pod)? {
+        args = crate::args::expand(&args, pod, container.name(), &pod_state.shared.node_name)?;
+    }
+    let invocation = match entrypoint::entrypoint(pod, container.name())? {
+        Some(_) if reactor(pod)? => {
+            return Err(anyhow::anyhow!(
+                "container {} is a reactor, so it cannot be started with a typed entrypoint",
+                container.name()
+            ))
+        }
+        Some(entrypoint) => Some(entrypoint.invocation(&args)?),
+        None => None,
+    };
+    let kv_dir = if pod_state.shared.config.host_kv {
+        Some(kv::pod_kv_dir(
+            &pod_state.shared.kv_path,
+            pod.namespace(),
+            pod.name(),
+        ))
+    } else {
+        None
+    };
 
-    let runtime = WasiRuntime::new(
+    Ok(WasiRuntime::new(
         container.name().to_owned(),
         module_data,
         env,
         args,
-        container_volumes,
-        pod_state.shared.log_path.clone(),
-        pod_state.run_context.status_sender.clone(),
-    )
-    .await?;
+        invocation,
+        FilePolicy {
+            read_only_root: read_only_root(pod, container)?,
+            max_open_files: max_open_files(pod_state, pod, container)?,
+        },
+        host_files,
+        super::validating::libraries_for(pod_state, container.name()),
+        pod_state.shared.hooks.host_extensions.clone(),
+        log_file,
+        status_sender,
+        events,
+        // A shadow run's steps would be mistaken for the container's
+        if shadow {
+            None
+        } else {
+            Some(LifecycleLog::new(
+                &pod_state.shared.log_path,
+                pod.namespace(),
+                pod.name(),
+                container.name(),
+                pod_state.shared.clock.clone(),
+            ))
+        },
+        ContainerMetrics::new(
+            if shadow {
+                Default::default()
+            } else {
+                pod_state.shared.metrics.clone()
+            },
+            pod.namespace(),
+            pod.name(),
+            container.name(),
+        ),
+        !shadow && memoize(pod_state, pod)?,
+        reactor(pod)?,
+        pod_state.shared.config.setup_timeouts,
+        stdout_policy(pod_state, pod)?,
+        kv_dir,
+        pod_state.run_context.pod_config.clone(),
+        if !shadow && snapshot(pod)? {
+            Some(pod_state.shared.snapshot_path.clone())
+        } else {
+            None
+        },
+        super::module_limits(pod_state, pod, container.name())
+            .stack_size
+            .unwrap_or(wasi_runtime::DEFAULT_STACK_SIZE),
+        time_budget(pod_state, pod, container.name())?,
+        idle_watch(pod, container)?,
+        pinned_cpu(pod_state, pod)?,
+        if pod_state.shared.config.host_watch {
+            Some(WatchScope {
+                client: client.clone(),
+                namespace: pod.namespace().to_owned(),
+                service_account: pod
+                    .as_kube_pod()
+                    .spec
+                    .as_ref()
+                    .and_then(|s| s.service_account_name.clone())
+                    .unwrap_or_else(|| "default".to_owned()),
+            })
+        } else {
+            None
+        },
+        pod_state.shared.burst.starts(),
+        pod_state.shared.clock.clone(),
+    ))
+}
 
-    debug!("Starting container {} on thread", container.name());
-    runtime.start().await
+/// Writes the pod's execution report, if the node attests pods, describing
+/// the modules its containers and the node's sidecar run now.
+pub(crate) fn attest(pod_state: &PodState, pod: &Pod) {
+    let attestor = match &pod_state.shared.attestor {
+        Some(attestor) => attestor,
+        None => return,
+    };
+    let config = &pod_state.shared.config;
+    let (host_files, _) = host_files(pod_state, pod);
+    let mut reports = Vec::new();
+    for container in pod
+        .containers()
+        .into_iter()
+        .chain(sidecar::container(config))
+    {
+        let module = match pod_state.run_context.modules.get(container.name()) {
+            Some(module) => module.clone(),
+            None => continue,
+        };
+        let mut capabilities = Vec::new();
+        if config.host_kv {
+            capabilities.push("kv");
+        }
+        if config.host_watch {
+            capabilities.push("k8s-watch");
+        }
+        if !host_files.is_empty() {
+            capabilities.push("host-files");
+        }
+        if !super::validating::libraries_for(pod_state, container.name()).is_empty() {
+            capabilities.push("libraries");
+        }
+        if read_only_root(pod, &container).unwrap_or(false) {
+            capabilities.push("read-only-root");
+        }
+        if let Ok(Some(_)) = max_open_files(pod_state, pod, &container) {
+            capabilities.push("max-open-files");
+        }
+        reports.push(ContainerReport {
+            name: container.name().to_owned(),
+            image_id: pod_state
+                .run_context
+                .pinned_images
+                .get(container.name())
+                .map(|p| p.image_id.clone()),
+            module,
+            wasi_profile: super::validating::wasi_profile(
+                &pod_state.shared.config,
+                pod,
+                container.name(),
+            )
+            .unwrap_or_default()
+            .to_string(),
+            capabilities,
+        });
+    }
+    let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+    attestor.attest(client, pod, reports);
+}
+
+/// The gateway route the pod asked for, checking its handler can be called.
+fn http_route(pod_state: &PodState, pod: &Pod) -> anyhow::Result<Option<gateway::Route>> {
+    let route = match gateway::route(pod)? {
+        Some(route) => route,
+        None => return Ok(None),
+    };
+    if pod_state.shared.config.gateway_addr.is_none() {
+        logging::with_fields(Fields::pod(pod), || {
+            warn!("Pod asks for an HTTP route, but the gateway is not enabled on this node")
+        });
+        return Ok(None);
+    }
+    if !memoize(pod_state, pod)? {
+        return Err(anyhow::anyhow!(
+            "container {} handles HTTP requests, so it must be a reactor or memoized",
+            route.container
+        ));
+    }
+    Ok(Some(route))
 }
 
 pub(crate) type ContainerHandleMap =
@@ -103,28 +873,101 @@ impl State<PodState> for Starting {
         pod: &Pod,
     ) -> anyhow::Result<Transition<PodState>> {
         let mut container_handles: ContainerHandleMap = HashMap::new();
+        // Init containers have all exited by now, so only the instances
+        // started here need stopping
+        let mut stoppers = Vec::new();
 
         {
             let mut lock = self.init_handles.lock().await;
             container_handles.extend((*lock).drain())
         }
 
-        info!("Starting containers for pod {:?}", pod.name());
-        for container in pod.containers() {
-            let container_handle = start_container(pod_state, &pod, &container).await?;
+        let containers = match start_order(pod) {
+            Ok(containers) => containers,
+            Err(e) => {
+                let message = e.to_string();
+                return Ok(Transition::next(self, Error { message }));
+            }
+        };
+        let route = match http_route(pod_state, pod) {
+            Ok(route) => route,
+            Err(e) => {
+                let message = e.to_string();
+                return Ok(Transition::next(self, Error { message }));
+            }
+        };
+        logging::with_fields(Fields::pod(pod).phase("Starting"), || {
+            info!("Starting containers for pod {:?}", pod.name())
+        });
+        for (container, dependencies) in containers {
+            // Containers started so far are dropped with their handles
+            if super::deleted_during_setup(pod_state).await {
+                return Ok(Transition::next(self, Terminated));
+            }
+            if let Err(e) = wait_for_dependencies(pod_state, container.name(), &dependencies).await
+            {
+                let message = e.to_string();
+                return Ok(Transition::next(self, Error { message }));
+            }
+            let container_handle = match start_container(pod_state, &pod, &container).await {
+                Ok((handle, stopper)) => {
+                    stoppers.push(stopper);
+                    handle
+                }
+                Err(e) => {
+                    logging::with_fields(
+                        Fields::pod(pod)
+                            .container(container.name())
+                            .phase("Starting"),
+                        || error!("Unable to start container {}: {:?}", container.name(), e),
+                    );
+                    let message = e.to_string();
+                    return Ok(Transition::next(self, Error { message }));
+                }
+            };
             container_handles.insert(
                 ContainerKey::App(container.name().to_string()),
                 container_handle,
             );
         }
+        if let Some(sidecar) = sidecar::container(&pod_state.shared.config) {
+            // The sidecar is an extra, so the pod runs without it
+            match start_container(pod_state, &pod, &sidecar).await {
+                Ok((handle, stopper)) => {
+                    stoppers.push(stopper);
+                    container_handles.insert(ContainerKey::App(sidecar.name().to_string()), handle);
+                }
+                Err(e) => logging::with_fields(Fields::pod(pod).phase("Starting"), || {
+                    error!("Unable to start sidecar for pod {}: {:?}", pod.name(), e)
+                }),
+            }
+        }
 
         let pod_handle = Handle::new(container_handles, pod.clone(), None).await?;
         let pod_key = key_from_pod(&pod);
-        {
-            let mut handles = pod_state.shared.handles.write().await;
-            handles.insert(pod_key, pod_handle);
+        pod_state
+            .shared
+            .handles
+            .insert(pod_key, pod_handle, stoppers)
+            .await;
+        if let Some(route) = route {
+            pod_state.shared.routes.insert(&pod_state.key, route);
         }
         info!("All containers started for pod {:?}.", pod.name());
+        if let Some(webhook) = &pod_state.shared.webhook {
+            webhook.notify(pod, LifecycleEvent::PodStarted);
+        }
+        // Before the modules are dropped in low memory mode
+        attest(pod_state, pod);
+        if pod_state.shared.config.low_memory {
+            // Running instances hold their own reference, so this only stops
+            // the modules being kept for restarts, which load them again
+            // from the store
+            pod_state.run_context.modules.clear();
+            pod_state.run_context.module_images.clear();
+            pod_state.run_context.libraries.clear();
+            pod_state.run_context.library_images.clear();
+        }
 
         Ok(Transition::next(self, Running))
     }
@@ -139,3 +982,5 @@ impl State<PodState> for Starting {
 }
 
 impl TransitionTo<Running> for Starting {}
+impl TransitionTo<Error> for Starting {}
+impl TransitionTo<Terminated> for Starting {}
@@ -1,5 +1,35 @@
+use std::time::Duration;
+
+use crate::handles;
+use crate::logging::{self, Fields};
+use crate::status;
 use crate::PodState;
 use kubelet::state::prelude::*;
+use log::info;
+
+/// Returns true if the pod was deleted with `--grace-period=0 --force`, in
+/// which case it is already gone from the API.
+fn force_deleted(pod: &Pod) -> bool {
+    pod.as_kube_pod().metadata.deletion_grace_period_seconds == Some(0)
+}
+
+/// How long the pod's instances are given to exit: the grace period it was
+/// deleted with, or else its termination grace period.
+fn grace_period(pod: &Pod) -> Duration {
+    let kube_pod = pod.as_kube_pod();
+    kube_pod
+        .metadata
+        .deletion_grace_period_seconds
+        .or_else(|| {
+            kube_pod
+                .spec
+                .as_ref()
+                .and_then(|s| s.termination_grace_period_seconds)
+        })
+        .map_or(handles::STOP_TIMEOUT, |secs| {
+            Duration::from_secs(secs.max(0) as u64)
+        })
+}
 
 /// Pod was deleted.
 #[derive(Default, Debug)]
@@ -10,11 +40,40 @@ impl State<PodState> for Terminated {
     async fn next(
         self: Box<Self>,
         pod_state: &mut PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<Transition<PodState>> {
-        let mut lock = pod_state.shared.handles.write().await;
-        if let Some(handle) = lock.get_mut(&pod_state.key) {
-            handle.stop().await?;
+        // Dropping memoized runtimes closes their work queues, which only
+        // they hold, so their threads exit once they are done with the work
+        // in hand instead of waiting for a restart that will never come
+        pod_state.run_context.memoized.clear();
+        if force_deleted(pod) {
+            // There is nothing to wait for or report to, so drop the handle
+            // and stop listening to the instances. wasm3 cannot interrupt a
+            // module, so a running one ends when `_start` returns and
+            // anything it reports after that is discarded.
+            logging::with_fields(Fields::pod(pod).phase("Terminated"), || {
+                info!(
+                    "Pod {} was force deleted, discarding its instances",
+                    pod_state.key
+                )
+            });
+            pod_state.shared.handles.remove(&pod_state.key).await;
+            let (tx, rx) = status::channel();
+            pod_state.run_context.status_sender = tx;
+            pod_state.run_context.status_recv = rx;
+            return Ok(Transition::Complete(Ok(())));
+        }
+        if let Some(handle) = pod_state.shared.handles.get(&pod_state.key).await {
+            // The pod is going away either way, so instances that do not
+            // exit within the grace period are left to exit on their own
+            handles::stop(
+                handle,
+                &pod_state.key,
+                grace_period(pod),
+                pod_state.shared.clock.as_ref(),
+                async {},
+            )
+            .await;
         }
         Ok(Transition::Complete(Ok(())))
     }
@@ -0,0 +1,242 @@
+use std::sync::Arc;
+
+use kubelet::state::prelude::*;
+use log::error;
+
+use crate::burst::Validated;
+use crate::config::{ModuleLimits, ProviderConfig};
+use crate::dead_letter;
+use crate::entrypoint::{self, Entrypoint};
+use crate::events::EventRecorder;
+use crate::hooks::HostExtension;
+use crate::logging::{self, Fields};
+use crate::sidecar;
+use crate::stack_tuning;
+use crate::validation::{self, ValidationError, WasiProfile};
+use crate::wasi_runtime::ModuleData;
+use crate::PodState;
+
+use super::error::Error;
+use super::volume_mount::VolumeMount;
+
+/// The function modules are started through, unless the pod names another.
+const ENTRYPOINT: &str = "_start";
+
+/// Annotation naming the WASI profile that limits what the pod's modules may
+/// import: `strict`, `standard` or `permissive`.
+const WASI_PROFILE_ANNOTATION: &str = "wasm3.krustlet.dev/wasi-profile";
+
+/// The WASI profile for `container`, from the pod's annotation or else its
+/// runtime profile. The node's sidecar is trusted by the node, so the pod's
+/// profile does not apply to it.
+pub(super) fn wasi_profile(
+    config: &ProviderConfig,
+    pod: &Pod,
+    container: &str,
+) -> anyhow::Result<WasiProfile> {
+    if sidecar::is_sidecar(container) {
+        return Ok(WasiProfile::Permissive);
+    }
+    match pod.annotations().get(WASI_PROFILE_ANNOTATION) {
+        Some(value) => value
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid {} annotation: {}", WASI_PROFILE_ANNOTATION, e)),
+        None => Ok(super::runtime_profile(config, pod, container)?
+            .and_then(|p| p.wasi_profile)
+            .unwrap_or_default()),
+    }
+}
+
+/// The libraries `container` is linked with. The node's sidecar does not
+/// belong to the pod, so it is not linked with the pod's libraries.
+pub(super) fn libraries_for(pod_state: &PodState, container: &str) -> Vec<(String, ModuleData)> {
+    if sidecar::is_sidecar(container) {
+        return Vec::new();
+    }
+    pod_state
+        .run_context
+        .libraries
+        .iter()
+        .map(|(name, data)| (name.clone(), data.clone()))
+        .collect()
+}
+
+/// Kubelet is checking that the pulled modules can be run.
+#[derive(Default, Debug)]
+pub struct Validating;
+
+#[async_trait::async_trait]
+impl State<PodState> for Validating {
+    async fn next(
+        self: Box<Self>,
+        pod_state: &mut PodState,
+        pod: &Pod,
+    ) -> anyhow::Result<Transition<PodState>> {
+        if let Err(e) = stack_tuning::validate(pod) {
+            let message = e.to_string();
+            return Ok(Transition::next(self, Error { message }));
+        }
+        if let Err(e) = super::reloading::shadow_window(pod) {
+            let message = e.to_string();
+            return Ok(Transition::next(self, Error { message }));
+        }
+        if let Err(e) = dead_letter::sink(pod) {
+            let message = e.to_string();
+            return Ok(Transition::next(self, Error { message }));
+        }
+        for container in pod.containers() {
+            if let Err(e) = super::runtime_profile(&pod_state.shared.config, pod, container.name())
+            {
+                let message = e.to_string();
+                return Ok(Transition::next(self, Error { message }));
+            }
+        }
+        let mut limited = Vec::new();
+        for (container, data) in pod_state.run_context.modules.iter() {
+            let limits = super::module_limits(pod_state, pod, container);
+            let profile = match wasi_profile(&pod_state.shared.config, pod, container) {
+                Ok(profile) => profile,
+                Err(e) => {
+                    let message = e.to_string();
+                    return Ok(Transition::next(self, Error { message }));
+                }
+            };
+            let entrypoint = match entrypoint::entrypoint(pod, container) {
+                Ok(entrypoint) => entrypoint,
+                Err(e) => {
+                    let message = e.to_string();
+                    return Ok(Transition::next(self, Error { message }));
+                }
+            };
+            let libraries = libraries_for(pod_state, container);
+            match prepare(
+                pod_state,
+                data.clone(),
+                limits,
+                profile,
+                entrypoint,
+                libraries,
+            )
+            .await?
+            {
+                Ok(Some(module)) => limited.push((container.clone(), module)),
+                Ok(None) => (),
+                Err(e) => {
+                    return Ok(Transition::next(
+                        self,
+                        fail(pod_state, pod, container, e).await,
+                    ))
+                }
+            }
+        }
+        pod_state.run_context.modules.extend(limited);
+        Ok(Transition::next(self, VolumeMount))
+    }
+
+    async fn json_status(
+        &self,
+        _pod_state: &mut PodState,
+        _pod: &Pod,
+    ) -> anyhow::Result<serde_json::Value> {
+        make_status(Phase::Pending, "Validating")
+    }
+}
+
+/// Checks that a module and the libraries it is linked with can be run within
+/// `limits` and `profile`, through the pod's typed `entrypoint` if it has
+/// one, with the host extensions the provider was built with, and caps the
+/// module's memory at their memory pages. Returns the module to run instead
+/// if it had to be rewritten for the cap. Pods checking the same module
+/// against the same things share the check, unless it is linked with
+/// libraries.
+pub(super) async fn prepare(
+    pod_state: &PodState,
+    module: ModuleData,
+    limits: ModuleLimits,
+    profile: WasiProfile,
+    entrypoint: Option<Entrypoint>,
+    libraries: Vec<(String, ModuleData)>,
+) -> anyhow::Result<Validated> {
+    let kv = pod_state.shared.config.host_kv;
+    let extensions = pod_state.shared.hooks.host_extensions.clone();
+    let checks = format!("{:?}", (kv, limits, profile, &entrypoint));
+    let shared = libraries.is_empty();
+    let check = check(
+        module.clone(),
+        kv,
+        limits,
+        profile,
+        entrypoint,
+        libraries,
+        extensions,
+    );
+    if shared {
+        pod_state
+            .shared
+            .burst
+            .validate(&module, &checks, check)
+            .await
+    } else {
+        check.await
+    }
+}
+
+/// The checks of [`prepare`], run on a blocking thread.
+async fn check(
+    module: ModuleData,
+    kv: bool,
+    limits: ModuleLimits,
+    profile: WasiProfile,
+    entrypoint: Option<Entrypoint>,
+    libraries: Vec<(String, ModuleData)>,
+    extensions: Vec<Arc<dyn HostExtension>>,
+) -> anyhow::Result<Validated> {
+    let result = tokio::task::spawn_blocking(move || {
+        let libraries = libraries
+            .iter()
+            .map(|(name, data)| {
+                validation::validate_library(data, limits.max_module_size, profile)
+                    .map(|info| (name.clone(), info))
+                    .map_err(|e| ValidationError::LibraryLink(format!("{}: {}", name, e)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let info = validation::validate(
+            &module,
+            entrypoint.as_ref().map_or(ENTRYPOINT, |e| e.name.as_str()),
+            kv,
+            limits.max_module_size,
+            profile,
+            &libraries,
+            &extensions,
+        )?;
+        if let Some(entrypoint) = &entrypoint {
+            validation::check_entrypoint(&info, entrypoint)?;
+        }
+        match limits.memory_pages {
+            Some(pages) => Ok(validation::limit_memory(&module, pages)?.map(ModuleData::from)),
+            None => Ok(None),
+        }
+    })
+    .await?;
+    Ok(result)
+}
+
+/// Reports a module that cannot be run, using the validation reason as the
+/// pod status reason.
+async fn fail(pod_state: &PodState, pod: &Pod, container: &str, e: ValidationError) -> Error {
+    let message = format!("container {}: {}", container, e);
+    logging::with_fields(
+        Fields::pod(pod).container(container).phase("Validating"),
+        || error!("Invalid module for pod {}: {}", pod.name(), message),
+    );
+    let client = kube::Client::new(pod_state.shared.kubeconfig.clone());
+    EventRecorder::new(client, pod, &pod_state.shared)
+        .warning(e.reason(), &message)
+        .await;
+    Error {
+        message: format!("{}: {}", e.reason(), message),
+    }
+}
+
+impl TransitionTo<VolumeMount> for Validating {}
+impl TransitionTo<Error> for Validating {}
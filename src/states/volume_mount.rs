@@ -1,3 +1,5 @@
+use crate::logging::{self, Fields};
+use crate::pod_config;
 use crate::PodState;
 use kubelet::state::prelude::*;
 use kubelet::volume::Ref;
@@ -22,13 +24,31 @@ impl State<PodState> for VolumeMount {
             match Ref::volumes_from_pod(&pod_state.shared.volume_path, &pod, &client).await {
                 Ok(volumes) => volumes,
                 Err(e) => {
-                    error!("{:?}", e);
+                    logging::with_fields(Fields::pod(pod).phase("VolumeMount"), || {
+                        error!("{:?}", e)
+                    });
                     let error_state = Error {
                         message: e.to_string(),
                     };
                     return Ok(Transition::next(self, error_state));
                 }
             };
+        // The volumes hold the same data, so a pod that got this far can
+        // read it
+        if let Err(e) = pod_config::start(
+            &pod_state.shared,
+            &pod_state.key,
+            pod,
+            &pod_state.run_context.pod_config,
+        )
+        .await
+        {
+            logging::with_fields(Fields::pod(pod).phase("VolumeMount"), || {
+                error!("Unable to load config data: {:?}", e)
+            });
+            let message = format!("unable to load config data: {}", e);
+            return Ok(Transition::next(self, Error { message }));
+        }
         Ok(Transition::next(self, Initializing))
     }
 
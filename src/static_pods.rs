@@ -0,0 +1,372 @@
+//! Static pods: pods defined by manifest files in the directory named by
+//! `WASM3_POD_MANIFEST_PATH`, as the kubelet's `--pod-manifest-path` does.
+//! They are started as soon as the provider is, without waiting for the API
+//! server, so an edge node can bring up what it needs to join the cluster,
+//! such as a VPN or a local registry mirror.
+//!
+//! Each static pod is named `<name>-<node name>`, as on other kubelets. Once
+//! the API server can be reached, a mirror pod of the same name is created
+//! there so the pod and its status show up in `kubectl`. A mirror is only a
+//! view of the static pod: the kubelet's own pod watch does not run it, and
+//! it is created again if it is deleted.
+//!
+//! The directory is read again every [`CHECK_INTERVAL`]. A pod whose file
+//! changed is stopped and started from the new manifest, and one whose file
+//! was removed is stopped and its mirror deleted. A file that cannot be read
+//! as a pod is logged and leaves whatever it last started running.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use k8s_openapi::api::core::v1::Pod as KubePod;
+use kube::api::{Api, DeleteParams, ListParams, PostParams};
+use kubelet::pod::{key_from_pod, Pod};
+use kubelet::provider::Provider;
+use kubelet::state::{run_to_completion, AsyncDrop};
+use log::{debug, error, info, warn};
+use sha2::{Digest, Sha256};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::states::is_not_found;
+use crate::states::registered::Registered;
+use crate::states::terminated::Terminated;
+use crate::{SharedPodState, WasiProvider};
+
+/// Annotation other kubelets set on pods read from a file.
+const CONFIG_SOURCE_ANNOTATION: &str = "kubernetes.io/config.source";
+
+/// Annotation holding the hash of a static pod's manifest.
+const CONFIG_HASH_ANNOTATION: &str = "kubernetes.io/config.hash";
+
+/// Annotation marking a pod in the API server as the mirror of a static pod,
+/// holding the hash of the manifest it mirrors.
+const CONFIG_MIRROR_ANNOTATION: &str = "kubernetes.io/config.mirror";
+
+/// How often the manifest directory is read again, as other kubelets do.
+const CHECK_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Returns true if `pod` is the mirror of a static pod, which runs from its
+/// manifest rather than from the API server.
+pub(crate) fn is_mirror(pod: &Pod) -> bool {
+    pod.annotations().contains_key(CONFIG_MIRROR_ANNOTATION)
+}
+
+/// The keys of the static pods running on the node.
+#[derive(Default)]
+pub(crate) struct StaticPods(Mutex<HashSet<String>>);
+
+impl StaticPods {
+    /// Returns true if the pod with `key` is a static pod.
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.0.lock().unwrap().contains(key)
+    }
+}
+
+/// A manifest file as it was last read.
+struct Manifest {
+    hash: String,
+    pod: anyhow::Result<Pod>,
+}
+
+/// A static pod that has been started.
+struct Running {
+    pod: Pod,
+    /// The hash of the manifest it was started from
+    hash: String,
+    stop_tx: oneshot::Sender<()>,
+    /// Finishes once the pod has stopped and been cleaned up
+    task: JoinHandle<()>,
+    /// Set while its mirror cannot be written, so the failure is only logged
+    /// once
+    mirror_failing: bool,
+}
+
+impl Running {
+    /// Stops the pod and waits until it has been cleaned up.
+    async fn stop(self) {
+        // A pod that has already completed is not listening
+        let _ = self.stop_tx.send(());
+        let _ = self.task.await;
+    }
+}
+
+/// Runs the static pods in `dir` and keeps their mirrors up to date. Runs
+/// until the process exits.
+pub(crate) async fn static_pod_loop(shared: SharedPodState, dir: PathBuf) {
+    let mut running: HashMap<PathBuf, Running> = HashMap::new();
+    // The hash of each manifest that could not be read as a pod, so it is
+    // only logged once
+    let mut invalid: HashMap<PathBuf, String> = HashMap::new();
+    loop {
+        let read = {
+            let dir = dir.clone();
+            let node_name = shared.node_name.clone();
+            tokio::task::spawn_blocking(move || read_manifests(&dir, &node_name)).await
+        };
+        match read {
+            Ok(Ok(manifests)) => sync(&shared, &mut running, &mut invalid, manifests).await,
+            // The pods already running are left alone until the directory
+            // can be read again
+            Ok(Err(e)) => error!(
+                "Unable to read static pod manifests in {}: {:?}",
+                dir.display(),
+                e
+            ),
+            Err(e) => error!("Unable to read static pod manifests: {:?}", e),
+        }
+        for pod in running.values_mut() {
+            match sync_mirror(&shared, pod).await {
+                Ok(()) => pod.mirror_failing = false,
+                Err(e) if pod.mirror_failing => {
+                    debug!(
+                        "Mirror of static pod {} still failing: {:?}",
+                        pod.pod.name(),
+                        e
+                    )
+                }
+                Err(e) => {
+                    warn!(
+                        "Unable to write mirror of static pod {}, will retry: {:?}",
+                        pod.pod.name(),
+                        e
+                    );
+                    pod.mirror_failing = true;
+                }
+            }
+        }
+        if let Err(e) = remove_stale_mirrors(&shared).await {
+            debug!("Unable to remove mirrors of removed static pods: {:?}", e);
+        }
+        tokio::time::delay_for(CHECK_INTERVAL).await;
+    }
+}
+
+/// Reads the pod manifests in `dir`, skipping hidden files and files that
+/// are not YAML or JSON, as other kubelets do. This does blocking IO.
+fn read_manifests(dir: &Path, node_name: &str) -> anyhow::Result<HashMap<PathBuf, Manifest>> {
+    let mut manifests = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map_or(true, |n| n.starts_with('.'));
+        let manifest = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map_or(false, |e| matches!(e, "yaml" | "yml" | "json"));
+        if hidden || !manifest || !path.is_file() {
+            continue;
+        }
+        let raw = match std::fs::read(&path) {
+            Ok(raw) => raw,
+            // Kept as an invalid manifest, so its pod is not stopped as if
+            // the file had been removed
+            Err(e) => {
+                let pod = Err(anyhow::anyhow!("unable to read the manifest: {}", e));
+                let hash = String::new();
+                manifests.insert(path, Manifest { hash, pod });
+                continue;
+            }
+        };
+        let hash = hex::encode(Sha256::digest(&raw))[..32].to_owned();
+        let pod = parse(&raw, &hash, node_name);
+        manifests.insert(path, Manifest { hash, pod });
+    }
+    Ok(manifests)
+}
+
+/// Reads a manifest as a pod on this node, named and annotated as other
+/// kubelets name and annotate static pods. The manifest's hash is its UID,
+/// so a changed manifest is a new pod.
+fn parse(raw: &[u8], hash: &str, node_name: &str) -> anyhow::Result<Pod> {
+    let mut pod: KubePod = serde_yaml::from_slice(raw)?;
+    let name = pod
+        .metadata
+        .name
+        .take()
+        .filter(|n| !n.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("the manifest has no metadata.name"))?;
+    pod.metadata.name = Some(format!("{}-{}", name, node_name));
+    if pod
+        .metadata
+        .namespace
+        .as_deref()
+        .map_or(true, str::is_empty)
+    {
+        pod.metadata.namespace = Some("default".to_owned());
+    }
+    pod.metadata.uid = Some(hash.to_owned());
+    pod.metadata.resource_version = None;
+    let annotations = pod.metadata.annotations.get_or_insert_with(BTreeMap::new);
+    annotations.insert(CONFIG_SOURCE_ANNOTATION.to_owned(), "file".to_owned());
+    annotations.insert(CONFIG_HASH_ANNOTATION.to_owned(), hash.to_owned());
+    // A manifest copied from a mirror must not look like one
+    annotations.remove(CONFIG_MIRROR_ANNOTATION);
+    pod.spec.get_or_insert_with(Default::default).node_name = Some(node_name.to_owned());
+    pod.status = None;
+    Ok(Pod::new(pod))
+}
+
+/// Stops the pods whose manifest changed or was removed, then starts those
+/// not running yet.
+async fn sync(
+    shared: &SharedPodState,
+    running: &mut HashMap<PathBuf, Running>,
+    invalid: &mut HashMap<PathBuf, String>,
+    manifests: HashMap<PathBuf, Manifest>,
+) {
+    invalid.retain(|path, _| manifests.contains_key(path));
+    let outdated: Vec<PathBuf> = running
+        .iter()
+        .filter(|(path, pod)| match manifests.get(*path) {
+            Some(manifest) => manifest.hash != pod.hash && manifest.pod.is_ok(),
+            None => true,
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+    for path in outdated {
+        let pod = match running.remove(&path) {
+            Some(pod) => pod,
+            None => continue,
+        };
+        let key = key_from_pod(&pod.pod);
+        info!("Stopping static pod {} from {}", key, path.display());
+        // Waited for, so a pod started from the changed manifest does not
+        // share its key with one still running
+        pod.stop().await;
+        shared.static_pods.0.lock().unwrap().remove(&key);
+    }
+    for (path, manifest) in manifests {
+        if running.contains_key(&path) {
+            continue;
+        }
+        match manifest.pod {
+            Ok(pod) => {
+                invalid.remove(&path);
+                let key = key_from_pod(&pod);
+                info!("Starting static pod {} from {}", key, path.display());
+                shared.static_pods.0.lock().unwrap().insert(key);
+                running.insert(path, start(shared, pod, manifest.hash));
+            }
+            Err(e) => {
+                if invalid.get(&path) != Some(&manifest.hash) {
+                    error!("Invalid static pod manifest {}: {:#}", path.display(), e);
+                    invalid.insert(path, manifest.hash);
+                }
+            }
+        }
+    }
+}
+
+/// Starts running a static pod in the background.
+fn start(shared: &SharedPodState, pod: Pod, hash: String) -> Running {
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let provider = WasiProvider {
+        shared: shared.clone(),
+    };
+    let task = tokio::spawn(run(provider, pod.clone(), stop_rx));
+    Running {
+        pod,
+        hash,
+        stop_tx,
+        task,
+        mirror_failing: false,
+    }
+}
+
+/// Takes a static pod through the same states as any other pod, until it
+/// completes or `stop` resolves, when it is terminated as a deleted pod is.
+/// Status updates go to its mirror, and are lost while there is none.
+async fn run(provider: WasiProvider, pod: Pod, stop: oneshot::Receiver<()>) {
+    let key = key_from_pod(&pod);
+    let client = kube::Client::new(provider.shared.kubeconfig.clone());
+    let mut pod_state = match provider.initialize_pod_state(&pod).await {
+        Ok(pod_state) => pod_state,
+        Err(e) => {
+            error!("Unable to start static pod {}: {:?}", key, e);
+            return;
+        }
+    };
+    let result = tokio::select! {
+        result = run_to_completion(&client, Registered, &mut pod_state, &pod) => result,
+        _ = stop => run_to_completion(&client, Terminated, &mut pod_state, &pod).await,
+    };
+    if let Err(e) = result {
+        error!("Static pod {} failed: {:?}", key, e);
+    }
+    pod_state.async_drop().await;
+}
+
+/// Deletes mirrors at once rather than waiting on a kubelet to confirm, as
+/// the static pod is stopped separately.
+fn mirror_delete_params() -> DeleteParams {
+    DeleteParams {
+        grace_period_seconds: Some(0),
+        ..Default::default()
+    }
+}
+
+/// Creates the mirror of a static pod if it has none, and replaces a mirror
+/// of an older manifest.
+async fn sync_mirror(shared: &SharedPodState, running: &Running) -> anyhow::Result<()> {
+    let pod = &running.pod;
+    let api: Api<KubePod> = Api::namespaced(
+        kube::Client::new(shared.kubeconfig.clone()),
+        pod.namespace(),
+    );
+    match api.get(pod.name()).await {
+        Ok(existing) => {
+            let existing = Pod::new(existing);
+            return match existing.annotations().get(CONFIG_MIRROR_ANNOTATION) {
+                Some(hash) if *hash == running.hash => Ok(()),
+                // An outdated mirror is created again once it is gone
+                Some(_) if existing.deletion_timestamp().is_some() => Ok(()),
+                Some(_) => {
+                    api.delete(pod.name(), &mirror_delete_params()).await?;
+                    Ok(())
+                }
+                None => Err(anyhow::anyhow!(
+                    "pod {} already exists and is not a mirror of the static pod",
+                    key_from_pod(pod)
+                )),
+            };
+        }
+        Err(e) if is_not_found(&e) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let mut mirror = pod.as_kube_pod().clone();
+    mirror.metadata.uid = None;
+    mirror
+        .metadata
+        .annotations
+        .get_or_insert_with(BTreeMap::new)
+        .insert(CONFIG_MIRROR_ANNOTATION.to_owned(), running.hash.clone());
+    api.create(&PostParams::default(), &mirror).await?;
+    info!("Created mirror pod for static pod {}", key_from_pod(pod));
+    Ok(())
+}
+
+/// Deletes the mirrors on this node whose static pod is no longer running,
+/// including those left from before the provider last started.
+async fn remove_stale_mirrors(shared: &SharedPodState) -> anyhow::Result<()> {
+    let client = kube::Client::new(shared.kubeconfig.clone());
+    let api: Api<KubePod> = Api::all(client.clone());
+    let params = ListParams::default().fields(&format!("spec.nodeName={}", shared.node_name));
+    for pod in api.list(&params).await?.items.into_iter().map(Pod::new) {
+        let key = key_from_pod(&pod);
+        if !is_mirror(&pod) || shared.static_pods.contains(&key) {
+            continue;
+        }
+        let api: Api<KubePod> = Api::namespaced(client.clone(), pod.namespace());
+        match api.delete(pod.name(), &mirror_delete_params()).await {
+            Ok(_) => info!("Deleted mirror pod {} of a removed static pod", key),
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => warn!("Unable to delete mirror pod {}: {:?}", key, e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,279 @@
+//! The channel container statuses travel on from instances to the pod's
+//! state machine.
+//!
+//! Instances report from their own threads and must never block on a
+//! receiver that is busy patching the API, so the channel keeps only what
+//! the receiver still needs: the latest status of each container. A status
+//! that is overtaken before it is received is dropped, except for
+//! terminations, which end a run and are always delivered in order. The
+//! receiver remembers what it last patched for each container, so a status
+//! that changes nothing is not sent to the API server again. A receiver
+//! that falls behind loses nothing it needs: it is handed the latest status
+//! of every container as soon as it asks.
+//!
+//! Modules that import `set_ready` report their own readiness alongside the
+//! status, see [`StatusSender::set_ready`]. A change of readiness delivers
+//! the container's running status again, so that it is patched with the new
+//! readiness.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use kubelet::container::Status;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// Statuses not yet received.
+#[derive(Default)]
+struct Pending {
+    /// Each container's undelivered statuses. Only the last may be a status
+    /// other than a termination.
+    statuses: HashMap<String, VecDeque<Status>>,
+    /// Containers with undelivered statuses, in the order they reported
+    order: VecDeque<String>,
+    /// The readiness each container's module last reported, for modules that
+    /// report it
+    ready: HashMap<String, bool>,
+    /// The last status each container reported
+    last: HashMap<String, Status>,
+}
+
+/// Creates a status channel.
+pub(crate) fn channel() -> (StatusSender, StatusReceiver) {
+    let pending: Arc<Mutex<Pending>> = Default::default();
+    let (wake, wake_rx) = mpsc::unbounded_channel();
+    (
+        StatusSender {
+            pending: pending.clone(),
+            wake,
+        },
+        StatusReceiver {
+            pending,
+            wake: wake_rx,
+            patched: Default::default(),
+        },
+    )
+}
+
+/// Reports container statuses. Sending never blocks.
+#[derive(Clone)]
+pub(crate) struct StatusSender {
+    pending: Arc<Mutex<Pending>>,
+    wake: UnboundedSender<()>,
+}
+
+impl StatusSender {
+    /// Reports the status of a container, replacing any status of it that
+    /// has not been received yet unless that is a termination. Returns false
+    /// if nobody is listening any more, for example after the pod's modules
+    /// were reloaded.
+    pub(crate) fn send(&self, name: &str, status: Status) -> bool {
+        self.pending.lock().unwrap().push(name, status);
+        self.wake.send(()).is_ok()
+    }
+
+    /// Sets the readiness a container's module reports, or `None` if its
+    /// module does not report readiness and the container is ready while it
+    /// runs. If this changes the readiness of a running container, its
+    /// running status is delivered again.
+    pub(crate) fn set_ready(&self, name: &str, ready: Option<bool>) {
+        let resend = {
+            let mut pending = self.pending.lock().unwrap();
+            let previous = match ready {
+                Some(ready) => pending.ready.insert(name.to_owned(), ready),
+                None => pending.ready.remove(name),
+            };
+            match pending.last.get(name) {
+                Some(status) if matches!(status, Status::Running { .. }) && previous != ready => {
+                    let status = status.clone();
+                    pending.push(name, status);
+                    true
+                }
+                _ => false,
+            }
+        };
+        if resend {
+            let _ = self.wake.send(());
+        }
+    }
+}
+
+impl Pending {
+    fn push(&mut self, name: &str, status: Status) {
+        self.last.insert(name.to_owned(), status.clone());
+        let queue = self.statuses.entry(name.to_owned()).or_default();
+        if queue.is_empty() {
+            self.order.push_back(name.to_owned());
+        }
+        match queue.back_mut() {
+            Some(last) if !is_terminated(last) => *last = status,
+            _ => queue.push_back(status),
+        }
+    }
+}
+
+/// Receives container statuses on the pod's state machine.
+pub(crate) struct StatusReceiver {
+    pending: Arc<Mutex<Pending>>,
+    wake: UnboundedReceiver<()>,
+    /// The last status and readiness of each container the API server was
+    /// sent
+    patched: HashMap<String, (Status, Option<bool>)>,
+}
+
+impl StatusReceiver {
+    /// Waits for the next status. Returns `None` once every sender is gone
+    /// and nothing is left.
+    pub(crate) async fn recv(&mut self) -> Option<(String, Status)> {
+        loop {
+            if let Some(next) = self.try_recv() {
+                return Some(next);
+            }
+            self.wake.recv().await?;
+        }
+    }
+
+    /// Takes the next status if there is one.
+    pub(crate) fn try_recv(&mut self) -> Option<(String, Status)> {
+        let mut pending = self.pending.lock().unwrap();
+        let Pending {
+            statuses, order, ..
+        } = &mut *pending;
+        let name = order.pop_front()?;
+        let queue = statuses.get_mut(&name)?;
+        let status = queue.pop_front()?;
+        if queue.is_empty() {
+            statuses.remove(&name);
+        } else {
+            // Take turns with other containers that have reported since
+            order.push_back(name.clone());
+        }
+        Some((name, status))
+    }
+
+    /// Returns true if a newer status of the container is waiting.
+    pub(crate) fn has_newer(&self, name: &str) -> bool {
+        self.pending.lock().unwrap().statuses.contains_key(name)
+    }
+
+    /// The readiness the container's module last reported, if its module
+    /// reports readiness.
+    pub(crate) fn ready(&self, name: &str) -> Option<bool> {
+        self.pending.lock().unwrap().ready.get(name).copied()
+    }
+
+    /// Forgets the last status a container reported, so that waiting for it
+    /// to start only sees its next run.
+    pub(crate) fn forget(&self, name: &str) {
+        self.pending.lock().unwrap().last.remove(name);
+    }
+
+    /// Waits until a container is running and ready, or has run to
+    /// completion, without taking its statuses. A container whose module
+    /// reports its readiness is ready once it says so. Fails with the
+    /// container's message if it fails, or once every sender is gone.
+    pub(crate) async fn started(&mut self, name: &str) -> Result<(), String> {
+        loop {
+            {
+                let pending = self.pending.lock().unwrap();
+                match pending.last.get(name) {
+                    Some(Status::Running { .. }) if pending.ready.get(name) != Some(&false) => {
+                        return Ok(())
+                    }
+                    Some(Status::Terminated { failed: false, .. }) => return Ok(()),
+                    Some(Status::Terminated { message, .. }) => return Err(message.clone()),
+                    _ => {}
+                }
+            }
+            // Statuses stay pending, so whoever receives them next still
+            // finds them without being woken
+            if self.wake.recv().await.is_none() {
+                return Err("the container's runtime went away".to_owned());
+            }
+        }
+    }
+
+    /// Returns true if the status or `ready` differs from the last patched
+    /// for the container, ignoring when the status was reported. Every
+    /// termination counts as a change, as each ends a different run.
+    pub(crate) fn changed(&self, name: &str, status: &Status, ready: Option<bool>) -> bool {
+        if is_terminated(status) {
+            return true;
+        }
+        match self.patched.get(name) {
+            Some((last, last_ready)) => !same(last, status) || *last_ready != ready,
+            None => true,
+        }
+    }
+
+    /// Records that the status and readiness of a container were patched.
+    pub(crate) fn patched(&mut self, name: &str, status: &Status, ready: Option<bool>) {
+        self.patched
+            .insert(name.to_owned(), (status.clone(), ready));
+    }
+}
+
+fn is_terminated(status: &Status) -> bool {
+    matches!(status, Status::Terminated { .. })
+}
+
+/// Whether two statuses say the same thing, whenever they were reported.
+fn same(a: &Status, b: &Status) -> bool {
+    match (a, b) {
+        (Status::Waiting { message: a, .. }, Status::Waiting { message: b, .. }) => a == b,
+        (Status::Running { .. }, Status::Running { .. }) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn running() -> Status {
+        Status::Running {
+            timestamp: chrono::Utc::now(),
+        }
+    }
+
+    fn terminated(failed: bool) -> Status {
+        Status::Terminated {
+            failed,
+            message: "done".to_owned(),
+            timestamp: chrono::Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn started_waits_for_a_module_that_reports_readiness_to_be_ready() {
+        let (sender, mut receiver) = channel();
+        assert!(receiver.started("app").now_or_never().is_none());
+
+        sender.set_ready("app", Some(false));
+        sender.send("app", running());
+        assert!(receiver.started("app").now_or_never().is_none());
+
+        sender.set_ready("app", Some(true));
+        assert_eq!(receiver.started("app").await, Ok(()));
+        // The statuses are left for the pod's state machine
+        assert!(matches!(
+            receiver.try_recv(),
+            Some((_, Status::Running { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn started_is_done_once_a_container_completes_and_fails_if_it_fails() {
+        let (sender, mut receiver) = channel();
+        sender.send("writer", terminated(false));
+        assert_eq!(receiver.started("writer").await, Ok(()));
+
+        sender.send("writer", terminated(true));
+        assert_eq!(receiver.started("writer").await, Err("done".to_owned()));
+
+        receiver.forget("writer");
+        assert!(receiver.started("writer").now_or_never().is_none());
+        drop(sender);
+        assert!(receiver.started("writer").await.is_err());
+    }
+}
@@ -0,0 +1,105 @@
+//! Module stores provided by this crate, in addition to the ones in
+//! [`kubelet::store`].
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use kubelet::store::{PullPolicy, Store};
+use log::debug;
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+
+mod object;
+
+pub use object::{object_store, CachingStore, HttpObjectStore, ObjectStore, S3ObjectStore};
+
+/// Name of the optional file in a [`DirectoryStore`] root mapping image
+/// references to module files.
+pub const INDEX_FILE_NAME: &str = "modules.json";
+
+const MODULE_FILE_NAME: &str = "module.wasm";
+
+/// A store that resolves images from a local directory, for nodes without
+/// registry access.
+///
+/// Images are first looked up in an optional `modules.json` file in the root
+/// that maps image references to module paths relative to the root:
+///
+/// ```json
+/// { "webassembly.azurecr.io/hello-wasm:v1": "hello.wasm" }
+/// ```
+///
+/// Images not in the index are looked up in the same layout the kubelet
+/// `FileStore` uses, `<root>/<registry>/<repository>/<tag or digest>/module.wasm`,
+/// so an existing module cache can be copied onto an air-gapped node as is.
+pub struct DirectoryStore {
+    root: PathBuf,
+}
+
+impl DirectoryStore {
+    /// Create a store rooted at `root`.
+    pub fn new<T: AsRef<Path>>(root: T) -> Self {
+        DirectoryStore {
+            root: root.as_ref().to_owned(),
+        }
+    }
+
+    async fn index(&self) -> anyhow::Result<HashMap<String, PathBuf>> {
+        let path = self.root.join(INDEX_FILE_NAME);
+        match tokio::fs::read(&path).await {
+            Ok(raw) => Ok(serde_json::from_slice(&raw)
+                .map_err(|e| anyhow::anyhow!("invalid module index {}: {}", path.display(), e))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn layout_path(&self, image_ref: &Reference) -> PathBuf {
+        let mut path = self.root.join(image_ref.registry());
+        path.push(image_ref.repository());
+        path.push(
+            image_ref
+                .digest()
+                .or_else(|| image_ref.tag())
+                .unwrap_or("latest"),
+        );
+        path.push(MODULE_FILE_NAME);
+        path
+    }
+
+    /// Returns where the module for `image_ref` lives in this store.
+    pub async fn module_path(&self, image_ref: &Reference) -> anyhow::Result<PathBuf> {
+        let index = self.index().await?;
+        Ok(match index.get(image_ref.whole()) {
+            Some(relative) => self.root.join(relative),
+            None => self.layout_path(image_ref),
+        })
+    }
+}
+
+#[async_trait]
+impl Store for DirectoryStore {
+    async fn get(
+        &self,
+        image_ref: &Reference,
+        _pull_policy: PullPolicy,
+        _auth: &RegistryAuth,
+    ) -> anyhow::Result<Vec<u8>> {
+        // There is nothing to pull from, so the pull policy does not apply
+        let path = self.module_path(image_ref).await?;
+        debug!(
+            "Loading module {} from {}",
+            image_ref.whole(),
+            path.display()
+        );
+        tokio::fs::read(&path).await.map_err(|e| {
+            anyhow::anyhow!(
+                "image {} not found in module directory ({}): {}",
+                image_ref.whole(),
+                path.display(),
+                e
+            )
+        })
+    }
+}
@@ -0,0 +1,376 @@
+//! A module cache shared between nodes through an object store, so a fleet
+//! scaling up pulls each image from the registry once instead of once per
+//! node.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac, NewMac};
+use kubelet::store::{PullPolicy, Store};
+use log::{debug, error};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+
+use crate::config::ModuleCache;
+
+/// Blob storage for cached modules, keyed by `/` separated paths.
+#[async_trait]
+pub trait ObjectStore {
+    /// Returns the object at `key`, or `None` if there is none.
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Stores `data` at `key`, replacing any object already there.
+    async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()>;
+}
+
+/// Creates the object store a [`ModuleCache`] setting names.
+pub fn object_store(cache: &ModuleCache) -> anyhow::Result<Arc<dyn ObjectStore + Send + Sync>> {
+    Ok(match cache {
+        ModuleCache::S3 { bucket, prefix } => Arc::new(S3ObjectStore::from_env(bucket, prefix)?),
+        ModuleCache::Http { url, token_file } => {
+            Arc::new(HttpObjectStore::new(url, token_file.clone()))
+        }
+    })
+}
+
+/// A [`Store`] that checks a shared [`ObjectStore`] before pulling from the
+/// store it wraps, and adds what it pulls to the shared cache.
+///
+/// Modules are kept in the same `<registry>/<repository>/<tag or
+/// digest>/module.wasm` layout as the kubelet `FileStore`. An image pulled by
+/// tag with `PullPolicy::Always` is always pulled, since the tag may have
+/// moved, but it still refreshes the shared copy.
+pub struct CachingStore {
+    inner: Arc<dyn Store + Send + Sync>,
+    cache: Arc<dyn ObjectStore + Send + Sync>,
+}
+
+impl CachingStore {
+    /// Create a store that caches modules pulled through `inner` in `cache`.
+    pub fn new(
+        inner: Arc<dyn Store + Send + Sync>,
+        cache: Arc<dyn ObjectStore + Send + Sync>,
+    ) -> Self {
+        CachingStore { inner, cache }
+    }
+}
+
+fn cache_key(image_ref: &Reference) -> String {
+    format!(
+        "{}/{}/{}/module.wasm",
+        image_ref.registry(),
+        image_ref.repository(),
+        image_ref
+            .digest()
+            .or_else(|| image_ref.tag())
+            .unwrap_or("latest")
+    )
+}
+
+#[async_trait]
+impl Store for CachingStore {
+    async fn get(
+        &self,
+        image_ref: &Reference,
+        pull_policy: PullPolicy,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<Vec<u8>> {
+        let key = cache_key(image_ref);
+        let always = matches!(pull_policy, PullPolicy::Always);
+        if image_ref.digest().is_some() || !always {
+            // A module already on this node needs neither the registry nor
+            // the shared cache
+            if !always {
+                if let Ok(bytes) = self.inner.get(image_ref, PullPolicy::Never, auth).await {
+                    return Ok(bytes);
+                }
+            }
+            match self.cache.get(&key).await {
+                Ok(Some(bytes)) => {
+                    debug!("Loaded module {} from the shared cache", image_ref.whole());
+                    return Ok(bytes);
+                }
+                Ok(None) => (),
+                // The registry is still there, so a cache outage only costs
+                // a pull
+                Err(e) => error!(
+                    "Unable to read module {} from the shared cache: {:?}",
+                    image_ref.whole(),
+                    e
+                ),
+            }
+        }
+
+        let bytes = self.inner.get(image_ref, pull_policy, auth).await?;
+        let cache = self.cache.clone();
+        let data = bytes.clone();
+        let image = image_ref.whole().to_owned();
+        tokio::spawn(async move {
+            match cache.put(&key, data).await {
+                Ok(()) => debug!("Added module {} to the shared cache", image),
+                Err(e) => error!(
+                    "Unable to add module {} to the shared cache: {:?}",
+                    image, e
+                ),
+            }
+        });
+        Ok(bytes)
+    }
+}
+
+/// Percent encodes a key for use in a URL path, leaving the `/` separators.
+fn encode_key(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for b in key.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// An object store reached with plain HTTP `GET` and `PUT` requests under a
+/// base URL. This covers Azure Blob Storage with a SAS URL, Google Cloud
+/// Storage with an OAuth token, and buckets behind a caching proxy.
+pub struct HttpObjectStore {
+    client: reqwest::Client,
+    /// The base URL, without its query
+    base: String,
+    /// The query of the base URL, such as a SAS token, added to every request
+    query: Option<String>,
+    /// File holding a bearer token. It is read for every request so a
+    /// rotated token is picked up.
+    token_file: Option<PathBuf>,
+    /// Azure needs the blob type on upload
+    azure: bool,
+}
+
+impl HttpObjectStore {
+    /// Create a store for objects under `url`.
+    pub fn new(url: &str, token_file: Option<PathBuf>) -> Self {
+        let (base, query) = match url.find('?') {
+            Some(i) => (&url[..i], Some(url[i + 1..].to_owned())),
+            None => (url, None),
+        };
+        HttpObjectStore {
+            client: reqwest::Client::new(),
+            base: base.trim_end_matches('/').to_owned(),
+            query,
+            token_file,
+            azure: base.contains(".blob.core.windows.net"),
+        }
+    }
+
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+    ) -> anyhow::Result<reqwest::RequestBuilder> {
+        let mut url = format!("{}/{}", self.base, encode_key(key));
+        if let Some(query) = &self.query {
+            url.push('?');
+            url.push_str(query);
+        }
+        let mut request = self.client.request(method, &url);
+        if let Some(path) = &self.token_file {
+            let token = tokio::fs::read_to_string(path).await.map_err(|e| {
+                anyhow::anyhow!("unable to read token file {}: {}", path.display(), e)
+            })?;
+            request = request.bearer_auth(token.trim());
+        }
+        Ok(request)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for HttpObjectStore {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let response = self
+            .request(reqwest::Method::GET, key)
+            .await?
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.bytes().await?.to_vec())),
+            status => Err(anyhow::anyhow!("GET {} returned {}", key, status)),
+        }
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        let mut request = self.request(reqwest::Method::PUT, key).await?;
+        if self.azure {
+            request = request.header("x-ms-blob-type", "BlockBlob");
+        }
+        let status = request.body(data).send().await?.status();
+        if !status.is_success() {
+            return Err(anyhow::anyhow!("PUT {} returned {}", key, status));
+        }
+        Ok(())
+    }
+}
+
+/// An S3 bucket, or a service with the same API, reached with requests signed
+/// by AWS Signature Version 4.
+///
+/// Credentials and the region come from the standard `AWS_ACCESS_KEY_ID`,
+/// `AWS_SECRET_ACCESS_KEY`, `AWS_SESSION_TOKEN` and `AWS_REGION` (or
+/// `AWS_DEFAULT_REGION`) environment variables. `AWS_ENDPOINT_URL` points the
+/// store at an S3 compatible service instead of AWS.
+pub struct S3ObjectStore {
+    client: reqwest::Client,
+    /// Scheme and host of the service, such as `https://s3.us-west-2.amazonaws.com`
+    endpoint: String,
+    host: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn required_env(key: &str) -> anyhow::Result<String> {
+    std::env::var(key).map_err(|_| anyhow::anyhow!("{} must be set to use an S3 module cache", key))
+}
+
+impl S3ObjectStore {
+    /// Create a store for objects under `prefix` in `bucket`, reading
+    /// credentials from the environment.
+    pub fn from_env(bucket: &str, prefix: &str) -> anyhow::Result<Self> {
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+            .map_err(|_| anyhow::anyhow!("AWS_REGION must be set to use an S3 module cache"))?;
+        let endpoint = std::env::var("AWS_ENDPOINT_URL")
+            .unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region));
+        let endpoint = endpoint.trim_end_matches('/').to_owned();
+        let host = endpoint
+            .splitn(2, "://")
+            .nth(1)
+            .ok_or_else(|| anyhow::anyhow!("invalid S3 endpoint {}", endpoint))?
+            .to_owned();
+        Ok(S3ObjectStore {
+            client: reqwest::Client::new(),
+            endpoint,
+            host,
+            bucket: bucket.to_owned(),
+            prefix: prefix.trim_matches('/').to_owned(),
+            region,
+            access_key: required_env("AWS_ACCESS_KEY_ID")?,
+            secret_key: required_env("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    /// Builds a request for `key` signed with Signature Version 4, using path
+    /// style addressing so bucket names with dots work over TLS.
+    fn signed(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        payload: &[u8],
+    ) -> anyhow::Result<reqwest::RequestBuilder> {
+        let key = if self.prefix.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}/{}", self.prefix, key)
+        };
+        let path = format!("/{}/{}", encode_key(&self.bucket), encode_key(&key));
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(payload));
+
+        let mut headers = vec![
+            ("host", self.host.clone()),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(k, v)| format!("{}:{}\n", k, v.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method.as_str(),
+            path,
+            canonical_headers,
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut signing_key = format!("AWS4{}", self.secret_key).into_bytes();
+        for part in &[date.as_str(), self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac(&signing_key, part.as_bytes())?;
+        }
+        let signature = hex::encode(hmac(&signing_key, string_to_sign.as_bytes())?);
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let mut request = self
+            .client
+            .request(method, &format!("{}{}", self.endpoint, path))
+            .header("Authorization", authorization);
+        // reqwest sets the host header itself
+        for (name, value) in headers.into_iter().filter(|(k, _)| *k != "host") {
+            request = request.header(name, value);
+        }
+        Ok(request)
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut mac =
+        HmacSha256::new_varkey(key).map_err(|e| anyhow::anyhow!("invalid HMAC key: {}", e))?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+#[async_trait]
+impl ObjectStore for S3ObjectStore {
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let response = self.signed(reqwest::Method::GET, key, &[])?.send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.bytes().await?.to_vec())),
+            status => Err(anyhow::anyhow!("GET {} returned {}", key, status)),
+        }
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        let status = self
+            .signed(reqwest::Method::PUT, key, &data)?
+            .body(data)
+            .send()
+            .await?
+            .status();
+        if !status.is_success() {
+            return Err(anyhow::anyhow!("PUT {} returned {}", key, status));
+        }
+        Ok(())
+    }
+}
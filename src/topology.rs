@@ -0,0 +1,227 @@
+//! Topology labels for the node, so pods can use zone and region affinity and
+//! spread constraints. Values come from the provider configuration, and any
+//! that are not configured can be looked up from the cloud's instance
+//! metadata service.
+
+use std::time::Duration;
+
+use log::{error, info};
+
+use crate::config::{CloudMetadata, ProviderConfig};
+
+/// The well-known topology labels.
+pub(crate) const ZONE_LABEL: &str = "topology.kubernetes.io/zone";
+pub(crate) const REGION_LABEL: &str = "topology.kubernetes.io/region";
+pub(crate) const INSTANCE_TYPE_LABEL: &str = "node.kubernetes.io/instance-type";
+
+/// The beta labels the stable ones replaced, which schedulers and manifests
+/// written for older clusters still select on.
+const LEGACY_ZONE_LABEL: &str = "failure-domain.beta.kubernetes.io/zone";
+const LEGACY_REGION_LABEL: &str = "failure-domain.beta.kubernetes.io/region";
+const LEGACY_INSTANCE_TYPE_LABEL: &str = "beta.kubernetes.io/instance-type";
+
+/// Metadata services are link-local, so anything slower than this means there
+/// is none.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(5);
+
+const AWS_METADATA_URL: &str = "http://169.254.169.254/latest";
+const AZURE_METADATA_URL: &str =
+    "http://169.254.169.254/metadata/instance/compute?api-version=2020-06-01";
+const GCE_METADATA_URL: &str = "http://metadata.google.internal/computeMetadata/v1/instance";
+
+/// Where the node sits.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Topology {
+    pub(crate) zone: Option<String>,
+    pub(crate) region: Option<String>,
+    pub(crate) instance_type: Option<String>,
+}
+
+impl Topology {
+    /// The node labels for the known values, under both the stable and the
+    /// legacy names.
+    pub(crate) fn labels(&self) -> Vec<(String, String)> {
+        let mut labels = Vec::new();
+        let mut add = |value: &Option<String>, keys: &[&str]| {
+            if let Some(value) = value {
+                for key in keys {
+                    labels.push(((*key).to_owned(), value.clone()));
+                }
+            }
+        };
+        add(&self.zone, &[ZONE_LABEL, LEGACY_ZONE_LABEL]);
+        add(&self.region, &[REGION_LABEL, LEGACY_REGION_LABEL]);
+        add(
+            &self.instance_type,
+            &[INSTANCE_TYPE_LABEL, LEGACY_INSTANCE_TYPE_LABEL],
+        );
+        labels
+    }
+
+    /// Fills in anything not set here from `other`.
+    fn or(self, other: Topology) -> Topology {
+        Topology {
+            zone: self.zone.or(other.zone),
+            region: self.region.or(other.region),
+            instance_type: self.instance_type.or(other.instance_type),
+        }
+    }
+}
+
+/// Works out the node's topology. Configured values take priority over the
+/// metadata service, and a failed lookup is logged rather than stopping the
+/// node from registering.
+pub(crate) async fn resolve(config: &ProviderConfig) -> Topology {
+    let configured = Topology {
+        zone: config.topology_zone.clone(),
+        region: config.topology_region.clone(),
+        instance_type: config.instance_type.clone(),
+    };
+    let cloud = match config.cloud_metadata {
+        Some(cloud) => cloud,
+        None => return configured,
+    };
+    match lookup(cloud).await {
+        Ok(found) => {
+            info!(
+                "Looked up node topology from {:?} metadata: {:?}",
+                cloud, found
+            );
+            configured.or(found)
+        }
+        Err(e) => {
+            error!(
+                "Unable to look up node topology from {:?} metadata: {:?}",
+                cloud, e
+            );
+            configured
+        }
+    }
+}
+
+/// Reads the topology from a cloud's instance metadata service.
+pub(crate) async fn lookup(cloud: CloudMetadata) -> anyhow::Result<Topology> {
+    let client = reqwest::Client::builder()
+        .timeout(METADATA_TIMEOUT)
+        .no_proxy()
+        .build()?;
+    match cloud {
+        CloudMetadata::Aws => lookup_aws(&client).await,
+        CloudMetadata::Azure => lookup_azure(&client).await,
+        CloudMetadata::Gce => lookup_gce(&client).await,
+    }
+}
+
+/// Uses IMDSv2, which needs a session token for every read.
+async fn lookup_aws(client: &reqwest::Client) -> anyhow::Result<Topology> {
+    let token = client
+        .put(&format!("{}/api/token", AWS_METADATA_URL))
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let get = |path: &'static str| {
+        client
+            .get(&format!("{}/meta-data/{}", AWS_METADATA_URL, path))
+            .header("X-aws-ec2-metadata-token", token.as_str())
+            .send()
+    };
+    let zone = get("placement/availability-zone")
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let region = get("placement/region")
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let instance_type = get("instance-type")
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    Ok(Topology {
+        zone: non_empty(zone),
+        region: non_empty(region),
+        instance_type: non_empty(instance_type),
+    })
+}
+
+/// Follows the Azure cloud provider: the zone is `<location>-<zone>` for VMs
+/// in an availability zone, and the fault domain otherwise.
+async fn lookup_azure(client: &reqwest::Client) -> anyhow::Result<Topology> {
+    let body = client
+        .get(AZURE_METADATA_URL)
+        .header("Metadata", "true")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let compute: serde_json::Value = serde_json::from_str(&body)?;
+    let field = |name: &str| {
+        compute
+            .get(name)
+            .and_then(|v| v.as_str())
+            .map(|v| v.trim().to_owned())
+            .filter(|v| !v.is_empty())
+    };
+    let location = field("location").map(|l| l.to_lowercase());
+    let zone = match (&location, field("zone")) {
+        (Some(location), Some(zone)) => Some(format!("{}-{}", location, zone)),
+        (_, None) => field("platformFaultDomain"),
+        (None, Some(_)) => None,
+    };
+    Ok(Topology {
+        zone,
+        region: location,
+        instance_type: field("vmSize"),
+    })
+}
+
+/// The zone and machine type come back as resource paths such as
+/// `projects/123/zones/us-central1-a`, and the region is the zone without its
+/// last part.
+async fn lookup_gce(client: &reqwest::Client) -> anyhow::Result<Topology> {
+    let get = |path: &'static str| {
+        client
+            .get(&format!("{}/{}", GCE_METADATA_URL, path))
+            .header("Metadata-Flavor", "Google")
+            .send()
+    };
+    let zone = get("zone").await?.error_for_status()?.text().await?;
+    let machine_type = get("machine-type")
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let zone = last_segment(&zone);
+    let region = zone
+        .as_deref()
+        .and_then(|z| z.rfind('-').map(|i| z[..i].to_owned()));
+    Ok(Topology {
+        zone,
+        region,
+        instance_type: last_segment(&machine_type),
+    })
+}
+
+fn last_segment(path: &str) -> Option<String> {
+    path.trim()
+        .rsplit('/')
+        .next()
+        .map(str::to_owned)
+        .and_then(non_empty)
+}
+
+fn non_empty(value: String) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_owned())
+    }
+}
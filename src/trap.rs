@@ -0,0 +1,130 @@
+use std::fmt;
+
+/// The maximum length of the message placed in a container's terminated
+/// state. Kubernetes caps termination messages at 4096 bytes, but we keep
+/// ours shorter so that the status stays readable in `kubectl describe`.
+pub(crate) const MAX_TERMINATION_MESSAGE_LEN: usize = 1024;
+
+/// The kind of trap raised by wasm3 while executing a module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TrapKind {
+    OutOfBoundsMemoryAccess,
+    DivisionByZero,
+    IntegerOverflow,
+    IntegerConversion,
+    IndirectCallTypeMismatch,
+    TableIndexOutOfRange,
+    Exit,
+    Abort,
+    Unreachable,
+    StackOverflow,
+    /// The error was not a trap, or was a trap wasm3 did not classify.
+    Unknown,
+}
+
+impl TrapKind {
+    /// Classifies a wasm3 error message. wasm3 reports traps as static
+    /// strings prefixed with `[trap]`, so we match on those.
+    fn from_message(message: &str) -> Self {
+        const KINDS: &[(&str, TrapKind)] = &[
+            (
+                "out of bounds memory access",
+                TrapKind::OutOfBoundsMemoryAccess,
+            ),
+            ("integer divide by zero", TrapKind::DivisionByZero),
+            ("integer overflow", TrapKind::IntegerOverflow),
+            ("invalid conversion to integer", TrapKind::IntegerConversion),
+            (
+                "indirect call type mismatch",
+                TrapKind::IndirectCallTypeMismatch,
+            ),
+            (
+                "table index is out of range",
+                TrapKind::TableIndexOutOfRange,
+            ),
+            ("undefined element", TrapKind::TableIndexOutOfRange),
+            ("program called exit", TrapKind::Exit),
+            ("program called abort", TrapKind::Abort),
+            ("unreachable executed", TrapKind::Unreachable),
+            ("stack overflow", TrapKind::StackOverflow),
+        ];
+        KINDS
+            .iter()
+            .find(|(needle, _)| message.contains(needle))
+            .map(|(_, kind)| *kind)
+            .unwrap_or(TrapKind::Unknown)
+    }
+
+    /// A CamelCase reason suitable for Kubernetes events.
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            TrapKind::OutOfBoundsMemoryAccess => "OutOfBoundsMemoryAccess",
+            TrapKind::DivisionByZero => "DivisionByZero",
+            TrapKind::IntegerOverflow => "IntegerOverflow",
+            TrapKind::IntegerConversion => "IntegerConversion",
+            TrapKind::IndirectCallTypeMismatch => "IndirectCallTypeMismatch",
+            TrapKind::TableIndexOutOfRange => "TableIndexOutOfRange",
+            TrapKind::Exit => "Exit",
+            TrapKind::Abort => "Abort",
+            TrapKind::Unreachable => "Unreachable",
+            TrapKind::StackOverflow => "StackOverflow",
+            TrapKind::Unknown => "Trap",
+        }
+    }
+}
+
+/// Everything we could learn about a failed `_start` call.
+#[derive(Debug)]
+pub(crate) struct TrapDetails {
+    pub(crate) kind: TrapKind,
+    /// The wasm3 error string
+    detail: String,
+    /// The debug representation of the error, which carries any backtrace
+    /// information wasm3 attached to it
+    backtrace: String,
+}
+
+impl TrapDetails {
+    pub(crate) fn new(error: &wasm3::error::Error) -> Self {
+        let detail = error.to_string();
+        TrapDetails {
+            kind: TrapKind::from_message(&detail),
+            detail,
+            backtrace: format!("{:?}", error),
+        }
+    }
+
+    /// A short message for the container's terminated state.
+    pub(crate) fn termination_message(&self) -> String {
+        truncate(
+            &format!("{}: {}", self.kind.reason(), self.detail),
+            MAX_TERMINATION_MESSAGE_LEN,
+        )
+    }
+}
+
+impl fmt::Display for TrapDetails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "module trapped ({}): {}\n{}",
+            self.kind.reason(),
+            self.detail,
+            self.backtrace
+        )
+    }
+}
+
+/// Truncates `message` to at most `max` bytes without splitting a character,
+/// marking the truncation with an ellipsis.
+pub(crate) fn truncate(message: &str, max: usize) -> String {
+    if message.len() <= max {
+        return message.to_owned();
+    }
+    let ellipsis = "...";
+    let mut end = max.saturating_sub(ellipsis.len());
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &message[..end], ellipsis)
+}
@@ -0,0 +1,725 @@
+//! Static checks run on modules before they are started, so that problems
+//! surface as a specific pod status instead of a generic runtime error.
+
+use std::fmt;
+use std::sync::Arc;
+
+use wasm3::{Environment, Module};
+
+use crate::compose;
+use crate::entrypoint::Entrypoint;
+use crate::hooks::{self, HostExtension};
+use crate::host;
+
+/// Import modules linked by wasm3's `link_wasi`.
+pub(crate) const WASI_MODULES: &[&str] = &["wasi_snapshot_preview1", "wasi_unstable"];
+
+/// The WASI functions wasm3's `link_wasi` implements at the revision pinned in
+/// `Cargo.toml`. Anything else in the WASI modules, such as the `sock_*` and
+/// most `path_*` functions, is left unlinked and traps when called. Update
+/// this alongside the pin.
+pub(crate) const WASI_FUNCTIONS: &[&str] = &[
+    "args_get",
+    "args_sizes_get",
+    "clock_res_get",
+    "clock_time_get",
+    "environ_get",
+    "environ_sizes_get",
+    "fd_close",
+    "fd_datasync",
+    "fd_fdstat_get",
+    "fd_fdstat_set_flags",
+    "fd_prestat_dir_name",
+    "fd_prestat_get",
+    "fd_read",
+    "fd_seek",
+    "fd_sync",
+    "fd_write",
+    "path_open",
+    "proc_exit",
+    "random_get",
+];
+
+/// WASI functions that open or sync files. Without `path_open` a module
+/// cannot reach the directories wasm3 preopens.
+const WASI_FS_FUNCTIONS: &[&str] = &["path_open", "fd_datasync", "fd_sync"];
+
+/// WASI functions that read the clocks.
+const WASI_CLOCK_FUNCTIONS: &[&str] = &["clock_res_get", "clock_time_get"];
+
+/// WASI functions that read the environment.
+const WASI_ENV_FUNCTIONS: &[&str] = &["environ_get", "environ_sizes_get"];
+
+/// Which groups of WASI functions a pod's modules may import. wasm3 links
+/// all of WASI or none of it, so a profile is enforced by rejecting modules
+/// that import functions outside it: a module can only call what it imports.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WasiProfile {
+    /// Standard I/O, arguments, randomness and exiting only
+    Strict,
+    /// `Strict` plus clocks and environment variables, but no filesystem
+    Standard,
+    /// Everything wasm3 implements
+    Permissive,
+}
+
+impl Default for WasiProfile {
+    fn default() -> Self {
+        WasiProfile::Permissive
+    }
+}
+
+impl std::str::FromStr for WasiProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "strict" => Ok(WasiProfile::Strict),
+            "standard" => Ok(WasiProfile::Standard),
+            "permissive" => Ok(WasiProfile::Permissive),
+            _ => Err(anyhow::anyhow!(
+                "invalid WASI profile {:?}, expected strict, standard or permissive",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for WasiProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WasiProfile::Strict => "strict",
+            WasiProfile::Standard => "standard",
+            WasiProfile::Permissive => "permissive",
+        })
+    }
+}
+
+impl WasiProfile {
+    /// Returns true if modules may import the WASI function `name`.
+    pub(crate) fn allows(self, name: &str) -> bool {
+        let denied: &[&[&str]] = match self {
+            WasiProfile::Strict => &[WASI_FS_FUNCTIONS, WASI_CLOCK_FUNCTIONS, WASI_ENV_FUNCTIONS],
+            WasiProfile::Standard => &[WASI_FS_FUNCTIONS],
+            WasiProfile::Permissive => &[],
+        };
+        !denied.iter().any(|group| group.contains(&name))
+    }
+}
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+const WASM_VERSION: u32 = 1;
+
+const SECTION_TYPE: u8 = 1;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_FUNCTION: u8 = 3;
+const SECTION_MEMORY: u8 = 5;
+const SECTION_GLOBAL: u8 = 6;
+const SECTION_EXPORT: u8 = 7;
+
+/// The most pages a 32 bit linear memory can have.
+const MAX_MEMORY_PAGES: u32 = 65536;
+
+/// The kind of an imported or exported item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ExternKind {
+    Function,
+    Table,
+    Memory,
+    Global,
+}
+
+impl ExternKind {
+    fn from_byte(b: u8) -> Result<Self, ValidationError> {
+        match b {
+            0 => Ok(ExternKind::Function),
+            1 => Ok(ExternKind::Table),
+            2 => Ok(ExternKind::Memory),
+            3 => Ok(ExternKind::Global),
+            _ => Err(ValidationError::Malformed(format!(
+                "unknown external kind {}",
+                b
+            ))),
+        }
+    }
+}
+
+/// The value type of an i32.
+pub(crate) const VALUE_I32: u8 = 0x7f;
+
+/// The parameter and result types of a function, as value type bytes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct FuncType {
+    pub(crate) params: Vec<u8>,
+    pub(crate) results: Vec<u8>,
+}
+
+impl fmt::Display for FuncType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = |types: &[u8]| -> String {
+            types
+                .iter()
+                .map(|t| match *t {
+                    VALUE_I32 => "i32",
+                    0x7e => "i64",
+                    0x7d => "f32",
+                    0x7c => "f64",
+                    _ => "?",
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        write!(f, "({})", names(&self.params))?;
+        if !self.results.is_empty() {
+            write!(f, " -> {}", names(&self.results))?;
+        }
+        Ok(())
+    }
+}
+
+/// An item imported by a module.
+#[derive(Debug, Clone)]
+pub(crate) struct Import {
+    pub(crate) module: String,
+    pub(crate) field: String,
+    pub(crate) kind: ExternKind,
+    /// The index of a function's type
+    pub(crate) type_index: Option<u32>,
+}
+
+/// An item exported by a module.
+#[derive(Debug, Clone)]
+pub(crate) struct Export {
+    pub(crate) name: String,
+    pub(crate) kind: ExternKind,
+    pub(crate) index: u32,
+}
+
+/// The imports and exports of a module.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ModuleInfo {
+    pub(crate) imports: Vec<Import>,
+    pub(crate) exports: Vec<Export>,
+    /// How many mutable globals the module imports
+    pub(crate) imported_mutable_globals: u32,
+    /// How many mutable globals the module defines
+    pub(crate) defined_mutable_globals: u32,
+    /// The function types the module declares
+    pub(crate) types: Vec<FuncType>,
+    /// The type index of every function, imported ones first
+    pub(crate) functions: Vec<u32>,
+}
+
+impl ModuleInfo {
+    /// Returns true if the module exports a function called `name`.
+    pub(crate) fn exports_function(&self, name: &str) -> bool {
+        self.exports
+            .iter()
+            .any(|e| e.kind == ExternKind::Function && e.name == name)
+    }
+
+    /// The type of an imported function.
+    pub(crate) fn import_type(&self, import: &Import) -> Option<&FuncType> {
+        self.types.get(import.type_index? as usize)
+    }
+
+    /// The type of the function exported as `name`.
+    pub(crate) fn export_type(&self, name: &str) -> Option<&FuncType> {
+        let export = self
+            .exports
+            .iter()
+            .find(|e| e.kind == ExternKind::Function && e.name == name)?;
+        let type_index = *self.functions.get(export.index as usize)?;
+        self.types.get(type_index as usize)
+    }
+
+    /// The names of the WASI functions the module imports.
+    pub(crate) fn wasi_imports(&self) -> Vec<&str> {
+        self.imports
+            .iter()
+            .filter(|i| WASI_MODULES.contains(&i.module.as_str()))
+            .map(|i| i.field.as_str())
+            .collect()
+    }
+}
+
+/// Why a module cannot be run.
+#[derive(Debug, Clone)]
+pub(crate) enum ValidationError {
+    /// The module is not well-formed WebAssembly
+    Malformed(String),
+    /// The module does not export the entrypoint function
+    MissingEntrypoint(String),
+    /// The module exports the entrypoint function with a different type than
+    /// the pod gives
+    EntrypointMismatch {
+        name: String,
+        expected: FuncType,
+        found: FuncType,
+    },
+    /// The module imports something the runtime does not provide
+    UnresolvedImport { module: String, field: String },
+    /// The module imports WASI functions that wasm3 does not implement
+    UnsupportedWasi(Vec<String>),
+    /// The module imports WASI functions its pod's profile does not allow
+    WasiNotAllowed {
+        profile: WasiProfile,
+        functions: Vec<String>,
+    },
+    /// The module starts with more memory pages than it is allowed
+    MemoryLimitExceeded { initial: u32, limit: u32 },
+    /// The module is larger than the node or namespace allows
+    ModuleTooLarge { size: u64, limit: u64 },
+    /// An import from one of the pod's libraries cannot be linked
+    LibraryLink(String),
+}
+
+impl ValidationError {
+    /// A CamelCase reason for the pod status and events.
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            ValidationError::Malformed(_) => "InvalidModule",
+            ValidationError::MissingEntrypoint(_) => "MissingEntrypoint",
+            ValidationError::EntrypointMismatch { .. } => "EntrypointTypeMismatch",
+            ValidationError::UnresolvedImport { .. } => "UnresolvedImport",
+            ValidationError::UnsupportedWasi(_) => "UnsupportedWasiImport",
+            ValidationError::WasiNotAllowed { .. } => "WasiCapabilityDenied",
+            ValidationError::MemoryLimitExceeded { .. } => "MemoryLimitExceeded",
+            ValidationError::ModuleTooLarge { .. } => "ModuleTooLarge",
+            ValidationError::LibraryLink(_) => "LibraryLinkError",
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::Malformed(m) => write!(f, "module is not valid WebAssembly: {}", m),
+            ValidationError::MissingEntrypoint(name) => {
+                write!(f, "module does not export a function named {}", name)
+            }
+            ValidationError::EntrypointMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "module exports {} with type {}, but the pod calls it as {}",
+                name, found, expected
+            ),
+            ValidationError::UnresolvedImport { module, field } => {
+                write!(
+                    f,
+                    "module imports {}.{}, which is not provided",
+                    module, field
+                )
+            }
+            ValidationError::UnsupportedWasi(functions) => write!(
+                f,
+                "module imports WASI functions the runtime does not implement: {}",
+                functions.join(", ")
+            ),
+            ValidationError::WasiNotAllowed { profile, functions } => write!(
+                f,
+                "module imports WASI functions the {} profile does not allow: {}",
+                profile,
+                functions.join(", ")
+            ),
+            ValidationError::MemoryLimitExceeded { initial, limit } => write!(
+                f,
+                "module needs {} memory pages to start, but is limited to {}",
+                initial, limit
+            ),
+            ValidationError::ModuleTooLarge { size, limit } => write!(
+                f,
+                "module is {} bytes, larger than the limit of {} bytes",
+                size, limit
+            ),
+            ValidationError::LibraryLink(m) => write!(f, "cannot link library: {}", m),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Checks that `bytes` is a module the runtime can start through
+/// `entrypoint`, given whether the key-value host functions are enabled,
+/// which WASI functions `profile` allows, the libraries it is linked with and
+/// the host extensions the provider was built with.
+/// This parses the module with wasm3, so it should be called from a blocking
+/// context. Modules larger than `max_size` are rejected before they are
+/// parsed, since wasm3 needs several times a module's size to parse it.
+pub(crate) fn validate(
+    bytes: &[u8],
+    entrypoint: &str,
+    kv: bool,
+    max_size: Option<u64>,
+    profile: WasiProfile,
+    libraries: &[(String, ModuleInfo)],
+    extensions: &[Arc<dyn HostExtension>],
+) -> Result<ModuleInfo, ValidationError> {
+    let info = parse(bytes, max_size)?;
+    if !info.exports_function(entrypoint) {
+        return Err(ValidationError::MissingEntrypoint(entrypoint.to_owned()));
+    }
+    check_wasi(&info, profile)?;
+    compose::check_imports(&info, libraries)?;
+    if let Some(import) = info.imports.iter().find(|i| {
+        !is_provided(i, kv)
+            && !libraries.iter().any(|(name, _)| *name == i.module)
+            && hooks::find_extension(extensions, &i.module, &i.field).is_none()
+    }) {
+        return Err(ValidationError::UnresolvedImport {
+            module: import.module.clone(),
+            field: import.field.clone(),
+        });
+    }
+    Ok(info)
+}
+
+/// Checks that a module validated with `validate` exports a typed
+/// `entrypoint` with the type the pod gives it.
+pub(crate) fn check_entrypoint(
+    info: &ModuleInfo,
+    entrypoint: &Entrypoint,
+) -> Result<(), ValidationError> {
+    let expected = entrypoint.func_type();
+    match info.export_type(&entrypoint.name) {
+        Some(found) if *found == expected => Ok(()),
+        Some(found) => Err(ValidationError::EntrypointMismatch {
+            name: entrypoint.name.clone(),
+            expected,
+            found: found.clone(),
+        }),
+        None => Err(ValidationError::MissingEntrypoint(entrypoint.name.clone())),
+    }
+}
+
+/// Checks that `bytes` is a module that can be linked as a library. Libraries
+/// can only import the WASI functions `profile` allows.
+pub(crate) fn validate_library(
+    bytes: &[u8],
+    max_size: Option<u64>,
+    profile: WasiProfile,
+) -> Result<ModuleInfo, ValidationError> {
+    let info = parse(bytes, max_size)?;
+    check_wasi(&info, profile)?;
+    if let Some(import) = info
+        .imports
+        .iter()
+        .find(|i| !WASI_MODULES.contains(&i.module.as_str()))
+    {
+        return Err(ValidationError::UnresolvedImport {
+            module: import.module.clone(),
+            field: import.field.clone(),
+        });
+    }
+    Ok(info)
+}
+
+/// Checks the size of a module and parses it with wasm3.
+fn parse(bytes: &[u8], max_size: Option<u64>) -> Result<ModuleInfo, ValidationError> {
+    let size = bytes.len() as u64;
+    if let Some(limit) = max_size.filter(|limit| size > *limit) {
+        return Err(ValidationError::ModuleTooLarge { size, limit });
+    }
+    let info = parse_module_info(bytes)?;
+    let env = Environment::new().map_err(|e| ValidationError::Malformed(e.to_string()))?;
+    Module::parse(&env, bytes).map_err(|e| ValidationError::Malformed(e.to_string()))?;
+    Ok(info)
+}
+
+/// Checks that wasm3 implements the WASI functions the module imports and
+/// that `profile` allows them.
+fn check_wasi(info: &ModuleInfo, profile: WasiProfile) -> Result<(), ValidationError> {
+    // List every unsupported WASI function at once, so a module built
+    // against a newer WASI libc can be fixed in one go
+    let unsupported: Vec<String> = info
+        .wasi_imports()
+        .into_iter()
+        .filter(|f| !WASI_FUNCTIONS.contains(f))
+        .map(str::to_owned)
+        .collect();
+    if !unsupported.is_empty() {
+        return Err(ValidationError::UnsupportedWasi(unsupported));
+    }
+    let denied: Vec<String> = info
+        .wasi_imports()
+        .into_iter()
+        .filter(|f| !profile.allows(f))
+        .map(str::to_owned)
+        .collect();
+    if !denied.is_empty() {
+        return Err(ValidationError::WasiNotAllowed {
+            profile,
+            functions: denied,
+        });
+    }
+    Ok(())
+}
+
+/// Returns true if the runtime links something for `import`.
+fn is_provided(import: &Import, kv: bool) -> bool {
+    (WASI_MODULES.contains(&import.module.as_str())
+        && WASI_FUNCTIONS.contains(&import.field.as_str()))
+        || (import.module == host::HOST_MODULE && host::provides(&import.field, kv))
+}
+
+/// Caps the linear memory a module defines at `max_pages` pages by lowering
+/// the maximum declared in its memory section, which wasm3 enforces when the
+/// module grows its memory. Returns the rewritten module, or `None` if the
+/// module already stays within the limit. Imported memories are not changed.
+pub(crate) fn limit_memory(
+    bytes: &[u8],
+    max_pages: u32,
+) -> Result<Option<Vec<u8>>, ValidationError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    // The header was checked when the module was validated
+    let mut out = reader.take(8)?.to_vec();
+    let mut changed = false;
+    while !reader.is_empty() {
+        let id = reader.byte()?;
+        let size = reader.leb_u32()? as usize;
+        let content = reader.take(size)?;
+        if id != SECTION_MEMORY {
+            out.push(id);
+            write_leb_u32(&mut out, size as u32);
+            out.extend_from_slice(content);
+            continue;
+        }
+        let mut section = Reader {
+            bytes: content,
+            pos: 0,
+        };
+        let count = section.leb_u32()?;
+        let mut rewritten = Vec::with_capacity(size);
+        write_leb_u32(&mut rewritten, count);
+        for _ in 0..count {
+            let flags = section.byte()?;
+            let initial = section.leb_u32()?;
+            let max = if flags & 1 == 1 {
+                section.leb_u32()?
+            } else {
+                MAX_MEMORY_PAGES
+            };
+            if initial > max_pages {
+                return Err(ValidationError::MemoryLimitExceeded {
+                    initial,
+                    limit: max_pages,
+                });
+            }
+            if max > max_pages {
+                changed = true;
+            }
+            rewritten.push(flags | 1);
+            write_leb_u32(&mut rewritten, initial);
+            write_leb_u32(&mut rewritten, max.min(max_pages));
+        }
+        out.push(id);
+        write_leb_u32(&mut out, rewritten.len() as u32);
+        out.extend_from_slice(&rewritten);
+    }
+    Ok(if changed { Some(out) } else { None })
+}
+
+fn write_leb_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let b = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(b);
+            return;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+/// Reads the import and export sections of a module.
+pub(crate) fn parse_module_info(bytes: &[u8]) -> Result<ModuleInfo, ValidationError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(4)? != WASM_MAGIC {
+        return Err(ValidationError::Malformed(
+            "missing wasm magic number".into(),
+        ));
+    }
+    let version = reader.take(4)?;
+    let version = u32::from_le_bytes([version[0], version[1], version[2], version[3]]);
+    if version != WASM_VERSION {
+        return Err(ValidationError::Malformed(format!(
+            "unsupported wasm version {}",
+            version
+        )));
+    }
+
+    let mut info = ModuleInfo::default();
+    while !reader.is_empty() {
+        let id = reader.byte()?;
+        let size = reader.leb_u32()? as usize;
+        let mut section = Reader {
+            bytes: reader.take(size)?,
+            pos: 0,
+        };
+        match id {
+            SECTION_TYPE => {
+                for _ in 0..section.leb_u32()? {
+                    if section.byte()? != 0x60 {
+                        return Err(ValidationError::Malformed("malformed function type".into()));
+                    }
+                    let count = section.leb_u32()? as usize;
+                    let params = section.take(count)?.to_vec();
+                    let count = section.leb_u32()? as usize;
+                    let results = section.take(count)?.to_vec();
+                    info.types.push(FuncType { params, results });
+                }
+            }
+            SECTION_IMPORT => {
+                for _ in 0..section.leb_u32()? {
+                    let module = section.name()?;
+                    let field = section.name()?;
+                    let kind = ExternKind::from_byte(section.byte()?)?;
+                    let mut type_index = None;
+                    if kind == ExternKind::Function {
+                        let index = section.leb_u32()?;
+                        info.functions.push(index);
+                        type_index = Some(index);
+                    } else if section.skip_import_desc(kind)? {
+                        info.imported_mutable_globals += 1;
+                    }
+                    info.imports.push(Import {
+                        module,
+                        field,
+                        kind,
+                        type_index,
+                    });
+                }
+            }
+            SECTION_FUNCTION => {
+                for _ in 0..section.leb_u32()? {
+                    info.functions.push(section.leb_u32()?);
+                }
+            }
+            SECTION_GLOBAL => {
+                for _ in 0..section.leb_u32()? {
+                    // The value type, then whether the global is mutable
+                    section.byte()?;
+                    if section.byte()? == 1 {
+                        info.defined_mutable_globals += 1;
+                    }
+                    // The rest of the section cannot be read past an
+                    // expression that is not understood, so assume the worst
+                    if !section.skip_init_expr()? {
+                        info.defined_mutable_globals = u32::MAX;
+                        break;
+                    }
+                }
+            }
+            SECTION_EXPORT => {
+                for _ in 0..section.leb_u32()? {
+                    let name = section.name()?;
+                    let kind = ExternKind::from_byte(section.byte()?)?;
+                    let index = section.leb_u32()?;
+                    info.exports.push(Export { name, kind, index });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ValidationError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(ValidationError::Malformed(
+                "unexpected end of module".into(),
+            ));
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, ValidationError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn leb_u32(&mut self) -> Result<u32, ValidationError> {
+        let mut result: u32 = 0;
+        for shift in (0..35).step_by(7) {
+            let b = self.byte()?;
+            result |= u32::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        Err(ValidationError::Malformed("integer too large".into()))
+    }
+
+    fn name(&mut self) -> Result<String, ValidationError> {
+        let len = self.leb_u32()? as usize;
+        let raw = self.take(len)?;
+        String::from_utf8(raw.to_vec())
+            .map_err(|_| ValidationError::Malformed("name is not valid UTF-8".into()))
+    }
+
+    fn limits(&mut self) -> Result<(), ValidationError> {
+        let flags = self.byte()?;
+        self.leb_u32()?;
+        if flags & 1 == 1 {
+            self.leb_u32()?;
+        }
+        Ok(())
+    }
+
+    /// Skips an import description, returning true if it is a mutable
+    /// global.
+    fn skip_import_desc(&mut self, kind: ExternKind) -> Result<bool, ValidationError> {
+        match kind {
+            ExternKind::Function => {
+                self.leb_u32()?;
+            }
+            ExternKind::Table => {
+                self.byte()?;
+                self.limits()?;
+            }
+            ExternKind::Memory => self.limits()?,
+            ExternKind::Global => {
+                self.byte()?;
+                return Ok(self.byte()? == 1);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Skips a constant expression, which is a single constant or
+    /// `global.get` followed by `end`. Returns false if the expression uses
+    /// an instruction this does not know the size of.
+    fn skip_init_expr(&mut self) -> Result<bool, ValidationError> {
+        loop {
+            match self.byte()? {
+                // end
+                0x0b => return Ok(true),
+                // i32.const, i64.const, global.get, whose operands are LEB128
+                0x41 | 0x42 | 0x23 => while self.byte()? & 0x80 != 0 {},
+                // f32.const
+                0x43 => {
+                    self.take(4)?;
+                }
+                // f64.const
+                0x44 => {
+                    self.take(8)?;
+                }
+                _ => return Ok(false),
+            }
+        }
+    }
+}
@@ -1,32 +1,257 @@
-use futures::task;
-use log::{error, info, trace};
+use log::{debug, error, info, warn};
 use std::collections::HashMap;
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::fmt;
+use std::io::{self, SeekFrom};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::task::{Context, Poll};
+use std::time::Duration;
 
-use tempfile::NamedTempFile;
-use tokio::sync::mpsc::Sender;
-use tokio::task::JoinHandle;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use tokio::io::{AsyncRead, AsyncSeek};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::{oneshot, Semaphore};
 use wasm3::{Environment, Module};
 
 use kubelet::container::Handle as ContainerHandle;
 use kubelet::container::Status;
 use kubelet::handle::StopHandler;
 
+use crate::clock::Clock;
+use crate::compose;
+use crate::config::{ContainerLogFormat, SetupTimeouts, StdoutPolicy};
+use crate::cpu;
+use crate::cri_log::{Decoder, LogStream};
+use crate::debug::{Activity, DebugState};
+use crate::entrypoint::Invocation;
+use crate::events::EventRecorder;
+use crate::gateway::{HttpRequest, HttpResponse};
+use crate::hooks::HostExtension;
+use crate::host::{self, FilePolicy, HostContext};
+use crate::kv::KvStore;
+use crate::lifecycle_log::{LifecycleLog, Step};
+use crate::log_files;
+use crate::logging;
+use crate::metrics::ContainerMetrics;
+use crate::module_error::ModuleError;
+use crate::output::ContainerOutput;
+use crate::pod_config::PodConfig;
+use crate::snapshot::{self, SnapshotStore};
+use crate::status::StatusSender;
+use crate::trap::{TrapDetails, TrapKind};
+use crate::validation;
+use crate::watch::{PodWatches, WatchScope};
+
+/// Module bytes shared between the pod state and every runtime started from
+/// them, so restarts never copy the module.
+pub type ModuleData = Arc<[u8]>;
+
+/// The stack size runtimes are created with when no limit is configured.
+pub(crate) const DEFAULT_STACK_SIZE: u32 = 1;
+
+/// How often a run is checked for having gone quiet.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What to do about a run that writes no output and calls no host functions
+/// for a while, which may mean the module is hung.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct IdleWatch {
+    /// How long a run may go without being seen doing anything
+    pub(crate) timeout: Duration,
+    /// Whether a quiet run fails the container so it is restarted, rather
+    /// than only being reported
+    pub(crate) restart: bool,
+}
+
+/// The outcome of a single run of a module's `_start` function.
+type RunResult = anyhow::Result<()>;
+
+/// Resolves once an instance's thread is done with it and its memory has
+/// been freed.
+type Exited = Shared<BoxFuture<'static, ()>>;
+
 pub struct Runtime {
-    handle: JoinHandle<anyhow::Result<()>>,
+    done: oneshot::Receiver<RunResult>,
+    stopper: Stopper,
+}
+
+/// Stops a container's instance. It is kept apart from the container's
+/// handle, so that waiting for an instance to exit holds no lock on the
+/// pod's handle.
+#[derive(Clone)]
+pub(crate) struct Stopper {
+    /// The queue of the instance, if it is kept alive between runs
+    queue: WeakQueue,
+    exited: Exited,
+}
+
+impl Stopper {
+    /// Closes the instance's work queue, so a memoized or reactor instance
+    /// exits once it is idle, and waits for the instance to exit. wasm3
+    /// cannot interrupt a module, so a run of `_start` or a call that is in
+    /// progress is waited for, which may be forever. Callers that cannot
+    /// wait that long should put a timeout on it.
+    pub(crate) async fn stop(self) {
+        close_queue(&self.queue);
+        self.exited.await;
+    }
+
+    /// A stopper for an instance that never exits.
+    #[cfg(test)]
+    pub(crate) fn never() -> Self {
+        Stopper {
+            queue: Weak::new(),
+            exited: futures::future::pending().boxed().shared(),
+        }
+    }
 }
 
 #[async_trait::async_trait]
 impl StopHandler for Runtime {
+    /// Stops the instance as [`Stopper::stop`] does.
     async fn stop(&mut self) -> anyhow::Result<()> {
+        self.stopper.clone().stop().await;
         Ok(())
     }
 
     async fn wait(&mut self) -> anyhow::Result<()> {
-        (&mut self.handle).await??;
-        Ok(())
+        (&mut self.done)
+            .await
+            .map_err(|_| ModuleError::Host("module exited without reporting a result".into()))?
+    }
+}
+
+/// The steps of setting up a module, each of which has its own timeout.
+#[derive(Clone, Copy, Debug)]
+enum SetupPhase {
+    Parse,
+    Instantiate,
+    Link,
+}
+
+impl SetupPhase {
+    fn timeout(self, timeouts: &SetupTimeouts) -> std::time::Duration {
+        match self {
+            SetupPhase::Parse => timeouts.parse,
+            SetupPhase::Instantiate => timeouts.instantiate,
+            SetupPhase::Link => timeouts.link,
+        }
+    }
+}
+
+impl fmt::Display for SetupPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SetupPhase::Parse => "parsing",
+            SetupPhase::Instantiate => "instantiation",
+            SetupPhase::Link => "linking",
+        })
+    }
+}
+
+/// Asks a memoized instance to run `_start` again.
+struct RunRequest {
+    done: oneshot::Sender<RunResult>,
+}
+
+/// Asks a live instance to call one of its exported functions.
+struct CallRequest {
+    export: String,
+    done: oneshot::Sender<anyhow::Result<()>>,
+}
+
+/// Asks a live instance to handle an HTTP request from the gateway with one
+/// of its exported functions.
+struct HttpCall {
+    export: String,
+    request: HttpRequest,
+    /// The most bytes the handler may write to the response body
+    max_body: usize,
+    done: oneshot::Sender<anyhow::Result<HttpResponse>>,
+}
+
+/// Work for a live instance. An instance does one piece of work at a time,
+/// in the order it was queued, so work sent while it is busy waits its turn.
+enum Work {
+    Run(RunRequest),
+    Call(CallRequest),
+    Http(HttpCall),
+}
+
+/// The queue of a container's live instance, owned by the runtime. The
+/// instance serves it until the sender is dropped, with the runtime or when
+/// it is cleared.
+type Queue = Arc<Mutex<Option<std::sync::mpsc::Sender<Work>>>>;
+
+/// A reference to a [`Queue`] that does not keep the instance alive.
+type WeakQueue = Weak<Mutex<Option<std::sync::mpsc::Sender<Work>>>>;
+
+/// Clears the queue, if its runtime is still around, so the instance exits
+/// once it is done with the work in hand and later work gets a fresh one.
+fn close_queue(queue: &WeakQueue) {
+    if let Some(queue) = queue.upgrade() {
+        *queue.lock().unwrap() = None;
+    }
+}
+
+/// A handle for queueing calls on a container's live instance. It does not
+/// keep the instance alive.
+#[derive(Clone)]
+pub(crate) struct WorkQueue {
+    queue: WeakQueue,
+}
+
+impl WorkQueue {
+    /// Queues work on the instance, failing if there is no live instance to
+    /// take it.
+    fn send(&self, work: Work) -> anyhow::Result<()> {
+        let sent = match self.queue.upgrade() {
+            Some(queue) => match queue.lock().unwrap().as_ref() {
+                Some(sender) => sender.send(work).is_ok(),
+                None => false,
+            },
+            None => false,
+        };
+        if sent {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("the container has no live instance"))
+        }
+    }
+
+    /// Calls `export` once the work queued ahead of it is done, and returns
+    /// its result.
+    pub(crate) async fn call(&self, export: &str) -> anyhow::Result<()> {
+        let (done, done_rx) = oneshot::channel();
+        self.send(Work::Call(CallRequest {
+            export: export.to_owned(),
+            done,
+        }))?;
+        done_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("the instance exited before calling {}", export))?
+    }
+
+    /// Has `export` handle `request` once the work queued ahead of it is
+    /// done, and returns the response it built.
+    pub(crate) async fn http(
+        &self,
+        export: &str,
+        request: HttpRequest,
+        max_body: usize,
+    ) -> anyhow::Result<HttpResponse> {
+        let (done, done_rx) = oneshot::channel();
+        self.send(Work::Http(HttpCall {
+            export: export.to_owned(),
+            request,
+            max_body,
+            done,
+        }))?;
+        done_rx
+            .await
+            .map_err(|_| anyhow::anyhow!("the instance exited before calling {}", export))?
     }
 }
 
@@ -37,36 +262,206 @@ pub struct WasiRuntime {
     name: String,
     /// Data needed for the runtime
     data: Arc<Data>,
-    /// The tempfile that output from the wasmtime process writes to
-    output: Arc<NamedTempFile>,
+    /// The log file that output from this instance of the container is
+    /// written to
+    output: PathBuf,
     /// A channel to send status updates on the runtime
-    status_sender: Sender<(String, Status)>,
+    status_sender: StatusSender,
     /// The stack size to be used with the wasm3 runtime.
     stack_size: u32,
+    /// Records events against the pod this runtime belongs to
+    events: EventRecorder,
+    /// Where the container's lifecycle steps are recorded, if anywhere
+    lifecycle: Option<LifecycleLog>,
+    /// Metrics for this container
+    metrics: ContainerMetrics,
+    /// Whether the instance is kept alive after `_start` returns so a restart
+    /// only calls `_start` again
+    memoize: bool,
+    /// Whether the instance serves calls after `_start` returns instead of
+    /// completing
+    reactor: bool,
+    /// Work for a memoized instance that is still alive
+    warm: Queue,
+    /// Resolves when the current instance has exited, once one was started
+    exited: Mutex<Option<Exited>>,
+    /// Limits on how long each setup phase may take
+    timeouts: SetupTimeouts,
+    /// How the module's output is buffered before it reaches the log
+    stdout: StdoutPolicy,
+    /// The pod's key-value store, if the key-value host functions are enabled
+    kv_dir: Option<PathBuf>,
+    /// The pod's ConfigMap and Secret data for the config host functions
+    config: Arc<PodConfig>,
+    /// Where snapshots of the memory left by `_initialize` are kept, if the
+    /// pod asked for them
+    snapshot_dir: Option<PathBuf>,
+    /// How long a single run of `_start` may take
+    budget: Option<Duration>,
+    /// How a run that goes quiet is handled, if it is watched
+    idle: Option<IdleWatch>,
+    /// What the instance publishes for `wasm3-debug`
+    debug: Arc<DebugState>,
+    /// The core the instance's thread is pinned to, if any
+    cpu: Option<usize>,
+    /// Where the watch host functions look, if the node allows them
+    watch: Option<WatchScope>,
+    /// Permits to set up an instance, if the node limits how many are set up
+    /// at once
+    starts: Option<Arc<Semaphore>>,
+    /// What deadlines, status times and log timestamps read the time from
+    clock: Arc<dyn Clock>,
 }
 
 struct Data {
     /// binary module data to be run as a wasm module
-    module_data: Vec<u8>,
+    module_data: ModuleData,
     /// key/value environment variables made available to the wasm process
     env: HashMap<String, String>,
     /// the arguments passed as the command-line arguments list
     args: Vec<String>,
-    /// a hash map of local file system paths to optional path names in the runtime
-    /// (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
-    /// the same path will be allowed in the runtime
-    dirs: HashMap<PathBuf, Option<PathBuf>>,
+    /// the typed function the module is started with instead of `_start`,
+    /// if the pod names one
+    invocation: Option<Invocation>,
+    /// how the module may open files
+    files: FilePolicy,
+    /// host files the module may read with the `host_file_read` host function
+    host_files: Vec<PathBuf>,
+    /// library modules the module is linked with, keyed by import module name
+    libraries: Vec<(String, ModuleData)>,
+    /// host functions linked for crates embedding the provider
+    host_extensions: Vec<Arc<dyn HostExtension>>,
 }
 
-/// Holds our tempfile handle.
+const LOG_BYTES_METRIC: &str = "wasm3_log_bytes_streamed_total";
+
+const MEMORY_METRIC: &str = "wasm3_memory_bytes";
+
+/// How often the size of an instance's linear memory is published.
+const MEMORY_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Opens readers of a container's log file.
 pub struct HandleFactory {
-    temp: Arc<NamedTempFile>,
+    path: PathBuf,
+    format: ContainerLogFormat,
+    metrics: ContainerMetrics,
 }
 
-impl kubelet::log::HandleFactory<tokio::fs::File> for HandleFactory {
-    /// Creates `tokio::fs::File` on demand for log reading.
-    fn new_handle(&self) -> tokio::fs::File {
-        tokio::fs::File::from_std(self.temp.reopen().unwrap())
+impl kubelet::log::HandleFactory<LogReader> for HandleFactory {
+    /// Creates a `LogReader` on demand for log reading. The instance's log
+    /// may have been swept since it started, in which case the container's
+    /// newest log is read instead, or nothing if it has none.
+    fn new_handle(&self) -> LogReader {
+        let file = std::fs::File::open(&self.path).or_else(|e| {
+            let latest = self
+                .path
+                .parent()
+                .and_then(|dir| log_files::latest_instance_log(dir).ok().flatten());
+            match latest {
+                Some(latest) => std::fs::File::open(latest),
+                None => Err(e),
+            }
+        });
+        let file = match file {
+            Ok(file) => Some(tokio::fs::File::from_std(file)),
+            Err(e) => {
+                warn!(
+                    "Unable to open container log {}: {:?}",
+                    self.path.display(),
+                    e
+                );
+                None
+            }
+        };
+        LogReader {
+            file,
+            metrics: self.metrics.clone(),
+            decoder: match self.format {
+                ContainerLogFormat::Plain => None,
+                ContainerLogFormat::Cri => Some(Decoder::default()),
+            },
+            decoded: Vec::new(),
+        }
+    }
+}
+
+/// Reads a container log, counting the bytes streamed to log consumers. A
+/// log in the CRI format is read back as the output it was made from.
+pub struct LogReader {
+    /// The log, or `None` if it could not be opened, which reads as empty
+    file: Option<tokio::fs::File>,
+    metrics: ContainerMetrics,
+    decoder: Option<Decoder>,
+    /// Decoded output not yet read
+    decoded: Vec<u8>,
+}
+
+impl LogReader {
+    fn poll_read_file(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Poll::Ready(Ok(0)),
+        };
+        let decoder = match self.decoder.as_mut() {
+            Some(decoder) => decoder,
+            None => return Pin::new(file).poll_read(cx, buf),
+        };
+        while self.decoded.is_empty() {
+            let mut raw = vec![0; buf.len().max(1)];
+            match Pin::new(&mut *file).poll_read(cx, &mut raw) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(n)) => self.decoded = decoder.decode(&raw[..n]),
+                poll => return poll,
+            }
+        }
+        let n = buf.len().min(self.decoded.len());
+        buf[..n].copy_from_slice(&self.decoded[..n]);
+        self.decoded.drain(..n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncRead for LogReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = self.poll_read_file(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            if n > 0 {
+                self.metrics.inc_counter(
+                    LOG_BYTES_METRIC,
+                    "Bytes of container log streamed to log consumers",
+                    n as f64,
+                );
+            }
+        }
+        poll
+    }
+}
+
+impl AsyncSeek for LogReader {
+    fn start_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        position: SeekFrom,
+    ) -> Poll<io::Result<()>> {
+        if let Some(decoder) = self.decoder.as_mut() {
+            decoder.reset();
+        }
+        self.decoded.clear();
+        match self.file.as_mut() {
+            Some(file) => Pin::new(file).start_seek(cx, position),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        match self.file.as_mut() {
+            Some(file) => Pin::new(file).poll_complete(cx),
+            None => Poll::Ready(Ok(0)),
+        }
     }
 }
 
@@ -78,264 +473,1078 @@ impl WasiRuntime {
     /// * `module_path` - the path to the WebAssembly binary
     /// * `env` - a collection of key/value pairs containing the environment variables
     /// * `args` - the arguments passed as the command-line arguments list
-    /// * `dirs` - a map of local file system paths to optional path names in the runtime
-    ///     (e.g. /tmp/foo/myfile -> /app/config). If the optional value is not given,
-    ///     the same path will be allowed in the runtime
-    /// * `log_dir` - location for storing logs
-    pub async fn new<L: AsRef<Path> + Send + Sync + 'static>(
+    /// * `invocation` - the typed function to start the module with instead of `_start`, if any
+    /// * `files` - how the module may open files, such as only for reading
+    /// * `host_files` - host files the module may read through the `host_file_read` host function
+    /// * `libraries` - library modules to link the module with, by the import module name they provide
+    /// * `log_file` - the file this instance's output is written to
+    /// * `events` - recorder for events against the owning pod
+    /// * `lifecycle` - where the container's lifecycle steps are recorded, if anywhere
+    /// * `metrics` - metrics for this container
+    /// * `memoize` - keep the parsed and linked module alive between runs
+    /// * `reactor` - keep the container running after `_start` returns, serving calls
+    /// * `timeouts` - limits on how long parsing, instantiation and linking may take
+    /// * `stdout` - how the module's output is buffered before it reaches the log
+    /// * `kv_dir` - the pod's key-value store, if the key-value host functions are enabled
+    /// * `config` - the pod's ConfigMap and Secret data for the config host functions
+    /// * `snapshot_dir` - where to keep snapshots of the memory left by `_initialize`, if any
+    /// * `stack_size` - bytes of stack the wasm3 runtime is created with
+    /// * `budget` - how long a single run of `_start` may take
+    /// * `idle` - how a run that writes no output and calls no host functions is handled, if at all
+    /// * `cpu` - the core to pin the instance's thread to, if any
+    /// * `watch` - where the watch host functions look, if the node allows them
+    /// * `starts` - permits to set up an instance, if the node limits how many are set up at once
+    /// * `clock` - what deadlines, status times and log timestamps read the time from
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
         name: String,
-        module_data: Vec<u8>,
+        module_data: ModuleData,
         env: HashMap<String, String>,
         args: Vec<String>,
-        dirs: HashMap<PathBuf, Option<PathBuf>>,
-        log_dir: L,
-        status_sender: Sender<(String, Status)>,
-    ) -> anyhow::Result<Self> {
-        let temp = tokio::task::spawn_blocking(move || -> anyhow::Result<NamedTempFile> {
-            Ok(NamedTempFile::new_in(log_dir)?)
-        })
-        .await??;
-
-        // We need to use named temp file because we need multiple file handles
-        // and if we are running in the temp dir, we run the possibility of the
-        // temp file getting cleaned out from underneath us while running. If we
-        // think it necessary, we can make these permanent files with a cleanup
-        // loop that runs elsewhere. These will get deleted when the reference
-        // is dropped
-        Ok(WasiRuntime {
+        invocation: Option<Invocation>,
+        files: FilePolicy,
+        host_files: Vec<PathBuf>,
+        libraries: Vec<(String, ModuleData)>,
+        host_extensions: Vec<Arc<dyn HostExtension>>,
+        log_file: PathBuf,
+        status_sender: StatusSender,
+        events: EventRecorder,
+        lifecycle: Option<LifecycleLog>,
+        metrics: ContainerMetrics,
+        memoize: bool,
+        reactor: bool,
+        timeouts: SetupTimeouts,
+        stdout: StdoutPolicy,
+        kv_dir: Option<PathBuf>,
+        config: Arc<PodConfig>,
+        snapshot_dir: Option<PathBuf>,
+        stack_size: u32,
+        budget: Option<Duration>,
+        idle: Option<IdleWatch>,
+        cpu: Option<usize>,
+        watch: Option<WatchScope>,
+        starts: Option<Arc<Semaphore>>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        WasiRuntime {
             name,
             data: Arc::new(Data {
                 module_data,
                 env,
                 args,
-                dirs,
+                invocation,
+                files,
+                host_files,
+                libraries,
+                host_extensions,
             }),
-            output: Arc::new(temp),
+            output: log_file,
             status_sender,
-            stack_size: 1,
-        })
+            stack_size,
+            events,
+            lifecycle,
+            metrics,
+            memoize,
+            reactor,
+            warm: Default::default(),
+            exited: Default::default(),
+            timeouts,
+            stdout,
+            kv_dir,
+            config,
+            snapshot_dir,
+            budget,
+            idle,
+            debug: Arc::new(DebugState::new(clock.clone())),
+            cpu,
+            watch,
+            starts,
+            clock,
+        }
+    }
+
+    /// The state the container's instance publishes for `wasm3-debug`.
+    pub(crate) fn debug_state(&self) -> Arc<DebugState> {
+        self.debug.clone()
+    }
+
+    /// A handle for queueing calls on the instance, if it is kept alive to
+    /// take them.
+    pub(crate) fn work_queue(&self) -> Option<WorkQueue> {
+        if self.memoize {
+            Some(WorkQueue {
+                queue: Arc::downgrade(&self.warm),
+            })
+        } else {
+            None
+        }
     }
 
     pub async fn start(&self) -> anyhow::Result<ContainerHandle<Runtime, HandleFactory>> {
-        let temp = self.output.clone();
-        // Because a reopen is blocking, run in a blocking task to get new
-        // handles to the tempfile
-        let output_write = tokio::task::spawn_blocking(move || -> anyhow::Result<std::fs::File> {
-            Ok(temp.reopen()?)
-        })
-        .await??;
+        let (done_tx, done_rx) = oneshot::channel();
+
+        // A memoized instance that is still alive only needs to be told to
+        // run again. If it has gone away, fall back to a fresh instance.
+        let done_tx = match self.warm.lock().unwrap().as_ref() {
+            Some(warm) => match warm.send(Work::Run(RunRequest { done: done_tx })) {
+                Ok(()) => None,
+                Err(std::sync::mpsc::SendError(Work::Run(request))) => Some(request.done),
+                Err(std::sync::mpsc::SendError(_)) => unreachable!(),
+            },
+            None => Some(done_tx),
+        };
 
-        let handle = self.spawn_wasm3(output_write).await?;
+        if let Some(done_tx) = done_tx {
+            let path = self.output.clone();
+            // Because opening is blocking, run in a blocking task to get a
+            // new handle to the log file
+            let output_write =
+                tokio::task::spawn_blocking(move || -> anyhow::Result<std::fs::File> {
+                    Ok(std::fs::OpenOptions::new().append(true).open(path)?)
+                })
+                .await??;
+            self.spawn_wasm3(output_write, done_tx).await?;
+        } else {
+            debug!("Reusing memoized instance of {}", self.name);
+        }
 
         let log_handle_factory = HandleFactory {
-            temp: self.output.clone(),
+            path: self.output.clone(),
+            format: self.stdout.format,
+            metrics: self.metrics.clone(),
         };
 
         Ok(ContainerHandle::new(
             Runtime {
-                handle,
+                done: done_rx,
+                stopper: self.stopper(),
             },
             log_handle_factory,
         ))
     }
 
+    /// Stops the instance last started.
+    pub(crate) fn stopper(&self) -> Stopper {
+        let exited = self
+            .exited
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| futures::future::ready(()).boxed().shared());
+        Stopper {
+            queue: Arc::downgrade(&self.warm),
+            exited,
+        }
+    }
+
     // Spawns a running wasmtime instance with the given context and status
     // channel. Due to the Instance type not being Send safe, all of the logic
-    // needs to be done within the spawned task
+    // needs to be done within the spawned task. Returns once the module is set
+    // up, or fails if a setup phase takes longer than its timeout
     async fn spawn_wasm3(
         &self,
-        _output_write: std::fs::File,
-    ) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
-        // Clone the module data Arc so it can be moved
-        let data = self.data.clone();
+        output_write: std::fs::File,
+        done: oneshot::Sender<RunResult>,
+    ) -> anyhow::Result<()> {
+        // Held until setup is over, so the setup timeouts only start once
+        // the instance may be set up
+        let _permit = match &self.starts {
+            Some(starts) => Some(starts.acquire().await),
+            None => None,
+        };
+        let queue = if self.memoize {
+            let (tx, rx) = std::sync::mpsc::channel();
+            *self.warm.lock().unwrap() = Some(tx);
+            Some(rx)
+        } else {
+            None
+        };
+        let (progress, mut progress_rx) = mpsc::unbounded_channel();
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let output = ContainerOutput::new(
+            output_write,
+            self.stdout,
+            self.clock.clone(),
+            &tokio::runtime::Handle::current(),
+        );
+        self.debug.set_output(&output, &self.output);
+        let instance = Instance {
+            // Clone the module data Arc so it can be moved
+            data: self.data.clone(),
+            name: self.name.clone(),
+            stack_size: self.stack_size,
+            status_sender: self.status_sender.clone(),
+            events: self.events.clone(),
+            lifecycle: self.lifecycle.clone(),
+            metrics: self.metrics.clone(),
+            kv_dir: self.kv_dir.clone(),
+            config: self.config.clone(),
+            snapshot_dir: self.snapshot_dir.clone(),
+            budget: self.budget,
+            idle: self.idle,
+            watch: self.watch.clone(),
+            reactor: self.reactor,
+            warm: Arc::downgrade(&self.warm),
+            debug: self.debug.clone(),
+            // Blocking threads are not part of the async runtime, so grab a
+            // handle to it for reporting events from inside the spawned task
+            runtime_handle: tokio::runtime::Handle::current(),
+            output,
+            abandoned: abandoned.clone(),
+            poisoned: false,
+            reports_readiness: false,
+            clock: self.clock.clone(),
+        };
+
+        let fields = self.events.log_fields().container(&self.name);
+        let debug = self.debug.clone();
+        let (exited, exited_rx) = oneshot::channel::<()>();
+        tokio::spawn(sample_memory(
+            self.debug.clone(),
+            self.metrics.clone(),
+            exited_rx,
+        ));
+        let (stopped, stopped_rx) = oneshot::channel::<()>();
+        *self.exited.lock().unwrap() = Some(stopped_rx.map(|_| ()).boxed().shared());
+        let run = move || {
+            // The thread runs nothing else until the instance is done, so
+            // everything it logs is about this container
+            logging::with_fields(fields, || {
+                let mut done = Some(done);
+                if let Err(e) = instance.run(&mut done, queue, progress) {
+                    if let Some(done) = done.take() {
+                        let _ = done.send(Err(e));
+                    }
+                }
+            });
+            debug.set_activity(Activity::Exited);
+            drop(exited);
+            drop(stopped);
+        };
+        match self.cpu {
+            // Blocking pool threads are reused for other work, so a pinned
+            // instance gets a thread of its own
+            Some(cpu) => {
+                let name = self.name.clone();
+                let spawned = std::thread::Builder::new()
+                    .name(format!("wasm3-cpu{}", cpu))
+                    .spawn(move || {
+                        if let Err(e) = cpu::pin_current_thread(cpu) {
+                            warn!("Unable to pin {} to core {}: {}", name, cpu, e);
+                        }
+                        run()
+                    });
+                // Dropping the instance closes its channels, which reports
+                // the failure below
+                if let Err(e) = spawned {
+                    error!("Unable to start a thread for {}: {}", self.name, e);
+                }
+            }
+            None => {
+                tokio::task::spawn_blocking(run);
+            }
+        }
+
+        // The instance reports each phase as it enters it and drops the
+        // channel once setup is over, whether or not it succeeded
+        let mut phase = SetupPhase::Parse;
+        loop {
+            let timeout = phase.timeout(&self.timeouts);
+            let progress = tokio::select! {
+                progress = progress_rx.recv() => Some(progress),
+                _ = self.clock.sleep(timeout) => None,
+            };
+            match progress {
+                Some(Some(next)) => phase = next,
+                Some(None) => return Ok(()),
+                None => {
+                    // wasm3 cannot be interrupted, so the blocking thread is
+                    // left to finish on its own and then discards the module
+                    abandoned.store(true, Ordering::SeqCst);
+                    *self.warm.lock().unwrap() = None;
+                    let error = ModuleError::SetupTimeout(format!(
+                        "module {} did not finish within {}s",
+                        phase,
+                        timeout.as_secs()
+                    ));
+                    let fields = self.events.log_fields().container(&self.name);
+                    logging::with_fields(fields, || error!("Container {}: {}", self.name, error));
+                    self.events
+                        .warning(error.reason(), &error.event_message(&self.name))
+                        .await;
+                    self.status_sender.send(&self.name, error.terminated());
+                    return Err(error.into());
+                }
+            }
+        }
+    }
+}
+
+/// Publishes the size of an instance's linear memory as the instance thread
+/// last saw it, until `exited` resolves when the instance is gone and its
+/// memory freed. Another thread cannot read the memory of a running module
+/// safely, so the size is as fresh as the module's last host call.
+async fn sample_memory(
+    debug: Arc<DebugState>,
+    metrics: ContainerMetrics,
+    mut exited: oneshot::Receiver<()>,
+) {
+    let help = "Size of the linear memory of the container's module";
+    let mut interval = tokio::time::interval(MEMORY_SAMPLE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                metrics.set_gauge(MEMORY_METRIC, help, debug.memory_size() as f64)
+            }
+            _ = &mut exited => break,
+        }
+    }
+    metrics.set_gauge(MEMORY_METRIC, help, 0.0);
+}
+
+/// Everything a wasm3 instance needs on the thread it runs on.
+struct Instance {
+    data: Arc<Data>,
+    name: String,
+    stack_size: u32,
+    status_sender: StatusSender,
+    events: EventRecorder,
+    lifecycle: Option<LifecycleLog>,
+    metrics: ContainerMetrics,
+    kv_dir: Option<PathBuf>,
+    config: Arc<PodConfig>,
+    snapshot_dir: Option<PathBuf>,
+    runtime_handle: tokio::runtime::Handle,
+    /// The container log, shared with the host functions
+    output: Arc<ContainerOutput>,
+    /// Set when setup timed out and nobody is waiting for this instance
+    abandoned: Arc<AtomicBool>,
+    budget: Option<Duration>,
+    idle: Option<IdleWatch>,
+    watch: Option<WatchScope>,
+    reactor: bool,
+    /// The owning runtime's work queue, cleared if this instance can no
+    /// longer serve it. Held weakly, as a strong reference would keep the
+    /// queue open, and the instance waiting on it, after the runtime is gone.
+    warm: WeakQueue,
+    debug: Arc<DebugState>,
+    /// Set once the module has trapped, after which the instance is not used
+    /// again
+    poisoned: bool,
+    /// Whether the module reports its own readiness with `set_ready`
+    reports_readiness: bool,
+    clock: Arc<dyn Clock>,
+}
+
+impl Instance {
+    /// Sets up the module and runs `_start`, reporting each run's result on
+    /// `done`. With a `queue`, the instance then serves calls and requests to
+    /// run again, one at a time, until the sending side goes away. Setup
+    /// phases are reported on `progress`, which is dropped once setup is
+    /// over.
+    fn run(
+        mut self,
+        done: &mut Option<oneshot::Sender<RunResult>>,
+        queue: Option<std::sync::mpsc::Receiver<Work>>,
+        progress: UnboundedSender<SetupPhase>,
+    ) -> RunResult {
+        self.debug.instance_started();
         let name = self.name.clone();
-        let stack_size = self.stack_size.clone();
         let status_sender = self.status_sender.clone();
+        let data = self.data.clone();
+        let abandoned = self.abandoned.clone();
+        let events = self.events.clone();
+        let runtime_handle = self.runtime_handle.clone();
+        let timed_out = || ModuleError::SetupTimeout("module setup timed out".into());
+        let enter = |phase: SetupPhase| -> RunResult {
+            if abandoned.load(Ordering::SeqCst) {
+                return Err(timed_out().into());
+            }
+            let _ = progress.send(phase);
+            Ok(())
+        };
 
-        let handle = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
-            let waker = task::noop_waker();
-            let mut cx = Context::from_waker(&waker);
+        // Every setup failure is reported the same way, so funnel them
+        // through here rather than matching on each step
+        let fail = |error: ModuleError| -> anyhow::Error {
+            error!("Container {}: {}", name, error);
+            // The timeout has already been reported for an abandoned instance
+            if !abandoned.load(Ordering::SeqCst) {
+                let events = events.clone();
+                let (reason, message) = (error.reason(), error.event_message(&name));
+                runtime_handle.spawn(async move {
+                    events.warning(reason, &message).await;
+                });
+                status_sender.send(&name, error.terminated());
+            }
+            error.into()
+        };
+        let parse =
+            |what: &str, e: wasm3::error::Error| ModuleError::Parse(format!("{}: {}", what, e));
+        let link =
+            |what: &str, e: wasm3::error::Error| ModuleError::Link(format!("{}: {}", what, e));
 
-            let env = match Environment::new() {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(e) => e,
-                Err(e) => {
-                    let message = "cannot create environment";
-                    error!("{}: {:?}", message, e);
-                    send(
-                        status_sender.clone(),
-                        name,
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                        &mut cx,
-                    );
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
-                }
-            };
+        let env = Environment::new().map_err(|e| fail(parse("cannot create environment", e)))?;
+        self.metrics.inc_counter(
+            "wasm3_environments_created_total",
+            "wasm3 environments created for the container, one per fresh instance",
+            1.0,
+        );
+        let rt = env
+            .create_runtime(self.stack_size)
+            .map_err(|e| fail(parse("cannot create runtime", e)))?;
+        let module = Module::parse(&env, &data.module_data)
+            .map_err(|e| fail(parse("cannot parse module", e)))?;
+        self.record(Step::Parsed, None);
+        enter(SetupPhase::Instantiate)?;
+        let mut module = rt
+            .load_module(module)
+            .map_err(|e| fail(parse("cannot load module", e)))?;
+        enter(SetupPhase::Link)?;
+        module
+            .link_wasi()
+            .map_err(|e| fail(link("cannot link WASI", e)))?;
+        // Set before linking, as libraries' `_initialize` runs as they are
+        // linked and may call host functions
+        let kv = self.kv_dir.clone().map(KvStore::new);
+        let _context = host::enter(
+            HostContext::new(
+                self.metrics.clone(),
+                self.output.clone(),
+                kv,
+                data.host_files.clone(),
+                self.debug.clone(),
+                self.config.clone(),
+                self.watch.clone().map(|scope| {
+                    PodWatches::new(scope, runtime_handle.clone(), self.metrics.clone())
+                }),
+            )
+            .with_files(data.files)
+            .with_command_line(&self.name, &data.args, &data.env)
+            .with_readiness(self.status_sender.clone(), &self.name),
+        );
+        // The module was validated before it was started, so this only
+        // fails if validation was skipped, and then nothing extra is linked
+        let info = validation::parse_module_info(&data.module_data).unwrap_or_default();
+        self.reports_readiness = info
+            .imports
+            .iter()
+            .any(|i| i.module == host::HOST_MODULE && i.field == host::SET_READY);
+        host::link(&mut module, &info, data.files, &data.host_extensions)
+            .map_err(|e| fail(link("cannot link host functions", e)))?;
+        // Libraries are freed when this is dropped, after the module's last
+        // call
+        let _libraries = compose::link(
+            &mut module,
+            &info,
+            &data.libraries,
+            self.stack_size,
+            data.files,
+        )
+        .map_err(|e| fail(ModuleError::Link(e)))?;
+        match &data.invocation {
+            Some(invocation) => invocation.check(&module).map_err(|e| {
+                fail(link(
+                    &format!("cannot find function '{}' in module", invocation.name()),
+                    e,
+                ))
+            })?,
+            None => {
+                module
+                    .find_function::<(), ()>("_start")
+                    .map_err(|e| fail(link("cannot find function '_start' in module", e)))?;
+            }
+        }
+        self.record(Step::Linked, None);
+        if abandoned.load(Ordering::SeqCst) {
+            return Err(timed_out().into());
+        }
+        // Closing the channel tells the waiting task that setup is over
+        drop(progress);
+        // Safety: only called between runs, when nothing else uses the memory
+        let memory_size = || unsafe { (*rt.memory()).len() };
+        self.debug.set_memory_size(memory_size());
 
-            let rt = match env.create_runtime(stack_size) {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(rt) => rt,
-                Err(e) => {
-                    let message = "cannot create runtime";
-                    error!("{}: {:?}", message, e);
-                    send(
-                        status_sender.clone(),
-                        name.clone(),
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                        &mut cx,
-                    );
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
-                }
+        // `_initialize` runs once per instance, so memoized restarts keep
+        // what it set up
+        if info.exports_function(snapshot::INITIALIZE) {
+            let snapshots = match &self.snapshot_dir {
+                Some(dir) if snapshot::can_snapshot(&info) => Some((
+                    SnapshotStore::new(dir),
+                    snapshot::snapshot_key(
+                        &data.module_data,
+                        &data.env,
+                        &data.args,
+                        self.stack_size,
+                    ),
+                )),
+                _ => None,
             };
-
-            let module = match Module::parse(&env, &data.module_data) {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(m) => m,
-                Err(e) => {
-                    let message = "cannot parse module";
-                    error!("{}: {:?}", message, e);
-                    send(
-                        status_sender.clone(),
-                        name.clone(),
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                        &mut cx,
-                    );
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
+            let restored = snapshots.as_ref().map_or(false, |(store, key)| {
+                store
+                    .load(key)
+                    .map_or(false, |memory| snapshot::restore(&rt, &memory))
+            });
+            if restored {
+                info!(
+                    "Restored {} from a snapshot instead of initializing it",
+                    name
+                );
+            } else {
+                let func = module
+                    .find_function::<(), ()>(snapshot::INITIALIZE)
+                    .map_err(|e| fail(link("cannot find function '_initialize' in module", e)))?;
+                self.debug.set_activity(Activity::Initializing);
+                if let Err(e) = func.call() {
+                    self.poison();
+                    return Err(self.report_trap(&e).into());
+                }
+                if let Some((store, key)) = snapshots {
+                    match store.save(&key, &snapshot::capture(&rt)) {
+                        Ok(()) => debug!("Saved a snapshot of {} after initializing it", name),
+                        Err(e) => warn!("Unable to save a snapshot of {}: {:?}", name, e),
+                    }
                 }
+            }
+        }
+
+        loop {
+            let func = match &data.invocation {
+                Some(_) => None,
+                None => Some(
+                    module
+                        .find_function::<(), ()>("_start")
+                        .map_err(|e| fail(link("cannot find function '_start' in module", e)))?,
+                ),
             };
 
-            let mut module = match rt.load_module(module) {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(m) => m,
-                Err(e) => {
-                    let message = "cannot load module";
-                    error!("{}: {:?}", message, e);
-                    send(
-                        status_sender.clone(),
-                        name.clone(),
+            let expired = Arc::new(AtomicBool::new(false));
+            let finished = self.watch_budget(expired.clone());
+            let quiet = self.watch_idle(expired.clone());
+            self.debug.set_activity(Activity::Starting);
+            self.record(Step::Started, None);
+            self.report_started();
+            let call = match (&func, &data.invocation) {
+                (Some(func), _) => func.call().map(|()| None),
+                (None, Some(invocation)) => invocation.call(&module),
+                (None, None) => unreachable!("_start is found when there is no entrypoint"),
+            };
+            for finished in finished.into_iter().chain(quiet) {
+                let _ = finished.send(());
+            }
+            // The log is complete before the run is reported
+            self.flush_output();
+            self.debug.set_memory_size(memory_size());
+            self.debug.set_activity(Activity::Idle);
+            // Whoever sets this first reports the run
+            if expired.swap(true, Ordering::SeqCst) {
+                info!("Stopped run of {} returned, discarding it", name);
+                return Ok(());
+            }
+            let result = match call {
+                // A reactor has only set itself up, and stays running to
+                // serve calls
+                Ok(_) if self.reactor => {
+                    info!("module {} started, serving calls", name);
+                    self.record(Step::Yielded, None);
+                    Ok(())
+                }
+                Ok(value) => {
+                    // The termination message is where a typed entrypoint's
+                    // result is read from
+                    let message = match (value, &data.invocation) {
+                        (Some(value), Some(invocation)) => format!(
+                            "Module run complete, {} returned {}",
+                            invocation.name(),
+                            value
+                        ),
+                        _ => "Module run complete".to_owned(),
+                    };
+                    info!("{}", message);
+                    self.record(Step::Exited, Some(&message));
+                    status_sender.send(
+                        &name,
                         Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
+                            failed: false,
+                            message,
+                            timestamp: self.clock.now(),
                         },
-                        &mut cx,
                     );
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
+                    Ok(())
                 }
-            };
-
-            match module.link_wasi() {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(_) => {}
                 Err(e) => {
-                    let message = "cannot link WASI";
-                    error!("{}: {:?}", message, e);
-                    send(
-                        status_sender.clone(),
-                        name.clone(),
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                        &mut cx,
-                    );
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
+                    self.poison();
+                    Err(self.report_trap(&e).into())
                 }
             };
+            if let Some(done) = done.take() {
+                let _ = done.send(result);
+            }
+            if self.poisoned {
+                return Ok(());
+            }
 
-            let func = match module.find_function::<(), ()>("_start") {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(f) => f,
-                Err(e) => {
-                    let message = "cannot find function '_start' in module";
-                    error!("{}: {:?}", message, e);
-                    send(
-                        status_sender.clone(),
-                        name.clone(),
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                        &mut cx,
-                    );
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
+            let request = loop {
+                match queue.as_ref().map(|q| q.recv()) {
+                    Some(Ok(Work::Run(request))) => break request,
+                    Some(Ok(Work::Call(request))) => {
+                        let result = self.call(&module, &request.export);
+                        let _ = request.done.send(result);
+                        if self.poisoned {
+                            return Ok(());
+                        }
+                        self.debug.set_memory_size(memory_size());
+                        self.debug.set_activity(Activity::Idle);
+                    }
+                    Some(Ok(Work::Http(request))) => {
+                        host::begin_http(request.request, request.max_body);
+                        let result = self.call(&module, &request.export);
+                        let response = host::end_http();
+                        let _ = request.done.send(result.and_then(|()| {
+                            response.ok_or_else(|| anyhow::anyhow!("the response was lost"))
+                        }));
+                        if self.poisoned {
+                            return Ok(());
+                        }
+                        self.debug.set_memory_size(memory_size());
+                        self.debug.set_activity(Activity::Idle);
+                    }
+                    _ => return Ok(()),
                 }
             };
+            info!("Restarting memoized instance of {}", name);
+            *done = Some(request.done);
+        }
+    }
 
-            match func.call() {
-                // We can't map errors here or it moves the send channel, so we
-                // do it in a match
-                Ok(_) => {}
-                Err(e) => {
-                    let message = "unable to run module";
-                    error!("{}: {:?}", message, e);
-                    send(
-                        status_sender.clone(),
-                        name.clone(),
-                        Status::Terminated {
-                            failed: true,
-                            message: message.into(),
-                            timestamp: chrono::Utc::now(),
-                        },
-                        &mut cx,
-                    );
-                    return Err(anyhow::anyhow!("{}: {}", message, e));
+    /// Calls an export for a queued request. A trap is returned to the caller,
+    /// and poisons the instance: the container is reported as failed so that
+    /// it is restarted with a fresh one.
+    fn call(&mut self, module: &Module<'_>, export: &str) -> anyhow::Result<()> {
+        if export == "_start" || export == snapshot::INITIALIZE {
+            Err(anyhow::anyhow!("{} cannot be called directly", export))
+        } else {
+            match module.find_function::<(), ()>(export) {
+                Ok(func) => {
+                    debug!("Calling {} on {}", export, self.name);
+                    self.debug
+                        .set_activity(Activity::Calling(export.to_owned()));
+                    let result = func.call();
+                    self.flush_output();
+                    result.map_err(|e| {
+                        let trap = TrapDetails::new(&e);
+                        error!("call to {} on {} failed: {}", export, self.name, trap);
+                        self.log_trap(&trap);
+                        self.record(Step::Trapped, Some(&format!("in {}: {}", export, trap)));
+                        let error = ModuleError::Trap(trap);
+                        self.poison();
+                        self.report_error(&error);
+                        error.into()
+                    })
                 }
-            };
+                Err(e) => Err(anyhow::anyhow!(
+                    "cannot find function '{}' in module: {}",
+                    export,
+                    e
+                )),
+            }
+        }
+    }
 
-            info!("module run complete");
-            send(
-                status_sender.clone(),
-                name,
-                Status::Terminated {
-                    failed: false,
-                    message: "Module run complete".into(),
-                    timestamp: chrono::Utc::now(),
-                },
-                &mut cx,
+    /// Starts timing a run of `_start` against the budget, if there is one.
+    /// If the run is still going when the budget is spent, it is reported as
+    /// `DeadlineExceeded` and `expired` is set. wasm3 cannot be interrupted,
+    /// so the run is left to finish on its own and its result is discarded.
+    /// Returns the sender to signal on once the run finishes.
+    fn watch_budget(&self, expired: Arc<AtomicBool>) -> Option<oneshot::Sender<()>> {
+        let budget = self.budget?;
+        let (finished, finished_rx) = oneshot::channel();
+        let name = self.name.clone();
+        let status_sender = self.status_sender.clone();
+        let events = self.events.clone();
+        let metrics = self.metrics.clone();
+        let warm = self.warm.clone();
+        let clock = self.clock.clone();
+        self.runtime_handle.spawn(async move {
+            let started = clock.instant();
+            tokio::select! {
+                _ = finished_rx => return,
+                _ = clock.sleep(budget) => {}
+            }
+            if expired.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            // The busy instance cannot take restart requests, so restarts
+            // get a fresh one
+            close_queue(&warm);
+            metrics.inc_counter(
+                "wasm3_budget_exceeded_total",
+                "Module runs stopped for going over their time budget",
+                1.0,
             );
+            let error = ModuleError::DeadlineExceeded(format!(
+                "module ran for {:.1}s, over its time budget of {}s",
+                clock
+                    .instant()
+                    .saturating_duration_since(started)
+                    .as_secs_f64(),
+                budget.as_secs()
+            ));
+            error!("Container {}: {}", name, error);
+            events
+                .warning(error.reason(), &error.event_message(&name))
+                .await;
+            status_sender.send(&name, error.terminated());
+        });
+        Some(finished)
+    }
 
-            Ok(())
+    /// Watches a run of `_start` for going quiet, if the pod asked for it. A
+    /// run that writes no output and calls no host functions for the idle
+    /// timeout is reported with a `ModuleIdle` warning, once each time it
+    /// goes quiet. With the restart action the run is also stopped, as an
+    /// over budget run is, and `expired` is set. Returns the sender to signal
+    /// on once the run finishes.
+    fn watch_idle(&self, expired: Arc<AtomicBool>) -> Option<oneshot::Sender<()>> {
+        let idle = self.idle?;
+        let (finished, mut finished_rx) = oneshot::channel();
+        let name = self.name.clone();
+        let status_sender = self.status_sender.clone();
+        let events = self.events.clone();
+        let metrics = self.metrics.clone();
+        let warm = self.warm.clone();
+        let debug = self.debug.clone();
+        let clock = self.clock.clone();
+        self.runtime_handle.spawn(async move {
+            let mut reported = false;
+            loop {
+                tokio::select! {
+                    _ = &mut finished_rx => return,
+                    _ = clock.sleep(idle.timeout.min(IDLE_CHECK_INTERVAL)) => {}
+                }
+                let quiet = debug.idle_for();
+                if quiet < idle.timeout {
+                    reported = false;
+                    continue;
+                }
+                if reported {
+                    continue;
+                }
+                reported = true;
+                metrics.inc_counter(
+                    "wasm3_idle_runs_total",
+                    "Module runs that wrote no output and called no host functions for their idle timeout",
+                    1.0,
+                );
+                let error = ModuleError::Idle(format!(
+                    "module wrote no output and called no host functions for {}s",
+                    quiet.as_secs()
+                ));
+                warn!("Container {}: {}", name, error);
+                events
+                    .warning(error.reason(), &error.event_message(&name))
+                    .await;
+                if !idle.restart {
+                    continue;
+                }
+                if expired.swap(true, Ordering::SeqCst) {
+                    return;
+                }
+                // As with an over budget run, restarts get a fresh instance
+                close_queue(&warm);
+                status_sender.send(&name, error.terminated());
+                return;
+            }
         });
+        Some(finished)
+    }
 
-        Ok(handle)
+    /// Reports the container running as `_start` begins, rather than when it
+    /// returns, so a module that runs for as long as the pod does still
+    /// counts as started.
+    fn report_started(&self) {
+        // Each run has to say it is ready again
+        self.status_sender
+            .set_ready(&self.name, Some(false).filter(|_| self.reports_readiness));
+        self.status_sender.send(
+            &self.name,
+            Status::Running {
+                timestamp: self.clock.now(),
+            },
+        );
+        let events = self.events.clone();
+        let message = format!("Started container {}", self.name);
+        self.runtime_handle.spawn(async move {
+            events.normal("Started", &message).await;
+        });
     }
-}
 
-fn send(mut sender: Sender<(String, Status)>, name: String, status: Status, cx: &mut Context<'_>) {
-    loop {
-        if let Poll::Ready(r) = sender.poll_ready(cx) {
-            if r.is_ok() {
-                sender
-                    .try_send((name, status))
-                    .expect("Possible deadlock, exiting");
-                return;
+    /// Writes trap details to the container log after any output still
+    /// buffered.
+    fn log_trap(&self, trap: &TrapDetails) {
+        let line = format!("{}\n", trap);
+        if let Err(e) = self
+            .output
+            .write(LogStream::Stderr, line.as_bytes())
+            .and_then(|()| self.output.flush())
+        {
+            error!("unable to write trap details to container log: {:?}", e);
+        }
+    }
+
+    /// Writes out any output the module left buffered.
+    fn flush_output(&self) {
+        if let Err(e) = self.output.flush() {
+            error!("unable to flush container output: {:?}", e);
+        }
+    }
+
+    /// Reports a trap from `_start` and returns it as the run's error.
+    fn report_trap(&mut self, e: &wasm3::error::Error) -> ModuleError {
+        let trap = TrapDetails::new(e);
+        error!("unable to run module {}: {}", self.name, trap);
+        // Put the full details in the container log so they show up in
+        // `kubectl logs` even though the status is truncated
+        self.log_trap(&trap);
+        self.record(Step::Trapped, Some(&trap.to_string()));
+        let error = ModuleError::Trap(trap);
+        self.report_error(&error);
+        error
+    }
+
+    /// Records a step in the container's lifecycle, if they are recorded.
+    fn record(&self, step: Step, detail: Option<&str>) {
+        if let Some(lifecycle) = &self.lifecycle {
+            lifecycle.record(step, detail);
+        }
+    }
+
+    /// Reports the container failed with `error`, with a warning event.
+    fn report_error(&self, error: &ModuleError) {
+        if let ModuleError::Trap(trap) = error {
+            if trap.kind == TrapKind::StackOverflow {
+                self.metrics.inc_counter(
+                    "wasm3_stack_overflows_total",
+                    "Traps because the module ran out of stack",
+                    1.0,
+                );
             }
-            trace!("Receiver for status showing as closed: {:?}", r);
         }
-        trace!(
-            "Channel for container {} not ready for send. Attempting again",
-            name
+        let (reason, message) = (error.reason(), error.event_message(&self.name));
+        let events = self.events.clone();
+        self.runtime_handle.spawn(async move {
+            events.warning(reason, &message).await;
+        });
+        self.status_sender.send(&self.name, error.terminated());
+    }
+
+    /// Marks the instance as poisoned after a trap. A trap can leave linear
+    /// memory and the module's globals half updated, so the instance stops
+    /// taking work and restarts get a fresh environment instead of this one.
+    fn poison(&mut self) {
+        if self.poisoned {
+            return;
+        }
+        self.poisoned = true;
+        close_queue(&self.warm);
+        self.metrics.inc_counter(
+            "wasm3_instances_poisoned_total",
+            "Instances discarded because their module trapped",
+            1.0,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::metrics::Metrics;
+    use crate::status::{self, StatusReceiver};
+    use kubelet::log::HandleFactory as _;
+    use tokio::io::AsyncReadExt;
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    const EMPTY: &str = r#"(module
+        (memory (export "memory") 1)
+        (func (export "_start")))"#;
+
+    const TRAP: &str = r#"(module
+        (memory (export "memory") 1)
+        (func (export "_start") unreachable))"#;
+
+    const HELLO: &str = r#"(module
+        (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+        (memory (export "memory") 1)
+        (data (i32.const 16) "hello\n")
+        (func (export "_start")
+            (i32.store (i32.const 0) (i32.const 16))
+            (i32.store (i32.const 4) (i32.const 6))
+            (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))"#;
+
+    /// Traps unless it is given two arguments, counting the program name,
+    /// and one environment variable.
+    const COMMAND_LINE: &str = r#"(module
+        (import "wasi_snapshot_preview1" "args_sizes_get"
+            (func $args_sizes_get (param i32 i32) (result i32)))
+        (import "wasi_snapshot_preview1" "environ_sizes_get"
+            (func $environ_sizes_get (param i32 i32) (result i32)))
+        (memory (export "memory") 1)
+        (func (export "_start")
+            (drop (call $args_sizes_get (i32.const 0) (i32.const 4)))
+            (if (i32.ne (i32.load (i32.const 0)) (i32.const 2)) (then unreachable))
+            (drop (call $environ_sizes_get (i32.const 0) (i32.const 4)))
+            (if (i32.ne (i32.load (i32.const 0)) (i32.const 1)) (then unreachable))))"#;
+
+    struct Test {
+        runtime: WasiRuntime,
+        statuses: StatusReceiver,
+        metrics: Arc<Metrics>,
+        log: tempfile::TempPath,
+    }
+
+    fn runtime(wat: &str, env: HashMap<String, String>, args: Vec<String>, memoize: bool) -> Test {
+        let module: ModuleData = wat::parse_str(wat).unwrap().into();
+        let log = tempfile::NamedTempFile::new().unwrap().into_temp_path();
+        let metrics: Arc<Metrics> = Default::default();
+        let (status_sender, statuses) = status::channel();
+        let runtime = WasiRuntime::new(
+            "test".to_owned(),
+            module,
+            env,
+            args,
+            None,
+            FilePolicy::default(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            log.to_path_buf(),
+            status_sender,
+            EventRecorder::local("test"),
+            None,
+            ContainerMetrics::new(metrics.clone(), "default", "test", "test"),
+            memoize,
+            false,
+            Default::default(),
+            Default::default(),
+            None,
+            Default::default(),
+            None,
+            64 * 1024,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Arc::new(SystemClock),
         );
+        Test {
+            runtime,
+            statuses,
+            metrics,
+            log,
+        }
+    }
+
+    /// Waits for the container to terminate, returning whether it failed.
+    async fn terminated(statuses: &mut StatusReceiver) -> bool {
+        loop {
+            let status = tokio::time::timeout(TEST_TIMEOUT, statuses.recv())
+                .await
+                .expect("the container did not terminate")
+                .expect("the runtime went away");
+            if let (_, Status::Terminated { failed, .. }) = status {
+                return failed;
+            }
+        }
+    }
+
+    async fn read_log(factory: &HandleFactory) -> String {
+        let mut log = String::new();
+        factory.new_handle().read_to_string(&mut log).await.unwrap();
+        log
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn a_run_that_returns_completes() {
+        let mut test = runtime(EMPTY, HashMap::new(), Vec::new(), false);
+        let mut handle = test.runtime.start().await.unwrap();
+        assert!(!terminated(&mut test.statuses).await);
+        handle.wait().await.unwrap();
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn a_trap_fails_the_container() {
+        let mut test = runtime(TRAP, HashMap::new(), Vec::new(), false);
+        let mut handle = test.runtime.start().await.unwrap();
+        assert!(terminated(&mut test.statuses).await);
+        assert!(handle.wait().await.is_err());
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn the_module_is_given_the_containers_arguments_and_environment() {
+        let env = vec![("GREETING".to_owned(), "hello".to_owned())]
+            .into_iter()
+            .collect();
+        let mut test = runtime(COMMAND_LINE, env, vec!["world".to_owned()], false);
+        let _handle = test.runtime.start().await.unwrap();
+        assert!(!terminated(&mut test.statuses).await);
+
+        let mut test = runtime(COMMAND_LINE, HashMap::new(), Vec::new(), false);
+        let _handle = test.runtime.start().await.unwrap();
+        assert!(terminated(&mut test.statuses).await);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn output_reaches_the_log() {
+        let mut test = runtime(HELLO, HashMap::new(), Vec::new(), false);
+        let _handle = test.runtime.start().await.unwrap();
+        assert!(!terminated(&mut test.statuses).await);
+        let factory = HandleFactory {
+            path: test.log.to_path_buf(),
+            format: ContainerLogFormat::Plain,
+            metrics: ContainerMetrics::new(test.metrics.clone(), "default", "test", "test"),
+        };
+        assert_eq!(read_log(&factory).await, "hello\n");
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn a_swept_log_reads_the_newest_one_or_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let factory = HandleFactory {
+            path: dir.path().join("0.log"),
+            format: ContainerLogFormat::Plain,
+            metrics: ContainerMetrics::new(Default::default(), "default", "test", "test"),
+        };
+        assert_eq!(read_log(&factory).await, "");
+
+        std::fs::write(dir.path().join("1.log"), "newer\n").unwrap();
+        assert_eq!(read_log(&factory).await, "newer\n");
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn a_memoized_instance_is_reused_and_exits_when_stopped() {
+        let mut test = runtime(EMPTY, HashMap::new(), Vec::new(), true);
+        let _first = test.runtime.start().await.unwrap();
+        assert!(!terminated(&mut test.statuses).await);
+        let mut second = test.runtime.start().await.unwrap();
+        assert!(!terminated(&mut test.statuses).await);
+        assert!(test.metrics.render().contains(
+            "wasm3_environments_created_total{namespace=\"default\",pod=\"test\",container=\"test\"} 1\n"
+        ));
+
+        tokio::time::timeout(TEST_TIMEOUT, second.stop())
+            .await
+            .expect("the instance did not exit")
+            .unwrap();
+        assert!(test
+            .runtime
+            .work_queue()
+            .unwrap()
+            .call("missing")
+            .await
+            .is_err());
     }
 }
@@ -0,0 +1,388 @@
+//! Kubernetes watches for the `k8s_watch` and `k8s_watch_next` host
+//! functions, so a module can follow resources and act on them as a small
+//! controller.
+//!
+//! Watches are confined to the pod's namespace. Before a watch starts, a
+//! `SubjectAccessReview` checks that the pod's service account may `watch`
+//! the resource, so a module can see no more than its service account
+//! allows. The watch itself is made with the node's credentials, which must
+//! also be allowed to watch the resource.
+//!
+//! Events are the API server's watch events, one JSON object each, queued
+//! for the module to take in order. A watch that ends is started again from
+//! the last version seen. If that version is too old, the watch starts from
+//! scratch, and the module is sent an `ADDED` event for every object again.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec,
+};
+use kube::api::{Api, PostParams};
+use log::{debug, warn};
+
+use crate::metrics::ContainerMetrics;
+
+/// The most watches an instance may have.
+const MAX_WATCHES: usize = 8;
+
+/// The most events queued for a watch. The oldest is dropped to make room.
+const MAX_QUEUED_EVENTS: usize = 256;
+
+/// How long a single watch request lasts before it is made again.
+const WATCH_TIMEOUT_SECS: u32 = 300;
+
+/// How long to wait before watching again after the watch fails.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Why a watch could not be started or read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum WatchError {
+    /// The node does not let modules watch resources
+    Disabled,
+    /// The resource, selector or watch is not valid
+    Invalid,
+    /// The instance has as many watches as it may have
+    Limit,
+    /// The watch has ended and every event has been taken
+    Closed,
+}
+
+/// Who a pod's watches are authorized as, and where they look.
+#[derive(Clone)]
+pub(crate) struct WatchScope {
+    pub(crate) client: kube::Client,
+    pub(crate) namespace: String,
+    pub(crate) service_account: String,
+}
+
+/// A resource a module can watch: `configmaps`, `v1/configmaps` or
+/// `apps/v1/deployments`.
+#[derive(Clone, Debug)]
+struct Resource {
+    group: String,
+    version: String,
+    plural: String,
+}
+
+impl Resource {
+    fn parse(resource: &str) -> Option<Self> {
+        let valid = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-')
+        };
+        let parts: Vec<&str> = resource.trim().split('/').collect();
+        let (group, version, plural) = match parts.as_slice() {
+            [plural] => ("", "v1", *plural),
+            [version, plural] => ("", *version, *plural),
+            [group, version, plural] => (*group, *version, *plural),
+            _ => return None,
+        };
+        if (!group.is_empty() && !valid(group)) || !valid(version) || !valid(plural) {
+            return None;
+        }
+        Some(Resource {
+            group: group.to_owned(),
+            version: version.to_owned(),
+            plural: plural.to_owned(),
+        })
+    }
+
+    fn path(&self, namespace: &str) -> String {
+        if self.group.is_empty() {
+            format!(
+                "/api/{}/namespaces/{}/{}",
+                self.version, namespace, self.plural
+            )
+        } else {
+            format!(
+                "/apis/{}/{}/namespaces/{}/{}",
+                self.group, self.version, namespace, self.plural
+            )
+        }
+    }
+}
+
+/// Events not yet taken by the module.
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<Vec<u8>>,
+    /// Set once the watch has ended for good
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Watch {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl Watch {
+    fn push(&self, event: Vec<u8>) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let dropped = queue.events.len() >= MAX_QUEUED_EVENTS;
+        if dropped {
+            queue.events.pop_front();
+        }
+        queue.events.push_back(event);
+        self.ready.notify_all();
+        dropped
+    }
+
+    fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// The watches of one instance. They end when it is dropped with the
+/// instance.
+pub(crate) struct PodWatches {
+    scope: WatchScope,
+    runtime: tokio::runtime::Handle,
+    metrics: ContainerMetrics,
+    watches: Mutex<Vec<Arc<Watch>>>,
+}
+
+impl PodWatches {
+    pub(crate) fn new(
+        scope: WatchScope,
+        runtime: tokio::runtime::Handle,
+        metrics: ContainerMetrics,
+    ) -> Self {
+        PodWatches {
+            scope,
+            runtime,
+            metrics,
+            watches: Default::default(),
+        }
+    }
+
+    /// Starts watching `resource`, limited to objects matching the label
+    /// `selector` if it is not empty, and returns the watch's ID.
+    pub(crate) fn start(&self, resource: &str, selector: &str) -> Result<i32, WatchError> {
+        let resource = Resource::parse(resource).ok_or(WatchError::Invalid)?;
+        let mut watches = self.watches.lock().unwrap();
+        if watches.len() >= MAX_WATCHES {
+            return Err(WatchError::Limit);
+        }
+        let watch: Arc<Watch> = Default::default();
+        self.runtime.spawn(run(
+            Arc::downgrade(&watch),
+            self.scope.clone(),
+            resource,
+            selector.trim().to_owned(),
+            self.metrics.clone(),
+        ));
+        watches.push(watch);
+        Ok(watches.len() as i32 - 1)
+    }
+
+    /// Waits up to `timeout` for an event on watch `id`, and copies it into
+    /// `buf` if it fits. Returns the event's length, which is more than
+    /// `buf` holds if it did not fit, in which case it stays queued, or 0 if
+    /// no event came in time.
+    pub(crate) fn next(
+        &self,
+        id: i32,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<usize, WatchError> {
+        let watch = match self.watches.lock().unwrap().get(id as usize) {
+            Some(watch) if id >= 0 => watch.clone(),
+            _ => return Err(WatchError::Invalid),
+        };
+        let queue = watch.queue.lock().unwrap();
+        let (mut queue, _) = watch
+            .ready
+            .wait_timeout_while(queue, timeout, |q| q.events.is_empty() && !q.closed)
+            .unwrap();
+        let len = match queue.events.front() {
+            Some(event) => event.len(),
+            None if queue.closed => return Err(WatchError::Closed),
+            None => return Ok(0),
+        };
+        if len <= buf.len() {
+            let event = queue.events.pop_front().unwrap_or_default();
+            buf[..len].copy_from_slice(&event);
+        }
+        Ok(len)
+    }
+}
+
+/// Asks the API server whether the pod's service account may watch the
+/// resource.
+async fn authorized(scope: &WatchScope, resource: &Resource) -> anyhow::Result<bool> {
+    let reviews: Api<SubjectAccessReview> = Api::all(scope.client.clone());
+    let review = SubjectAccessReview {
+        spec: SubjectAccessReviewSpec {
+            user: Some(format!(
+                "system:serviceaccount:{}:{}",
+                scope.namespace, scope.service_account
+            )),
+            groups: Some(vec![
+                "system:serviceaccounts".to_owned(),
+                format!("system:serviceaccounts:{}", scope.namespace),
+                "system:authenticated".to_owned(),
+            ]),
+            resource_attributes: Some(ResourceAttributes {
+                verb: Some("watch".to_owned()),
+                group: Some(resource.group.clone()),
+                version: Some(resource.version.clone()),
+                resource: Some(resource.plural.clone()),
+                namespace: Some(scope.namespace.clone()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    Ok(reviews
+        .create(&PostParams::default(), &review)
+        .await?
+        .status
+        .map_or(false, |s| s.allowed))
+}
+
+/// An `ERROR` watch event, as the API server would send it.
+fn error_event(reason: &str, message: &str) -> Vec<u8> {
+    serde_json::json!({
+        "type": "ERROR",
+        "object": {
+            "kind": "Status",
+            "apiVersion": "v1",
+            "status": "Failure",
+            "reason": reason,
+            "message": message,
+        }
+    })
+    .to_string()
+    .into_bytes()
+}
+
+/// Percent encodes a query parameter value.
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Authorizes the watch, then feeds its events to the module until the
+/// module's instance is gone.
+async fn run(
+    watch: Weak<Watch>,
+    scope: WatchScope,
+    resource: Resource,
+    selector: String,
+    metrics: ContainerMetrics,
+) {
+    let allowed = authorized(&scope, &resource).await;
+    let denied = match allowed {
+        Ok(true) => None,
+        Ok(false) => Some(error_event(
+            "Forbidden",
+            &format!(
+                "service account {} may not watch {} in namespace {}",
+                scope.service_account, resource.plural, scope.namespace
+            ),
+        )),
+        Err(e) => {
+            warn!(
+                "Unable to authorize a watch of {}: {:?}",
+                resource.plural, e
+            );
+            Some(error_event(
+                "InternalError",
+                "unable to authorize the watch",
+            ))
+        }
+    };
+    if let Some(event) = denied {
+        if let Some(watch) = watch.upgrade() {
+            watch.push(event);
+            watch.close();
+        }
+        return;
+    }
+
+    let mut version: Option<String> = None;
+    loop {
+        match watch_once(&watch, &scope, &resource, &selector, &mut version, &metrics).await {
+            Ok(true) => (),
+            Ok(false) => return,
+            Err(_) if watch.upgrade().is_none() => return,
+            Err(e) => {
+                debug!("Watch of {} failed, will retry: {:?}", resource.plural, e);
+                tokio::time::delay_for(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Makes one watch request, queueing its events. Returns false once the
+/// module's instance is gone.
+async fn watch_once(
+    watch: &Weak<Watch>,
+    scope: &WatchScope,
+    resource: &Resource,
+    selector: &str,
+    version: &mut Option<String>,
+    metrics: &ContainerMetrics,
+) -> anyhow::Result<bool> {
+    let mut uri = format!(
+        "{}?watch=true&timeoutSeconds={}",
+        resource.path(&scope.namespace),
+        WATCH_TIMEOUT_SECS
+    );
+    if !selector.is_empty() {
+        uri.push_str(&format!("&labelSelector={}", encode(selector)));
+    }
+    if let Some(version) = version {
+        uri.push_str(&format!("&resourceVersion={}", encode(version)));
+    }
+    let request = warp::http::Request::get(uri).body(Vec::new())?;
+    let stream = scope.client.request_text_stream(request).await?;
+    tokio::pin!(stream);
+    let mut pending = Vec::new();
+    while let Some(chunk) = stream.try_next().await? {
+        pending.extend_from_slice(&chunk);
+        while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = &line[..line.len() - 1];
+            if line.is_empty() {
+                continue;
+            }
+            let event: serde_json::Value = serde_json::from_slice(line)?;
+            if event["type"] == "ERROR" && event["object"]["code"] == 410 {
+                // The version is too old, so start again from scratch
+                *version = None;
+                return Ok(watch.upgrade().is_some());
+            }
+            if let Some(v) = event["object"]["metadata"]["resourceVersion"].as_str() {
+                *version = Some(v.to_owned());
+            }
+            let watch = match watch.upgrade() {
+                Some(watch) => watch,
+                None => return Ok(false),
+            };
+            if watch.push(line.to_vec()) {
+                metrics.inc_counter(
+                    "wasm3_watch_events_dropped_total",
+                    "Watch events dropped because the module did not take them in time",
+                    1.0,
+                );
+            }
+        }
+    }
+    Ok(watch.upgrade().is_some())
+}
@@ -0,0 +1,124 @@
+//! Lifecycle notifications for external systems. With a webhook configured,
+//! the provider POSTs a JSON object when a pod's containers have started and
+//! when a container terminates, so platforms can follow what the node runs
+//! without scraping logs. Delivery is best effort: notifications are sent in
+//! the background, and one that cannot be delivered is logged and counted but
+//! never holds up the pod.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use kubelet::pod::Pod;
+use log::{debug, warn};
+
+use crate::metrics::Metrics;
+
+/// How long a notification may take before it is given up on.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something that happened to a pod.
+pub(crate) enum LifecycleEvent {
+    /// All of the pod's containers have started
+    PodStarted,
+    /// One of the pod's containers finished or failed
+    ContainerTerminated {
+        container: String,
+        exit_code: i32,
+        message: String,
+    },
+}
+
+impl LifecycleEvent {
+    fn kind(&self) -> &'static str {
+        match self {
+            LifecycleEvent::PodStarted => "PodStarted",
+            LifecycleEvent::ContainerTerminated { .. } => "ContainerTerminated",
+        }
+    }
+}
+
+/// Sends lifecycle events to the configured webhook.
+pub(crate) struct Notifier {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    node_name: String,
+    metrics: Arc<Metrics>,
+}
+
+impl Notifier {
+    pub(crate) fn new(url: &str, node_name: &str, metrics: Arc<Metrics>) -> anyhow::Result<Self> {
+        let url = reqwest::Url::parse(url)
+            .map_err(|e| anyhow::anyhow!("invalid lifecycle webhook URL: {}", e))?;
+        Ok(Notifier {
+            client: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()?,
+            url,
+            node_name: node_name.to_owned(),
+            metrics,
+        })
+    }
+
+    /// Sends `event` about `pod` in the background.
+    pub(crate) fn notify(&self, pod: &Pod, event: LifecycleEvent) {
+        let body = self.body(pod, &event);
+        let kind = event.kind();
+        let namespace = pod.namespace().to_owned();
+        let request = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        let metrics = self.metrics.clone();
+        tokio::spawn(async move {
+            let result = match request.body(body.to_string()).send().await {
+                Ok(response) => response.error_for_status().map(|_| ()),
+                Err(e) => Err(e),
+            };
+            let outcome = match result {
+                Ok(()) => {
+                    debug!("Sent {} lifecycle event for {}", kind, body["pod"]);
+                    "delivered"
+                }
+                Err(e) => {
+                    warn!(
+                        "Unable to send {} lifecycle event for {}: {}",
+                        kind, body["pod"], e
+                    );
+                    "failed"
+                }
+            };
+            metrics.inc_counter(
+                "wasm3_lifecycle_webhook_events_total",
+                "Lifecycle events sent to the webhook, by outcome",
+                &[
+                    ("namespace", namespace.as_str()),
+                    ("event", kind),
+                    ("outcome", outcome),
+                ],
+                1.0,
+            );
+        });
+    }
+
+    fn body(&self, pod: &Pod, event: &LifecycleEvent) -> serde_json::Value {
+        let mut body = serde_json::json!({
+            "type": event.kind(),
+            "time": chrono::Utc::now().to_rfc3339(),
+            "node": self.node_name,
+            "namespace": pod.namespace(),
+            "pod": pod.name(),
+            "uid": crate::pod_uid(pod),
+        });
+        if let LifecycleEvent::ContainerTerminated {
+            container,
+            exit_code,
+            message,
+        } = event
+        {
+            body["container"] = container.as_str().into();
+            body["exitCode"] = (*exit_code).into();
+            body["message"] = message.as_str().into();
+        }
+        body
+    }
+}
@@ -0,0 +1,112 @@
+//! Pods added again, deleted and recreated under the same key, and deleted
+//! while they are being added.
+
+mod common;
+
+use common::{deleting, pod, TestNode};
+use kubelet::state::AsyncDrop;
+use serde_json::json;
+
+fn recreated(pod: &serde_json::Value, uid: &str) -> serde_json::Value {
+    let mut pod = pod.clone();
+    pod["metadata"]["uid"] = json!(uid);
+    pod
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_repeated_add_is_ignored_until_the_pod_is_dropped() {
+    let node = TestNode::start().await;
+    let hello = pod("hello", "hello", "Never");
+
+    let state = node.add(&hello).await.unwrap();
+    assert!(node.add(&hello).await.is_err());
+
+    state.async_drop().await;
+    node.add(&hello).await.unwrap();
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_pod_added_again_after_it_was_deleted_runs_again() {
+    let node = TestNode::start().await;
+    let hello = pod("hello", "hello", "Never");
+
+    for _ in 0..2 {
+        let mut running = node.run(&hello).await;
+        running.finished().await.unwrap();
+        node.phase("hello", "Succeeded").await;
+        // The log of the run before was removed with it
+        assert_eq!(node.logs("hello", "hello").await.unwrap(), "hello, world\n");
+        running.delete(&deleting(&hello, false)).await;
+        assert!(node.logs("hello", "hello").await.is_err());
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn dropping_a_pod_that_was_recreated_leaves_the_new_one() {
+    let node = TestNode::start().await;
+    let old = pod("hello", "hello", "Never");
+    let new = recreated(&old, "hello-uid-2");
+
+    let old_state = node.add(&old).await.unwrap();
+    let new_state = node.add(&new).await.unwrap();
+    old_state.async_drop().await;
+
+    // The new pod is still known, so adding it again is still ignored
+    assert!(node.add(&new).await.is_err());
+    let mut running = node.run_added(&new, new_state);
+    running.finished().await.unwrap();
+    assert_eq!(node.logs("hello", "hello").await.unwrap(), "hello, world\n");
+    running.delete(&deleting(&new, false)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn concurrent_deletes_of_the_same_key_free_it() {
+    let node = TestNode::start().await;
+    let old = pod("hello", "hello", "Never");
+    let new = recreated(&old, "hello-uid-2");
+
+    let old_state = node.add(&old).await.unwrap();
+    let new_state = node.add(&new).await.unwrap();
+    futures::join!(old_state.async_drop(), new_state.async_drop());
+
+    node.add(&recreated(&old, "hello-uid-3")).await.unwrap();
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn concurrent_deletes_of_running_pods_clean_up_each() {
+    let node = TestNode::start().await;
+    let first = pod("first", "hello", "Never");
+    let second = pod("second", "hello", "Never");
+    let mut first_running = node.run(&first).await;
+    let mut second_running = node.run(&second).await;
+    first_running.finished().await.unwrap();
+    second_running.finished().await.unwrap();
+
+    futures::join!(
+        first_running.delete(&deleting(&first, false)),
+        second_running.delete(&deleting(&second, true)),
+    );
+
+    for name in &["first", "second"] {
+        assert!(node.logs(name, name).await.is_err());
+        node.add(&pod(name, "hello", "Never")).await.unwrap();
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_pod_deleted_while_it_is_added_is_not_started() {
+    let node = TestNode::start().await;
+    let hello = pod("hello", "hello", "Never");
+    let state = node.add(&hello).await.unwrap();
+
+    // The deletion reaches the API server before the pod's state machine
+    // starts, and the provider sees it the next time it lists pods
+    node.api.put_pod(&deleting(&hello, false));
+    node.relist().await;
+
+    let mut running = node.run_added(&hello, state);
+    running.finished().await.unwrap();
+    assert_ne!(node.api.phase("hello").as_deref(), Some("Succeeded"));
+    assert!(node.logs("hello", "hello").await.is_err());
+    running.delete(&deleting(&hello, false)).await;
+}
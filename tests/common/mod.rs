@@ -0,0 +1,511 @@
+//! A harness for running pods through the provider in tests, against a mock
+//! Kubernetes API server and with modules built from the WAT fixtures in
+//! `tests/fixtures`.
+//!
+//! Each [`TestNode`] has a data directory of its own and a
+//! [`SimulatedClock`], so restart backoff, TTLs and the provider's periodic
+//! loops only move when a test advances the clock.
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use krustlet_wasm3::clock::SimulatedClock;
+use krustlet_wasm3::config::ModuleSource;
+use krustlet_wasm3::store::DirectoryStore;
+use krustlet_wasm3::{PodState, ProviderConfig, WasiProvider};
+use kubelet::pod::Pod;
+use kubelet::provider::Provider;
+use kubelet::state::AsyncDrop;
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use warp::http::{Method, StatusCode};
+use warp::Filter;
+
+pub const NAMESPACE: &str = "default";
+
+pub const NODE_NAME: &str = "test-node";
+
+/// How long a test waits for something that should happen straight away.
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the provider watches pods again after a watch ends.
+const WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The fixture modules, by name, and the image each is served as.
+const FIXTURES: &[(&str, &str)] = &[
+    ("hello", include_str!("../fixtures/hello.wat")),
+    ("env-echo", include_str!("../fixtures/env-echo.wat")),
+    ("crash", include_str!("../fixtures/crash.wat")),
+    ("loop-forever", include_str!("../fixtures/loop-forever.wat")),
+];
+
+/// The image a fixture is pulled as.
+pub fn image(fixture: &str) -> String {
+    format!("fixtures.test/{}:v1", fixture)
+}
+
+/// A request the mock API server received.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub query: String,
+    pub body: Value,
+}
+
+impl Request {
+    /// Returns true if this is a watch of pods.
+    pub fn is_pod_watch(&self) -> bool {
+        self.method == Method::GET && self.path.ends_with("/pods") && self.query.contains("watch=")
+    }
+}
+
+#[derive(Default)]
+struct ApiState {
+    pods: HashMap<(String, String), Value>,
+    requests: Vec<Request>,
+    cordoned: bool,
+}
+
+/// A mock API server, holding pods in memory. Patches are merged into the
+/// pods they are for, events are accepted, lists return the pods it holds and
+/// watches end straight away. Anything else is not found.
+#[derive(Clone)]
+pub struct MockApi {
+    addr: SocketAddr,
+    state: Arc<Mutex<ApiState>>,
+}
+
+impl MockApi {
+    pub fn start() -> Self {
+        let state: Arc<Mutex<ApiState>> = Default::default();
+        let handler = state.clone();
+        let routes = warp::method()
+            .and(warp::path::full())
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and(warp::body::bytes())
+            .map(
+                move |method: Method,
+                      path: warp::path::FullPath,
+                      query: String,
+                      body: warp::hyper::body::Bytes| {
+                    let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                    let (status, reply) =
+                        handler
+                            .lock()
+                            .unwrap()
+                            .handle(method, path.as_str(), &query, body);
+                    warp::http::Response::builder()
+                        .status(status)
+                        .header("content-type", "application/json")
+                        .body(reply.map(|r| r.to_string()).unwrap_or_default())
+                },
+            );
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        MockApi { addr, state }
+    }
+
+    /// A kubeconfig for talking to the server.
+    pub fn kubeconfig(&self) -> kube::Config {
+        kube::Config::new(format!("http://{}", self.addr).parse().unwrap())
+    }
+
+    /// Adds or replaces a pod.
+    pub fn put_pod(&self, pod: &Value) {
+        let key = (
+            pod["metadata"]["namespace"].as_str().unwrap().to_owned(),
+            pod["metadata"]["name"].as_str().unwrap().to_owned(),
+        );
+        self.state.lock().unwrap().pods.insert(key, pod.clone());
+    }
+
+    /// The pod as the server has it, with every patch applied.
+    pub fn pod(&self, name: &str) -> Option<Value> {
+        self.state
+            .lock()
+            .unwrap()
+            .pods
+            .get(&(NAMESPACE.to_owned(), name.to_owned()))
+            .cloned()
+    }
+
+    /// The phase the pod's status was last patched to.
+    pub fn phase(&self, name: &str) -> Option<String> {
+        self.pod(name)?["status"]["phase"]
+            .as_str()
+            .map(str::to_owned)
+    }
+
+    /// Every request received so far.
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Cordons the node, as `kubectl cordon` does.
+    pub fn cordon(&self) {
+        self.state.lock().unwrap().cordoned = true;
+    }
+
+    /// The reasons of the events recorded against a pod, in order.
+    pub fn event_reasons(&self, name: &str) -> Vec<String> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.method == Method::POST && r.path.ends_with("/events"))
+            .filter(|r| r.body["involvedObject"]["name"] == name)
+            .filter_map(|r| r.body["reason"].as_str().map(str::to_owned))
+            .collect()
+    }
+}
+
+impl ApiState {
+    fn handle(
+        &mut self,
+        method: Method,
+        path: &str,
+        query: &str,
+        body: Value,
+    ) -> (StatusCode, Option<Value>) {
+        self.requests.push(Request {
+            method: method.clone(),
+            path: path.to_owned(),
+            query: query.to_owned(),
+            body: body.clone(),
+        });
+        if query.contains("watch=") {
+            // An empty watch, which the provider starts again later
+            return (StatusCode::OK, None);
+        }
+        let (status, reply) = self.reply(&method, path, body);
+        (status, Some(reply))
+    }
+
+    fn reply(&mut self, method: &Method, path: &str, body: Value) -> (StatusCode, Value) {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["api", "v1", "namespaces", namespace, "pods", name, ..] => {
+                let key = ((*namespace).to_owned(), (*name).to_owned());
+                match self.pods.get_mut(&key) {
+                    Some(pod) => {
+                        if *method == Method::PATCH {
+                            merge(pod, &body);
+                        }
+                        (StatusCode::OK, pod.clone())
+                    }
+                    None => not_found(),
+                }
+            }
+            ["api", "v1", "pods"] | ["api", "v1", "namespaces", _, "pods"]
+                if *method == Method::GET =>
+            {
+                let items: Vec<Value> = self.pods.values().cloned().collect();
+                (
+                    StatusCode::OK,
+                    json!({
+                        "apiVersion": "v1",
+                        "kind": "PodList",
+                        "metadata": {"resourceVersion": "1"},
+                        "items": items,
+                    }),
+                )
+            }
+            ["api", "v1", "namespaces", _, "events"] if *method == Method::POST => {
+                (StatusCode::CREATED, body)
+            }
+            ["api", "v1", "nodes", name, ..] => (
+                StatusCode::OK,
+                json!({
+                    "apiVersion": "v1",
+                    "kind": "Node",
+                    "metadata": {"name": name, "labels": {}, "annotations": {}},
+                    "spec": {"unschedulable": self.cordoned},
+                }),
+            ),
+            _ => not_found(),
+        }
+    }
+}
+
+fn not_found() -> (StatusCode, Value) {
+    (
+        StatusCode::NOT_FOUND,
+        json!({
+            "apiVersion": "v1",
+            "kind": "Status",
+            "status": "Failure",
+            "message": "not found",
+            "reason": "NotFound",
+            "code": 404,
+        }),
+    )
+}
+
+/// Applies a JSON merge patch.
+fn merge(target: &mut Value, patch: &Value) {
+    match (target.as_object_mut(), patch.as_object()) {
+        (Some(target), Some(patch)) => {
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge(target.entry(key.clone()).or_insert(Value::Null), value);
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
+/// A pod that runs one fixture as a container named after the pod.
+pub fn pod(name: &str, fixture: &str, restart_policy: &str) -> Value {
+    json!({
+        "apiVersion": "v1",
+        "kind": "Pod",
+        "metadata": {
+            "name": name,
+            "namespace": NAMESPACE,
+            "uid": format!("{}-uid", name),
+        },
+        "spec": {
+            "nodeName": NODE_NAME,
+            "restartPolicy": restart_policy,
+            "containers": [{"name": name, "image": image(fixture)}],
+        },
+        "status": {"phase": "Pending"},
+    })
+}
+
+/// A copy of `pod` that the API server is deleting, with no grace period if
+/// `force` is set.
+pub fn deleting(pod: &Value, force: bool) -> Value {
+    let mut pod = pod.clone();
+    pod["metadata"]["deletionTimestamp"] = json!("2020-10-16T12:00:00Z");
+    pod["metadata"]["deletionGracePeriodSeconds"] = json!(if force { 0 } else { 30 });
+    pod
+}
+
+pub fn kubelet_pod(pod: &Value) -> Pod {
+    Pod::new(serde_json::from_value(pod.clone()).unwrap())
+}
+
+/// A provider for a node with its own data directory, talking to a mock API
+/// server and pulling modules from the fixtures.
+pub struct TestNode {
+    pub provider: WasiProvider,
+    pub api: MockApi,
+    pub clock: Arc<SimulatedClock>,
+    pub data_dir: tempfile::TempDir,
+    _modules: tempfile::TempDir,
+}
+
+impl TestNode {
+    pub async fn start() -> Self {
+        Self::with_config(ProviderConfig::default()).await
+    }
+
+    pub async fn with_config(mut config: ProviderConfig) -> Self {
+        let api = MockApi::start();
+        let modules = tempfile::tempdir().unwrap();
+        let mut index = HashMap::new();
+        for (name, wat) in FIXTURES {
+            let file = format!("{}.wasm", name);
+            std::fs::write(modules.path().join(&file), wat::parse_str(wat).unwrap()).unwrap();
+            index.insert(image(name), file);
+        }
+        std::fs::write(
+            modules.path().join(krustlet_wasm3::store::INDEX_FILE_NAME),
+            serde_json::to_vec(&index).unwrap(),
+        )
+        .unwrap();
+        config.module_source = ModuleSource::Directory(modules.path().to_owned());
+
+        let data_dir = tempfile::tempdir().unwrap();
+        let mut kubelet_config = kubelet::config::Config::default();
+        kubelet_config.node_name = NODE_NAME.to_owned();
+        kubelet_config.data_dir = data_dir.path().to_owned();
+        kubelet_config.max_pods = 10;
+        let clock = Arc::new(SimulatedClock::new(chrono::Utc::now()));
+        let provider = WasiProvider::builder()
+            .store(Arc::new(DirectoryStore::new(modules.path())))
+            .kubelet_config(&kubelet_config)
+            .kubeconfig(api.kubeconfig())
+            .provider_config(config)
+            .clock(clock.clone())
+            .build()
+            .await
+            .unwrap();
+        TestNode {
+            provider,
+            api,
+            clock,
+            data_dir,
+            _modules: modules,
+        }
+    }
+
+    /// Adds a pod to the API server and the provider, as the kubelet does
+    /// when a pod is assigned to the node.
+    pub async fn add(&self, pod: &Value) -> anyhow::Result<PodState> {
+        self.api.put_pod(pod);
+        self.provider.initialize_pod_state(&kubelet_pod(pod)).await
+    }
+
+    /// Adds a pod and runs it as the kubelet does, until it is deleted.
+    pub async fn run(&self, pod: &Value) -> RunningPod {
+        let state = self.add(pod).await.unwrap();
+        self.run_added(pod, state)
+    }
+
+    /// Runs a pod that was added with [`TestNode::add`].
+    pub fn run_added(&self, pod: &Value, state: PodState) -> RunningPod {
+        RunningPod::start(
+            kube::Client::new(self.api.kubeconfig()),
+            kubelet_pod(pod),
+            state,
+        )
+    }
+
+    /// Reads a container's log through the provider, as `kubectl logs` does.
+    pub async fn logs(&self, pod: &str, container: &str) -> anyhow::Result<String> {
+        let (body_tx, body) = warp::hyper::Body::channel();
+        let options = serde_json::from_value(json!({"follow": false})).unwrap();
+        let sender = kubelet::log::Sender::new(body_tx, options);
+        let provider = self.provider.clone();
+        let (pod, container) = (pod.to_owned(), container.to_owned());
+        let logs = tokio::spawn(async move {
+            provider
+                .logs(NAMESPACE.to_owned(), pod, container, sender)
+                .await
+        });
+        let bytes = warp::hyper::body::to_bytes(body).await?;
+        logs.await??;
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+
+    /// Waits until `check` holds, failing the test if it does not soon.
+    pub async fn until(&self, what: &str, mut check: impl FnMut(&Self) -> bool) {
+        self.eventually(what, || future::ready(check(self))).await
+    }
+
+    /// Like [`TestNode::until`], for checks that have to wait on the
+    /// provider themselves.
+    pub async fn eventually<F, C>(&self, what: &str, mut check: C)
+    where
+        C: FnMut() -> F,
+        F: Future<Output = bool>,
+    {
+        let waited = tokio::time::timeout(TEST_TIMEOUT, async {
+            while !check().await {
+                tokio::time::delay_for(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        assert!(waited.is_ok(), "timed out waiting for {}", what);
+    }
+
+    /// Waits for a pod's status to be patched to `phase`.
+    pub async fn phase(&self, pod: &str, phase: &str) {
+        self.until(&format!("pod {} to be {}", pod, phase), |node| {
+            node.api.phase(pod).as_deref() == Some(phase)
+        })
+        .await;
+    }
+
+    /// Moves the clock on until the provider has listed pods again, so it
+    /// has seen every change made to them through the API before this.
+    pub async fn relist(&self) {
+        let watches = self.pod_watches();
+        self.until("the provider to list pods again", |node| {
+            node.clock.advance(WATCH_RETRY_INTERVAL);
+            // The watch after the list that saw the changes
+            node.pod_watches() > watches + 1
+        })
+        .await;
+    }
+
+    fn pod_watches(&self) -> usize {
+        self.api
+            .requests()
+            .iter()
+            .filter(|r| r.is_pod_watch())
+            .count()
+    }
+
+    /// Waits for the provider to be waiting on the clock, then moves it on.
+    pub async fn advance(&self, by: Duration) {
+        self.until("the provider to wait on the clock", |node| {
+            node.clock.sleepers() > 0
+        })
+        .await;
+        self.clock.advance(by);
+    }
+}
+
+/// A pod's state machine, run as the kubelet runs it: from the provider's
+/// initial state until the pod finishes, then through its terminated state
+/// once the pod is deleted, and finally dropping its pod state.
+pub struct RunningPod {
+    finished: Option<oneshot::Receiver<Result<(), String>>>,
+    delete: Option<oneshot::Sender<Pod>>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl RunningPod {
+    fn start(client: kube::Client, pod: Pod, mut state: PodState) -> Self {
+        let (finished_tx, finished) = oneshot::channel();
+        let (delete, mut deleted) = oneshot::channel::<Pod>();
+        let task = tokio::spawn(async move {
+            let deleted_pod = {
+                let initial = <WasiProvider as Provider>::InitialState::default();
+                let run = Box::pin(kubelet::state::run_to_completion(
+                    &client, initial, &mut state, &pod,
+                ));
+                match future::select(run, &mut deleted).await {
+                    Either::Left((result, deleted)) => {
+                        let _ = finished_tx.send(result.map_err(|e| e.to_string()));
+                        deleted.await.ok()
+                    }
+                    Either::Right((deleted, _)) => deleted.ok(),
+                }
+            };
+            if let Some(pod) = deleted_pod {
+                let terminated = <WasiProvider as Provider>::TerminatedState::default();
+                kubelet::state::run_to_completion(&client, terminated, &mut state, &pod).await?;
+            }
+            state.async_drop().await;
+            Ok(())
+        });
+        RunningPod {
+            finished: Some(finished),
+            delete: Some(delete),
+            task,
+        }
+    }
+
+    /// Waits for the pod's state machine to finish before it is deleted.
+    pub async fn finished(&mut self) -> Result<(), String> {
+        let finished = self.finished.take().expect("already waited for the pod");
+        tokio::time::timeout(TEST_TIMEOUT, finished)
+            .await
+            .expect("the pod did not finish")
+            .expect("the pod was deleted before it finished")
+    }
+
+    /// Deletes the pod, as `deleting` returns it, and waits for its pod state
+    /// to be dropped.
+    pub async fn delete(mut self, pod: &Value) {
+        let _ = self.delete.take().unwrap().send(kubelet_pod(pod));
+        tokio::time::timeout(TEST_TIMEOUT, self.task)
+            .await
+            .expect("the pod was not cleaned up")
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,5 @@
+;; Traps as soon as it starts.
+(module
+  (memory (export "memory") 1)
+  (func (export "_start")
+    unreachable))
@@ -0,0 +1,38 @@
+;; Prints each environment variable it is given, one per line, and exits.
+(module
+  (import "wasi_snapshot_preview1" "environ_sizes_get"
+    (func $environ_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_get"
+    (func $environ_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 8) "\n")
+  (func (export "_start")
+    (local $i i32)
+    (local $count i32)
+    (local $ptr i32)
+    (local $len i32)
+    (drop (call $environ_sizes_get (i32.const 0) (i32.const 4)))
+    (local.set $count (i32.load (i32.const 0)))
+    ;; Pointers to the variables at 1024, the variables themselves at 4096
+    (drop (call $environ_get (i32.const 1024) (i32.const 4096)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $count)))
+        (local.set $ptr
+          (i32.load (i32.add (i32.const 1024) (i32.mul (local.get $i) (i32.const 4)))))
+        (local.set $len (i32.const 0))
+        (block $end
+          (loop $byte
+            (br_if $end (i32.eqz (i32.load8_u (i32.add (local.get $ptr) (local.get $len)))))
+            (local.set $len (i32.add (local.get $len) (i32.const 1)))
+            (br $byte)))
+        ;; The variable, then a newline
+        (i32.store (i32.const 16) (local.get $ptr))
+        (i32.store (i32.const 20) (local.get $len))
+        (i32.store (i32.const 24) (i32.const 8))
+        (i32.store (i32.const 28) (i32.const 1))
+        (drop (call $fd_write (i32.const 1) (i32.const 16) (i32.const 2) (i32.const 32)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))))
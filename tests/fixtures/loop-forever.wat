@@ -0,0 +1,6 @@
+;; Never returns from _start.
+(module
+  (memory (export "memory") 1)
+  (func (export "_start")
+    (loop $forever
+      (br $forever))))
@@ -0,0 +1,167 @@
+//! Job pods: what a run that completes or fails is reported as under each
+//! restart policy, and what a finished pod leaves behind.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{deleting, pod, TestNode};
+use krustlet_wasm3::ProviderConfig;
+use serde_json::json;
+
+/// A pod the Job controller creates for one completion of `job`.
+fn job_pod(job: &str, index: usize, fixture: &str, restart_policy: &str) -> serde_json::Value {
+    let mut pod = pod(&format!("{}-{}", job, index), fixture, restart_policy);
+    pod["metadata"]["labels"] = json!({ "job-name": job });
+    pod
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_module_that_exits_succeeds_under_either_job_restart_policy() {
+    let node = TestNode::start().await;
+    for (index, policy) in ["Never", "OnFailure"].iter().enumerate() {
+        let job = job_pod("hello", index, "hello", policy);
+        let name = job["metadata"]["name"].as_str().unwrap().to_owned();
+        let mut running = node.run(&job).await;
+        running.finished().await.unwrap();
+        node.phase(&name, "Succeeded").await;
+        running.delete(&deleting(&job, false)).await;
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn every_completion_of_a_job_succeeds() {
+    let node = TestNode::start().await;
+    let completions: Vec<_> = (0..3)
+        .map(|i| job_pod("hello", i, "hello", "OnFailure"))
+        .collect();
+    let mut running = Vec::new();
+    for job in &completions {
+        running.push(node.run(job).await);
+    }
+    for (job, running) in completions.iter().zip(running.iter_mut()) {
+        running.finished().await.unwrap();
+        node.phase(job["metadata"]["name"].as_str().unwrap(), "Succeeded")
+            .await;
+    }
+    for (job, running) in completions.iter().zip(running) {
+        running.delete(&deleting(job, false)).await;
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_module_that_traps_fails_for_good_if_it_is_never_restarted() {
+    let node = TestNode::start().await;
+    let job = job_pod("crash", 0, "crash", "Never");
+    let mut running = node.run(&job).await;
+
+    running.finished().await.unwrap();
+    node.phase("crash-0", "Failed").await;
+
+    running.delete(&deleting(&job, false)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_module_that_traps_is_restarted_on_failure() {
+    let node = TestNode::start().await;
+    let job = job_pod("crash", 0, "crash", "OnFailure");
+    let running = node.run(&job).await;
+
+    // Each restart waits out a backoff on the clock first
+    node.until("the module to be restarted", |node| {
+        node.clock.advance(Duration::from_secs(60));
+        node.api
+            .event_reasons("crash-0")
+            .iter()
+            .filter(|reason| *reason == "Unreachable")
+            .count()
+            >= 2
+    })
+    .await;
+    assert_ne!(node.api.phase("crash-0").as_deref(), Some("Failed"));
+
+    running.delete(&deleting(&job, true)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_finished_pods_handle_is_removed_after_its_ttl() {
+    let mut config = ProviderConfig::default();
+    config.finished_pod_ttl = Duration::from_secs(60);
+    let node = TestNode::with_config(config).await;
+    let job = job_pod("hello", 0, "hello", "Never");
+    let mut running = node.run(&job).await;
+    running.finished().await.unwrap();
+
+    // The handle, and so the log, is still there until the TTL has passed
+    assert_eq!(
+        node.logs("hello-0", "hello-0").await.unwrap(),
+        "hello, world\n"
+    );
+    node.clock.advance(Duration::from_secs(59));
+    assert!(node.logs("hello-0", "hello-0").await.is_ok());
+    node.clock.advance(Duration::from_secs(1));
+    node.eventually("the handle to be removed", || async {
+        node.logs("hello-0", "hello-0").await.is_err()
+    })
+    .await;
+
+    running.delete(&deleting(&job, false)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_deleted_pods_logs_and_store_are_removed() {
+    let node = TestNode::start().await;
+    let job = job_pod("hello", 0, "hello", "Never");
+    let logs = node.data_dir.path().join("wasi-logs/default/hello-0");
+    let kv = node.data_dir.path().join("kv/default/hello-0");
+    std::fs::create_dir_all(&kv).unwrap();
+    std::fs::write(kv.join("key"), "value").unwrap();
+
+    let mut running = node.run(&job).await;
+    running.finished().await.unwrap();
+    assert!(logs.exists());
+
+    running.delete(&deleting(&job, false)).await;
+    assert!(!logs.exists());
+    assert!(!kv.exists());
+    assert!(node.logs("hello-0", "hello-0").await.is_err());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_pod_admitted_before_the_node_was_cordoned_is_still_restarted() {
+    let node = TestNode::start().await;
+    let job = job_pod("crash", 0, "crash", "OnFailure");
+    let running = node.run(&job).await;
+    let crashes = |node: &TestNode| {
+        node.api
+            .event_reasons("crash-0")
+            .iter()
+            .filter(|reason| *reason == "Unreachable")
+            .count()
+    };
+    node.until("the module to crash", |node| crashes(node) >= 1)
+        .await;
+
+    node.api.cordon();
+    node.until("the node to report that it is cordoned", |node| {
+        node.clock.advance(Duration::from_secs(10));
+        node.api.requests().iter().any(|r| {
+            r.path.starts_with("/api/v1/nodes/") && r.body.to_string().contains("NodeCordoned")
+        })
+    })
+    .await;
+    let before = crashes(&node);
+    node.until("the module to be restarted", |node| {
+        node.clock.advance(Duration::from_secs(60));
+        crashes(node) > before
+    })
+    .await;
+    assert!(!node
+        .api
+        .event_reasons("crash-0")
+        .iter()
+        .any(|reason| reason == "NodeCordoned"));
+    assert_ne!(node.api.phase("crash-0").as_deref(), Some("Failed"));
+
+    running.delete(&deleting(&job, true)).await;
+}
@@ -0,0 +1,148 @@
+//! Pods run through the provider from being added to being deleted, with
+//! each of the fixture modules.
+
+mod common;
+
+use std::path::Path;
+
+use common::{deleting, pod, TestNode};
+use krustlet_wasm3::config::Executor;
+use krustlet_wasm3::ProviderConfig;
+use serde_json::json;
+
+#[tokio::test(threaded_scheduler)]
+async fn a_module_that_prints_and_exits_succeeds() {
+    let node = TestNode::start().await;
+    let hello = pod("hello", "hello", "Never");
+    let mut running = node.run(&hello).await;
+
+    running.finished().await.unwrap();
+    node.phase("hello", "Succeeded").await;
+    assert_eq!(node.logs("hello", "hello").await.unwrap(), "hello, world\n");
+
+    running.delete(&deleting(&hello, false)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_module_is_given_its_containers_environment() {
+    let node = TestNode::start().await;
+    let mut echo = pod("env-echo", "env-echo", "Never");
+    echo["spec"]["containers"][0]["env"] = json!([{"name": "GREETING", "value": "hi"}]);
+    let mut running = node.run(&echo).await;
+
+    running.finished().await.unwrap();
+    let log = node.logs("env-echo", "env-echo").await.unwrap();
+    assert!(
+        log.lines().any(|line| line == "GREETING=hi"),
+        "environment missing from {:?}",
+        log
+    );
+
+    running.delete(&deleting(&echo, false)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_module_that_traps_fails() {
+    let node = TestNode::start().await;
+    let crash = pod("crash", "crash", "Never");
+    let mut running = node.run(&crash).await;
+
+    running.finished().await.unwrap();
+    node.phase("crash", "Failed").await;
+    node.until("the trap to be reported", |node| {
+        node.api
+            .event_reasons("crash")
+            .iter()
+            .any(|reason| reason == "Unreachable")
+    })
+    .await;
+
+    running.delete(&deleting(&crash, false)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_module_that_never_returns_runs_until_it_is_deleted() {
+    // A pinned instance runs on a thread of its own, so the module's loop
+    // does not hold up the runtime's blocking pool when the test ends
+    let mut config = ProviderConfig::default();
+    config.executor = Executor::Pinned(vec![0]);
+    let node = TestNode::with_config(config).await;
+    let forever = pod("loop-forever", "loop-forever", "Always");
+    let running = node.run(&forever).await;
+
+    node.phase("loop-forever", "Running").await;
+
+    running.delete(&deleting(&forever, true)).await;
+    assert!(node.logs("loop-forever", "loop-forever").await.is_err());
+}
+
+/// What each instance of a container logged, oldest first.
+fn instance_logs(node: &TestNode, pod: &str, container: &str) -> Vec<String> {
+    let dir = Path::new("wasi-logs/default").join(pod).join(container);
+    let mut paths: Vec<_> = std::fs::read_dir(node.data_dir.path().join(dir))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| std::fs::read_to_string(path).unwrap())
+        .collect()
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_running_pod_is_reloaded_when_its_reload_annotation_changes() {
+    let node = TestNode::start().await;
+    // A reactor keeps running once `_start` returns, until it is stopped
+    let mut reactor = pod("reactor", "hello", "Always");
+    reactor["metadata"]["annotations"] = json!({"wasm3.krustlet.dev/reactor": "true"});
+    let running = node.run(&reactor).await;
+    node.phase("reactor", "Running").await;
+    // The pod as it started is seen first, so that it is not what reloads it
+    node.relist().await;
+
+    let mut reloaded = node.api.pod("reactor").unwrap();
+    reloaded["metadata"]["annotations"]["wasm3.krustlet.dev/reload"] = json!("1");
+    node.api.put_pod(&reloaded);
+    node.relist().await;
+
+    // The reload waits for the old instance to stop before it is reported,
+    // and the clock is no longer moved, so it cannot have given up on it
+    node.until("the pod to be reloaded", |node| {
+        node.api
+            .event_reasons("reactor")
+            .iter()
+            .any(|reason| reason == "Reloaded")
+    })
+    .await;
+    node.phase("reactor", "Running").await;
+    assert_eq!(
+        instance_logs(&node, "reactor", "reactor"),
+        vec!["hello, world\n", "hello, world\n"]
+    );
+
+    running.delete(&deleting(&reactor, false)).await;
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn a_pod_that_mounts_a_sub_path_is_rejected() {
+    let node = TestNode::start().await;
+    let mut hello = pod("hello", "hello", "Never");
+    hello["spec"]["volumes"] = json!([{"name": "data", "emptyDir": {}}]);
+    hello["spec"]["containers"][0]["volumeMounts"] =
+        json!([{"name": "data", "mountPath": "/data", "subPath": "hello"}]);
+    let mut running = node.run(&hello).await;
+
+    running.finished().await.unwrap();
+    node.phase("hello", "Failed").await;
+    node.until("the rejection to be reported", |node| {
+        node.api
+            .event_reasons("hello")
+            .iter()
+            .any(|reason| reason == "SubPathUnsupported")
+    })
+    .await;
+    assert!(node.logs("hello", "hello").await.is_err());
+
+    running.delete(&deleting(&hello, false)).await;
+}